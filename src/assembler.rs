@@ -1 +1,7 @@
+#[allow(clippy::module_inception)]
 pub mod assembler;
+pub mod dump;
+pub mod elf;
+pub mod listing;
+pub mod output;
+pub mod symbols;
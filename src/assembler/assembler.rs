@@ -1 +1,831 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
 
+use crate::{
+    assembler::symbols::{SymbolTable, Warning},
+    instructions::{itype::IInst, Inst},
+    machine::address::Address,
+    parser::{
+        expression::Expr,
+        parser::*,
+        span::{FileId, Span, Spanned},
+    },
+};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    #[default]
+    Little,
+}
+
+impl Endian {
+    pub fn u16_bytes(self, v: u16) -> [u8; 2] {
+        match self {
+            Endian::Big => v.to_be_bytes(),
+            Endian::Little => v.to_le_bytes(),
+        }
+    }
+    pub fn u32_bytes(self, v: u32) -> [u8; 4] {
+        match self {
+            Endian::Big => v.to_be_bytes(),
+            Endian::Little => v.to_le_bytes(),
+        }
+    }
+    pub fn u64_bytes(self, v: u64) -> [u8; 8] {
+        match self {
+            Endian::Big => v.to_be_bytes(),
+            Endian::Little => v.to_le_bytes(),
+        }
+    }
+    pub fn read_u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self {
+            Endian::Big => u16::from_be_bytes(b),
+            Endian::Little => u16::from_le_bytes(b),
+        }
+    }
+    pub fn read_u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self {
+            Endian::Big => u32::from_be_bytes(b),
+            Endian::Little => u32::from_le_bytes(b),
+        }
+    }
+    pub fn read_u64(self, b: &[u8]) -> u64 {
+        let b = [b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]];
+        match self {
+            Endian::Big => u64::from_be_bytes(b),
+            Endian::Little => u64::from_le_bytes(b),
+        }
+    }
+}
+
+/// Default start addresses for segments that don't give one explicitly.
+#[derive(Copy, Clone, Debug)]
+pub struct Layout {
+    pub text: u32,
+    pub data: u32,
+    pub ktext: u32,
+    pub kdata: u32,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            text: 0x0040_0000,
+            data: 0x1001_0000,
+            ktext: 0x8000_0180,
+            kdata: 0x9000_0000,
+        }
+    }
+}
+
+impl Layout {
+    pub fn mars() -> Layout {
+        Layout::default()
+    }
+    // SPIM puts .data at the bottom of the 0x10000000 region rather than above $gp
+    pub fn spim() -> Layout {
+        Layout {
+            data: 0x1000_0000,
+            ..Layout::default()
+        }
+    }
+    // MARS's "compact, text at address 0" configuration, for small memories on FPGAs
+    pub fn compact() -> Layout {
+        Layout {
+            text: 0x0000_0000,
+            data: 0x0000_2000,
+            ktext: 0x0000_4180,
+            kdata: 0x0000_5000,
+        }
+    }
+    pub fn named(name: &str) -> Option<Layout> {
+        match name.to_lowercase().as_ref() {
+            "mars" => Some(Layout::mars()),
+            "spim" => Some(Layout::spim()),
+            "compact" => Some(Layout::compact()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AssemblerOptions {
+    pub layout: Layout,
+    pub endian: Endian,
+    // leave references to undefined symbols for a linker instead of failing
+    pub relocatable: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SegmentKind {
+    Text,
+    KText,
+    Data,
+    KData,
+    // zeroed storage for `.extern` symbols
+    Bss,
+}
+
+impl SegmentKind {
+    pub fn is_text(self) -> bool {
+        match self {
+            SegmentKind::Text | SegmentKind::KText => true,
+            SegmentKind::Data | SegmentKind::KData | SegmentKind::Bss => false,
+        }
+    }
+}
+
+impl From<SegmentKind> for String {
+    fn from(k: SegmentKind) -> String {
+        match k {
+            SegmentKind::Text => ".text",
+            SegmentKind::KText => ".ktext",
+            SegmentKind::Data => ".data",
+            SegmentKind::KData => ".kdata",
+            SegmentKind::Bss => ".bss",
+        }
+        .to_owned()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AssembledSegment {
+    pub kind: SegmentKind,
+    pub start_address: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl AssembledSegment {
+    pub fn end_address(&self) -> u32 {
+        self.start_address.wrapping_add(self.bytes.len() as u32)
+    }
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.start_address && address < self.end_address()
+    }
+}
+
+/// One entry per emitted instruction word or data directive, ordered by address.
+#[derive(Copy, Clone, Debug)]
+pub struct LineEntry {
+    pub address: u32,
+    pub size: u32,
+    /// The size of each item in it: 4 for instructions, 2 for `.half` and so on, and 1
+    /// for strings and space.
+    pub item_size: u32,
+    pub span: Span,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RelocationKind {
+    Word32,
+    Half16,
+    Jump26,
+    Hi16,
+    Lo16,
+    Pc16,
+}
+
+/// A location whose contents were computed from `symbol + addend`, kept so that
+/// object writers can let a linker redo the arithmetic.
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    pub address: u32,
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Assembled {
+    pub endian: Endian,
+    pub segments: Vec<AssembledSegment>,
+    pub labels: BTreeMap<String, u32>,
+    pub globals: BTreeSet<String>,
+    pub line_table: Vec<LineEntry>,
+    pub relocations: Vec<Relocation>,
+    pub symbols: SymbolTable,
+}
+
+impl Assembled {
+    pub fn line_entry(&self, address: u32) -> Option<&LineEntry> {
+        let i = match self
+            .line_table
+            .binary_search_by_key(&address, |e| e.address)
+        {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let entry = &self.line_table[i];
+        if address < entry.address + entry.size {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+    // `__start` as in SPIM's startup code, otherwise `main`, otherwise the first instruction
+    pub fn entry_point(&self) -> u32 {
+        ["__start", "main"]
+            .iter()
+            .find_map(|l| self.labels.get(*l).copied())
+            .or_else(|| {
+                self.segments
+                    .iter()
+                    .find(|s| s.kind == SegmentKind::Text)
+                    .map(|s| s.start_address)
+            })
+            .unwrap_or(0)
+    }
+    pub fn span_for_address(&self, address: u32) -> Option<Span> {
+        self.line_entry(address).map(|e| e.span)
+    }
+    pub fn addresses_for_line(&self, file: FileId, line: u32) -> Vec<u32> {
+        self.line_table
+            .iter()
+            .filter(|e| e.span.file == file && e.span.line == line)
+            .map(|e| e.address)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AssembleError {
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl AssembleError {
+    fn new(span: Option<Span>, message: String) -> AssembleError {
+        AssembleError { span, message }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}: {}", span, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+fn start_of(start_address: &Option<Address>, default: u32) -> u32 {
+    match start_address {
+        Some(a) => a.numeric.map_or(0, |n| n.get()),
+        None => default,
+    }
+}
+
+fn align_up(address: u32, alignment: u32) -> u32 {
+    if alignment <= 1 {
+        return address;
+    }
+    (address + alignment - 1) & !(alignment - 1)
+}
+
+fn data_entry_len(entry: &DataEntry, address: u32) -> u32 {
+    match entry {
+        DataEntry::Alignment(a) => align_up(address, a.alignment) - address,
+        DataEntry::CString(c) => c.chars.1.len() as u32,
+        DataEntry::Bytes(b) => b.bytes.1.len() as u32,
+        DataEntry::Halfs(h) => 2 * h.halfs.1.len() as u32,
+        DataEntry::Words(w) => 4 * w.words.1.len() as u32,
+        DataEntry::DWords(d) => 8 * d.dwords.1.len() as u32,
+        DataEntry::Space(s) => s.spaces.1.len() as u32,
+    }
+}
+
+fn data_entry_labels(entry: &DataEntry) -> &Labels {
+    static NO_LABELS: Labels = None;
+    match entry {
+        DataEntry::Alignment(_) => &NO_LABELS,
+        DataEntry::CString(c) => &c.chars.0,
+        DataEntry::Bytes(b) => &b.bytes.0,
+        DataEntry::Halfs(h) => &h.halfs.0,
+        DataEntry::Words(w) => &w.words.0,
+        DataEntry::DWords(d) => &d.dwords.0,
+        DataEntry::Space(s) => &s.spaces.0,
+    }
+}
+
+fn text_padding(alignments: &[(usize, Spanned<DataAlignment>)], index: usize, address: u32) -> u32 {
+    alignments
+        .iter()
+        .filter(|(i, _)| *i == index)
+        .fold(address, |a, (_, alignment)| {
+            align_up(a, alignment.node.alignment)
+        })
+        - address
+}
+
+struct TextInput<'a> {
+    kind: SegmentKind,
+    start_address: &'a Option<Address>,
+    instructions: &'a [(Labels, Spanned<Inst>)],
+    alignments: &'a [(usize, Spanned<DataAlignment>)],
+    trailing_labels: &'a Labels,
+}
+
+struct DataInput<'a> {
+    kind: SegmentKind,
+    start_address: &'a Option<Address>,
+    data_entries: &'a [Spanned<DataEntry>],
+    trailing_labels: &'a Labels,
+}
+
+#[derive(Default)]
+struct Emitted {
+    line_table: Vec<LineEntry>,
+    relocations: Vec<Relocation>,
+    references: Vec<(String, Span)>,
+}
+
+struct Assembler<'a> {
+    options: &'a AssemblerOptions,
+    labels: BTreeMap<String, u32>,
+    definitions: BTreeMap<String, Span>,
+    warnings: Vec<Warning>,
+    texts: Vec<TextInput<'a>>,
+    datas: Vec<DataInput<'a>>,
+    externs: &'a [Spanned<ExternSymbol>],
+    // storage reserved for externs the program never defined: (address, size, span)
+    extern_area: Vec<(u32, u32, Span)>,
+}
+
+impl<'a> Assembler<'a> {
+    fn new(parsed: &'a Parsed, options: &'a AssemblerOptions) -> Assembler<'a> {
+        let mut texts = Vec::new();
+        for t in &parsed.text_segment {
+            texts.push(TextInput {
+                kind: SegmentKind::Text,
+                start_address: &t.start_address,
+                instructions: &t.instructions,
+                alignments: &t.alignments,
+                trailing_labels: &t.trailing_labels,
+            });
+        }
+        for t in &parsed.ktext_segment {
+            texts.push(TextInput {
+                kind: SegmentKind::KText,
+                start_address: &t.start_address,
+                instructions: &t.instructions,
+                alignments: &t.alignments,
+                trailing_labels: &t.trailing_labels,
+            });
+        }
+        let mut datas = Vec::new();
+        for d in &parsed.data_segment {
+            datas.push(DataInput {
+                kind: SegmentKind::Data,
+                start_address: &d.start_address,
+                data_entries: &d.data_entries,
+                trailing_labels: &d.trailing_labels,
+            });
+        }
+        for d in &parsed.kdata_segment {
+            datas.push(DataInput {
+                kind: SegmentKind::KData,
+                start_address: &d.start_address,
+                data_entries: &d.data_entries,
+                trailing_labels: &d.trailing_labels,
+            });
+        }
+        Assembler {
+            options,
+            labels: BTreeMap::new(),
+            definitions: BTreeMap::new(),
+            warnings: Vec::new(),
+            texts,
+            datas,
+            externs: &parsed.externs,
+            extern_area: Vec::new(),
+        }
+    }
+
+    fn default_start(&self, kind: SegmentKind) -> u32 {
+        let layout = &self.options.layout;
+        match kind {
+            SegmentKind::Text => layout.text,
+            SegmentKind::KText => layout.ktext,
+            SegmentKind::Data => layout.data,
+            SegmentKind::KData => layout.kdata,
+            SegmentKind::Bss => unreachable!("the extern area follows .data"),
+        }
+    }
+
+    fn define(&mut self, labels: &Labels, address: u32) -> Result<(), AssembleError> {
+        if let Some(labels) = labels {
+            for l in labels {
+                let name = match l.node.label {
+                    Some(ref name) => name.clone(),
+                    None => continue,
+                };
+                match self.labels.insert(name.clone(), address) {
+                    // harmless when both definitions land on the same address
+                    Some(previous) if previous == address => self.warnings.push(Warning {
+                        span: l.span,
+                        message: format!("Label defined more than once: {}", name),
+                    }),
+                    Some(_) => {
+                        return Err(AssembleError::new(
+                            Some(l.span),
+                            format!("Label defined more than once: {}", name),
+                        ))
+                    }
+                    None => {
+                        self.definitions.insert(name, l.span);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn text_starts(&mut self) -> Result<Vec<u32>, AssembleError> {
+        let mut cursors: BTreeMap<SegmentKind, u32> = BTreeMap::new();
+        let mut starts = Vec::new();
+        for i in 0..self.texts.len() {
+            let kind = self.texts[i].kind;
+            let default = *cursors
+                .entry(kind)
+                .or_insert_with(|| self.default_start(kind));
+            let mut address = start_of(self.texts[i].start_address, default);
+            starts.push(address);
+            let instructions = self.texts[i].instructions;
+            let alignments = self.texts[i].alignments;
+            for (index, (labels, inst)) in instructions.iter().enumerate() {
+                address += text_padding(alignments, index, address);
+                self.define(labels, address)?;
+                address += 4 * inst.node.expand().len() as u32;
+            }
+            address += text_padding(alignments, instructions.len(), address);
+            self.define(self.texts[i].trailing_labels, address)?;
+            cursors.insert(kind, address);
+        }
+        Ok(starts)
+    }
+
+    fn data_starts(&mut self) -> Result<Vec<u32>, AssembleError> {
+        let mut cursors: BTreeMap<SegmentKind, u32> = BTreeMap::new();
+        let mut starts = Vec::new();
+        for i in 0..self.datas.len() {
+            let kind = self.datas[i].kind;
+            let default = *cursors
+                .entry(kind)
+                .or_insert_with(|| self.default_start(kind));
+            let mut address = start_of(self.datas[i].start_address, default);
+            starts.push(address);
+            let entries = self.datas[i].data_entries;
+            for entry in entries {
+                self.define(data_entry_labels(&entry.node), address)?;
+                address += data_entry_len(&entry.node, address);
+            }
+            self.define(self.datas[i].trailing_labels, address)?;
+            cursors.insert(kind, address);
+        }
+        // undefined externs get zeroed storage after the last .data segment
+        let mut address = match cursors.get(&SegmentKind::Data) {
+            Some(a) => *a,
+            None => self.default_start(SegmentKind::Data),
+        };
+        for e in self.externs {
+            let size = match e.node.size {
+                Some(size) if !self.labels.contains_key(&e.node.name) => size,
+                _ => continue,
+            };
+            address = align_up(address, 4);
+            self.labels.insert(e.node.name.clone(), address);
+            self.definitions.insert(e.node.name.clone(), e.span);
+            self.extern_area.push((address, size, e.span));
+            address += size;
+        }
+        Ok(starts)
+    }
+
+    // None is an undefined symbol that a relocatable object leaves to the linker
+    fn resolve(&self, address: &Address, span: Span) -> Result<Option<u32>, AssembleError> {
+        match (&address.label, address.numeric) {
+            (Some(label), _) => match self.labels.get(label) {
+                Some(a) => Ok(Some(*a)),
+                None if self.options.relocatable => Ok(None),
+                None => Err(AssembleError::new(
+                    Some(span),
+                    format!("Undefined label: {}", label),
+                )),
+            },
+            (None, Some(n)) => Ok(Some(n.get())),
+            (None, None) => Ok(Some(0)),
+        }
+    }
+
+    fn encode(
+        &self,
+        inst: &Inst,
+        pc: u32,
+        span: Span,
+        out: &mut Emitted,
+    ) -> Result<u32, AssembleError> {
+        let mut relocate = |address: &Address, kind| {
+            if let Some(ref symbol) = address.label {
+                out.references.push((symbol.clone(), span));
+                out.relocations.push(Relocation {
+                    address: pc,
+                    kind,
+                    symbol: symbol.clone(),
+                    addend: 0,
+                });
+            }
+        };
+        match inst {
+            Inst::R(r) => Ok(u32::from(*r)),
+            Inst::C0(c) => Ok(u32::from(*c)),
+            Inst::IImm(i) => Ok(u32::from(i.clone())),
+            Inst::ILabel(i) => {
+                let kind = match i.opcode() {
+                    IInst::beq | IInst::bne => RelocationKind::Pc16,
+                    IInst::lui => RelocationKind::Hi16,
+                    _ => RelocationKind::Lo16,
+                };
+                relocate(i.label(), kind);
+                let target = match self.resolve(i.label(), span)? {
+                    Some(target) => target,
+                    None => return Ok(u32::from(i.resolve(0))),
+                };
+                let imm = match i.opcode() {
+                    IInst::beq | IInst::bne => {
+                        let offset = (target.wrapping_sub(pc.wrapping_add(4)) as i32) >> 2;
+                        if offset < i32::from(i16::MIN) || offset > i32::from(i16::MAX) {
+                            return Err(AssembleError::new(
+                                Some(span),
+                                format!("Branch target 0x{:08X} is out of range", target),
+                            ));
+                        }
+                        offset as u16
+                    }
+                    // %hi carries into the upper half when the low half is negative
+                    IInst::lui => (target.wrapping_add(0x8000) >> 16) as u16,
+                    _ => target as u16,
+                };
+                Ok(u32::from(i.resolve(imm)))
+            }
+            Inst::J(j) => {
+                relocate(j.address(), RelocationKind::Jump26);
+                let target = match self.resolve(j.address(), span)? {
+                    Some(target) => target,
+                    None => return Ok(j.encode_with_target(0)),
+                };
+                if target & 0xF000_0000 != pc.wrapping_add(4) & 0xF000_0000 {
+                    return Err(AssembleError::new(
+                        Some(span),
+                        format!("Jump target 0x{:08X} is outside the current region", target),
+                    ));
+                }
+                Ok(j.encode_with_target(target))
+            }
+            Inst::PImm(_) | Inst::PLabel(_) => unreachable!("pseudo instructions are expanded"),
+        }
+    }
+
+    fn emit_text(
+        &self,
+        input: &TextInput,
+        start: u32,
+        out: &mut Emitted,
+    ) -> Result<AssembledSegment, AssembleError> {
+        let endian = self.options.endian;
+        let mut bytes = Vec::new();
+        let mut address = start;
+        for (index, (_, inst)) in input.instructions.iter().enumerate() {
+            let padding = text_padding(input.alignments, index, address);
+            bytes.resize(bytes.len() + padding as usize, 0);
+            address += padding;
+            for expanded in inst.node.expand() {
+                let word = self.encode(&expanded, address, inst.span, out)?;
+                bytes.extend_from_slice(&endian.u32_bytes(word));
+                out.line_table.push(LineEntry {
+                    address,
+                    size: 4,
+                    item_size: 4,
+                    span: inst.span,
+                });
+                address += 4;
+            }
+        }
+        let padding = text_padding(input.alignments, input.instructions.len(), address);
+        bytes.resize(bytes.len() + padding as usize, 0);
+        Ok(AssembledSegment {
+            kind: input.kind,
+            start_address: start,
+            bytes,
+        })
+    }
+
+    fn evaluate(
+        &self,
+        expr: &Expr,
+        address: u32,
+        kind: Option<RelocationKind>,
+        span: Span,
+        out: &mut Emitted,
+    ) -> Result<i64, AssembleError> {
+        let relocatable = self.options.relocatable;
+        let lookup = |l: &str| match self.labels.get(l) {
+            Some(a) => Some(*a),
+            None if relocatable => Some(0),
+            None => None,
+        };
+        let value = expr
+            .evaluate(&lookup)
+            .map_err(|e| AssembleError::new(Some(span), e))?;
+        for symbol in expr.symbols() {
+            out.references.push((symbol.to_owned(), span));
+        }
+        match (kind, expr.symbol_offset()) {
+            (Some(kind), Some((Some(symbol), addend))) => out.relocations.push(Relocation {
+                address,
+                kind,
+                symbol: symbol.to_owned(),
+                addend,
+            }),
+            _ => {
+                if let Some(undefined) = expr
+                    .symbols()
+                    .iter()
+                    .find(|s| !self.labels.contains_key(**s))
+                {
+                    return Err(AssembleError::new(
+                        Some(span),
+                        format!(
+                            "Cannot relocate expression using undefined label: {}",
+                            undefined
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn emit_data(
+        &self,
+        input: &DataInput,
+        start: u32,
+        out: &mut Emitted,
+    ) -> Result<AssembledSegment, AssembleError> {
+        let endian = self.options.endian;
+        let mut bytes = Vec::new();
+        for entry in input.data_entries {
+            let address = start + bytes.len() as u32;
+            let span = entry.span;
+            match entry.node {
+                DataEntry::Alignment(_) => {
+                    let padding = data_entry_len(&entry.node, address);
+                    bytes.resize(bytes.len() + padding as usize, 0);
+                }
+                DataEntry::CString(ref c) => bytes.extend_from_slice(&c.chars.1),
+                DataEntry::Bytes(ref b) => {
+                    for byte in &b.bytes.1 {
+                        let at = start + bytes.len() as u32;
+                        let byte = match byte {
+                            DataValue::Literal(v) => *v,
+                            DataValue::Expression(e) => {
+                                self.evaluate(e, at, None, span, out)? as u8
+                            }
+                        };
+                        bytes.push(byte);
+                    }
+                }
+                DataEntry::Halfs(ref h) => {
+                    for half in &h.halfs.1 {
+                        let at = start + bytes.len() as u32;
+                        let half = match half {
+                            DataValue::Literal(v) => *v,
+                            DataValue::Expression(e) => {
+                                self.evaluate(e, at, Some(RelocationKind::Half16), span, out)?
+                                    as u16
+                            }
+                        };
+                        bytes.extend_from_slice(&endian.u16_bytes(half));
+                    }
+                }
+                DataEntry::Words(ref w) => {
+                    for word in &w.words.1 {
+                        let at = start + bytes.len() as u32;
+                        let word = match word {
+                            DataValue::Literal(v) => *v,
+                            DataValue::Expression(e) => {
+                                self.evaluate(e, at, Some(RelocationKind::Word32), span, out)?
+                                    as u32
+                            }
+                        };
+                        bytes.extend_from_slice(&endian.u32_bytes(word));
+                    }
+                }
+                DataEntry::DWords(ref d) => {
+                    for dword in &d.dwords.1 {
+                        let at = start + bytes.len() as u32;
+                        let dword = match dword {
+                            DataValue::Literal(v) => *v,
+                            DataValue::Expression(e) => {
+                                self.evaluate(e, at, None, span, out)? as u64
+                            }
+                        };
+                        bytes.extend_from_slice(&endian.u64_bytes(dword));
+                    }
+                }
+                DataEntry::Space(ref s) => bytes.extend_from_slice(&s.spaces.1),
+            }
+            let size = start + bytes.len() as u32 - address;
+            let item_size = match entry.node {
+                DataEntry::Halfs(_) => 2,
+                DataEntry::Words(_) => 4,
+                DataEntry::DWords(_) => 8,
+                _ => 1,
+            };
+            let padding = matches!(entry.node, DataEntry::Alignment(_));
+            if size > 0 && !padding {
+                out.line_table.push(LineEntry {
+                    address,
+                    size,
+                    item_size,
+                    span,
+                });
+            }
+        }
+        Ok(AssembledSegment {
+            kind: input.kind,
+            start_address: start,
+            bytes,
+        })
+    }
+}
+
+pub fn assemble(parsed: &Parsed, options: &AssemblerOptions) -> Result<Assembled, AssembleError> {
+    let mut assembler = Assembler::new(parsed, options);
+    let text_starts = assembler.text_starts()?;
+    let data_starts = assembler.data_starts()?;
+
+    let mut segments = Vec::new();
+    let mut out = Emitted::default();
+    for (input, start) in assembler.texts.iter().zip(text_starts) {
+        segments.push(assembler.emit_text(input, start, &mut out)?);
+    }
+    for (input, start) in assembler.datas.iter().zip(data_starts) {
+        segments.push(assembler.emit_data(input, start, &mut out)?);
+    }
+    if let Some(&(start, _, _)) = assembler.extern_area.first() {
+        let mut bytes = Vec::new();
+        for &(address, size, span) in &assembler.extern_area {
+            bytes.resize((address - start + size) as usize, 0);
+            out.line_table.push(LineEntry {
+                address,
+                size,
+                item_size: 1,
+                span,
+            });
+        }
+        segments.push(AssembledSegment {
+            kind: SegmentKind::Bss,
+            start_address: start,
+            bytes,
+        });
+    }
+    out.line_table.sort_by_key(|e| e.address);
+
+    let globals = parsed
+        .globals
+        .iter()
+        .map(|g| g.node.clone())
+        .chain(parsed.externs.iter().map(|e| e.node.name.clone()))
+        .collect();
+    let sizes = parsed
+        .externs
+        .iter()
+        .filter_map(|e| e.node.size.map(|size| (e.node.name.clone(), size)))
+        .collect();
+    let symbols = SymbolTable::build(
+        &assembler.labels,
+        &assembler.definitions,
+        &sizes,
+        &globals,
+        &segments,
+        &out.line_table,
+        out.references,
+        assembler.warnings,
+    );
+
+    Ok(Assembled {
+        endian: options.endian,
+        segments,
+        labels: assembler.labels,
+        globals,
+        line_table: out.line_table,
+        relocations: out.relocations,
+        symbols,
+    })
+}
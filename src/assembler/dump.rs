@@ -0,0 +1,189 @@
+use std::fmt::Write;
+
+use crate::assembler::{
+    assembler::{Assembled, SegmentKind},
+    output::regions,
+};
+
+// MARS's "Hexadecimal Text with addresses" puts this many words on a line
+const WORDS_PER_LINE: usize = 8;
+
+/// The formats offered by MARS's "Dump Memory" dialog. All of them are word
+/// oriented; the raw binary one always stores each word little-endian.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Binary,
+    HexText,
+    BinaryText,
+    AsciiText,
+    HexTextWithAddresses,
+}
+
+impl From<&str> for DumpFormat {
+    fn from(s: &str) -> DumpFormat {
+        match s.to_lowercase().as_ref() {
+            "binary" | "bin" => DumpFormat::Binary,
+            "hextext" | "hex" => DumpFormat::HexText,
+            "binarytext" | "bintext" => DumpFormat::BinaryText,
+            "asciitext" | "ascii" => DumpFormat::AsciiText,
+            "hexaddresses" | "hexwithaddresses" => DumpFormat::HexTextWithAddresses,
+            _ => panic!("No such dump format: {}", s),
+        }
+    }
+}
+
+impl From<DumpFormat> for String {
+    fn from(f: DumpFormat) -> String {
+        match f {
+            DumpFormat::Binary => "binary",
+            DumpFormat::HexText => "hextext",
+            DumpFormat::BinaryText => "binarytext",
+            DumpFormat::AsciiText => "asciitext",
+            DumpFormat::HexTextWithAddresses => "hexaddresses",
+        }
+        .to_owned()
+    }
+}
+
+fn ascii(b: u8) -> String {
+    match b {
+        0 => "\\0".to_owned(),
+        b'\t' => "\\t".to_owned(),
+        b'\n' => "\\n".to_owned(),
+        b'\r' => "\\r".to_owned(),
+        0x20..=0x7E => (b as char).to_string(),
+        _ => ".".to_owned(),
+    }
+}
+
+/// Formats `words`, the first of which lives at `address`.
+pub fn dump_words(address: u32, words: &[u32], format: DumpFormat) -> Vec<u8> {
+    let mut out = String::new();
+    match format {
+        DumpFormat::Binary => return words.iter().flat_map(|w| w.to_le_bytes()).collect(),
+        DumpFormat::HexText => {
+            for w in words {
+                writeln!(out, "{:08x}", w).unwrap();
+            }
+        }
+        DumpFormat::BinaryText => {
+            for w in words {
+                writeln!(out, "{:032b}", w).unwrap();
+            }
+        }
+        DumpFormat::AsciiText => {
+            for w in words {
+                let chars: Vec<String> = w.to_be_bytes().iter().map(|b| ascii(*b)).collect();
+                writeln!(
+                    out,
+                    "{:>2} {:>2} {:>2} {:>2}",
+                    chars[0], chars[1], chars[2], chars[3]
+                )
+                .unwrap();
+            }
+        }
+        DumpFormat::HexTextWithAddresses => {
+            for (i, line) in words.chunks(WORDS_PER_LINE).enumerate() {
+                let at = address.wrapping_add((i * WORDS_PER_LINE * 4) as u32);
+                write!(out, "0x{:08x}   ", at).unwrap();
+                for w in line {
+                    write!(out, " 0x{:08x}", w).unwrap();
+                }
+                out.push('\n');
+            }
+        }
+    }
+    out.into_bytes()
+}
+
+/// Dumps every segment of `kind` as one block running from its lowest to its highest
+/// address, or `None` when the program has no such segment.
+pub fn dump_segment(
+    assembled: &Assembled,
+    kind: SegmentKind,
+    format: DumpFormat,
+) -> Option<Vec<u8>> {
+    let name = String::from(kind);
+    let region = regions(assembled, false, 0)
+        .ok()?
+        .into_iter()
+        .find(|r| r.name == name)?;
+    let words: Vec<u32> = region
+        .bytes
+        .chunks(4)
+        .map(|c| {
+            let mut w = [0; 4];
+            w[..c.len()].copy_from_slice(c);
+            assembled.endian.read_u32(&w)
+        })
+        .collect();
+    Some(dump_words(region.address, &words, format))
+}
+
+fn hex_word(s: &str) -> Option<u32> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Reads a dump back into runs of consecutive words. Formats without addresses are
+/// placed at `address`; ASCII dumps are lossy and can't be read.
+pub fn read_dump(
+    bytes: &[u8],
+    format: DumpFormat,
+    address: u32,
+) -> Result<Vec<(u32, Vec<u32>)>, String> {
+    let text = || std::str::from_utf8(bytes).map_err(|_| "Dump is not valid text".to_owned());
+    let radix_words = |radix: u32| -> Result<Vec<(u32, Vec<u32>)>, String> {
+        let mut words = Vec::new();
+        for (n, line) in text()?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let digits = line.strip_prefix("0x").unwrap_or(line);
+            match u32::from_str_radix(digits, radix) {
+                Ok(w) => words.push(w),
+                Err(_) => return Err(format!("Line {}: `{}` is not a word", n + 1, line)),
+            }
+        }
+        Ok(vec![(address, words)])
+    };
+    match format {
+        DumpFormat::Binary => {
+            if bytes.len() % 4 != 0 {
+                return Err(format!(
+                    "Binary dump of {} bytes is not whole words",
+                    bytes.len()
+                ));
+            }
+            let words = bytes
+                .chunks(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            Ok(vec![(address, words)])
+        }
+        DumpFormat::HexText => radix_words(16),
+        DumpFormat::BinaryText => radix_words(2),
+        DumpFormat::AsciiText => Err("ASCII dumps cannot be read back".to_owned()),
+        DumpFormat::HexTextWithAddresses => {
+            let mut runs: Vec<(u32, Vec<u32>)> = Vec::new();
+            for line in text()?.lines() {
+                let mut tokens = line.split_whitespace();
+                // column headers and blank lines have no leading address
+                let at = match tokens.next().and_then(hex_word) {
+                    Some(at) => at,
+                    None => continue,
+                };
+                // text segment lines go on with the disassembly after the code column
+                let words: Vec<u32> = tokens.map_while(hex_word).collect();
+                match runs.last_mut() {
+                    Some((start, run)) if start.wrapping_add(run.len() as u32 * 4) == at => {
+                        run.extend(words)
+                    }
+                    _ => runs.push((at, words)),
+                }
+            }
+            Ok(runs)
+        }
+    }
+}
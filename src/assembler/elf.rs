@@ -0,0 +1,567 @@
+use std::collections::BTreeMap;
+
+use crate::assembler::assembler::{Assembled, Endian, Relocation, RelocationKind, SegmentKind};
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_MIPS: u16 = 8;
+
+const EF_MIPS_NOREORDER: u32 = 0x0000_0001;
+const EF_MIPS_ABI_O32: u32 = 0x0000_1000;
+const EF_MIPS_ARCH_32: u32 = 0x5000_0000;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+
+const SHF_WRITE: u32 = 0x1;
+const SHF_ALLOC: u32 = 0x2;
+const SHF_EXECINSTR: u32 = 0x4;
+const SHF_INFO_LINK: u32 = 0x40;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+const R_MIPS_16: u32 = 1;
+const R_MIPS_32: u32 = 2;
+const R_MIPS_26: u32 = 4;
+const R_MIPS_HI16: u32 = 5;
+const R_MIPS_LO16: u32 = 6;
+const R_MIPS_PC16: u32 = 10;
+
+const EHDR_SIZE: u32 = 52;
+const PHDR_SIZE: u32 = 32;
+const SHDR_SIZE: u32 = 40;
+const SYM_SIZE: u32 = 16;
+const REL_SIZE: u32 = 8;
+const PAGE_SIZE: u32 = 0x1000;
+
+impl From<RelocationKind> for u32 {
+    fn from(k: RelocationKind) -> u32 {
+        match k {
+            RelocationKind::Half16 => R_MIPS_16,
+            RelocationKind::Word32 => R_MIPS_32,
+            RelocationKind::Jump26 => R_MIPS_26,
+            RelocationKind::Hi16 => R_MIPS_HI16,
+            RelocationKind::Lo16 => R_MIPS_LO16,
+            RelocationKind::Pc16 => R_MIPS_PC16,
+        }
+    }
+}
+
+struct Buffer {
+    endian: Endian,
+    bytes: Vec<u8>,
+}
+
+impl Buffer {
+    fn new(endian: Endian) -> Buffer {
+        Buffer {
+            endian,
+            bytes: Vec::new(),
+        }
+    }
+    fn len(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+    fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        let b = self.endian.u16_bytes(v);
+        self.bytes.extend_from_slice(&b);
+    }
+    fn u32(&mut self, v: u32) {
+        let b = self.endian.u32_bytes(v);
+        self.bytes.extend_from_slice(&b);
+    }
+    fn pad_to(&mut self, offset: u32) {
+        self.bytes.resize(offset as usize, 0);
+    }
+    fn align(&mut self, alignment: u32) {
+        let offset = self.len().div_ceil(alignment) * alignment;
+        self.pad_to(offset);
+    }
+}
+
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable { bytes: vec![0] }
+    }
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(s.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+// All assembled segments of one kind, merged into a single contiguous section.
+struct Section {
+    kind: SegmentKind,
+    address: u32,
+    bytes: Vec<u8>,
+}
+
+impl Section {
+    fn end_address(&self) -> u32 {
+        self.address + self.bytes.len() as u32
+    }
+    fn flags(&self) -> u32 {
+        match self.kind {
+            SegmentKind::Text | SegmentKind::KText => SHF_ALLOC | SHF_EXECINSTR,
+            SegmentKind::Data | SegmentKind::KData | SegmentKind::Bss => SHF_ALLOC | SHF_WRITE,
+        }
+    }
+    fn section_type(&self) -> u32 {
+        match self.kind {
+            SegmentKind::Bss => SHT_NOBITS,
+            _ => SHT_PROGBITS,
+        }
+    }
+    fn file_size(&self) -> u32 {
+        match self.kind {
+            SegmentKind::Bss => 0,
+            _ => self.bytes.len() as u32,
+        }
+    }
+    fn patch_u16(&mut self, endian: Endian, offset: usize, v: u16) {
+        self.bytes[offset..offset + 2].copy_from_slice(&endian.u16_bytes(v));
+    }
+    fn patch_u32(&mut self, endian: Endian, offset: usize, v: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&endian.u32_bytes(v));
+    }
+}
+
+fn sections(assembled: &Assembled) -> Vec<Section> {
+    let mut by_kind: BTreeMap<SegmentKind, Vec<_>> = BTreeMap::new();
+    for s in &assembled.segments {
+        if !s.bytes.is_empty() {
+            by_kind.entry(s.kind).or_default().push(s);
+        }
+    }
+    by_kind
+        .into_iter()
+        .map(|(kind, segments)| {
+            let address = segments.iter().map(|s| s.start_address).min().unwrap();
+            let end = segments.iter().map(|s| s.end_address()).max().unwrap();
+            let mut bytes = vec![0; (end - address) as usize];
+            for s in segments {
+                let offset = (s.start_address - address) as usize;
+                bytes[offset..offset + s.bytes.len()].copy_from_slice(&s.bytes);
+            }
+            Section {
+                kind,
+                address,
+                bytes,
+            }
+        })
+        .collect()
+}
+
+// Index into `sections` of the section a symbol at `address` belongs to. A label just past
+// the end of a section (like one trailing the last instruction) still counts as inside it.
+fn section_of(sections: &[Section], address: u32) -> Option<usize> {
+    sections
+        .iter()
+        .position(|s| address >= s.address && address < s.end_address())
+        .or_else(|| sections.iter().position(|s| address == s.end_address()))
+}
+
+struct Symbol {
+    name: u32,
+    value: u32,
+    size: u32,
+    section: u16,
+    binding: u8,
+    kind: u8,
+}
+
+struct SymbolTable {
+    symbols: Vec<Symbol>,
+    indices: BTreeMap<String, u32>,
+    first_global: u32,
+}
+
+// Section symbols first, then local labels, then globals, as ELF requires locals to lead.
+fn symbol_table(
+    assembled: &Assembled,
+    sections: &[Section],
+    strtab: &mut StringTable,
+    relative: bool,
+) -> SymbolTable {
+    let mut symbols = vec![Symbol {
+        name: 0,
+        value: 0,
+        size: 0,
+        section: SHN_UNDEF,
+        binding: STB_LOCAL,
+        kind: STT_NOTYPE,
+    }];
+    for (i, s) in sections.iter().enumerate() {
+        symbols.push(Symbol {
+            name: 0,
+            value: if relative { 0 } else { s.address },
+            size: 0,
+            section: i as u16 + 1,
+            binding: STB_LOCAL,
+            kind: STT_SECTION,
+        });
+    }
+    let mut indices = BTreeMap::new();
+    let mut label = |name: &str, address: u32, binding: u8, symbols: &mut Vec<Symbol>| {
+        let (section, value) = match section_of(sections, address) {
+            Some(i) if relative => (i as u16 + 1, address - sections[i].address),
+            Some(i) => (i as u16 + 1, address),
+            None => (SHN_ABS, address),
+        };
+        indices.insert(name.to_owned(), symbols.len() as u32);
+        symbols.push(Symbol {
+            name: strtab.add(name),
+            value,
+            size: assembled.symbols.get(name).map_or(0, |s| s.size),
+            section,
+            binding,
+            kind: STT_NOTYPE,
+        });
+    };
+    for (name, address) in &assembled.labels {
+        if !assembled.globals.contains(name) {
+            label(name, *address, STB_LOCAL, &mut symbols);
+        }
+    }
+    let first_global = symbols.len() as u32;
+    for (name, address) in &assembled.labels {
+        if assembled.globals.contains(name) {
+            label(name, *address, STB_GLOBAL, &mut symbols);
+        }
+    }
+    let mut undefined: Vec<&str> = assembled
+        .globals
+        .iter()
+        .map(|g| g.as_str())
+        .chain(assembled.relocations.iter().map(|r| r.symbol.as_str()))
+        .filter(|s| !assembled.labels.contains_key(*s))
+        .collect();
+    undefined.sort_unstable();
+    undefined.dedup();
+    for name in undefined {
+        indices.insert(name.to_owned(), symbols.len() as u32);
+        symbols.push(Symbol {
+            name: strtab.add(name),
+            value: 0,
+            size: 0,
+            section: SHN_UNDEF,
+            binding: STB_GLOBAL,
+            kind: STT_NOTYPE,
+        });
+    }
+    SymbolTable {
+        symbols,
+        indices,
+        first_global,
+    }
+}
+
+// Rewrites the relocated field so it holds only the addend, which is what a REL entry
+// expects; the assembler already filled in absolute values.
+fn patch(section: &mut Section, endian: Endian, offset: usize, kind: RelocationKind, value: u32) {
+    match kind {
+        RelocationKind::Word32 => section.patch_u32(endian, offset, value),
+        RelocationKind::Half16 => section.patch_u16(endian, offset, value as u16),
+        _ => {
+            let word = endian.read_u32(&section.bytes[offset..]);
+            let word = match kind {
+                RelocationKind::Jump26 => (word & 0xFC00_0000) | ((value >> 2) & 0x03FF_FFFF),
+                RelocationKind::Hi16 => (word & 0xFFFF_0000) | (value.wrapping_add(0x8000) >> 16),
+                RelocationKind::Lo16 => (word & 0xFFFF_0000) | (value & 0xFFFF),
+                _ => (word & 0xFFFF_0000) | ((value.wrapping_sub(4) >> 2) & 0xFFFF),
+            };
+            section.patch_u32(endian, offset, word);
+        }
+    }
+}
+
+fn relocate(
+    assembled: &Assembled,
+    sections: &mut [Section],
+    symbols: &SymbolTable,
+) -> Vec<Vec<(u32, u32)>> {
+    let endian = assembled.endian;
+    let mut entries = vec![Vec::new(); sections.len()];
+    for r in &assembled.relocations {
+        let Relocation {
+            address,
+            kind,
+            ref symbol,
+            addend,
+        } = *r;
+        let index = match section_of(sections, address) {
+            Some(i) => i,
+            None => continue,
+        };
+        let offset = address - sections[index].address;
+        let global = assembled.globals.contains(symbol);
+        let (symbol_index, value) = match assembled.labels.get(symbol) {
+            Some(&target) if !global => match section_of(sections, target) {
+                // a branch within its own section doesn't move relative to its target
+                Some(t) if t == index && kind == RelocationKind::Pc16 => continue,
+                Some(t) => (
+                    t as u32 + 1,
+                    (target - sections[t].address).wrapping_add(addend as u32),
+                ),
+                None => continue,
+            },
+            _ => (symbols.indices[symbol], addend as u32),
+        };
+        patch(&mut sections[index], endian, offset as usize, kind, value);
+        entries[index].push((offset, (symbol_index << 8) | u32::from(kind)));
+    }
+    entries
+}
+
+struct SectionHeader {
+    name: u32,
+    section_type: u32,
+    flags: u32,
+    address: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    alignment: u32,
+    entry_size: u32,
+}
+
+fn write_header(out: &mut Buffer, file_type: u16, entry: u32, phnum: u16, shoff: u32, shnum: u16) {
+    out.bytes[..4].copy_from_slice(b"\x7FELF");
+    out.bytes[4] = 1; // ELFCLASS32
+    out.bytes[5] = match out.endian {
+        Endian::Little => 1,
+        Endian::Big => 2,
+    };
+    out.bytes[6] = 1; // EV_CURRENT
+    let mut header = Buffer::new(out.endian);
+    header.u16(file_type);
+    header.u16(EM_MIPS);
+    header.u32(1);
+    header.u32(entry);
+    header.u32(if phnum > 0 { EHDR_SIZE } else { 0 });
+    header.u32(shoff);
+    header.u32(EF_MIPS_ARCH_32 | EF_MIPS_ABI_O32 | EF_MIPS_NOREORDER);
+    header.u16(EHDR_SIZE as u16);
+    header.u16(PHDR_SIZE as u16);
+    header.u16(phnum);
+    header.u16(SHDR_SIZE as u16);
+    header.u16(shnum);
+    header.u16(shnum - 1); // .shstrtab is always last
+    out.bytes[16..EHDR_SIZE as usize].copy_from_slice(&header.bytes);
+}
+
+fn write_symbols(out: &mut Buffer, symbols: &[Symbol]) {
+    for s in symbols {
+        out.u32(s.name);
+        out.u32(s.value);
+        out.u32(s.size);
+        out.u8((s.binding << 4) | s.kind);
+        out.u8(0);
+        out.u16(s.section);
+    }
+}
+
+fn write(assembled: &Assembled, executable: bool) -> Vec<u8> {
+    let endian = assembled.endian;
+    let mut sections = sections(assembled);
+    let mut strtab = StringTable::new();
+    let mut shstrtab = StringTable::new();
+    let symbols = symbol_table(assembled, &sections, &mut strtab, !executable);
+    let relocations = if executable {
+        vec![Vec::new(); sections.len()]
+    } else {
+        relocate(assembled, &mut sections, &symbols)
+    };
+
+    let mut out = Buffer::new(endian);
+    out.pad_to(EHDR_SIZE);
+    let phnum = if executable { sections.len() as u32 } else { 0 };
+    out.pad_to(EHDR_SIZE + phnum * PHDR_SIZE);
+
+    let mut headers = Vec::new();
+    for s in &sections {
+        let name = String::from(s.kind);
+        if executable {
+            // loaders map whole pages, so file offsets have to agree with addresses
+            let offset = out.len();
+            let offset = offset + (s.address.wrapping_sub(offset) % PAGE_SIZE);
+            out.pad_to(offset);
+        } else {
+            out.align(16);
+        }
+        headers.push(SectionHeader {
+            name: shstrtab.add(&name),
+            section_type: s.section_type(),
+            flags: s.flags(),
+            address: if executable { s.address } else { 0 },
+            offset: out.len(),
+            size: s.bytes.len() as u32,
+            link: 0,
+            info: 0,
+            alignment: 16,
+            entry_size: 0,
+        });
+        out.bytes
+            .extend_from_slice(&s.bytes[..s.file_size() as usize]);
+    }
+
+    let symtab_index =
+        (sections.len() + relocations.iter().filter(|r| !r.is_empty()).count()) as u32 + 1;
+    for (i, entries) in relocations.iter().enumerate() {
+        if entries.is_empty() {
+            continue;
+        }
+        out.align(4);
+        let name = format!(".rel{}", String::from(sections[i].kind));
+        headers.push(SectionHeader {
+            name: shstrtab.add(&name),
+            section_type: SHT_REL,
+            flags: SHF_INFO_LINK,
+            address: 0,
+            offset: out.len(),
+            size: entries.len() as u32 * REL_SIZE,
+            link: symtab_index,
+            info: i as u32 + 1,
+            alignment: 4,
+            entry_size: REL_SIZE,
+        });
+        for &(offset, info) in entries {
+            out.u32(offset);
+            out.u32(info);
+        }
+    }
+
+    out.align(4);
+    headers.push(SectionHeader {
+        name: shstrtab.add(".symtab"),
+        section_type: SHT_SYMTAB,
+        flags: 0,
+        address: 0,
+        offset: out.len(),
+        size: symbols.symbols.len() as u32 * SYM_SIZE,
+        link: symtab_index + 1,
+        info: symbols.first_global,
+        alignment: 4,
+        entry_size: SYM_SIZE,
+    });
+    write_symbols(&mut out, &symbols.symbols);
+
+    headers.push(SectionHeader {
+        name: shstrtab.add(".strtab"),
+        section_type: SHT_STRTAB,
+        flags: 0,
+        address: 0,
+        offset: out.len(),
+        size: strtab.bytes.len() as u32,
+        link: 0,
+        info: 0,
+        alignment: 1,
+        entry_size: 0,
+    });
+    out.bytes.extend_from_slice(&strtab.bytes);
+
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    headers.push(SectionHeader {
+        name: shstrtab_name,
+        section_type: SHT_STRTAB,
+        flags: 0,
+        address: 0,
+        offset: out.len(),
+        size: shstrtab.bytes.len() as u32,
+        link: 0,
+        info: 0,
+        alignment: 1,
+        entry_size: 0,
+    });
+    out.bytes.extend_from_slice(&shstrtab.bytes);
+
+    out.align(4);
+    let shoff = out.len();
+    for _ in 0..SHDR_SIZE {
+        out.u8(0); // SHN_UNDEF
+    }
+    for h in &headers {
+        out.u32(h.name);
+        out.u32(h.section_type);
+        out.u32(h.flags);
+        out.u32(h.address);
+        out.u32(h.offset);
+        out.u32(h.size);
+        out.u32(h.link);
+        out.u32(h.info);
+        out.u32(h.alignment);
+        out.u32(h.entry_size);
+    }
+
+    if executable {
+        let mut phdrs = Buffer::new(endian);
+        for (s, h) in sections.iter().zip(&headers) {
+            let flags = if s.flags() & SHF_EXECINSTR != 0 {
+                PF_R | PF_X
+            } else {
+                PF_R | PF_W
+            };
+            phdrs.u32(PT_LOAD);
+            phdrs.u32(h.offset);
+            phdrs.u32(s.address);
+            phdrs.u32(s.address);
+            phdrs.u32(s.file_size());
+            phdrs.u32(s.bytes.len() as u32);
+            phdrs.u32(flags);
+            phdrs.u32(PAGE_SIZE);
+        }
+        let start = EHDR_SIZE as usize;
+        out.bytes[start..start + phdrs.bytes.len()].copy_from_slice(&phdrs.bytes);
+    }
+
+    let (file_type, entry) = if executable {
+        (ET_EXEC, assembled.entry_point())
+    } else {
+        (ET_REL, 0)
+    };
+    let shnum = headers.len() as u16 + 1;
+    write_header(&mut out, file_type, entry, phnum as u16, shoff, shnum);
+    out.bytes
+}
+
+/// A relocatable object with `.rel` sections for every symbolic reference, for `ld`.
+pub fn write_object(assembled: &Assembled) -> Vec<u8> {
+    write(assembled, false)
+}
+
+/// A statically linked executable with a `PT_LOAD` header per section. Fails if the
+/// program still refers to symbols it never defined.
+pub fn write_executable(assembled: &Assembled) -> Result<Vec<u8>, String> {
+    if let Some(r) = assembled
+        .relocations
+        .iter()
+        .find(|r| !assembled.labels.contains_key(&r.symbol))
+    {
+        return Err(format!("Undefined symbol: {}", r.symbol));
+    }
+    Ok(write(assembled, true))
+}
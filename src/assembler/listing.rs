@@ -0,0 +1,183 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    assembler::assembler::{Assembled, LineEntry},
+    machine::state::disassemble,
+    parser::span::{FileId, SourceMap},
+};
+
+// a `.space 4096` shouldn't turn into a thousand lines
+const MAX_DATA_ROWS: usize = 4;
+
+fn row(out: &mut String, address: Option<u32>, code: &str, line: Option<u32>, text: &str) {
+    let address = address.map_or(String::new(), |a| format!("0x{:08x}", a));
+    let line = line.map_or(String::new(), |l| l.to_string());
+    let row = format!("{:<10} {:<10} {:>5}  {}", address, code, line, text);
+    out.push_str(row.trim_end());
+    out.push('\n');
+}
+
+// one row of data: up to four bytes in the order they're stored, or one bigger item
+// read as a number
+fn chunk(assembled: &Assembled, bytes: &[u8], item_size: u32) -> String {
+    let endian = assembled.endian;
+    match item_size {
+        2 => format!("0x{:04x}", endian.read_u16(bytes)),
+        4 => format!("0x{:08x}", endian.read_u32(bytes)),
+        8 => format!("0x{:016x}", endian.read_u64(bytes)),
+        _ => bytes
+            .iter()
+            .fold("0x".to_owned(), |s, b| s + &format!("{:02x}", b)),
+    }
+}
+
+fn bytes_at<'a>(assembled: &'a Assembled, entry: &LineEntry) -> &'a [u8] {
+    assembled
+        .segments
+        .iter()
+        .find(|s| s.contains(entry.address))
+        .map_or(&[], |s| {
+            let start = (entry.address - s.start_address) as usize;
+            &s.bytes[start..start + entry.size as usize]
+        })
+}
+
+// A line can hold several statements separated by `;`, each listed on its own row. A
+// pseudo-instruction is listed with what it expanded to indented under it.
+fn list_instructions(
+    out: &mut String,
+    assembled: &Assembled,
+    sources: &SourceMap,
+    entries: &[&LineEntry],
+    line: u32,
+    text: &str,
+) {
+    let mnemonic = |s: &str| s.split_whitespace().next().unwrap_or("").to_lowercase();
+    let mut first = true;
+    let mut rest = entries;
+    while let Some(entry) = rest.first() {
+        let count = rest.iter().take_while(|e| e.span == entry.span).count();
+        let (statement, after) = rest.split_at(count);
+        rest = after;
+        let words: Vec<(u32, u32)> = statement
+            .iter()
+            .map(|e| (e.address, assembled.endian.read_u32(bytes_at(assembled, e))))
+            .collect();
+        let expansion: Vec<String> = words.iter().map(|(a, w)| disassemble(*w, *a)).collect();
+        let source = sources.snippet(entry.span).unwrap_or(text);
+        // the line's number and text go on its first row, and later statements show
+        // just their own text
+        let (line, text) = if first {
+            (Some(line), text)
+        } else {
+            (None, source)
+        };
+        first = false;
+        if words.len() == 1 && mnemonic(source) == mnemonic(&expansion[0]) {
+            let code = format!("0x{:08x}", words[0].1);
+            row(out, Some(words[0].0), &code, line, text);
+            continue;
+        }
+        row(out, None, "", line, text);
+        for ((address, word), inst) in words.iter().zip(expansion) {
+            let code = format!("0x{:08x}", word);
+            row(out, Some(*address), &code, None, &format!("    {}", inst));
+        }
+    }
+}
+
+fn list_data(
+    out: &mut String,
+    assembled: &Assembled,
+    entries: &[&LineEntry],
+    line: u32,
+    text: &str,
+) {
+    let mut first = true;
+    for entry in entries {
+        let bytes = bytes_at(assembled, entry);
+        let per_row = match entry.item_size {
+            1 => 4,
+            size => size,
+        };
+        for (i, c) in bytes.chunks(per_row as usize).enumerate() {
+            if i == MAX_DATA_ROWS {
+                row(out, None, "...", None, "");
+                break;
+            }
+            let address = Some(entry.address + per_row * i as u32);
+            let code = chunk(assembled, c, entry.item_size);
+            if first {
+                row(out, address, &code, Some(line), text);
+                first = false;
+            } else {
+                row(out, address, &code, None, "");
+            }
+        }
+    }
+    if first {
+        row(out, None, "", Some(line), text);
+    }
+}
+
+/// Builds a listing of every source line with the addresses and words it produced,
+/// followed by the symbol table.
+pub fn write_listing(assembled: &Assembled, sources: &SourceMap) -> String {
+    let mut by_line: BTreeMap<(FileId, u32), Vec<&LineEntry>> = BTreeMap::new();
+    for entry in &assembled.line_table {
+        by_line
+            .entry((entry.span.file, entry.span.line))
+            .or_default()
+            .push(entry);
+    }
+    let mut out = String::new();
+    for (file, source) in sources.files() {
+        writeln!(out, "{}\n", source.name).unwrap();
+        writeln!(
+            out,
+            "{:<10} {:<10} {:>5}  Source",
+            "Address", "Code", "Line"
+        )
+        .unwrap();
+        for (i, text) in source.contents.lines().enumerate() {
+            let line = i as u32 + 1;
+            let entries = match by_line.get(&(file, line)) {
+                Some(entries) => entries,
+                None => {
+                    row(&mut out, None, "", Some(line), text);
+                    continue;
+                }
+            };
+            let executable = assembled
+                .segments
+                .iter()
+                .any(|s| s.kind.is_text() && s.contains(entries[0].address));
+            if executable {
+                list_instructions(&mut out, assembled, sources, entries, line, text);
+            } else {
+                list_data(&mut out, assembled, entries, line, text);
+            }
+        }
+        out.push('\n');
+    }
+    writeln!(out, "Symbols\n").unwrap();
+    writeln!(
+        out,
+        "{:<10} {:<8} {:<6} {:<7} Name",
+        "Address", "Segment", "Size", "Scope"
+    )
+    .unwrap();
+    for symbol in assembled.symbols.iter() {
+        writeln!(
+            out,
+            "0x{:08x} {:<8} {:<6} {:<7} {}",
+            symbol.address,
+            symbol.segment.map_or("*ABS*".to_owned(), String::from),
+            symbol.size,
+            String::from(symbol.binding),
+            symbol.name
+        )
+        .unwrap();
+    }
+    out
+}
@@ -0,0 +1,294 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::assembler::assembler::{Assembled, Endian, SegmentKind};
+
+// merging across the default MARS layout would mean hundreds of megabytes of fill
+const MAX_IMAGE: u32 = 16 * 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Binary,
+    IntelHex,
+    SRecord,
+    ReadMemH,
+    ReadMemB,
+    Logisim,
+}
+
+impl From<&str> for OutputFormat {
+    fn from(s: &str) -> OutputFormat {
+        match s.to_lowercase().as_ref() {
+            "bin" | "binary" => OutputFormat::Binary,
+            "ihex" | "hex" => OutputFormat::IntelHex,
+            "srec" | "s-record" => OutputFormat::SRecord,
+            "readmemh" => OutputFormat::ReadMemH,
+            "readmemb" => OutputFormat::ReadMemB,
+            "logisim" => OutputFormat::Logisim,
+            _ => panic!("No such output format: {}", s),
+        }
+    }
+}
+
+impl From<OutputFormat> for String {
+    fn from(f: OutputFormat) -> String {
+        match f {
+            OutputFormat::Binary => "bin",
+            OutputFormat::IntelHex => "ihex",
+            OutputFormat::SRecord => "srec",
+            OutputFormat::ReadMemH => "readmemh",
+            OutputFormat::ReadMemB => "readmemb",
+            OutputFormat::Logisim => "logisim",
+        }
+        .to_owned()
+    }
+}
+
+/// How to turn assembled segments into memory images. `base` is the absolute address
+/// that becomes address zero in the image, the same kind of address `.text 0x...` takes;
+/// without it images start at their first byte (binary, readmem, Logisim) or keep
+/// absolute addresses (Intel HEX, S-records).
+#[derive(Copy, Clone, Debug)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    pub endian: Option<Endian>,
+    pub fill: u8,
+    pub base: Option<u32>,
+    pub merge: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            format: OutputFormat::Binary,
+            endian: None,
+            fill: 0,
+            base: None,
+            merge: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub name: String,
+    pub address: u32,
+    pub bytes: Vec<u8>,
+}
+
+fn span(name: String, parts: &[(u32, &[u8])], fill: u8) -> Result<Region, String> {
+    let address = parts.iter().map(|p| p.0).min().unwrap_or(0);
+    let end = parts
+        .iter()
+        .map(|p| p.0 + p.1.len() as u32)
+        .max()
+        .unwrap_or(0);
+    if end - address > MAX_IMAGE {
+        return Err(format!(
+            "{} would be {} bytes, write segments separately or use a more compact layout",
+            name,
+            end - address
+        ));
+    }
+    let mut bytes = vec![fill; (end - address) as usize];
+    for (start, b) in parts {
+        let offset = (start - address) as usize;
+        bytes[offset..offset + b.len()].copy_from_slice(b);
+    }
+    Ok(Region {
+        name,
+        address,
+        bytes,
+    })
+}
+
+/// One region per segment kind, or a single one covering everything when merging.
+pub fn regions(assembled: &Assembled, merge: bool, fill: u8) -> Result<Vec<Region>, String> {
+    let mut by_kind: BTreeMap<SegmentKind, Vec<(u32, &[u8])>> = BTreeMap::new();
+    for s in &assembled.segments {
+        if !s.bytes.is_empty() {
+            by_kind
+                .entry(s.kind)
+                .or_default()
+                .push((s.start_address, &s.bytes));
+        }
+    }
+    if merge {
+        let all: Vec<_> = by_kind.values().flatten().copied().collect();
+        if all.is_empty() {
+            return Ok(Vec::new());
+        }
+        return Ok(vec![span("image".to_owned(), &all, fill)?]);
+    }
+    by_kind
+        .into_iter()
+        .map(|(kind, parts)| span(String::from(kind), &parts, fill))
+        .collect()
+}
+
+// Images are word arrays to the hardware reading them, so a change of byte order
+// swaps every aligned word.
+fn reorder(bytes: &mut Vec<u8>, from: Endian, to: Endian, fill: u8) {
+    if from != to {
+        bytes.resize(bytes.len().next_multiple_of(4), fill);
+        for word in bytes.chunks_mut(4) {
+            word.reverse();
+        }
+    }
+}
+
+fn words(region: &Region, endian: Endian, fill: u8) -> Vec<u32> {
+    region
+        .bytes
+        .chunks(4)
+        .map(|c| {
+            let mut w = [fill; 4];
+            w[..c.len()].copy_from_slice(c);
+            endian.read_u32(&w)
+        })
+        .collect()
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b))
+}
+
+fn intel_hex(region: &Region, offset: u32, entry: u32) -> String {
+    let mut out = String::new();
+    let mut record = |kind: u8, address: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = checksum(&bytes).wrapping_neg();
+        out.push(':');
+        for b in bytes.iter().chain(std::iter::once(&sum)) {
+            write!(out, "{:02X}", b).unwrap();
+        }
+        out.push('\n');
+    };
+    let mut upper = None;
+    for (i, chunk) in region.bytes.chunks(16).enumerate() {
+        let address = offset.wrapping_add(i as u32 * 16);
+        if upper != Some(address >> 16) {
+            upper = Some(address >> 16);
+            record(0x04, 0, &((address >> 16) as u16).to_be_bytes());
+        }
+        record(0x00, address as u16, chunk);
+    }
+    record(0x05, 0, &entry.to_be_bytes());
+    record(0x01, 0, &[]);
+    out
+}
+
+fn s_record(region: &Region, offset: u32, entry: u32) -> String {
+    let mut out = String::new();
+    let mut record = |kind: char, address: &[u8], data: &[u8]| {
+        let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(data);
+        let sum = !checksum(&bytes);
+        out.push('S');
+        out.push(kind);
+        for b in bytes.iter().chain(std::iter::once(&sum)) {
+            write!(out, "{:02X}", b).unwrap();
+        }
+        out.push('\n');
+    };
+    record('0', &[0, 0], region.name.as_bytes());
+    let mut count = 0u32;
+    for (i, chunk) in region.bytes.chunks(16).enumerate() {
+        let address = offset.wrapping_add(i as u32 * 16);
+        record('3', &address.to_be_bytes(), chunk);
+        count += 1;
+    }
+    if count <= 0xFFFF {
+        record('5', &(count as u16).to_be_bytes(), &[]);
+    }
+    record('7', &entry.to_be_bytes(), &[]);
+    out
+}
+
+fn readmem(region: &Region, offset: u32, endian: Endian, fill: u8, binary: bool) -> String {
+    let mut out = format!("// {} at 0x{:08X}\n", region.name, region.address);
+    if offset != 0 {
+        writeln!(out, "@{:08X}", offset / 4).unwrap();
+    }
+    for w in words(region, endian, fill) {
+        if binary {
+            writeln!(out, "{:032b}", w).unwrap();
+        } else {
+            writeln!(out, "{:08X}", w).unwrap();
+        }
+    }
+    out
+}
+
+// Logisim's RAM/ROM image format: hex words, runs written as `count*value`.
+fn logisim(region: &Region, offset: u32, endian: Endian, fill: u8) -> String {
+    let mut values = vec![u32::from_ne_bytes([fill; 4]); (offset / 4) as usize];
+    values.extend(words(region, endian, fill));
+    let mut runs: Vec<(usize, u32)> = Vec::new();
+    for v in values {
+        match runs.last_mut() {
+            Some((n, last)) if *last == v => *n += 1,
+            _ => runs.push((1, v)),
+        }
+    }
+    let mut out = "v2.0 raw\n".to_owned();
+    for (i, (n, v)) in runs.iter().enumerate() {
+        if *n > 1 {
+            write!(out, "{}*{:x}", n, v).unwrap();
+        } else {
+            write!(out, "{:x}", v).unwrap();
+        }
+        out.push(if i % 8 == 7 { '\n' } else { ' ' });
+    }
+    out.truncate(out.trim_end().len());
+    out.push('\n');
+    out
+}
+
+/// Renders every region in the chosen format, as (region name, file contents) pairs.
+pub fn write_images(
+    assembled: &Assembled,
+    options: &OutputOptions,
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let endian = options.endian.unwrap_or(assembled.endian);
+    let entry = assembled.entry_point();
+    let mut images = Vec::new();
+    for mut region in regions(assembled, options.merge, options.fill)? {
+        reorder(&mut region.bytes, assembled.endian, endian, options.fill);
+        let addressed = matches!(
+            options.format,
+            OutputFormat::IntelHex | OutputFormat::SRecord
+        );
+        let base = match options.base {
+            Some(base) => base,
+            None if addressed => 0,
+            None => region.address,
+        };
+        if base > region.address {
+            return Err(format!(
+                "Base address 0x{:08X} is above {} at 0x{:08X}",
+                base, region.name, region.address
+            ));
+        }
+        let offset = region.address - base;
+        let contents = match options.format {
+            OutputFormat::Binary => {
+                let mut bytes = vec![options.fill; offset as usize];
+                bytes.extend_from_slice(&region.bytes);
+                bytes
+            }
+            OutputFormat::IntelHex => intel_hex(&region, offset, entry).into_bytes(),
+            OutputFormat::SRecord => s_record(&region, offset, entry).into_bytes(),
+            OutputFormat::ReadMemH => {
+                readmem(&region, offset, endian, options.fill, false).into_bytes()
+            }
+            OutputFormat::ReadMemB => {
+                readmem(&region, offset, endian, options.fill, true).into_bytes()
+            }
+            OutputFormat::Logisim => logisim(&region, offset, endian, options.fill).into_bytes(),
+        };
+        images.push((region.name, contents));
+    }
+    Ok(images)
+}
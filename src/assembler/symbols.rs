@@ -0,0 +1,234 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Write},
+};
+
+use crate::{
+    assembler::assembler::{AssembledSegment, LineEntry, SegmentKind},
+    parser::span::{SourceMap, Span},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Local,
+    Global,
+}
+
+impl From<Binding> for String {
+    fn from(b: Binding) -> String {
+        match b {
+            Binding::Local => "local",
+            Binding::Global => "global",
+        }
+        .to_owned()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    // None for a label sitting outside every segment, e.g. in an empty one
+    pub segment: Option<SegmentKind>,
+    pub address: u32,
+    pub size: u32,
+    pub binding: Binding,
+    pub defined: Option<Span>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Warning {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: warning: {}", self.span, self.message)
+    }
+}
+
+/// Everything the assembler knows about the program's labels. Sizes are explicit for
+/// `.extern` symbols and otherwise cover the instructions and data from the symbol up
+/// to the next one, leaving out any padding that aligns what comes after.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: BTreeMap<String, Symbol>,
+    references: BTreeMap<String, Vec<Span>>,
+    warnings: Vec<Warning>,
+}
+
+// the label is at the end of a segment when nothing follows it
+fn segment_of(segments: &[AssembledSegment], address: u32) -> Option<&AssembledSegment> {
+    segments
+        .iter()
+        .find(|s| s.contains(address))
+        .or_else(|| segments.iter().find(|s| s.end_address() == address))
+}
+
+impl SymbolTable {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn build(
+        labels: &BTreeMap<String, u32>,
+        definitions: &BTreeMap<String, Span>,
+        sizes: &BTreeMap<String, u32>,
+        globals: &BTreeSet<String>,
+        segments: &[AssembledSegment],
+        line_table: &[LineEntry],
+        references: Vec<(String, Span)>,
+        mut warnings: Vec<Warning>,
+    ) -> SymbolTable {
+        let mut by_address: Vec<(u32, &String)> = labels.iter().map(|(n, a)| (*a, n)).collect();
+        by_address.sort();
+        let mut symbols = BTreeMap::new();
+        for (i, (address, name)) in by_address.iter().enumerate() {
+            let segment = segment_of(segments, *address);
+            let size = match sizes.get(*name) {
+                Some(size) => *size,
+                None => {
+                    let next = by_address[i + 1..]
+                        .iter()
+                        .map(|(a, _)| *a)
+                        .find(|a| a > address)
+                        .unwrap_or(u32::MAX);
+                    // the line table is sorted, and has no entries for padding
+                    let first = line_table.partition_point(|e| e.address < *address);
+                    line_table[first..]
+                        .iter()
+                        .take_while(|e| {
+                            e.address < next && segment.is_some_and(|s| s.contains(e.address))
+                        })
+                        .map(|e| (e.address + e.size).min(next))
+                        .max()
+                        .map_or(0, |end| end - address)
+                }
+            };
+            let binding = if globals.contains(*name) {
+                Binding::Global
+            } else {
+                Binding::Local
+            };
+            symbols.insert(
+                (*name).clone(),
+                Symbol {
+                    name: (*name).clone(),
+                    segment: segment.map(|s| s.kind),
+                    address: *address,
+                    size,
+                    binding,
+                    defined: definitions.get(*name).copied(),
+                },
+            );
+        }
+
+        let mut refs: BTreeMap<String, Vec<Span>> = BTreeMap::new();
+        for (name, span) in references {
+            let spans = refs.entry(name).or_default();
+            if !spans.contains(&span) {
+                spans.push(span);
+            }
+        }
+        for spans in refs.values_mut() {
+            spans.sort();
+        }
+
+        let mut folded: BTreeMap<String, &Symbol> = BTreeMap::new();
+        for symbol in symbols.values() {
+            if let Some(other) = folded.insert(symbol.name.to_lowercase(), symbol) {
+                if let Some(span) = symbol.defined {
+                    warnings.push(Warning {
+                        span,
+                        message: format!(
+                            "Labels `{}` and `{}` differ only in case",
+                            other.name, symbol.name
+                        ),
+                    });
+                }
+            }
+        }
+        for symbol in symbols.values() {
+            // entry points are used by whoever starts the program
+            let entry = symbol.name == "main" || symbol.name == "__start";
+            if symbol.binding == Binding::Local && !entry && !refs.contains_key(&symbol.name) {
+                if let Some(span) = symbol.defined {
+                    warnings.push(Warning {
+                        span,
+                        message: format!("Label is never used: {}", symbol.name),
+                    });
+                }
+            }
+        }
+        warnings.sort_by_key(|w| w.span);
+
+        SymbolTable {
+            symbols,
+            references: refs,
+            warnings,
+        }
+    }
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+    /// All symbols ordered by address, then name.
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        let mut symbols: Vec<&Symbol> = self.symbols.values().collect();
+        symbols.sort_by_key(|s| (s.address, s.name.as_str()));
+        symbols.into_iter()
+    }
+    pub fn at(&self, address: u32) -> impl Iterator<Item = &Symbol> {
+        self.iter().filter(move |s| s.address == address)
+    }
+    /// The symbol whose extent covers `address`, preferring the closest one below it.
+    pub fn containing(&self, address: u32) -> Option<&Symbol> {
+        self.iter()
+            .filter(|s| s.address <= address && address - s.address < s.size.max(1))
+            .last()
+    }
+    pub fn in_segment(&self, kind: SegmentKind) -> impl Iterator<Item = &Symbol> {
+        self.iter().filter(move |s| s.segment == Some(kind))
+    }
+    pub fn globals(&self) -> impl Iterator<Item = &Symbol> {
+        self.iter().filter(|s| s.binding == Binding::Global)
+    }
+    pub fn references(&self, name: &str) -> &[Span] {
+        self.references.get(name).map_or(&[], |r| r.as_slice())
+    }
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+    pub fn cross_reference(&self, sources: &SourceMap) -> String {
+        let place = |span: Span| match sources.name(span.file) {
+            Some(name) => format!("{}:{}", name, span.line),
+            None => span.line.to_string(),
+        };
+        let mut out = format!(
+            "{:<20} {:<10} {:<7} {:<6} {:<14} References\n",
+            "Symbol", "Address", "Segment", "Size", "Defined"
+        );
+        for symbol in self.symbols.values() {
+            let segment = symbol.segment.map_or("*ABS*".to_owned(), String::from);
+            let defined = symbol.defined.map_or("-".to_owned(), place);
+            let references: Vec<String> = self
+                .references(&symbol.name)
+                .iter()
+                .map(|s| place(*s))
+                .collect();
+            let line = format!(
+                "{:<20} 0x{:08x} {:<7} {:<6} {:<14} {}",
+                symbol.name,
+                symbol.address,
+                segment,
+                symbol.size,
+                defined,
+                references.join(", ")
+            );
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+        out
+    }
+}
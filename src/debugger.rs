@@ -0,0 +1,885 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    assembler::assembler::Assembled,
+    machine::{
+        bitmap::{self, BitmapConfig, ImageFormat},
+        history::History,
+        register::Reg,
+        state::{disassemble, Event, Exception, Register, State, Status},
+        watch::{Condition, Hit, Trigger, Watchpoint, Watchpoints},
+    },
+    parser::span::SourceMap,
+};
+
+const JR_RA: u32 = 0x03E0_0008;
+// how far up the stack `backtrace` looks for return addresses
+const STACK_SCAN: u32 = 256;
+
+fn is_jal(word: u32) -> bool {
+    word >> 26 == 0x03
+}
+
+/// Why execution handed control back to the user.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Stepped,
+    Breakpoint(u32),
+    Watchpoint(Hit),
+    Returned,
+    Finished(Status),
+    Fault(Exception),
+    // going backwards, nothing older is recorded
+    StartOfHistory,
+}
+
+pub struct Debugger {
+    pub state: State,
+    /// Where the bitmap display is, for `bitmap`.
+    pub bitmap: Option<BitmapConfig>,
+    program: Option<(Assembled, SourceMap)>,
+    watchpoints: Watchpoints,
+    history: History,
+    // what the user typed for each breakpoint
+    locations: BTreeMap<u32, String>,
+}
+
+impl Debugger {
+    pub fn new(mut state: State) -> Debugger {
+        let history = History::new(&mut state);
+        Debugger {
+            state,
+            bitmap: None,
+            program: None,
+            watchpoints: Watchpoints::new(),
+            history,
+            locations: BTreeMap::new(),
+        }
+    }
+
+    /// Keeps the assembler output around so locations can be given and shown as source lines.
+    pub fn with_source(state: State, assembled: Assembled, sources: SourceMap) -> Debugger {
+        Debugger {
+            program: Some((assembled, sources)),
+            ..Debugger::new(state)
+        }
+    }
+
+    /// Breakpoints and watchpoints share one numbering.
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    /// Turns a label, a number, `line` or `file:line` into an address.
+    pub fn resolve(&self, location: &str) -> Result<u32, String> {
+        if let Some(address) = self.state.find_label_by_name(location) {
+            return Ok(address);
+        }
+        if let Some(n) = location
+            .strip_prefix("0x")
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        {
+            return Ok(n);
+        }
+        let (file, line) = match location.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, location),
+        };
+        let line: u32 = line
+            .parse()
+            .map_err(|_| format!("No label or line called {}", location))?;
+        let (assembled, sources) = self
+            .program
+            .as_ref()
+            .ok_or("Source lines need the program's source, not a binary")?;
+        let ids = sources
+            .files()
+            .filter(|(_, f)| file.map_or(true, |name| f.name == name || f.name.ends_with(name)))
+            .map(|(id, _)| id);
+        ids.flat_map(|id| assembled.addresses_for_line(id, line))
+            .min()
+            .ok_or_else(|| format!("Line {} has no code", location))
+    }
+
+    pub fn add_breakpoint(
+        &mut self,
+        location: &str,
+        condition: Option<&str>,
+    ) -> Result<u32, String> {
+        let address = self.resolve(location)?;
+        let condition = condition.map(Condition::try_from).transpose()?;
+        let id = self.watchpoints.add(Trigger::Execute(address), condition);
+        self.locations.insert(id, location.to_owned());
+        Ok(id)
+    }
+
+    /// Watches `mem[a]`, `memh[a]` or `memb[a]` for reads, writes or both, a register
+    /// for changes, or, when only writes are wanted, any expression for becoming true.
+    pub fn add_watchpoint(&mut self, what: &str, reads: bool, writes: bool) -> Result<u32, String> {
+        let trigger = match (sized_memory_operand(what), reads, writes) {
+            (Some((length, address)), _, _) => {
+                let address = self.value(address)?;
+                match (reads, writes) {
+                    (true, true) => Trigger::Access { address, length },
+                    (true, false) => Trigger::Read { address, length },
+                    _ => Trigger::Write { address, length },
+                }
+            }
+            (None, false, true) => match what {
+                "hi" | "$hi" => Trigger::Register(Register::Hi),
+                "lo" | "$lo" => Trigger::Register(Register::Lo),
+                _ if what.starts_with('$') && register(what).is_ok() => {
+                    Trigger::Register(Register::General(u8::from(register(what)?)))
+                }
+                _ => Trigger::Becomes(Condition::try_from(what)?),
+            },
+            (None, _, _) => return Err(format!("Only memory can be watched for reads: {}", what)),
+        };
+        Ok(self.watchpoints.add(trigger, None))
+    }
+
+    pub fn remove_breakpoint(&mut self, id: u32) -> bool {
+        self.locations.remove(&id);
+        self.watchpoints.remove(id)
+    }
+
+    fn breakpoint_at_pc(&mut self) -> Option<u32> {
+        self.watchpoints.breakpoint(&self.state)
+    }
+
+    fn current_word(&self) -> u32 {
+        self.state.memory().read_u32(self.state.read_pc())
+    }
+
+    fn single(&mut self) -> Option<Stop> {
+        let result = self.watchpoints.step(&mut self.state);
+        self.history.record(&mut self.state);
+        match result {
+            Err(e) => Some(Stop::Fault(e)),
+            Ok(_) if self.state.status() != Status::Running => {
+                Some(Stop::Finished(self.state.status()))
+            }
+            Ok(hit) => hit.map(Stop::Watchpoint),
+        }
+    }
+
+    pub fn step(&mut self, count: u64) -> Stop {
+        for _ in 0..count {
+            if let Some(stop) = self.single() {
+                return stop;
+            }
+        }
+        Stop::Stepped
+    }
+
+    // runs until `done` says so, stopping early at breakpoints other than the one we start on
+    fn run_until(&mut self, mut done: impl FnMut(&State) -> bool) -> Stop {
+        loop {
+            if let Some(stop) = self.single() {
+                return stop;
+            }
+            if done(&self.state) {
+                return Stop::Returned;
+            }
+            if let Some(id) = self.breakpoint_at_pc() {
+                return Stop::Breakpoint(id);
+            }
+        }
+    }
+
+    pub fn cont(&mut self) -> Stop {
+        self.run_until(|_| false)
+    }
+
+    /// Swaps in another machine, such as one restored from a snapshot. History starts
+    /// over from it; breakpoints and watchpoints stay.
+    pub fn replace_state(&mut self, mut state: State) {
+        self.history = History::new(&mut state);
+        self.state = state;
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    pub fn step_back(&mut self, count: u64) -> Stop {
+        if self.history.step_back(&mut self.state, count) < count {
+            Stop::StartOfHistory
+        } else {
+            Stop::Stepped
+        }
+    }
+
+    /// Runs backwards to the previous breakpoint or watched write.
+    pub fn reverse_continue(&mut self) -> Stop {
+        let id = self
+            .history
+            .reverse_continue(&mut self.state, &self.watchpoints);
+        let execute = |id| {
+            let w = self.watchpoints.get(id);
+            w.is_some_and(|w| matches!(w.trigger, Trigger::Execute(_)))
+        };
+        match id {
+            Some(id) if execute(id) => Stop::Breakpoint(id),
+            Some(id) => Stop::Watchpoint(Hit { id, event: None }),
+            None => Stop::StartOfHistory,
+        }
+    }
+
+    /// Runs back to just before the last write to `$reg`, `hi`, `lo` or `mem[a]`.
+    pub fn last_write(&mut self, what: &str) -> Result<Stop, String> {
+        let found = match (what, sized_memory_operand(what)) {
+            (_, Some((length, address))) => {
+                let address = self.value(address)?;
+                self.history.reverse_until(&mut self.state, |_, undo| {
+                    undo.wrote_memory(address, length)
+                })
+            }
+            ("hi" | "$hi", _) | ("lo" | "$lo", _) => {
+                let r = if what.ends_with("hi") {
+                    Register::Hi
+                } else {
+                    Register::Lo
+                };
+                self.history
+                    .reverse_until(&mut self.state, |_, undo| undo.wrote_register(r))
+            }
+            _ => {
+                let r = Register::General(u8::from(register(what)?));
+                self.history
+                    .reverse_until(&mut self.state, |_, undo| undo.wrote_register(r))
+            }
+        };
+        Ok(if found {
+            Stop::Stepped
+        } else {
+            Stop::StartOfHistory
+        })
+    }
+
+    /// Steps over a `jal`, running the whole call; anything else is a plain step.
+    pub fn step_over(&mut self) -> Stop {
+        if !is_jal(self.current_word()) {
+            return self.step(1);
+        }
+        let target = self.state.return_address();
+        let sp = self.state.read_reg(Reg::sp);
+        // recursive calls come back to the same address with a deeper stack
+        match self.run_until(|s| s.read_pc() == target && s.read_reg(Reg::sp) >= sp) {
+            Stop::Returned => Stop::Stepped,
+            stop => stop,
+        }
+    }
+
+    /// Runs until the current function returns through `jr $ra`.
+    pub fn finish(&mut self) -> Stop {
+        let mut depth = 0u32;
+        loop {
+            let word = self.current_word();
+            if is_jal(word) {
+                depth += 1;
+            } else if word == JR_RA {
+                if depth == 0 {
+                    let mut stop = self.single();
+                    if stop.is_none() && self.state.delayed_branches {
+                        stop = self.single();
+                    }
+                    return stop.unwrap_or(Stop::Returned);
+                }
+                depth -= 1;
+            }
+            if let Some(stop) = self.single() {
+                return stop;
+            }
+            if let Some(id) = self.breakpoint_at_pc() {
+                return Stop::Breakpoint(id);
+            }
+        }
+    }
+
+    /// `label+offset` for the closest label at or below `address`.
+    pub fn symbolize(&self, address: u32) -> String {
+        let nearest = self
+            .state
+            .labels()
+            .iter()
+            .filter(|(_, a)| **a <= address)
+            .max_by_key(|(name, a)| (**a, std::cmp::Reverse(name.as_str())));
+        match nearest {
+            Some((name, a)) if *a == address => name.clone(),
+            Some((name, a)) => format!("{}+0x{:x}", name, address - a),
+            None => format!("0x{:08x}", address),
+        }
+    }
+
+    fn in_text(&self, address: u32) -> bool {
+        self.state
+            .text_ranges()
+            .iter()
+            .any(|r| address >= r.0 && address < r.1)
+    }
+
+    // a word is a plausible return address if the instruction before it (or before its
+    // delay slot) is a jal
+    fn is_return_address(&self, address: u32) -> bool {
+        let back = if self.state.delayed_branches { 8 } else { 4 };
+        address % 4 == 0
+            && self.in_text(address)
+            && is_jal(self.state.memory().read_u32(address.wrapping_sub(back)))
+    }
+
+    /// Call frames innermost first: the pc, `$ra`, then return addresses saved on the stack.
+    pub fn backtrace(&self) -> Vec<u32> {
+        let mut frames = vec![self.state.read_pc()];
+        let ra = self.state.read_reg(Reg::ra);
+        if self.is_return_address(ra) {
+            frames.push(ra);
+        }
+        let sp = self.state.read_reg(Reg::sp);
+        for offset in (0..STACK_SCAN).step_by(4) {
+            let Some(slot) = sp.checked_add(offset) else {
+                break;
+            };
+            let word = self.state.memory().read_u32(slot);
+            if self.is_return_address(word) && frames.last() != Some(&word) {
+                frames.push(word);
+            }
+        }
+        frames
+    }
+
+    fn source_line(&self, address: u32) -> Option<String> {
+        let (assembled, sources) = self.program.as_ref()?;
+        let span = assembled.span_for_address(address)?;
+        let text = sources.line(span.file, span.line)?;
+        Some(format!(
+            "{}:{}  {}",
+            sources.name(span.file)?,
+            span.line,
+            text.trim()
+        ))
+    }
+
+    fn show_pc(&self, out: &mut dyn Write) -> io::Result<()> {
+        let pc = self.state.read_pc();
+        let word = self.current_word();
+        let inst = disassemble(word, pc);
+        match self.source_line(pc) {
+            Some(line) => writeln!(out, "0x{:08x}: {:08x}  {:<24} ; {}", pc, word, inst, line),
+            None => writeln!(out, "0x{:08x}: {:08x}  {}", pc, word, inst),
+        }
+    }
+
+    fn show_stop(&self, stop: Stop, out: &mut dyn Write) -> io::Result<()> {
+        match stop {
+            Stop::Stepped | Stop::Returned => (),
+            Stop::Breakpoint(id) => writeln!(
+                out,
+                "Breakpoint {}, {}",
+                id,
+                self.symbolize(self.state.read_pc())
+            )?,
+            Stop::Finished(Status::Exited(code)) => {
+                return writeln!(out, "Program exited with code {}", code)
+            }
+            Stop::Finished(status) => return writeln!(out, "Program stopped: {:?}", status),
+            Stop::Watchpoint(hit) => {
+                let what = self
+                    .watchpoints
+                    .get(hit.id)
+                    .map_or(String::new(), |w| self.describe(w));
+                writeln!(out, "Watchpoint {}: {}", hit.id, what)?;
+                match hit.event {
+                    Some(Event::Register { old, new, .. }) => {
+                        writeln!(out, "Old value = 0x{:08x}\nNew value = 0x{:08x}", old, new)?
+                    }
+                    Some(Event::Memory(a)) => writeln!(
+                        out,
+                        "{} of {} byte{} at 0x{:08x}",
+                        if a.write { "Write" } else { "Read" },
+                        a.size,
+                        if a.size == 1 { "" } else { "s" },
+                        a.address
+                    )?,
+                    None => (),
+                }
+            }
+            Stop::Fault(e) => writeln!(out, "{}", e)?,
+            Stop::StartOfHistory => {
+                writeln!(out, "No more history; this is as far back as it goes")?
+            }
+        }
+        self.show_pc(out)
+    }
+
+    fn describe(&self, w: &Watchpoint) -> String {
+        let memory = |kind: &str, address: u32, length: u32| {
+            let operand = match length {
+                1 => "memb",
+                2 => "memh",
+                _ => "mem",
+            };
+            format!("{} {}[0x{:08x}]", kind, operand, address)
+        };
+        let mut text = match w.trigger {
+            Trigger::Execute(address) => format!(
+                "break {} (0x{:08x})",
+                self.locations.get(&w.id).map_or("", String::as_str),
+                address
+            ),
+            Trigger::Read { address, length } => memory("rwatch", address, length),
+            Trigger::Write { address, length } => memory("watch", address, length),
+            Trigger::Access { address, length } => memory("awatch", address, length),
+            Trigger::Register(Register::General(r)) => {
+                format!("watch {}", String::from(Reg::from(r)))
+            }
+            Trigger::Register(Register::Hi) => "watch $hi".to_owned(),
+            Trigger::Register(Register::Lo) => "watch $lo".to_owned(),
+            Trigger::Becomes(ref c) => format!("watch {}", c),
+        };
+        if let Some(ref c) = w.condition {
+            text += &format!(" if {}", c);
+        }
+        text
+    }
+
+    fn show_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        for r in 0..32u8 {
+            write!(
+                out,
+                "{:>5} {:08x}{}",
+                String::from(Reg::from(r)),
+                self.state.read_reg(r),
+                if r % 4 == 3 { "\n" } else { "  " }
+            )?;
+        }
+        writeln!(
+            out,
+            "   pc {:08x}     hi {:08x}     lo {:08x}",
+            self.state.read_pc(),
+            self.state.read_hi(),
+            self.state.read_lo()
+        )
+    }
+
+    fn value(&self, s: &str) -> Result<u32, String> {
+        if let Some(n) = s.strip_prefix('-').and_then(|n| n.parse::<i32>().ok()) {
+            return Ok(n.wrapping_neg() as u32);
+        }
+        if let Ok(n) = s.parse::<u32>() {
+            return Ok(n);
+        }
+        self.resolve(s)
+    }
+
+    fn read(&self, what: &str) -> Result<u32, String> {
+        match what {
+            "pc" | "$pc" => Ok(self.state.read_pc()),
+            "hi" | "$hi" => Ok(self.state.read_hi()),
+            "lo" | "$lo" => Ok(self.state.read_lo()),
+            _ if what.starts_with('$') => Ok(self.state.read_reg(register(what)?)),
+            _ => match memory_operand(what) {
+                Some(address) => {
+                    let address = self.value(address)?;
+                    // looking mustn't take a key from a device
+                    if address % 4 != 0 {
+                        return Err(Exception::AddressError(address).to_string());
+                    }
+                    Ok(self.state.memory().read_u32(address))
+                }
+                None => self.value(what),
+            },
+        }
+    }
+
+    fn write(&mut self, what: &str, value: u32) -> Result<(), String> {
+        match what {
+            "pc" | "$pc" => self.state.set_pc(value),
+            "hi" | "$hi" => self.state.write_hi_lo(value, self.state.read_lo()),
+            "lo" | "$lo" => self.state.write_hi_lo(self.state.read_hi(), value),
+            _ if what.starts_with('$') => self.state.write_reg(register(what)?, value),
+            _ => match memory_operand(what) {
+                Some(address) => {
+                    let address = self.value(address)?;
+                    self.state
+                        .write_mem(address, value)
+                        .map_err(|e| e.to_string())?
+                }
+                None => return Err(format!("Cannot assign to {}", what)),
+            },
+        }
+        Ok(())
+    }
+
+    fn point(&mut self, id: &str) -> Result<&mut Watchpoint, String> {
+        let point = match id.parse() {
+            Ok(n) => self.watchpoints.get_mut(n),
+            Err(_) => None,
+        };
+        point.ok_or(format!("No breakpoint {}", id))
+    }
+
+    fn examine(&self, address: u32, count: u32, out: &mut dyn Write) -> io::Result<()> {
+        for row in 0..count.div_ceil(4) {
+            let at = address.wrapping_add(row * 16);
+            write!(out, "0x{:08x}:", at)?;
+            for i in 0..(count - row * 4).min(4) {
+                write!(
+                    out,
+                    " {:08x}",
+                    self.state.memory().read_u32(at.wrapping_add(i * 4))
+                )?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// Executes one command line, returning false once the session should end.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result: Result<(), String> = match words.as_slice() {
+            [] => Ok(()),
+            ["s"] | ["step"] => {
+                let stop = self.step(1);
+                self.show_stop(stop, out)?;
+                Ok(())
+            }
+            ["s", n] | ["step", n] => match n.parse() {
+                Ok(n) => {
+                    let stop = self.step(n);
+                    self.show_stop(stop, out)?;
+                    Ok(())
+                }
+                Err(_) => Err(format!("Not a step count: {}", n)),
+            },
+            ["n"] | ["next"] => {
+                let stop = self.step_over();
+                self.show_stop(stop, out)?;
+                Ok(())
+            }
+            ["rs"] | ["reverse-step"] => {
+                let stop = self.step_back(1);
+                self.show_stop(stop, out)?;
+                Ok(())
+            }
+            ["rs", n] | ["reverse-step", n] => match n.parse() {
+                Ok(n) => {
+                    let stop = self.step_back(n);
+                    self.show_stop(stop, out)?;
+                    Ok(())
+                }
+                Err(_) => Err(format!("Not a step count: {}", n)),
+            },
+            ["rc"] | ["reverse-continue"] => {
+                let stop = self.reverse_continue();
+                self.show_stop(stop, out)?;
+                Ok(())
+            }
+            ["last-write", what] => match self.last_write(what) {
+                Ok(stop) => {
+                    self.show_stop(stop, out)?;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            ["snapshot", file] => {
+                fs::write(file, self.state.snapshot()).map_err(|e| format!("{}: {}", file, e))
+            }
+            ["bitmap", file] => match (self.bitmap, ImageFormat::from_path(file)) {
+                (None, _) => Err("No bitmap display; start with --bitmap".to_owned()),
+                (_, None) => Err(format!("Not a .png or .ppm file: {}", file)),
+                (Some(config), Some(format)) => {
+                    bitmap::encode(self.state.memory(), &config, format).and_then(|image| {
+                        fs::write(file, image).map_err(|e| format!("{}: {}", file, e))
+                    })
+                }
+            },
+            ["restore", file] => fs::read(file)
+                .map_err(|e| format!("{}: {}", file, e))
+                .and_then(|bytes| State::from_snapshot(&bytes))
+                .map(|state| {
+                    self.replace_state(state);
+                    self.show_pc(out)
+                })
+                .and_then(|r| r.map_err(|e| e.to_string())),
+            ["history"] => {
+                let back = self.state.steps() - self.history.earliest(&self.state);
+                writeln!(
+                    out,
+                    "{} step{} can be undone, keeping the last {} and a checkpoint every {}",
+                    back,
+                    if back == 1 { "" } else { "s" },
+                    self.history.limit,
+                    self.history.checkpoint_interval
+                )?;
+                Ok(())
+            }
+            ["history", "limit", n] => match n.parse() {
+                Ok(n) => {
+                    self.history.limit = n;
+                    Ok(())
+                }
+                Err(_) => Err(format!("Not a step count: {}", n)),
+            },
+            ["f"] | ["finish"] => {
+                let stop = self.finish();
+                self.show_stop(stop, out)?;
+                Ok(())
+            }
+            ["c"] | ["continue"] => {
+                let stop = self.cont();
+                self.show_stop(stop, out)?;
+                Ok(())
+            }
+            ["b", location, ..] | ["break", location, ..] => {
+                let condition = match words.get(2) {
+                    Some(&"if") => Ok(Some(rest(line, 3))),
+                    Some(_) => Err(format!("Expected `if`: {}", line)),
+                    None => Ok(None),
+                };
+                match condition.and_then(|c| self.add_breakpoint(location, c)) {
+                    Ok(id) => {
+                        let address = match self.watchpoints.get(id).map(|w| &w.trigger) {
+                            Some(Trigger::Execute(address)) => *address,
+                            _ => unreachable!(),
+                        };
+                        writeln!(
+                            out,
+                            "Breakpoint {} at 0x{:08x} ({})",
+                            id,
+                            address,
+                            self.symbolize(address)
+                        )?;
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            [command @ ("watch" | "rwatch" | "awatch"), _, ..] => {
+                let reads = *command != "watch";
+                let writes = *command != "rwatch";
+                match self.add_watchpoint(rest(line, 1), reads, writes) {
+                    Ok(id) => {
+                        let w = self.watchpoints.get(id).unwrap();
+                        writeln!(out, "Watchpoint {}: {}", id, self.describe(w))?;
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            ["cond", id, ..] | ["condition", id, ..] => {
+                let condition = match words.len() {
+                    2 => Ok(None),
+                    _ => Condition::try_from(rest(line, 2)).map(Some),
+                };
+                match (self.point(id), condition) {
+                    (Ok(w), Ok(c)) => {
+                        w.condition = c;
+                        Ok(())
+                    }
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                }
+            }
+            ["ignore", id, n] => match (self.point(id), n.parse()) {
+                (Ok(w), Ok(n)) => {
+                    w.ignore = n;
+                    Ok(())
+                }
+                (Err(e), _) => Err(e),
+                (_, Err(_)) => Err(format!("Not a count: {}", n)),
+            },
+            [command @ ("enable" | "disable"), id] => self.point(id).map(|w| {
+                w.enabled = *command == "enable";
+            }),
+            ["d", id] | ["delete", id] => match id.parse() {
+                Ok(id) if self.remove_breakpoint(id) => Ok(()),
+                _ => Err(format!("No breakpoint {}", id)),
+            },
+            ["breaks"] | ["info", "breaks"] => {
+                for w in self.watchpoints.iter() {
+                    write!(out, "{:>3} {}", w.id, self.describe(w))?;
+                    if !w.enabled {
+                        write!(out, " [disabled]")?;
+                    }
+                    if w.hits > 0 {
+                        write!(
+                            out,
+                            ", hit {} time{}",
+                            w.hits,
+                            if w.hits == 1 { "" } else { "s" }
+                        )?;
+                    }
+                    if w.ignore > 0 {
+                        write!(out, ", ignoring {} more", w.ignore)?;
+                    }
+                    writeln!(out)?;
+                }
+                Ok(())
+            }
+            ["r"] | ["regs"] => {
+                self.show_registers(out)?;
+                Ok(())
+            }
+            ["p", what] | ["print", what] => match self.read(what) {
+                Ok(v) => {
+                    writeln!(out, "{} = 0x{:08x} ({})", what, v, v as i32)?;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            ["set", what, "=", value] | ["set", what, value] => {
+                self.value(value).and_then(|v| self.write(what, v))
+            }
+            ["x", address] | ["x", address, _] => {
+                let count = words.get(2).map_or(Ok(1), |c| c.parse::<u32>());
+                match (self.value(address), count) {
+                    (Ok(address), Ok(count)) => {
+                        self.examine(address, count, out)?;
+                        Ok(())
+                    }
+                    (Err(e), _) => Err(e),
+                    (_, Err(_)) => Err(format!("Not a word count: {}", words[2])),
+                }
+            }
+            ["l"] | ["where"] => {
+                self.show_pc(out)?;
+                Ok(())
+            }
+            ["bt"] | ["backtrace"] => {
+                for (i, frame) in self.backtrace().iter().enumerate() {
+                    let line = self.source_line(*frame).unwrap_or_default();
+                    writeln!(
+                        out,
+                        "#{:<2} 0x{:08x} {:<16} {}",
+                        i,
+                        frame,
+                        self.symbolize(*frame),
+                        line
+                    )?;
+                }
+                Ok(())
+            }
+            ["q"] | ["quit"] => return Ok(false),
+            ["h"] | ["help"] => {
+                write!(out, "{}", HELP)?;
+                Ok(())
+            }
+            _ => Err(format!("Unknown command: {}", line)),
+        };
+        if let Err(e) = result {
+            writeln!(out, "{}", e)?;
+        }
+        let output = self.state.take_output();
+        out.write_all(&output)?;
+        Ok(true)
+    }
+
+    /// Reads commands until `quit` or the end of `input`. With `echo` each command is
+    /// printed after the prompt, so a scripted session reads like an interactive one.
+    pub fn repl(
+        &mut self,
+        input: &mut dyn BufRead,
+        out: &mut dyn Write,
+        echo: bool,
+    ) -> io::Result<()> {
+        self.show_pc(out)?;
+        let mut line = String::new();
+        loop {
+            write!(out, "(mips) ")?;
+            out.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return writeln!(out);
+            }
+            if echo {
+                writeln!(out, "{}", line.trim_end())?;
+            }
+            if !self.execute(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+const HELP: &str = "\
+step [n]            execute n instructions (s)
+next                step, running through calls made with jal (n)
+finish              run until the current function returns (f)
+reverse-step [n]    undo n instructions (rs)
+reverse-continue    run backwards to the previous breakpoint or watched write (rc)
+last-write <what>   run back to the last write to a register or mem[address]
+history [limit <n>] show how far back execution can go, or keep n steps of undo
+snapshot <file>     save the whole machine to a file
+bitmap <file>       save the bitmap display as a .png or .ppm image
+restore <file>      carry on from a saved machine
+continue            run until a breakpoint or the end of the program (c)
+break <location>    stop at a label, 0x address, line or file:line (b)
+  [if <expr>]       only when an expression holds, e.g. $t0 == 5 && mem[0x10010000] > 3
+watch <what>        stop when mem[a], memh[a], memb[a] or a register changes, or an
+                    expression becomes true
+rwatch <what>       stop when memory is read; awatch stops on reads and writes
+cond <id> [expr]    set or clear the condition on a breakpoint or watchpoint
+ignore <id> <n>     let the next n hits through
+enable/disable <id> turn a breakpoint or watchpoint on or off
+delete <id>         remove a breakpoint or watchpoint (d)
+breaks              list breakpoints and watchpoints with their hit counts
+regs                show all registers (r)
+print <what>        show $reg, pc, hi, lo, mem[address] or a label's address (p)
+set <what> = <val>  change a register, pc, hi, lo or mem[address]
+x <address> [n]     show n words of memory
+where               show the current instruction (l)
+backtrace           show return addresses found in $ra and on the stack (bt)
+quit                leave the debugger (q)
+";
+
+fn register(name: &str) -> Result<Reg, String> {
+    let name = name.to_lowercase();
+    let known = name.len() > 1
+        && (name[1..].parse::<u8>().is_ok_and(|n| n < 32)
+            || (0..32u8).any(|r| String::from(Reg::from(r)) == name));
+    if known {
+        Ok(Reg::from(name.as_str()))
+    } else {
+        Err(format!("No register {}", name))
+    }
+}
+
+// `mem[address]`
+fn memory_operand(s: &str) -> Option<&str> {
+    s.strip_prefix("mem[")?.strip_suffix(']')
+}
+
+// `mem[address]`, `memh[address]` or `memb[address]`, with the size in bytes
+fn sized_memory_operand(s: &str) -> Option<(u32, &str)> {
+    let (size, rest) = match s {
+        _ if s.starts_with("memh[") => (2, &s[5..]),
+        _ if s.starts_with("memb[") => (1, &s[5..]),
+        _ => (4, s.strip_prefix("mem[")?),
+    };
+    Some((size, rest.strip_suffix(']')?))
+}
+
+// the line from its `n`th word on
+fn rest(line: &str, n: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
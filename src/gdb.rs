@@ -0,0 +1,654 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::machine::{
+    cp0,
+    history::History,
+    state::{Exception, State, Status},
+    watch::{Trigger, Watchpoints},
+};
+
+// GDB's numbering for 32-bit MIPS: r0-r31, then these, then f0-f31, fcsr and fir
+const SR: usize = 32;
+const LO: usize = 33;
+const HI: usize = 34;
+const BAD: usize = 35;
+const CAUSE: usize = 36;
+const PC: usize = 37;
+const REGISTERS: usize = 72;
+
+// how many instructions run between checks for a ^C from the debugger
+const POLL_INTERVAL: u64 = 1024;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+const SIGSYS: u8 = 12;
+
+/// A stream GDB talks over; non-blocking reads let a running program notice a ^C.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Waits for a single debugger to connect.
+pub fn accept_tcp<A: ToSocketAddrs>(address: A) -> io::Result<TcpStream> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+#[cfg(unix)]
+pub fn accept_unix(path: &str) -> io::Result<std::os::unix::net::UnixStream> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    Ok(stream)
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |a, b| a.wrapping_add(b))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        write!(s, "{:02x}", b).unwrap();
+        s
+    })
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+// "addr,len" as used by m, M and Z packets
+fn address_length(s: &str) -> Option<(u32, u32)> {
+    let (address, length) = s.split_once(',')?;
+    Some((hex(address)?, hex(length)?))
+}
+
+/// Reads and writes `$data#checksum` packets, acknowledging them until no-ack mode.
+struct Packets<'a> {
+    conn: &'a mut dyn Connection,
+    pending: Vec<u8>,
+    no_ack: bool,
+}
+
+enum Incoming {
+    Packet(String),
+    Interrupt,
+    Closed,
+}
+
+impl<'a> Packets<'a> {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.remove(0)));
+        }
+        let mut b = [0];
+        match self.conn.read(&mut b)? {
+            0 => Ok(None),
+            _ => Ok(Some(b[0])),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Incoming> {
+        loop {
+            match self.byte()? {
+                None => return Ok(Incoming::Closed),
+                Some(0x03) => return Ok(Incoming::Interrupt),
+                Some(b'$') => break,
+                // acks for our replies, or noise
+                Some(_) => continue,
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            match self.byte()? {
+                None => return Ok(Incoming::Closed),
+                Some(b'#') => break,
+                Some(b) => data.push(b),
+            }
+        }
+        let mut sum = [0; 2];
+        for s in sum.iter_mut() {
+            *s = self.byte()?.unwrap_or(0);
+        }
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        if !self.no_ack {
+            let ok = expected == Some(checksum(&data));
+            self.conn.write_all(if ok { b"+" } else { b"-" })?;
+            if !ok {
+                return self.receive();
+            }
+        }
+        Ok(Incoming::Packet(data))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        loop {
+            write!(self.conn, "${}#{:02x}", data, checksum(data))?;
+            self.conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.byte()? {
+                Some(b'+') | None => return Ok(()),
+                Some(b'-') => continue,
+                // the next packet started without an ack
+                Some(b) => {
+                    self.pending.insert(0, b);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // a ^C while the program runs; anything else is kept for later
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.conn.set_nonblocking(true)?;
+        let mut b = [0];
+        let read = self.conn.read(&mut b);
+        self.conn.set_nonblocking(false)?;
+        match read {
+            Ok(1) if b[0] == 0x03 => Ok(true),
+            Ok(1) => {
+                self.pending.push(b[0]);
+                Ok(false)
+            }
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BreakKind {
+    Software,
+    Hardware,
+}
+
+// the stop reason GDB expects for a watchpoint, with the range it covers
+fn watch_reason(trigger: &Trigger) -> Option<(&'static str, u32, u32)> {
+    match *trigger {
+        Trigger::Write { address, length } => Some(("watch", address, length)),
+        Trigger::Read { address, length } => Some(("rwatch", address, length)),
+        Trigger::Access { address, length } => Some(("awatch", address, length)),
+        _ => None,
+    }
+}
+
+/// Serves one GDB session for a machine.
+pub struct GdbServer {
+    pub state: State,
+    breakpoints: BTreeMap<u32, BreakKind>,
+    watchpoints: Watchpoints,
+    history: History,
+    // stop reasons GDB said it understands in qSupported
+    swbreak: bool,
+    hwbreak: bool,
+    last_stop: String,
+}
+
+fn signal(e: Exception) -> u8 {
+    match e {
+        Exception::AddressError(_) => SIGBUS,
+        Exception::ReservedInstruction(_) => SIGILL,
+        Exception::ArithmeticOverflow => SIGFPE,
+        Exception::UnknownSyscall(_) | Exception::BadHeapRequest(_) => SIGSYS,
+    }
+}
+
+impl GdbServer {
+    pub fn new(mut state: State) -> GdbServer {
+        let history = History::new(&mut state);
+        GdbServer {
+            state,
+            breakpoints: BTreeMap::new(),
+            watchpoints: Watchpoints::new(),
+            history,
+            swbreak: false,
+            hwbreak: false,
+            last_stop: format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn register(&self, n: usize) -> u32 {
+        match n {
+            0..=31 => self.state.read_reg(n as u8),
+            LO => self.state.read_lo(),
+            HI => self.state.read_hi(),
+            PC => self.state.read_pc(),
+            SR => self.state.read_cp0(cp0::STATUS),
+            BAD => self.state.read_cp0(cp0::BAD_VADDR),
+            CAUSE => self.state.read_cp0(cp0::CAUSE),
+            // no floating point
+            _ => 0,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, value: u32) {
+        match n {
+            1..=31 => self.state.write_reg(n as u8, value),
+            LO => self.state.write_hi_lo(self.state.read_hi(), value),
+            HI => self.state.write_hi_lo(value, self.state.read_lo()),
+            PC => self.state.set_pc(value),
+            SR => self.state.cp0_mut().set(cp0::STATUS, value),
+            BAD => self.state.cp0_mut().set(cp0::BAD_VADDR, value),
+            CAUSE => self.state.cp0_mut().set(cp0::CAUSE, value),
+            _ => (),
+        }
+    }
+
+    fn encode_register(&self, n: usize) -> String {
+        hex_bytes(&self.state.memory().endian().u32_bytes(self.register(n)))
+    }
+
+    fn decode_register(&self, s: &str) -> Option<u32> {
+        parse_hex_bytes(s)
+            .filter(|b| b.len() == 4)
+            .map(|b| self.state.memory().endian().read_u32(&b))
+    }
+
+    fn exit_reply(status: Status) -> String {
+        match status {
+            Status::Exited(code) => format!("W{:02x}", code as u8),
+            _ => "W00".to_owned(),
+        }
+    }
+
+    // one instruction, reporting a watchpoint the access touched
+    fn step_watched(&mut self) -> Result<Option<String>, String> {
+        let result = self.watchpoints.step(&mut self.state);
+        self.history.record(&mut self.state);
+        let hit = match result {
+            Ok(_) if self.state.status() != Status::Running => {
+                return Err(GdbServer::exit_reply(self.state.status()))
+            }
+            Ok(hit) => hit,
+            Err(e) => return Err(format!("S{:02x}", signal(e))),
+        };
+        let reason = hit
+            .and_then(|h| self.watchpoints.get(h.id))
+            .and_then(|w| watch_reason(&w.trigger));
+        Ok(reason.map(|(name, address, _)| format!("T{:02x}{}:{:x};", SIGTRAP, name, address)))
+    }
+
+    // `bs` and `bc`: back one step, or back to a breakpoint or watched write
+    fn reverse(&mut self, single: bool) -> String {
+        let found = if single {
+            self.history.step_back(&mut self.state, 1) == 1
+        } else {
+            let (breakpoints, watchpoints) = (&self.breakpoints, &self.watchpoints);
+            self.history.reverse_until(&mut self.state, |_, undo| {
+                breakpoints.contains_key(&undo.pc())
+                    || watchpoints.iter().any(|w| match watch_reason(&w.trigger) {
+                        Some((name, address, length)) => {
+                            name != "rwatch" && undo.wrote_memory(address, length)
+                        }
+                        None => false,
+                    })
+            })
+        };
+        match self.breakpoint_stop() {
+            _ if !found => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Some(stop) if !single => stop,
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn breakpoint_stop(&self) -> Option<String> {
+        let reason = match self.breakpoints.get(&self.state.read_pc())? {
+            BreakKind::Software if self.swbreak => "swbreak:;",
+            BreakKind::Hardware if self.hwbreak => "hwbreak:;",
+            _ => "",
+        };
+        Some(format!("T{:02x}{}", SIGTRAP, reason))
+    }
+
+    fn resume(&mut self, packets: &mut Packets, single: bool) -> io::Result<String> {
+        if single {
+            return Ok(match self.step_watched() {
+                Ok(stop) => stop.unwrap_or_else(|| format!("S{:02x}", SIGTRAP)),
+                Err(stop) => stop,
+            });
+        }
+        let mut steps = 0u64;
+        loop {
+            match self.step_watched() {
+                Ok(Some(stop)) | Err(stop) => return Ok(stop),
+                Ok(None) => (),
+            }
+            if let Some(stop) = self.breakpoint_stop() {
+                return Ok(stop);
+            }
+            steps += 1;
+            if steps % POLL_INTERVAL == 0 && packets.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn features(&self, annex: &str) -> Option<String> {
+        if annex != "target.xml" {
+            return None;
+        }
+        let mut xml = String::from(
+            "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+             <target><architecture>mips</architecture>\
+             <feature name=\"org.gnu.gdb.mips.cpu\">",
+        );
+        for r in 0..32 {
+            write!(
+                xml,
+                "<reg name=\"r{}\" bitsize=\"32\" regnum=\"{}\"/>",
+                r, r
+            )
+            .unwrap();
+        }
+        write!(
+            xml,
+            "<reg name=\"lo\" bitsize=\"32\" regnum=\"{}\"/>\
+             <reg name=\"hi\" bitsize=\"32\" regnum=\"{}\"/>\
+             <reg name=\"pc\" bitsize=\"32\" regnum=\"{}\"/></feature>\
+             <feature name=\"org.gnu.gdb.mips.cp0\">\
+             <reg name=\"status\" bitsize=\"32\" regnum=\"{}\"/>\
+             <reg name=\"badvaddr\" bitsize=\"32\" regnum=\"{}\"/>\
+             <reg name=\"cause\" bitsize=\"32\" regnum=\"{}\"/></feature>\
+             <feature name=\"org.gnu.gdb.mips.fpu\">",
+            LO, HI, PC, SR, BAD, CAUSE
+        )
+        .unwrap();
+        for f in 0..32 {
+            write!(
+                xml,
+                "<reg name=\"f{}\" bitsize=\"32\" type=\"ieee_single\" regnum=\"{}\"/>",
+                f,
+                PC + 1 + f
+            )
+            .unwrap();
+        }
+        write!(
+            xml,
+            "<reg name=\"fcsr\" bitsize=\"32\" group=\"float\" regnum=\"70\"/>\
+             <reg name=\"fir\" bitsize=\"32\" group=\"float\" regnum=\"71\"/></feature></target>"
+        )
+        .unwrap();
+        Some(xml)
+    }
+
+    fn set_point(&mut self, packet: &str, insert: bool) -> String {
+        let mut fields = packet[1..].splitn(2, ',');
+        let kind = fields.next().unwrap_or("");
+        let (address, length) = match fields.next().and_then(address_length) {
+            Some(al) => al,
+            None => return "E01".to_owned(),
+        };
+        let trigger = match kind {
+            "0" | "1" => {
+                let kind = if kind == "0" {
+                    BreakKind::Software
+                } else {
+                    BreakKind::Hardware
+                };
+                if insert {
+                    self.breakpoints.insert(address, kind);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_owned();
+            }
+            "2" => Trigger::Write { address, length },
+            "3" => Trigger::Read { address, length },
+            "4" => Trigger::Access { address, length },
+            _ => return String::new(),
+        };
+        if insert {
+            self.watchpoints.add(trigger, None);
+        } else {
+            let wanted = watch_reason(&trigger);
+            let found = self
+                .watchpoints
+                .iter()
+                .find(|w| watch_reason(&w.trigger) == wanted)
+                .map(|w| w.id);
+            if let Some(id) = found {
+                self.watchpoints.remove(id);
+            }
+        }
+        "OK".to_owned()
+    }
+
+    // None ends the session
+    fn handle(&mut self, packet: &str, packets: &mut Packets) -> io::Result<Option<String>> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.last_stop.clone(),
+            Some(b'g') => (0..REGISTERS).map(|n| self.encode_register(n)).collect(),
+            Some(b'G') => {
+                for n in 0..REGISTERS {
+                    if let Some(value) = packet
+                        .get(1 + 8 * n..9 + 8 * n)
+                        .and_then(|s| self.decode_register(s))
+                    {
+                        self.set_register(n, value);
+                    }
+                }
+                "OK".to_owned()
+            }
+            Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(n) if n < REGISTERS => self.encode_register(n),
+                _ => "E01".to_owned(),
+            },
+            Some(b'P') => {
+                let set = packet[1..].split_once('=').and_then(|(n, v)| {
+                    Some((usize::from_str_radix(n, 16).ok()?, self.decode_register(v)?))
+                });
+                match set {
+                    Some((n, value)) if n < REGISTERS => {
+                        self.set_register(n, value);
+                        "OK".to_owned()
+                    }
+                    _ => "E01".to_owned(),
+                }
+            }
+            Some(b'm') => match address_length(&packet[1..]) {
+                Some((address, length)) => {
+                    hex_bytes(&self.state.memory().read_bytes(address, length))
+                }
+                None => "E01".to_owned(),
+            },
+            Some(b'M') => {
+                let write = packet[1..].split_once(':').and_then(|(al, data)| {
+                    let (address, length) = address_length(al)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    Some((address, bytes)).filter(|(_, b)| b.len() == length as usize)
+                });
+                match write {
+                    Some((address, bytes)) => {
+                        self.state.memory_mut().load(address, &bytes);
+                        "OK".to_owned()
+                    }
+                    None => "E01".to_owned(),
+                }
+            }
+            Some(b'c') | Some(b's') => {
+                if let Some(address) = hex(&packet[1..]) {
+                    self.state.set_pc(address);
+                }
+                let stop = self.resume(packets, packet.starts_with('s'))?;
+                self.last_stop = stop.clone();
+                stop
+            }
+            Some(b'b') if packet == "bs" || packet == "bc" => {
+                let stop = self.reverse(packet == "bs");
+                self.last_stop = stop.clone();
+                stop
+            }
+            Some(b'Z') => self.set_point(packet, true),
+            Some(b'z') => self.set_point(packet, false),
+            Some(b'H') | Some(b'T') => "OK".to_owned(),
+            Some(b'k') => return Ok(None),
+            Some(b'D') => {
+                packets.send("OK")?;
+                return Ok(None);
+            }
+            Some(b'q') | Some(b'Q') => self.query(packet, packets),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str, packets: &mut Packets) -> String {
+        if let Some(features) = packet.strip_prefix("qSupported") {
+            self.swbreak = features.contains("swbreak+");
+            self.hwbreak = features.contains("hwbreak+");
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+;\
+                    ReverseStep+;ReverseContinue+"
+                .to_owned();
+        }
+        if let Some(request) = packet.strip_prefix("qXfer:features:read:") {
+            let read = request
+                .split_once(':')
+                .and_then(|(annex, range)| Some((self.features(annex)?, address_length(range)?)));
+            return match read {
+                Some((xml, (offset, length))) => {
+                    let rest = xml.get(offset as usize..).unwrap_or("");
+                    if rest.len() > length as usize {
+                        format!("m{}", &rest[..length as usize])
+                    } else {
+                        format!("l{}", rest)
+                    }
+                }
+                None => "E00".to_owned(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                // the OK is still acknowledged
+                packets.pending.clear();
+                let reply = packets.send("OK");
+                packets.no_ack = reply.is_ok();
+                String::new()
+            }
+            "qAttached" => "1".to_owned(),
+            "qC" => "QC1".to_owned(),
+            "qfThreadInfo" => "m1".to_owned(),
+            "qsThreadInfo" => "l".to_owned(),
+            _ => String::new(),
+        }
+    }
+
+    /// Answers packets until GDB kills or detaches from the program, or disconnects.
+    pub fn serve(&mut self, conn: &mut dyn Connection) -> io::Result<()> {
+        let mut packets = Packets {
+            conn,
+            pending: Vec::new(),
+            no_ack: false,
+        };
+        loop {
+            let packet = match packets.receive()? {
+                Incoming::Packet(p) => p,
+                Incoming::Interrupt => continue,
+                Incoming::Closed => return Ok(()),
+            };
+            let no_ack_request = packet == "QStartNoAckMode";
+            match self.handle(&packet, &mut packets)? {
+                // QStartNoAckMode has replied already
+                Some(_) if no_ack_request => (),
+                Some(reply) => packets.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// The other end of the protocol, enough to drive the server from tests and scripts.
+pub struct Client<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Client<S> {
+        Client { stream }
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut b = [0];
+        self.stream.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    /// Sends `packet` and returns the server's reply.
+    pub fn request(&mut self, packet: &str) -> io::Result<String> {
+        write!(self.stream, "${}#{:02x}", packet, checksum(packet))?;
+        self.stream.flush()?;
+        while self.byte()? != b'$' {}
+        let mut reply = Vec::new();
+        loop {
+            match self.byte()? {
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+        self.byte()?;
+        self.byte()?;
+        self.stream.write_all(b"+")?;
+        Ok(String::from_utf8_lossy(&reply).into_owned())
+    }
+
+    /// Reads a register by GDB number, decoding it in the given byte order.
+    pub fn read_register(&mut self, n: usize, big_endian: bool) -> io::Result<u32> {
+        let reply = self.request(&format!("p{:x}", n))?;
+        let bytes = parse_hex_bytes(&reply).filter(|b| b.len() == 4);
+        let bytes = bytes.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, reply))?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Ends the session. The server acknowledges a kill but doesn't answer it.
+    pub fn kill(&mut self) -> io::Result<()> {
+        write!(self.stream, "$k#{:02x}", checksum("k"))?;
+        self.stream.flush()?;
+        self.byte().map(|_| ())
+    }
+
+    pub fn interrupt(&mut self) -> io::Result<()> {
+        self.stream.write_all(&[0x03])
+    }
+}
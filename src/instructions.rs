@@ -1,5 +1,7 @@
+pub mod cop0;
 pub mod itype;
 pub mod jtype;
+pub mod pseudo;
 pub mod rtype;
 
 #[derive(Clone, Debug)]
@@ -8,6 +10,19 @@ pub enum Inst {
     ILabel(itype::ITypeLabel),
     R(rtype::RType),
     J(jtype::JType),
+    C0(cop0::C0Type),
+    PImm(pseudo::PseudoImm),
+    PLabel(pseudo::PseudoLabel),
+}
+
+impl Inst {
+    pub fn expand(&self) -> Vec<Inst> {
+        match self {
+            Inst::PImm(p) => p.expand(),
+            Inst::PLabel(p) => p.expand(),
+            _ => vec![self.clone()],
+        }
+    }
 }
 
 impl From<itype::ITypeImm> for Inst {
@@ -22,7 +37,6 @@ impl From<itype::ITypeLabel> for Inst {
     }
 }
 
-
 impl From<rtype::RType> for Inst {
     fn from(r: rtype::RType) -> Self {
         Inst::R(r)
@@ -34,3 +48,21 @@ impl From<jtype::JType> for Inst {
         Inst::J(j)
     }
 }
+
+impl From<cop0::C0Type> for Inst {
+    fn from(c: cop0::C0Type) -> Self {
+        Inst::C0(c)
+    }
+}
+
+impl From<pseudo::PseudoImm> for Inst {
+    fn from(p: pseudo::PseudoImm) -> Self {
+        Inst::PImm(p)
+    }
+}
+
+impl From<pseudo::PseudoLabel> for Inst {
+    fn from(p: pseudo::PseudoLabel) -> Self {
+        Inst::PLabel(p)
+    }
+}
@@ -0,0 +1,122 @@
+use crate::machine::{
+    register::Reg,
+    state::{Exception, InstructionClass, Operands, State},
+};
+
+pub const COP0_OPCODE: u32 = 0x10;
+const MF: u32 = 0x00;
+const MT: u32 = 0x04;
+const ERET: u32 = 0x4200_0018;
+
+/// Moves to and from coprocessor 0, and returning from an exception. `rd` is the
+/// coprocessor register.
+#[derive(Copy, Clone, Debug)]
+pub struct C0Type {
+    op: C0Inst,
+    rt: Reg,
+    rd: u8,
+}
+
+impl C0Type {
+    pub fn new(op: C0Inst, rt: Reg, rd: u8) -> C0Type {
+        C0Type { op, rt, rd }
+    }
+    pub fn decode(n: u32) -> Option<C0Type> {
+        if n >> 26 != COP0_OPCODE {
+            return None;
+        }
+        if n == ERET {
+            return Some(C0Type::new(C0Inst::eret, Reg::zero, 0));
+        }
+        let op = match (n >> 21) & 0x1F {
+            MF => C0Inst::mfc0,
+            MT => C0Inst::mtc0,
+            _ => return None,
+        };
+        if n & 0x7FF != 0 {
+            return None;
+        }
+        Some(C0Type::new(
+            op,
+            Reg::from(n >> 16),
+            ((n >> 11) & 0x1F) as u8,
+        ))
+    }
+    pub fn perform(&self, state: &mut State) -> Result<(), Exception> {
+        match self.op {
+            C0Inst::mfc0 => state.write_reg(self.rt, state.read_cp0(self.rd)),
+            C0Inst::mtc0 => state.write_cp0(self.rd, state.read_reg(self.rt)),
+            C0Inst::eret => state.eret(),
+        }
+        Ok(())
+    }
+    pub fn class(&self) -> InstructionClass {
+        match self.op {
+            C0Inst::eret => InstructionClass::Jump,
+            // register moves, like the ALU ones
+            _ => InstructionClass::Arithmetic,
+        }
+    }
+    pub fn mnemonic(&self) -> String {
+        String::from(self.op)
+    }
+    pub fn operands(&self) -> Operands {
+        match self.op {
+            C0Inst::mfc0 => Operands::new(&[], None, Some(self.rt)),
+            C0Inst::mtc0 => Operands::new(&[self.rt], None, None),
+            C0Inst::eret => Operands::default(),
+        }
+    }
+    pub fn disassemble(&self) -> String {
+        match self.op {
+            C0Inst::eret => String::from(self.op),
+            _ => format!(
+                "{} {}, ${}",
+                String::from(self.op),
+                String::from(self.rt),
+                self.rd
+            ),
+        }
+    }
+}
+
+impl From<C0Type> for u32 {
+    fn from(c: C0Type) -> u32 {
+        let rs = match c.op {
+            C0Inst::mfc0 => MF,
+            C0Inst::mtc0 => MT,
+            C0Inst::eret => return ERET,
+        };
+        (COP0_OPCODE << 26) | (rs << 21) | (u32::from(c.rt) << 16) | (u32::from(c.rd) << 11)
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum C0Inst {
+    mfc0,
+    mtc0,
+    eret,
+}
+
+impl From<C0Inst> for String {
+    fn from(c: C0Inst) -> String {
+        match c {
+            C0Inst::mfc0 => "mfc0",
+            C0Inst::mtc0 => "mtc0",
+            C0Inst::eret => "eret",
+        }
+        .to_owned()
+    }
+}
+
+impl From<&str> for C0Inst {
+    fn from(s: &str) -> C0Inst {
+        match s.to_lowercase().as_ref() {
+            "mfc0" => C0Inst::mfc0,
+            "mtc0" => C0Inst::mtc0,
+            "eret" => C0Inst::eret,
+            _ => panic!("No match for coprocessor 0 instruction: {}", s),
+        }
+    }
+}
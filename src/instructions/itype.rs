@@ -1,7 +1,10 @@
-//use crate::machine::state::State;
 use std::{convert::TryFrom, num::NonZeroU32};
 
-use crate::machine::{address::Address, register::Reg};
+use crate::machine::{
+    address::Address,
+    register::Reg,
+    state::{Exception, InstructionClass, Operands, State},
+};
 
 #[derive(Clone, Debug)]
 pub struct ITypeImm {
@@ -28,31 +31,122 @@ impl ITypeImm {
             imm,
         }
     }
-    /*
-    pub fn perform(&self, state: &mut State) {
+    pub fn perform(&self, state: &mut State) -> Result<(), Exception> {
         let rs = state.read_reg(self.rs);
         let rt = state.read_reg(self.rt);
         let imm = u32::from(self.imm);
+        let simm = i32::from(self.imm as i16) as u32;
+        let address = u32::wrapping_add(rs, simm);
+        let branch = state.read_pc().wrapping_add(4).wrapping_add(simm << 2);
         match self.opcode {
-            IInst::addi => state.write_reg(self.rt, i32::wrapping_add(rs as i32, imm as i32) as u32),
-            IInst::addiu => state.write_reg(self.rt, u32::wrapping_add(rs, imm)),
+            IInst::addi => {
+                let sum = (rs as i32)
+                    .checked_add(simm as i32)
+                    .ok_or(Exception::ArithmeticOverflow)?;
+                state.write_reg(self.rt, sum as u32)
+            }
+            IInst::addiu => state.write_reg(self.rt, u32::wrapping_add(rs, simm)),
             IInst::andi => state.write_reg(self.rt, rs & imm),
-            IInst::beq => if rs == rt { state.jump(imm) },
-            IInst::bne => if rs != rt { state.jump(imm) },
-            IInst::lbu => state.write_reg(self.rt, state.read_mem(u32::wrapping_add(rs, imm)) & 0xFFu32),
-            IInst::lhu => state.write_reg(self.rt, state.read_mem(u32::wrapping_add(rs, imm)) & 0xFFFFu32),
-            IInst::ll | IInst::lw => state.write_reg(self.rt, state.read_mem(u32::wrapping_add(rs, imm))),
-            IInst::li | IInst::la => state.write_reg(self.rt, imm),
+            IInst::beq => {
+                if rs == rt {
+                    state.jump(branch)
+                }
+            }
+            IInst::bne => {
+                if rs != rt {
+                    state.jump(branch)
+                }
+            }
+            IInst::lbu => state.write_reg(self.rt, state.read_byte(address)),
+            IInst::lhu => state.write_reg(self.rt, state.read_half(address)?),
+            IInst::ll | IInst::lw => state.write_reg(self.rt, state.read_mem(address)?),
+            IInst::li | IInst::la => {
+                return Err(Exception::ReservedInstruction(u32::from(self.clone())))
+            }
             IInst::lui => state.write_reg(self.rt, imm << 16),
             IInst::ori => state.write_reg(self.rt, rs | imm),
-            IInst::slti => state.write_reg(self.rt, match (rs as i32) < (imm as i32) { true => 1u32, false => 0u32 }),
-            IInst::sltiu => state.write_reg(self.rt, match rs < imm { true => 1u32, false => 0u32 }),
-            IInst::sb => state.write_mem(u32::wrapping_add(rs, imm), rt & 0xFFu32),
-            IInst::sc => unimplemented!(),
-            IInst::sh => state.write_mem(u32::wrapping_add(rs, imm), rt & 0xFFFFu32),
-            IInst::sw => state.write_mem(u32::wrapping_add(rs, imm), rt),
+            IInst::slti => state.write_reg(self.rt, ((rs as i32) < (simm as i32)) as u32),
+            IInst::sltiu => state.write_reg(self.rt, (rs < simm) as u32),
+            IInst::sb => state.write_byte(address, rt as u8),
+            // nothing else runs, so the link is never broken
+            IInst::sc => {
+                state.write_mem(address, rt)?;
+                state.write_reg(self.rt, 1u32)
+            }
+            IInst::sh => state.write_half(address, rt as u16)?,
+            IInst::sw => state.write_mem(address, rt)?,
+        }
+        Ok(())
+    }
+    pub fn class(&self) -> InstructionClass {
+        match self.opcode {
+            IInst::andi | IInst::ori => InstructionClass::Logical,
+            IInst::beq | IInst::bne => InstructionClass::Branch,
+            IInst::lbu | IInst::lhu | IInst::ll | IInst::lw => InstructionClass::Load,
+            IInst::sb | IInst::sc | IInst::sh | IInst::sw => InstructionClass::Store,
+            _ => InstructionClass::Arithmetic,
+        }
+    }
+    pub fn mnemonic(&self) -> String {
+        String::from(self.opcode)
+    }
+    pub fn operands(&self) -> Operands {
+        match self.opcode {
+            IInst::beq | IInst::bne => Operands::new(&[self.rs, self.rt], None, None),
+            IInst::sb | IInst::sh | IInst::sw => Operands::new(&[self.rs], Some(self.rt), None),
+            IInst::sc => Operands::new(&[self.rs], Some(self.rt), Some(self.rt)),
+            _ => Operands::new(&[self.rs], None, Some(self.rt)),
+        }
+    }
+    // branches write no registers, so this holds after they ran as well as before
+    pub fn branch_taken(&self, state: &State) -> Option<bool> {
+        let rs = state.read_reg(self.rs);
+        let rt = state.read_reg(self.rt);
+        match self.opcode {
+            IInst::beq => Some(rs == rt),
+            IInst::bne => Some(rs != rt),
+            _ => None,
+        }
+    }
+    pub fn branch_target(&self, address: u32) -> Option<u32> {
+        match self.opcode {
+            IInst::beq | IInst::bne => Some(
+                address
+                    .wrapping_add(4)
+                    .wrapping_add((i32::from(self.imm as i16) << 2) as u32),
+            ),
+            _ => None,
+        }
+    }
+    // `address` is where the instruction sits, so branches can show their target
+    pub fn disassemble(&self, address: u32) -> String {
+        let op = String::from(self.opcode);
+        let rs = String::from(self.rs);
+        let rt = String::from(self.rt);
+        let simm = self.imm as i16;
+        match self.opcode {
+            IInst::addi | IInst::addiu | IInst::slti | IInst::sltiu => {
+                format!("{} {}, {}, {}", op, rt, rs, simm)
+            }
+            IInst::andi | IInst::ori => format!("{} {}, {}, 0x{:x}", op, rt, rs, self.imm),
+            IInst::beq | IInst::bne => {
+                let target = address
+                    .wrapping_add(4)
+                    .wrapping_add((i32::from(simm) << 2) as u32);
+                format!("{} {}, {}, 0x{:08x}", op, rs, rt, target)
+            }
+            IInst::lbu
+            | IInst::lhu
+            | IInst::ll
+            | IInst::lw
+            | IInst::sb
+            | IInst::sc
+            | IInst::sh
+            | IInst::sw => format!("{} {}, {}({})", op, rt, simm, rs),
+            IInst::li | IInst::la | IInst::lui => format!("{} {}, 0x{:x}", op, rt, self.imm),
         }
     }
+    /*
     pub fn convert_to_string(&self, state: &State) -> String {
         let imm_str_label = match self.imm {
             Imm::Address(j) => state.find_label_by_addr(j),
@@ -168,6 +262,15 @@ impl ITypeLabel {
             label,
         }
     }
+    pub fn opcode(&self) -> IInst {
+        self.opcode
+    }
+    pub fn label(&self) -> &Address {
+        &self.label
+    }
+    pub fn resolve(&self, imm: u16) -> ITypeImm {
+        ITypeImm::new(self.opcode, self.rs, self.rt, imm)
+    }
 }
 
 impl From<u32> for ITypeImm {
@@ -256,6 +359,18 @@ pub enum IInst {
     sw,
 }
 
+impl IInst {
+    // `li` and `la` only have made up opcodes for the parser, so they never decode
+    pub fn decode(opcode: u32) -> Option<IInst> {
+        match opcode & 0x3F {
+            0x3F | 0x01 => None,
+            0x08 | 0x09 | 0x0C | 0x04 | 0x05 | 0x24 | 0x25 | 0x30 | 0x0F | 0x23 | 0x0D | 0x0A
+            | 0x0B | 0x28 | 0x38 | 0x29 | 0x2B => Some(IInst::from(opcode)),
+            _ => None,
+        }
+    }
+}
+
 impl From<IInst> for String {
     fn from(i: IInst) -> String {
         match i {
@@ -1,7 +1,10 @@
-//use crate::machine::state::State;
 use std::{convert::TryFrom, num::NonZeroU32};
 
-use crate::machine::address::Address;
+use crate::machine::{
+    address::Address,
+    register::Reg,
+    state::{Exception, State},
+};
 
 #[derive(Clone, Debug)]
 pub struct JType {
@@ -13,14 +16,37 @@ impl JType {
     pub fn new(opcode: JInst, address: Address) -> JType {
         JType { opcode, address }
     }
-    /*
-    pub fn perform(&self, state: &mut State) {
-        let address = u32::from(self.address);
+    pub fn opcode(&self) -> JInst {
+        self.opcode
+    }
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+    pub fn encode_with_target(&self, target: u32) -> u32 {
+        (u32::from(self.opcode) << 26) | ((target >> 2) & 0x3FF_FFFF)
+    }
+    // `j 0` is a perfectly good instruction, unlike what From<u32> allows
+    pub fn decode(opcode: JInst, n: u32) -> JType {
+        JType::new(opcode, Address::new(NonZeroU32::new(n & 0x3FF_FFFF), None))
+    }
+    pub fn perform(&self, state: &mut State) -> Result<(), Exception> {
+        let index = self.address.numeric.map_or(0, |n| n.get());
+        let address = (state.read_pc().wrapping_add(4) & 0xF000_0000) | (index << 2);
         match self.opcode {
             JInst::j => state.jump(address),
-            JInst::jal => { state.write_reg(Reg::ra, state.read_pc()); state.jump(address); },
+            JInst::jal => {
+                state.write_reg(Reg::ra, state.return_address());
+                state.jump(address);
+            }
         }
+        Ok(())
     }
+    pub fn disassemble(&self, address: u32) -> String {
+        let index = self.address.numeric.map_or(0, |n| n.get());
+        let target = (address.wrapping_add(4) & 0xF000_0000) | (index << 2);
+        format!("{} 0x{:08x}", String::from(self.opcode), target)
+    }
+    /*
     pub fn convert_to_string(&self, state: &State) -> String {
         let address_str_label: Option<String> = match self.address {
             Imm::Address(a) => state.find_label_by_addr(a),
@@ -95,9 +121,19 @@ pub enum JInst {
     jal,
 }
 
+impl JInst {
+    pub fn decode(opcode: u32) -> Option<JInst> {
+        match opcode & 0x3F {
+            0x02 => Some(JInst::j),
+            0x03 => Some(JInst::jal),
+            _ => None,
+        }
+    }
+}
+
 impl From<JInst> for String {
-    fn from(j: JInst) -> String {
-        match j {
+    fn from(inst: JInst) -> String {
+        match inst {
             JInst::j => "j",
             JInst::jal => "jal",
         }
@@ -132,8 +168,8 @@ macro_rules! jinst_map {
 macro_rules! jinst_inv_map {
     ($type_name: ty) => {
         impl From<JInst> for $type_name {
-            fn from(j: JInst) -> Self {
-                match j {
+            fn from(inst: JInst) -> Self {
+                match inst {
                     JInst::j => 0x02,
                     JInst::jal => 0x03,
                 }
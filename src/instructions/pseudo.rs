@@ -0,0 +1,90 @@
+use crate::{
+    instructions::{
+        itype::{IInst, ITypeImm, ITypeLabel},
+        Inst,
+    },
+    machine::{address::Address, register::Reg},
+};
+
+#[derive(Clone, Debug)]
+pub struct PseudoImm {
+    opcode: PInst,
+    rt: Reg,
+    imm: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct PseudoLabel {
+    opcode: PInst,
+    rt: Reg,
+    label: Address,
+}
+
+impl PseudoImm {
+    pub fn new(opcode: PInst, rt: Reg, imm: u32) -> PseudoImm {
+        PseudoImm { opcode, rt, imm }
+    }
+    pub fn opcode(&self) -> PInst {
+        self.opcode
+    }
+    pub fn expand(&self) -> Vec<Inst> {
+        let imm = self.imm;
+        if imm as i32 >= i32::from(i16::MIN) && imm as i32 <= i32::from(i16::MAX) {
+            vec![ITypeImm::new(IInst::addiu, Reg::zero, self.rt, imm as u16).into()]
+        } else if imm <= 0xFFFF {
+            vec![ITypeImm::new(IInst::ori, Reg::zero, self.rt, imm as u16).into()]
+        } else {
+            vec![
+                ITypeImm::new(IInst::lui, Reg::zero, Reg::at, (imm >> 16) as u16).into(),
+                ITypeImm::new(IInst::ori, Reg::at, self.rt, imm as u16).into(),
+            ]
+        }
+    }
+}
+
+impl PseudoLabel {
+    pub fn new(opcode: PInst, rt: Reg, label: Address) -> PseudoLabel {
+        PseudoLabel { opcode, rt, label }
+    }
+    pub fn opcode(&self) -> PInst {
+        self.opcode
+    }
+    pub fn label(&self) -> &Address {
+        &self.label
+    }
+    pub fn expand(&self) -> Vec<Inst> {
+        // always two words so that layout doesn't depend on where the label lands, and
+        // addiu rather than ori so the pair matches what a linker does with HI16/LO16
+        vec![
+            ITypeLabel::new(IInst::lui, Reg::zero, Reg::at, self.label.clone()).into(),
+            ITypeLabel::new(IInst::addiu, Reg::at, self.rt, self.label.clone()).into(),
+        ]
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug)]
+pub enum PInst {
+    li,
+    la,
+}
+
+impl From<PInst> for String {
+    fn from(p: PInst) -> String {
+        match p {
+            PInst::li => "li",
+            PInst::la => "la",
+        }
+        .to_owned()
+    }
+}
+
+impl From<&str> for PInst {
+    fn from(s: &str) -> PInst {
+        match s.to_lowercase().as_ref() {
+            "li" => PInst::li,
+            "la" => PInst::la,
+            _ => panic!("No such pseudo instruction: {}", s),
+        }
+    }
+}
@@ -1,5 +1,7 @@
-//use crate::machine::state::State;
-use crate::machine::register::Reg;
+use crate::machine::{
+    register::Reg,
+    state::{Exception, InstructionClass, Operands, State},
+};
 
 #[derive(Copy, Clone, Debug)]
 pub struct RType {
@@ -20,27 +22,95 @@ impl RType {
             funct,
         }
     }
-    /*
-    pub fn perform(&self, state: &mut State) {
+    pub fn perform(&self, state: &mut State) -> Result<(), Exception> {
         let rs = state.read_reg(self.rs);
         let rt = state.read_reg(self.rt);
         match self.funct {
-            RInst::add => state.write_reg(self.rd, i32::wrapping_add(rs as i32, rt as i32) as u32),
+            RInst::add => {
+                let sum = (rs as i32)
+                    .checked_add(rt as i32)
+                    .ok_or(Exception::ArithmeticOverflow)?;
+                state.write_reg(self.rd, sum as u32)
+            }
             RInst::addu => state.write_reg(self.rd, u32::wrapping_add(rs, rt)),
             RInst::and => state.write_reg(self.rd, rs & rt),
             RInst::jr => state.jump(rs),
             RInst::nor => state.write_reg(self.rd, !(rs | rt)),
             RInst::or => state.write_reg(self.rd, rs | rt),
-            RInst::slt => state.write_reg(self.rd, match (rs as i32) < (rt as i32) { true => 1u32, false => 0u32 }),
-            RInst::sltu => state.write_reg(self.rd, match rs < rt { true => 1u32, false => 0u32 }),
-            RInst::sll => state.write_reg(self.rd, rt << self.shamt),
-            RInst::srl => state.write_reg(self.rd, rt >> self.shamt),
-            RInst::sub => state.write_reg(self.rd, i32::wrapping_sub(rs as i32, rt as i32) as u32),
+            RInst::slt => state.write_reg(self.rd, ((rs as i32) < (rt as i32)) as u32),
+            RInst::sltu => state.write_reg(self.rd, (rs < rt) as u32),
+            RInst::sll => state.write_reg(self.rd, rt << (self.shamt & 0x1f)),
+            RInst::srl => state.write_reg(self.rd, rt >> (self.shamt & 0x1f)),
+            RInst::sub => {
+                let difference = (rs as i32)
+                    .checked_sub(rt as i32)
+                    .ok_or(Exception::ArithmeticOverflow)?;
+                state.write_reg(self.rd, difference as u32)
+            }
             RInst::subu => state.write_reg(self.rd, u32::wrapping_sub(rs, rt)),
-            RInst::div => state.write_reg(self.rd, ((rs as i32) / (rt as i32)) as u32),
-            RInst::divu => state.write_reg(self.rd, rs / rt),
+            // dividing by zero leaves HI and LO alone, the result is unpredictable anyway;
+            // the quotient also goes to rd for the three operand form the parser accepts
+            RInst::div => {
+                if rt != 0 {
+                    let quotient = (rs as i32).wrapping_div(rt as i32) as u32;
+                    let remainder = (rs as i32).wrapping_rem(rt as i32) as u32;
+                    state.write_hi_lo(remainder, quotient);
+                    state.write_reg(self.rd, quotient);
+                }
+            }
+            RInst::divu => {
+                if rt != 0 {
+                    state.write_hi_lo(rs % rt, rs / rt);
+                    state.write_reg(self.rd, rs / rt);
+                }
+            }
+            RInst::syscall => state.syscall()?,
+        }
+        Ok(())
+    }
+    pub fn class(&self) -> InstructionClass {
+        match self.funct {
+            RInst::and | RInst::nor | RInst::or => InstructionClass::Logical,
+            RInst::sll | RInst::srl => InstructionClass::Shift,
+            RInst::jr => InstructionClass::Jump,
+            RInst::syscall => InstructionClass::Syscall,
+            _ => InstructionClass::Arithmetic,
+        }
+    }
+    pub fn mnemonic(&self) -> String {
+        String::from(self.funct)
+    }
+    pub fn operands(&self) -> Operands {
+        match self.funct {
+            RInst::jr => Operands::new(&[self.rs], None, None),
+            RInst::sll | RInst::srl => Operands::new(&[self.rt], None, Some(self.rd)),
+            // whether $v0 gets written depends on the call, so assume it does
+            RInst::syscall => Operands::new(&[Reg::v0, Reg::a0, Reg::a1], None, Some(Reg::v0)),
+            _ => Operands::new(&[self.rs, self.rt], None, Some(self.rd)),
+        }
+    }
+    pub fn disassemble(&self) -> String {
+        let op = String::from(self.funct);
+        match self.funct {
+            RInst::sll | RInst::srl => format!(
+                "{} {}, {}, {}",
+                op,
+                String::from(self.rd),
+                String::from(self.rt),
+                self.shamt
+            ),
+            RInst::jr => format!("{} {}", op, String::from(self.rs)),
+            RInst::syscall => op,
+            _ => format!(
+                "{} {}, {}, {}",
+                op,
+                String::from(self.rd),
+                String::from(self.rs),
+                String::from(self.rt)
+            ),
         }
     }
+    /*
     pub fn convert_to_string(&self, _state: &State) -> String {
         match self.funct {
             RInst::add  |
@@ -113,7 +183,7 @@ impl From<RType> for u32 {
         x |= u32::from(r.rs) << 21;
         x |= u32::from(r.rt) << 16;
         x |= u32::from(r.rd) << 11;
-        x |= u32::from(r.shamt) << 6;
+        x |= u32::from(r.shamt & 0x1f) << 6;
         x |= u32::from(r.funct);
         x
     }
@@ -136,6 +206,32 @@ pub enum RInst {
     subu,
     div,
     divu,
+    syscall,
+}
+
+const RINSTS: [RInst; 15] = [
+    RInst::add,
+    RInst::addu,
+    RInst::and,
+    RInst::jr,
+    RInst::nor,
+    RInst::or,
+    RInst::slt,
+    RInst::sltu,
+    RInst::sll,
+    RInst::srl,
+    RInst::sub,
+    RInst::subu,
+    RInst::div,
+    RInst::divu,
+    RInst::syscall,
+];
+
+impl RInst {
+    // unlike From, unknown funct codes aren't a panic since they come from memory
+    pub fn decode(n: u32) -> Option<RInst> {
+        RINSTS.iter().copied().find(|r| u32::from(*r) == n & 0x3F)
+    }
 }
 
 impl From<RInst> for String {
@@ -155,6 +251,7 @@ impl From<RInst> for String {
             RInst::subu => "subu",
             RInst::div => "div",
             RInst::divu => "divu",
+            RInst::syscall => "syscall",
         }
         .to_owned()
     }
@@ -177,6 +274,7 @@ impl From<&str> for RInst {
             "subu" => RInst::subu,
             "div" => RInst::div,
             "divu" => RInst::divu,
+            "syscall" => RInst::syscall,
             _ => panic!("No match for RType: {}", s),
         }
     }
@@ -201,6 +299,7 @@ macro_rules! rinst_map {
                     0x23 => RInst::subu,
                     0x1A => RInst::div,
                     0x1B => RInst::divu,
+                    0x0C => RInst::syscall,
                     _ => panic!("No match for RType funct code: 0x{:08X}", num),
                 }
             }
@@ -227,6 +326,7 @@ macro_rules! rinst_inv_map {
                     RInst::subu => 0x23,
                     RInst::div => 0x1A,
                     RInst::divu => 0x1B,
+                    RInst::syscall => 0x0C,
                 }
             }
         }
@@ -4,7 +4,9 @@ use std::{fs, io, path};
 pub mod assembler;
 pub mod parser;
 mod instructions;
-mod machine;
+pub mod machine;
+pub mod debugger;
+pub mod gdb;
 
 pub fn load_file(p: &path::Path) -> String {
     let mut file: fs::File;
@@ -1,3 +1,16 @@
-//pub mod state;
 pub mod address;
+pub mod bitmap;
+pub mod cache;
+pub mod cp0;
+pub mod device;
+pub mod dump;
+pub mod elf;
+pub mod history;
+pub mod memory;
+pub mod pipeline;
+pub mod predictor;
 pub mod register;
+pub mod state;
+pub mod stats;
+pub mod trace;
+pub mod watch;
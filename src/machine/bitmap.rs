@@ -0,0 +1,275 @@
+use std::convert::TryFrom;
+
+use crate::machine::memory::Memory;
+
+/// The size and place of a bitmap display, as set up in MARS: the display is `width`
+/// by `height` pixels and each word of the frame buffer, 0x00RRGGBB, fills a unit of
+/// `unit_width` by `unit_height` of them, row by row from `base`. As in MARS the frame
+/// buffer is ordinary memory, so it can overlap the program's data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BitmapConfig {
+    pub base: u32,
+    pub width: u32,
+    pub height: u32,
+    pub unit_width: u32,
+    pub unit_height: u32,
+}
+
+impl Default for BitmapConfig {
+    fn default() -> Self {
+        BitmapConfig {
+            base: 0x1001_0000,
+            width: 512,
+            height: 256,
+            unit_width: 1,
+            unit_height: 1,
+        }
+    }
+}
+
+// 16384 by 16384, which keeps the image and its PNG chunks well under 4GB
+const MAX_PIXELS: u64 = 1 << 28;
+
+// the base addresses MARS offers
+const BASES: [(&str, u32); 5] = [
+    ("global", 0x1000_0000),
+    ("gp", 0x1000_8000),
+    ("data", 0x1001_0000),
+    ("heap", 0x1004_0000),
+    ("mmio", 0xffff_0000),
+];
+
+fn number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// `8` or `8x4`
+fn dimensions(s: &str) -> Option<(u32, u32)> {
+    match s.split_once('x') {
+        Some((w, h)) => Some((w.parse().ok()?, h.parse().ok()?)),
+        None => s.parse().ok().map(|n| (n, n)),
+    }
+}
+
+impl BitmapConfig {
+    /// Frame buffer words across and down.
+    pub fn columns(&self) -> u32 {
+        self.width.checked_div(self.unit_width).unwrap_or(0)
+    }
+    pub fn rows(&self) -> u32 {
+        self.height.checked_div(self.unit_height).unwrap_or(0)
+    }
+    /// The bytes the frame buffer takes up, which can be more than fit in memory, up
+    /// to `u64::MAX`.
+    pub fn size(&self) -> u64 {
+        (u64::from(self.columns()) * u64::from(self.rows())).saturating_mul(4)
+    }
+    /// Whether the display can be drawn: `try_from` only gives configs that can, but
+    /// the fields can be set by hand.
+    pub fn check(&self) -> Result<(), String> {
+        if self.unit_width == 0 || self.unit_height == 0 {
+            return Err("Bitmap units can't be empty".to_owned());
+        }
+        if self.width % self.unit_width != 0 || self.height % self.unit_height != 0 {
+            return Err(format!(
+                "A {}x{} display can't be made of {}x{} units",
+                self.width, self.height, self.unit_width, self.unit_height
+            ));
+        }
+        if self.columns() == 0 || self.rows() == 0 {
+            return Err("The bitmap display is empty".to_owned());
+        }
+        if self.base % 4 != 0 {
+            return Err(format!(
+                "The frame buffer has to be word aligned, not at 0x{:08x}",
+                self.base
+            ));
+        }
+        if u64::from(self.base).saturating_add(self.size()) > 1 << 32 {
+            return Err("The frame buffer runs off the end of memory".to_owned());
+        }
+        if u64::from(self.width) * u64::from(self.height) > MAX_PIXELS {
+            return Err(format!(
+                "A {}x{} display is too big to draw",
+                self.width, self.height
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for BitmapConfig {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let mut config = BitmapConfig::default();
+        for item in s.split(',').map(str::trim) {
+            let bad = || format!("Bad bitmap setting {}", item);
+            match item.split_once('=') {
+                Some(("unit", v)) => {
+                    let (w, h) = dimensions(v).ok_or_else(bad)?;
+                    config.unit_width = w;
+                    config.unit_height = h;
+                }
+                Some(("base", v)) => {
+                    config.base = BASES
+                        .iter()
+                        .find(|(name, _)| *name == v)
+                        .map(|(_, a)| *a)
+                        .or_else(|| number(v))
+                        .ok_or_else(bad)?
+                }
+                Some(_) => return Err(bad()),
+                None => {
+                    let (w, h) = dimensions(item).ok_or_else(bad)?;
+                    config.width = w;
+                    config.height = h;
+                }
+            }
+        }
+        config.check()?;
+        Ok(config)
+    }
+}
+
+impl From<BitmapConfig> for String {
+    fn from(c: BitmapConfig) -> String {
+        format!(
+            "{}x{}, unit {}x{}, base 0x{:08x}",
+            c.width, c.height, c.unit_width, c.unit_height, c.base
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    /// Goes by the file's extension, if it's one of ours.
+    pub fn from_path(path: &str) -> Option<ImageFormat> {
+        let (_, extension) = path.rsplit_once('.')?;
+        match extension.to_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "ppm" => Some(ImageFormat::Ppm),
+            _ => None,
+        }
+    }
+}
+
+/// Draws what's in the frame buffer as RGB rows, `width` by `height` pixels, unless
+/// the config doesn't `check` or there isn't the memory for the image.
+pub fn render(memory: &Memory, config: &BitmapConfig) -> Result<Vec<u8>, String> {
+    config.check()?;
+    let mut image = Vec::new();
+    image
+        .try_reserve_exact(config.width as usize * config.height as usize * 3)
+        .map_err(|_| {
+            format!(
+                "Not enough memory for a {}x{} image",
+                config.width, config.height
+            )
+        })?;
+    let stride = config.columns() * 4;
+    for y in 0..config.height {
+        let row = config.base + (y / config.unit_height) * stride;
+        for x in 0..config.width {
+            let pixel = memory.read_u32(row + (x / config.unit_width) * 4);
+            image.extend_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+    }
+    Ok(image)
+}
+
+/// The frame buffer as an image file.
+pub fn encode(
+    memory: &Memory,
+    config: &BitmapConfig,
+    format: ImageFormat,
+) -> Result<Vec<u8>, String> {
+    let image = render(memory, config)?;
+    Ok(match format {
+        ImageFormat::Ppm => {
+            let mut out = format!("P6\n{} {}\n255\n", config.width, config.height).into_bytes();
+            out.extend(image);
+            out
+        }
+        ImageFormat::Png => png(config.width, config.height, &image),
+    })
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in bytes.chunks(5552) {
+        for x in chunk {
+            a += u32::from(*x);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// An 8-bit RGB PNG. The pixels aren't compressed, only stored in zlib's format, which
+// keeps this short and is plenty for comparing frames.
+fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let stride = (width * 3) as usize;
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks(stride) {
+        // no filter
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i + 1 == blocks.len()) as u8);
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits a channel, RGB, deflate, adaptive filtering, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut out, b"IHDR", &header);
+    chunk(&mut out, b"IDAT", &zlib);
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
@@ -0,0 +1,498 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    fmt::Write,
+};
+
+use crate::machine::state::Event;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Capacity in bytes.
+    pub size: u32,
+    pub block_size: u32,
+    /// Blocks per set: 1 is direct mapped, and `size / block_size` fully associative.
+    pub ways: u32,
+    pub replacement: Replacement,
+    /// Writes go on to the next level straight away instead of when a block is evicted.
+    pub write_through: bool,
+    /// A write miss brings the block into the cache.
+    pub write_allocate: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            size: 4096,
+            block_size: 16,
+            ways: 1,
+            replacement: Replacement::Lru,
+            write_through: false,
+            write_allocate: true,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn blocks(&self) -> u32 {
+        self.size / self.block_size
+    }
+    pub fn sets(&self) -> u32 {
+        self.blocks() / self.ways
+    }
+}
+
+fn size(s: &str) -> Option<u32> {
+    let s = s.to_lowercase();
+    let (digits, scale) = match s.strip_suffix('k') {
+        Some(digits) => (digits, 1024),
+        None => match s.strip_suffix('m') {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (s.as_str(), 1),
+        },
+    };
+    digits.parse::<u32>().ok()?.checked_mul(scale)
+}
+
+/// Reads a description like `8k,block=32,ways=2,fifo,write-through,no-write-allocate`.
+/// Anything left out is as in `CacheConfig::default`; `direct` and `full` stand for the
+/// number of ways.
+impl TryFrom<&str> for CacheConfig {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let mut config = CacheConfig::default();
+        let mut fully_associative = false;
+        for item in s.split(',').map(str::trim) {
+            let bad = || format!("Bad cache setting {}", item);
+            match item.split_once('=') {
+                Some(("size", v)) => config.size = size(v).ok_or_else(bad)?,
+                Some(("block", v)) => config.block_size = size(v).ok_or_else(bad)?,
+                Some(("ways", "full")) => fully_associative = true,
+                Some(("ways", v)) => config.ways = v.parse().map_err(|_| bad())?,
+                Some(_) => return Err(bad()),
+                None => match item {
+                    "direct" => config.ways = 1,
+                    "full" => fully_associative = true,
+                    "lru" => config.replacement = Replacement::Lru,
+                    "fifo" => config.replacement = Replacement::Fifo,
+                    "random" => config.replacement = Replacement::Random,
+                    "write-through" => config.write_through = true,
+                    "write-back" => config.write_through = false,
+                    "write-allocate" => config.write_allocate = true,
+                    "no-write-allocate" => config.write_allocate = false,
+                    _ => config.size = size(item).ok_or_else(bad)?,
+                },
+            }
+        }
+        if !config.block_size.is_power_of_two() || config.block_size < 4 {
+            return Err(format!(
+                "Cache blocks have to be a power of two of at least 4 bytes, not {}",
+                config.block_size
+            ));
+        }
+        if config.size < config.block_size || config.size % config.block_size != 0 {
+            return Err(format!(
+                "A {} byte cache can't be made of {} byte blocks",
+                config.size, config.block_size
+            ));
+        }
+        if fully_associative {
+            config.ways = config.blocks();
+        }
+        if config.ways == 0 || config.blocks() % config.ways != 0 {
+            return Err(format!(
+                "{} blocks don't divide into sets of {}",
+                config.blocks(),
+                config.ways
+            ));
+        }
+        if !config.sets().is_power_of_two() {
+            return Err(format!(
+                "The number of sets has to be a power of two, not {}",
+                config.sets()
+            ));
+        }
+        Ok(config)
+    }
+}
+
+impl From<CacheConfig> for String {
+    fn from(c: CacheConfig) -> String {
+        let ways = match c.ways {
+            1 => "direct mapped".to_owned(),
+            w if w == c.blocks() => "fully associative".to_owned(),
+            w => format!("{}-way", w),
+        };
+        format!(
+            "{} bytes, {} byte blocks, {}, {}, {}, {}",
+            c.size,
+            c.block_size,
+            ways,
+            match c.replacement {
+                Replacement::Lru => "LRU",
+                Replacement::Fifo => "FIFO",
+                Replacement::Random => "random",
+            },
+            if c.write_through {
+                "write-through"
+            } else {
+                "write-back"
+            },
+            if c.write_allocate {
+                "write-allocate"
+            } else {
+                "no-write-allocate"
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_misses: u64,
+    pub write_misses: u64,
+    /// The first time a block was wanted.
+    pub compulsory: u64,
+    /// Misses a fully associative LRU cache of the same size would have had too.
+    pub capacity: u64,
+    /// The rest, down to blocks competing for the same set.
+    pub conflict: u64,
+    /// Blocks brought in from the next level.
+    pub fills: u64,
+    /// Dirty blocks written back on eviction.
+    pub write_backs: u64,
+    /// Writes passed straight on to the next level.
+    pub writes_through: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+    pub fn hit_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            0.0
+        } else {
+            (self.accesses() - self.misses()) as f64 / self.accesses() as f64
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SetUsage {
+    pub accesses: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Line {
+    block: u32,
+    dirty: bool,
+    used: u64,
+    filled: u64,
+}
+
+// a fully associative LRU cache that only tracks which blocks it holds
+#[derive(Clone, Debug, Default)]
+struct Shadow {
+    capacity: usize,
+    last_used: HashMap<u32, u64>,
+    by_age: BTreeMap<u64, u32>,
+}
+
+impl Shadow {
+    // whether `block` was there, leaving it there as the most recently used
+    fn touch(&mut self, block: u32, now: u64) -> bool {
+        let hit = match self.last_used.insert(block, now) {
+            Some(then) => {
+                self.by_age.remove(&then);
+                true
+            }
+            None => false,
+        };
+        self.by_age.insert(now, block);
+        if self.by_age.len() > self.capacity {
+            let (_, oldest) = self.by_age.pop_first().unwrap();
+            self.last_used.remove(&oldest);
+        }
+        hit
+    }
+}
+
+/// A cache that keeps track of which blocks it would hold, not their contents.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    usage: Vec<SetUsage>,
+    stats: CacheStats,
+    seen: HashSet<u32>,
+    shadow: Shadow,
+    clock: u64,
+    random: u32,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        Cache {
+            config,
+            sets: vec![Vec::new(); config.sets() as usize],
+            usage: vec![SetUsage::default(); config.sets() as usize],
+            stats: CacheStats::default(),
+            seen: HashSet::new(),
+            shadow: Shadow {
+                capacity: config.blocks() as usize,
+                ..Shadow::default()
+            },
+            clock: 0,
+            random: 0x2545_f491,
+        }
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+    /// How each set was used, by set number.
+    pub fn sets(&self) -> &[SetUsage] {
+        &self.usage
+    }
+
+    // xorshift, so runs come out the same every time
+    fn next_random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    /// Reads or writes the block holding `address`, passing what it has to on to
+    /// `below`, or to memory when that's `None`. True on a hit.
+    pub fn access(&mut self, address: u32, write: bool, mut below: Option<&mut Cache>) -> bool {
+        self.clock += 1;
+        let now = self.clock;
+        let block = address / self.config.block_size;
+        let index = (block % self.config.sets()) as usize;
+        let block_address = block.wrapping_mul(self.config.block_size);
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+        self.usage[index].accesses += 1;
+        let first_time = self.seen.insert(block);
+        let in_shadow = self.shadow.touch(block, now);
+
+        if let Some(line) = self.sets[index].iter_mut().find(|l| l.block == block) {
+            line.used = now;
+            if write {
+                if self.config.write_through {
+                    self.stats.writes_through += 1;
+                    if let Some(below) = below {
+                        below.access(address, true, None);
+                    }
+                } else {
+                    line.dirty = true;
+                }
+            }
+            return true;
+        }
+
+        if write {
+            self.stats.write_misses += 1;
+        } else {
+            self.stats.read_misses += 1;
+        }
+        self.usage[index].misses += 1;
+        if first_time {
+            self.stats.compulsory += 1;
+        } else if !in_shadow {
+            self.stats.capacity += 1;
+        } else {
+            self.stats.conflict += 1;
+        }
+
+        if write && !self.config.write_allocate {
+            self.stats.writes_through += 1;
+            if let Some(below) = below {
+                below.access(address, true, None);
+            }
+            return false;
+        }
+        if self.sets[index].len() == self.config.ways as usize {
+            let victim = match self.config.replacement {
+                Replacement::Lru => (0..self.sets[index].len())
+                    .min_by_key(|i| self.sets[index][*i].used)
+                    .unwrap(),
+                Replacement::Fifo => (0..self.sets[index].len())
+                    .min_by_key(|i| self.sets[index][*i].filled)
+                    .unwrap(),
+                Replacement::Random => (self.next_random() % self.config.ways) as usize,
+            };
+            let evicted = self.sets[index].swap_remove(victim);
+            self.usage[index].evictions += 1;
+            if evicted.dirty {
+                self.stats.write_backs += 1;
+                if let Some(ref mut below) = below {
+                    let address = evicted.block.wrapping_mul(self.config.block_size);
+                    below.access(address, true, None);
+                }
+            }
+        }
+        self.stats.fills += 1;
+        if let Some(ref mut below) = below {
+            below.access(block_address, false, None);
+        }
+        if write && self.config.write_through {
+            self.stats.writes_through += 1;
+            if let Some(below) = below {
+                below.access(address, true, None);
+            }
+        }
+        self.sets[index].push(Line {
+            block,
+            dirty: write && !self.config.write_through,
+            used: now,
+            filled: now,
+        });
+        false
+    }
+
+    /// Accesses every block `size` bytes from `address` touch.
+    pub fn access_range(
+        &mut self,
+        address: u32,
+        size: u32,
+        write: bool,
+        mut below: Option<&mut Cache>,
+    ) {
+        let block_size = self.config.block_size;
+        let first = address / block_size;
+        let last = address.saturating_add(size.max(1) - 1) / block_size;
+        for block in first..=last {
+            let at = if block == first {
+                address
+            } else {
+                block * block_size
+            };
+            self.access(at, write, below.as_deref_mut());
+        }
+    }
+
+    pub fn report(&self, name: &str) -> String {
+        let s = &self.stats;
+        let mut out = format!("{}: {}\n", name, String::from(self.config));
+        let mut row = |name: &str, value: String| {
+            writeln!(out, "  {:<18} {:>10}", name, value).unwrap();
+        };
+        row("accesses", s.accesses().to_string());
+        row("hit rate", format!("{:.2}%", s.hit_rate() * 100.0));
+        row("reads", s.reads.to_string());
+        row("read misses", s.read_misses.to_string());
+        row("writes", s.writes.to_string());
+        row("write misses", s.write_misses.to_string());
+        row("compulsory", s.compulsory.to_string());
+        row("capacity", s.capacity.to_string());
+        row("conflict", s.conflict.to_string());
+        row("fills", s.fills.to_string());
+        row("write-backs", s.write_backs.to_string());
+        row("writes through", s.writes_through.to_string());
+        writeln!(
+            out,
+            "  sets used {} of {}",
+            self.usage.iter().filter(|u| u.accesses > 0).count(),
+            self.usage.len()
+        )
+        .unwrap();
+        for (i, u) in self
+            .usage
+            .iter()
+            .enumerate()
+            .filter(|(_, u)| u.accesses > 0)
+        {
+            writeln!(
+                out,
+                "    set {:<6} {:>8} accesses {:>8} misses {:>8} evictions",
+                i, u.accesses, u.misses, u.evictions
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Separate instruction and data caches, either of which can be left out, and an
+/// optional second level both of them miss into.
+#[derive(Clone, Debug, Default)]
+pub struct Caches {
+    pub instruction: Option<Cache>,
+    pub data: Option<Cache>,
+    pub second_level: Option<Cache>,
+}
+
+impl Caches {
+    pub fn fetch(&mut self, pc: u32) {
+        match self.instruction {
+            Some(ref mut cache) => cache.access_range(pc, 4, false, self.second_level.as_mut()),
+            None => {
+                if let Some(ref mut l2) = self.second_level {
+                    l2.access_range(pc, 4, false, None);
+                }
+            }
+        }
+    }
+
+    pub fn data(&mut self, address: u32, size: u32, write: bool) {
+        match self.data {
+            Some(ref mut cache) => {
+                cache.access_range(address, size, write, self.second_level.as_mut())
+            }
+            None => {
+                if let Some(ref mut l2) = self.second_level {
+                    l2.access_range(address, size, write, None);
+                }
+            }
+        }
+    }
+
+    /// Goes through the fetch of the instruction at `pc` and the memory accesses it
+    /// made, as recorded by the machine.
+    pub fn record(&mut self, pc: u32, events: &[Event]) {
+        self.fetch(pc);
+        for event in events {
+            if let Event::Memory(a) = event {
+                self.data(a.address, a.size, a.write);
+            }
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        if let Some(ref c) = self.instruction {
+            out += &c.report("L1 instruction cache");
+        }
+        if let Some(ref c) = self.data {
+            out += &c.report("L1 data cache");
+        }
+        if let Some(ref c) = self.second_level {
+            out += &c.report("L2 cache");
+        }
+        out
+    }
+}
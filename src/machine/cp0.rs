@@ -0,0 +1,208 @@
+use std::{convert::TryFrom, time::Instant};
+
+// coprocessor 0 register numbers
+pub const BAD_VADDR: u8 = 8;
+pub const COUNT: u8 = 9;
+pub const COMPARE: u8 = 11;
+pub const STATUS: u8 = 12;
+pub const CAUSE: u8 = 13;
+pub const EPC: u8 = 14;
+
+pub const STATUS_IE: u32 = 1;
+pub const STATUS_EXL: u32 = 1 << 1;
+/// The IM bits of Status, which line up with the IP bits of Cause.
+pub const INTERRUPT_MASK: u32 = 0xff00;
+pub const CAUSE_BD: u32 = 1 << 31;
+pub const CAUSE_TI: u32 = 1 << 30;
+/// IP7, raised when Count reaches Compare.
+pub const TIMER_INTERRUPT: u32 = 1 << 15;
+// IP0 and IP1 are the only ones software can set
+const SOFTWARE_INTERRUPTS: u32 = 0x300;
+
+/// Where interrupts go, as SPIM and MARS have it.
+pub const EXCEPTION_VECTOR: u32 = 0x8000_0180;
+
+/// What Count and the timers count.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ClockRate {
+    /// One tick per instruction, so that runs are reproducible.
+    #[default]
+    Instructions,
+    /// Ticks per second of real time.
+    Hertz(u32),
+}
+
+impl TryFrom<&str> for ClockRate {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let lower = s.to_lowercase();
+        if lower == "instructions" {
+            return Ok(ClockRate::Instructions);
+        }
+        let (number, scale) = if let Some(n) = lower.strip_suffix("mhz") {
+            (n, 1_000_000)
+        } else if let Some(n) = lower.strip_suffix("khz") {
+            (n, 1_000)
+        } else {
+            (lower.strip_suffix("hz").unwrap_or(&lower), 1)
+        };
+        number
+            .parse::<u32>()
+            .ok()
+            .and_then(|n| n.checked_mul(scale))
+            .filter(|n| *n > 0)
+            .map(ClockRate::Hertz)
+            .ok_or(format!("Bad clock {}", s))
+    }
+}
+
+impl From<ClockRate> for String {
+    fn from(c: ClockRate) -> String {
+        match c {
+            ClockRate::Instructions => "instructions".to_owned(),
+            ClockRate::Hertz(hz) => format!("{}hz", hz),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Clock {
+    rate: ClockRate,
+    started: Instant,
+    ticks: u64,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new(ClockRate::default())
+    }
+}
+
+impl Clock {
+    pub fn new(rate: ClockRate) -> Clock {
+        Clock {
+            rate,
+            started: Instant::now(),
+            ticks: 0,
+        }
+    }
+    pub fn rate(&self) -> ClockRate {
+        self.rate
+    }
+    /// Called after every instruction, giving how many ticks have gone by since the
+    /// last call.
+    pub fn advance(&mut self) -> u64 {
+        let now = match self.rate {
+            ClockRate::Instructions => self.ticks + 1,
+            ClockRate::Hertz(hz) => {
+                (self.started.elapsed().as_nanos() * u128::from(hz) / 1_000_000_000) as u64
+            }
+        };
+        let passed = now.saturating_sub(self.ticks);
+        self.ticks = self.ticks.max(now);
+        passed
+    }
+}
+
+/// The registers of coprocessor 0 that SPIM and MARS have, with Count and Compare for
+/// a timer. Hardware interrupts from devices aren't kept here; they're added to Cause
+/// when it's read.
+#[derive(Clone, Debug, Default)]
+pub struct Cp0 {
+    bad_vaddr: u32,
+    count: u32,
+    compare: u32,
+    status: u32,
+    cause: u32,
+    epc: u32,
+    clock: Clock,
+}
+
+impl Cp0 {
+    pub fn set_clock(&mut self, rate: ClockRate) {
+        self.clock = Clock::new(rate);
+    }
+    pub fn clock(&self) -> ClockRate {
+        self.clock.rate()
+    }
+
+    pub fn read(&self, register: u8) -> u32 {
+        match register {
+            BAD_VADDR => self.bad_vaddr,
+            COUNT => self.count,
+            COMPARE => self.compare,
+            STATUS => self.status,
+            CAUSE => self.cause,
+            EPC => self.epc,
+            _ => 0,
+        }
+    }
+    pub fn write(&mut self, register: u8, value: u32) {
+        match register {
+            COUNT => self.count = value,
+            COMPARE => {
+                self.compare = value;
+                self.cause &= !(CAUSE_TI | TIMER_INTERRUPT);
+            }
+            STATUS => self.status = value,
+            CAUSE => {
+                self.cause = (self.cause & !SOFTWARE_INTERRUPTS) | (value & SOFTWARE_INTERRUPTS)
+            }
+            EPC => self.epc = value,
+            _ => (),
+        }
+    }
+
+    /// Sets a register outright, without the limits on what a program can write, for
+    /// debuggers and snapshots.
+    pub fn set(&mut self, register: u8, value: u32) {
+        match register {
+            BAD_VADDR => self.bad_vaddr = value,
+            COUNT => self.count = value,
+            COMPARE => self.compare = value,
+            STATUS => self.status = value,
+            CAUSE => self.cause = value,
+            EPC => self.epc = value,
+            _ => (),
+        }
+    }
+
+    /// Moves Count on, raising the timer interrupt if it passes Compare.
+    pub fn tick(&mut self) {
+        let passed = self.clock.advance();
+        if passed == 0 {
+            return;
+        }
+        let before = self.count;
+        self.count = before.wrapping_add(passed as u32);
+        if passed > u64::from(u32::MAX)
+            || self.compare.wrapping_sub(before).wrapping_sub(1) < passed as u32
+        {
+            self.cause |= CAUSE_TI | TIMER_INTERRUPT;
+        }
+    }
+
+    /// Whether an interrupt gets taken, given the IP bits `devices` are raising.
+    pub fn interrupted(&self, devices: u32) -> bool {
+        self.status & (STATUS_IE | STATUS_EXL) == STATUS_IE
+            && (self.cause | devices) & self.status & INTERRUPT_MASK != 0
+    }
+
+    /// Goes into exception level for an interrupt, to come back to `epc`.
+    pub fn enter(&mut self, epc: u32, delay_slot: bool) {
+        self.epc = epc;
+        // ExcCode 0 is an interrupt
+        self.cause &= !(CAUSE_BD | 0x7c);
+        if delay_slot {
+            self.cause |= CAUSE_BD;
+        }
+        self.status |= STATUS_EXL;
+    }
+
+    /// Leaves exception level, giving where to carry on.
+    pub fn eret(&mut self) -> u32 {
+        self.status &= !STATUS_EXL;
+        self.epc
+    }
+}
@@ -0,0 +1,430 @@
+use std::{collections::VecDeque, fmt};
+
+use crate::machine::cp0::{Clock, ClockRate};
+
+/// Where SPIM and MARS put the keyboard and display registers.
+pub const TERMINAL_ADDRESS: u32 = 0xffff_0000;
+pub const TERMINAL_SIZE: u32 = 16;
+
+/// Cause register bits raised by the terminal, as MARS numbers them.
+pub const KEYBOARD_INTERRUPT: u32 = 1 << 8;
+pub const DISPLAY_INTERRUPT: u32 = 1 << 9;
+
+pub const TIMER_ADDRESS: u32 = 0xffff_0010;
+pub const TIMER_SIZE: u32 = 12;
+/// What the timer raises when it isn't connected to the interrupt controller: IP3.
+pub const TIMER_INTERRUPT: u32 = 1 << 11;
+
+pub const CONTROLLER_ADDRESS: u32 = 0xffff_0020;
+pub const CONTROLLER_SIZE: u32 = 16;
+/// The interrupt controller raises IP2, the first hardware interrupt.
+pub const CONTROLLER_INTERRUPT: u32 = 1 << 10;
+
+const READY: u32 = 1;
+const INTERRUPT_ENABLE: u32 = 2;
+
+/// Something that answers loads and stores to a range of addresses in place of
+/// memory. Registers are words, at offsets from the start of the range; narrower
+/// accesses are made out of whole words by `Memory`.
+pub trait Device: fmt::Debug {
+    /// A load by the program, which may have side effects like taking a key.
+    fn read(&mut self, offset: u32) -> u32;
+    fn write(&mut self, offset: u32, value: u32);
+    /// What `read` would give, for debuggers and dumps.
+    fn peek(&self, offset: u32) -> u32;
+    /// Called once for every instruction executed.
+    fn tick(&mut self) {}
+    /// The Cause register bits the device is asserting.
+    fn interrupts(&self) -> u32 {
+        0
+    }
+    /// For interrupt controllers: the lines raised by the devices connected to it.
+    fn route(&mut self, _lines: u32) {}
+    /// Queues input for devices that take any.
+    fn feed(&mut self, _bytes: &[u8]) {}
+    /// Whether the program is waiting for input that hasn't been fed.
+    fn wants_input(&self) -> bool {
+        false
+    }
+    /// What the device has printed since the last call.
+    fn take_output(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// The device's name and everything `restore` needs to make it again, for
+    /// snapshots.
+    fn save(&self) -> (&'static str, Vec<u32>);
+    fn box_clone(&self) -> Box<dyn Device>;
+}
+
+/// Makes a device again from what its `save` gave.
+pub fn restore(name: &str, saved: &[u32]) -> Result<Box<dyn Device>, String> {
+    let mut words = Words(saved.iter());
+    match name {
+        "terminal" => {
+            let mut terminal = Terminal::new(0);
+            terminal.ticks = words.u64()?;
+            terminal.receiver_control = words.next()?;
+            terminal.receiver_data = words.next()?;
+            terminal.transmitter_control = words.next()?;
+            terminal.transmitter_data = words.next()?;
+            terminal.busy = words.next()?;
+            terminal.delay = words.next()?;
+            terminal.starved = words.next()? != 0;
+            for _ in 0..words.next()? {
+                terminal.output.push(words.next()? as u8);
+            }
+            for _ in 0..words.next()? {
+                let at = words.u64()?;
+                terminal.keys.push_back((at, words.next()? as u8));
+            }
+            Ok(Box::new(terminal))
+        }
+        "timer" => {
+            let control = words.next()?;
+            let interval = words.next()?;
+            let count = words.next()?;
+            let rate = match words.next()? {
+                0 => ClockRate::Instructions,
+                hz => ClockRate::Hertz(hz),
+            };
+            Ok(Box::new(Timer {
+                control,
+                interval,
+                count,
+                clock: Clock::new(rate),
+            }))
+        }
+        "interrupt controller" => Ok(Box::new(InterruptController {
+            lines: words.next()?,
+            mask: words.next()?,
+        })),
+        _ => Err(format!("Unknown device `{}`", name)),
+    }
+}
+
+struct Words<'a>(std::slice::Iter<'a, u32>);
+
+impl Words<'_> {
+    fn next(&mut self) -> Result<u32, String> {
+        self.0
+            .next()
+            .copied()
+            .ok_or_else(|| "Saved device is truncated".to_owned())
+    }
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from(self.next()?) | u64::from(self.next()?) << 32)
+    }
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// The SPIM/MARS keyboard and display: receiver control and data at offsets 0 and 4,
+/// transmitter control and data at 8 and 12. Bit 0 of a control register is ready and
+/// bit 1 enables the interrupt, which is raised for as long as both are set.
+///
+/// A key waits in the queue until the last one has been read, so scripted input is
+/// never lost. A character written to the display while it's ready is printed
+/// `delay` instructions later, and the display isn't ready until then; writes while
+/// it's busy are dropped.
+#[derive(Clone, Debug)]
+pub struct Terminal {
+    // keys and the tick they're typed at, in order
+    keys: VecDeque<(u64, u8)>,
+    ticks: u64,
+    receiver_control: u32,
+    receiver_data: u32,
+    transmitter_control: u32,
+    transmitter_data: u32,
+    busy: u32,
+    delay: u32,
+    starved: bool,
+    output: Vec<u8>,
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Terminal::new(5)
+    }
+}
+
+impl Terminal {
+    pub fn new(delay: u32) -> Terminal {
+        Terminal {
+            keys: VecDeque::new(),
+            ticks: 0,
+            receiver_control: 0,
+            receiver_data: 0,
+            transmitter_control: READY,
+            transmitter_data: 0,
+            busy: 0,
+            delay,
+            starved: false,
+            output: Vec::new(),
+        }
+    }
+
+    /// Types `bytes` once `ticks` instructions have run, or as soon as the ones before
+    /// have been read.
+    pub fn type_at(&mut self, ticks: u64, bytes: &[u8]) {
+        self.keys.extend(bytes.iter().map(|b| (ticks, *b)));
+    }
+
+    fn receive(&mut self) {
+        if self.receiver_control & READY != 0 {
+            return;
+        }
+        if let Some(&(at, key)) = self.keys.front() {
+            if at <= self.ticks {
+                self.keys.pop_front();
+                self.receiver_data = u32::from(key);
+                self.receiver_control |= READY;
+                self.starved = false;
+            }
+        }
+    }
+
+    fn transmit(&mut self) {
+        self.output.push(self.transmitter_data as u8);
+        self.transmitter_control |= READY;
+    }
+}
+
+impl Device for Terminal {
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            0 => {
+                self.starved = self.receiver_control & READY == 0 && self.keys.is_empty();
+                self.receiver_control
+            }
+            4 => {
+                self.receiver_control &= !READY;
+                let data = self.receiver_data;
+                self.receive();
+                data
+            }
+            _ => self.peek(offset),
+        }
+    }
+    fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            0 => {
+                self.receiver_control = (self.receiver_control & READY) | (value & INTERRUPT_ENABLE)
+            }
+            8 => {
+                self.transmitter_control =
+                    (self.transmitter_control & READY) | (value & INTERRUPT_ENABLE)
+            }
+            12 if self.transmitter_control & READY != 0 => {
+                self.transmitter_data = value & 0xff;
+                self.transmitter_control &= !READY;
+                self.busy = self.delay;
+                if self.busy == 0 {
+                    self.transmit();
+                }
+            }
+            _ => (),
+        }
+    }
+    fn peek(&self, offset: u32) -> u32 {
+        match offset {
+            0 => self.receiver_control,
+            4 => self.receiver_data,
+            8 => self.transmitter_control,
+            12 => self.transmitter_data,
+            _ => 0,
+        }
+    }
+    fn tick(&mut self) {
+        self.ticks += 1;
+        self.receive();
+        if self.transmitter_control & READY == 0 {
+            self.busy = self.busy.saturating_sub(1);
+            if self.busy == 0 {
+                self.transmit();
+            }
+        }
+    }
+    fn interrupts(&self) -> u32 {
+        let raised =
+            |control: u32| control & (READY | INTERRUPT_ENABLE) == READY | INTERRUPT_ENABLE;
+        let mut bits = 0;
+        if raised(self.receiver_control) {
+            bits |= KEYBOARD_INTERRUPT;
+        }
+        if raised(self.transmitter_control) {
+            bits |= DISPLAY_INTERRUPT;
+        }
+        bits
+    }
+    fn feed(&mut self, bytes: &[u8]) {
+        let now = self.ticks;
+        self.type_at(now, bytes);
+        self.receive();
+    }
+    fn wants_input(&self) -> bool {
+        self.starved
+    }
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    fn save(&self) -> (&'static str, Vec<u32>) {
+        let mut words = vec![
+            self.ticks as u32,
+            (self.ticks >> 32) as u32,
+            self.receiver_control,
+            self.receiver_data,
+            self.transmitter_control,
+            self.transmitter_data,
+            self.busy,
+            self.delay,
+            u32::from(self.starved),
+            self.output.len() as u32,
+        ];
+        words.extend(self.output.iter().map(|b| u32::from(*b)));
+        words.push(self.keys.len() as u32);
+        for (at, key) in &self.keys {
+            words.extend([*at as u32, (*at >> 32) as u32, u32::from(*key)]);
+        }
+        ("terminal", words)
+    }
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+const ENABLE: u32 = 1;
+const EXPIRED: u32 = 4;
+
+/// Counts clock ticks and expires every `interval` of them. Control, at offset 0, has
+/// enable in bit 0, interrupt enable in bit 1 and expired in bit 2, which stays set
+/// until control is next written. Writing the interval at offset 4 starts the count
+/// again; offset 8 has the ticks since the timer last expired.
+#[derive(Clone, Debug)]
+pub struct Timer {
+    control: u32,
+    interval: u32,
+    count: u32,
+    clock: Clock,
+}
+
+impl Timer {
+    /// A timer that's already running, with its interrupt enabled, unless `interval`
+    /// is zero.
+    pub fn new(interval: u32, rate: ClockRate) -> Timer {
+        Timer {
+            control: if interval > 0 {
+                ENABLE | INTERRUPT_ENABLE
+            } else {
+                0
+            },
+            interval,
+            count: 0,
+            clock: Clock::new(rate),
+        }
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: u32) -> u32 {
+        self.peek(offset)
+    }
+    fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            0 => self.control = value & (ENABLE | INTERRUPT_ENABLE),
+            4 => {
+                self.interval = value;
+                self.count = 0;
+            }
+            _ => (),
+        }
+    }
+    fn peek(&self, offset: u32) -> u32 {
+        match offset {
+            0 => self.control,
+            4 => self.interval,
+            8 => self.count,
+            _ => 0,
+        }
+    }
+    fn tick(&mut self) {
+        let passed = self.clock.advance();
+        if self.control & ENABLE == 0 || self.interval == 0 {
+            return;
+        }
+        let count = u64::from(self.count) + passed;
+        if count >= u64::from(self.interval) {
+            self.control |= EXPIRED;
+        }
+        self.count = (count % u64::from(self.interval)) as u32;
+    }
+    fn interrupts(&self) -> u32 {
+        if self.control & (EXPIRED | INTERRUPT_ENABLE) == EXPIRED | INTERRUPT_ENABLE {
+            TIMER_INTERRUPT
+        } else {
+            0
+        }
+    }
+    fn save(&self) -> (&'static str, Vec<u32>) {
+        let rate = match self.clock.rate() {
+            ClockRate::Instructions => 0,
+            ClockRate::Hertz(hz) => hz,
+        };
+        ("timer", vec![self.control, self.interval, self.count, rate])
+    }
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+/// Gathers interrupts from up to 32 devices, connected to its lines with
+/// `Memory::connect`, and raises IP2 while any line that isn't masked is up. Offset 0
+/// reads the unmasked lines that are up, 4 is the mask with a bit set for each line
+/// let through (none to begin with), 8 reads every line that's up and 12 the lowest
+/// numbered unmasked line that's up, or all ones if there isn't one.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptController {
+    lines: u32,
+    mask: u32,
+}
+
+impl Device for InterruptController {
+    fn read(&mut self, offset: u32) -> u32 {
+        self.peek(offset)
+    }
+    fn write(&mut self, offset: u32, value: u32) {
+        if offset == 4 {
+            self.mask = value;
+        }
+    }
+    fn peek(&self, offset: u32) -> u32 {
+        let pending = self.lines & self.mask;
+        match offset {
+            0 => pending,
+            4 => self.mask,
+            8 => self.lines,
+            12 if pending != 0 => pending.trailing_zeros(),
+            12 => u32::MAX,
+            _ => 0,
+        }
+    }
+    fn route(&mut self, lines: u32) {
+        self.lines = lines;
+    }
+    fn interrupts(&self) -> u32 {
+        if self.lines & self.mask != 0 {
+            CONTROLLER_INTERRUPT
+        } else {
+            0
+        }
+    }
+    fn save(&self) -> (&'static str, Vec<u32>) {
+        ("interrupt controller", vec![self.lines, self.mask])
+    }
+    fn box_clone(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
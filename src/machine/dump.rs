@@ -0,0 +1,31 @@
+use crate::{
+    assembler::dump::{dump_words, read_dump, DumpFormat},
+    machine::state::State,
+};
+
+/// Loads a MARS memory dump into the machine, with `address` used for the formats that
+/// don't record their own.
+pub fn load_dump(
+    state: &mut State,
+    bytes: &[u8],
+    format: DumpFormat,
+    address: u32,
+    executable: bool,
+) -> Result<(), String> {
+    let endian = state.memory().endian();
+    for (start, words) in read_dump(bytes, format, address)? {
+        let bytes: Vec<u8> = words.iter().flat_map(|w| endian.u32_bytes(*w)).collect();
+        state.load_segment(start, &bytes, bytes.len() as u32, executable);
+    }
+    Ok(())
+}
+
+/// Dumps the words in `start..end` of the machine's memory, the way MARS dumps a
+/// segment of a running program.
+pub fn dump_memory(state: &State, start: u32, end: u32, format: DumpFormat) -> Vec<u8> {
+    let words: Vec<u32> = (start..end)
+        .step_by(4)
+        .map(|a| state.memory().read_u32(a))
+        .collect();
+    dump_words(start, &words, format)
+}
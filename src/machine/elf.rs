@@ -0,0 +1,182 @@
+use crate::{assembler::assembler::Endian, machine::state::State};
+
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_MIPS: u16 = 8;
+
+const EF_MIPS_ARCH: u32 = 0xF000_0000;
+const EF_MIPS_ABI: u32 = 0x0000_F000;
+const EF_MIPS_ABI_O32: u32 = 0x0000_1000;
+const EF_MIPS_ARCH_ASE_M16: u32 = 0x0400_0000;
+const EF_MIPS_MICROMIPS: u32 = 0x0200_0000;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 0x1;
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: u32, len: u32) -> Result<&'a [u8], String> {
+        let start = offset as usize;
+        let end = start.checked_add(len as usize);
+        match end {
+            Some(end) if end <= self.bytes.len() => Ok(&self.bytes[start..end]),
+            _ => Err(format!(
+                "File is truncated, needed {} bytes at offset 0x{:X}",
+                len, offset
+            )),
+        }
+    }
+    fn u8(&self, offset: u32) -> Result<u8, String> {
+        Ok(self.slice(offset, 1)?[0])
+    }
+    fn u16(&self, offset: u32) -> Result<u16, String> {
+        Ok(self.endian.read_u16(self.slice(offset, 2)?))
+    }
+    fn u32(&self, offset: u32) -> Result<u32, String> {
+        Ok(self.endian.read_u32(self.slice(offset, 4)?))
+    }
+    fn c_str(&self, offset: u32) -> Result<String, String> {
+        let rest = self.bytes.get(offset as usize..).unwrap_or(&[]);
+        match rest.iter().position(|b| *b == 0) {
+            Some(end) => Ok(String::from_utf8_lossy(&rest[..end]).into_owned()),
+            None => Err(format!("Unterminated string at offset 0x{:X}", offset)),
+        }
+    }
+}
+
+fn arch_name(flags: u32) -> Option<&'static str> {
+    match flags & EF_MIPS_ARCH {
+        0x0000_0000 => Some("MIPS I"),
+        0x1000_0000 => Some("MIPS II"),
+        0x5000_0000 => Some("MIPS32"),
+        0x7000_0000 => Some("MIPS32r2"),
+        _ => None,
+    }
+}
+
+fn check_header(r: &Reader) -> Result<(), String> {
+    match r.u8(4)? {
+        ELFCLASS32 => (),
+        ELFCLASS64 => return Err("64-bit ELF files are not supported".to_owned()),
+        c => return Err(format!("Unknown ELF class {}", c)),
+    }
+    if r.u8(6)? != 1 {
+        return Err("Unknown ELF version".to_owned());
+    }
+    match r.u16(18)? {
+        EM_MIPS => (),
+        m => return Err(format!("Not a MIPS executable (machine {})", m)),
+    }
+    match r.u16(16)? {
+        ET_EXEC => (),
+        ET_REL => return Err("Relocatable objects have to be linked first".to_owned()),
+        ET_DYN => {
+            return Err("Position independent and shared objects are not supported".to_owned())
+        }
+        t => return Err(format!("Unsupported ELF type {}", t)),
+    }
+    let flags = r.u32(36)?;
+    if arch_name(flags).is_none() {
+        return Err(format!(
+            "Unsupported ISA in ELF flags 0x{:08X}, only MIPS I/II and MIPS32 (r1, r2) run here",
+            flags
+        ));
+    }
+    if flags & EF_MIPS_ARCH_ASE_M16 != 0 {
+        return Err("MIPS16 code is not supported".to_owned());
+    }
+    if flags & EF_MIPS_MICROMIPS != 0 {
+        return Err("microMIPS code is not supported".to_owned());
+    }
+    let abi = flags & EF_MIPS_ABI;
+    if abi != 0 && abi != EF_MIPS_ABI_O32 {
+        return Err(format!("Unsupported ABI in ELF flags 0x{:08X}", flags));
+    }
+    Ok(())
+}
+
+fn load_segments(r: &Reader, state: &mut State) -> Result<(), String> {
+    let phoff = r.u32(28)?;
+    let phentsize = u32::from(r.u16(42)?);
+    let phnum = u32::from(r.u16(44)?);
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        if r.u32(ph)? != PT_LOAD {
+            continue;
+        }
+        let offset = r.u32(ph + 4)?;
+        let vaddr = r.u32(ph + 8)?;
+        let filesz = r.u32(ph + 16)?;
+        let memsz = r.u32(ph + 20)?;
+        let flags = r.u32(ph + 24)?;
+        if filesz > memsz {
+            return Err(format!(
+                "Segment {} is larger in the file than in memory",
+                i
+            ));
+        }
+        let bytes = r.slice(offset, filesz)?;
+        state.load_segment(vaddr, bytes, memsz, flags & PF_X != 0);
+    }
+    Ok(())
+}
+
+fn load_symbols(r: &Reader, state: &mut State) -> Result<(), String> {
+    let shoff = r.u32(32)?;
+    let shentsize = u32::from(r.u16(46)?);
+    let shnum = u32::from(r.u16(48)?);
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        if r.u32(sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let offset = r.u32(sh + 16)?;
+        let size = r.u32(sh + 20)?;
+        let link = r.u32(sh + 24)?;
+        let entsize = r.u32(sh + 36)?.max(16);
+        let strtab = r.u32(shoff + link * shentsize + 16)?;
+        for s in 0..size / entsize {
+            let sym = offset + s * entsize;
+            let name = r.u32(sym)?;
+            let value = r.u32(sym + 4)?;
+            let kind = r.u8(sym + 12)? & 0xF;
+            let section = r.u16(sym + 14)?;
+            if name == 0 || section == SHN_UNDEF || kind == STT_SECTION || kind == STT_FILE {
+                continue;
+            }
+            state.add_label(value, &r.c_str(strtab + name)?);
+        }
+    }
+    Ok(())
+}
+
+/// Validates a statically linked MIPS executable and builds a machine from it, with
+/// its `PT_LOAD` segments mapped, symbols imported and the pc at the entry point.
+pub fn load_elf(bytes: &[u8]) -> Result<State, String> {
+    if bytes.len() < 52 || &bytes[..4] != b"\x7FELF" {
+        return Err("Not an ELF file".to_owned());
+    }
+    let endian = match bytes[5] {
+        1 => Endian::Little,
+        2 => Endian::Big,
+        d => return Err(format!("Unknown ELF data encoding {}", d)),
+    };
+    let r = Reader { bytes, endian };
+    check_header(&r)?;
+    let mut state = State::new(endian);
+    load_segments(&r, &mut state)?;
+    load_symbols(&r, &mut state)?;
+    state.set_pc(r.u32(24)?);
+    Ok(state)
+}
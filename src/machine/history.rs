@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+
+use crate::machine::{
+    state::{Exception, State, Status, Undo},
+    watch::{Trigger, Watchpoints},
+};
+
+pub const DEFAULT_LIMIT: usize = 100_000;
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10_000;
+pub const DEFAULT_CHECKPOINTS: usize = 8;
+
+/// Lets a machine run backwards.
+///
+/// The last `limit` steps are kept as undo records. Every `checkpoint_interval` steps a
+/// copy of the whole machine is kept as well, the newest `max_checkpoints` of them, so
+/// going back past the undo records restores a checkpoint and replays forward from it.
+/// Replaying assumes nothing but the program changed the machine in between.
+#[derive(Clone, Debug)]
+pub struct History {
+    undo: VecDeque<Undo>,
+    checkpoints: VecDeque<State>,
+    pub limit: usize,
+    pub checkpoint_interval: u64,
+    pub max_checkpoints: usize,
+}
+
+impl History {
+    /// Starts recording `state`, with a checkpoint where it stands now.
+    pub fn new(state: &mut State) -> History {
+        state.record_undo(true);
+        History {
+            undo: VecDeque::new(),
+            checkpoints: vec![state.clone()].into(),
+            limit: DEFAULT_LIMIT,
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            max_checkpoints: DEFAULT_CHECKPOINTS,
+        }
+    }
+
+    /// Takes the undo records of the steps `state` made since the last call. Call it
+    /// after every step, or use `step`, so checkpoints land where they should.
+    pub fn record(&mut self, state: &mut State) {
+        self.undo.extend(state.take_undo());
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+        let steps = state.steps();
+        let due = self.checkpoint_interval > 0 && steps % self.checkpoint_interval == 0;
+        if due && self.checkpoints.back().map_or(true, |c| c.steps() < steps) {
+            self.checkpoints.push_back(state.clone());
+            while self.checkpoints.len() > self.max_checkpoints.max(1) {
+                self.checkpoints.pop_front();
+            }
+        }
+    }
+
+    pub fn step(&mut self, state: &mut State) -> Result<Status, Exception> {
+        let result = state.step();
+        self.record(state);
+        result
+    }
+
+    /// The earliest step count `state` can be taken back to.
+    pub fn earliest(&self, state: &State) -> u64 {
+        let logged = state.steps().saturating_sub(self.undo.len() as u64);
+        self.checkpoints
+            .front()
+            .map_or(logged, |c| c.steps().min(logged))
+    }
+
+    pub fn clear(&mut self, state: &State) {
+        self.undo.clear();
+        self.checkpoints = vec![state.clone()].into();
+    }
+
+    // restores the newest checkpoint before the current step and replays up to it
+    fn replay(&mut self, state: &mut State) -> bool {
+        let now = state.steps();
+        let checkpoint = match self.checkpoints.iter().rev().find(|c| c.steps() < now) {
+            Some(c) => c.clone(),
+            None => return false,
+        };
+        *state = checkpoint;
+        while state.steps() < now {
+            if self.step(state) != Ok(Status::Running) {
+                break;
+            }
+        }
+        // it was shown the first time round
+        state.take_output();
+        !self.undo.is_empty()
+    }
+
+    // undoes one step, handing back what it changed
+    fn back(&mut self, state: &mut State) -> Option<Undo> {
+        if self.undo.is_empty() && !self.replay(state) {
+            return None;
+        }
+        let undo = self.undo.pop_back()?;
+        state.undo(&undo);
+        let steps = state.steps();
+        self.checkpoints.retain(|c| c.steps() <= steps);
+        Some(undo)
+    }
+
+    /// Undoes up to `n` steps, returning how many there were to undo.
+    pub fn step_back(&mut self, state: &mut State, n: u64) -> u64 {
+        (0..n).take_while(|_| self.back(state).is_some()).count() as u64
+    }
+
+    /// Goes back a step at a time until `stop` accepts the step just undone, with the
+    /// machine as it was before that step. False if history ran out first.
+    pub fn reverse_until(
+        &mut self,
+        state: &mut State,
+        mut stop: impl FnMut(&State, &Undo) -> bool,
+    ) -> bool {
+        while let Some(undo) = self.back(state) {
+            if stop(state, &undo) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Runs back to the last breakpoint passed, or the last write a memory or register
+    /// watchpoint would have caught. Reads and `Becomes` conditions can't be followed
+    /// backwards and are ignored; hit and ignore counts are left alone.
+    pub fn reverse_continue(
+        &mut self,
+        state: &mut State,
+        watchpoints: &Watchpoints,
+    ) -> Option<u32> {
+        let mut found = None;
+        self.reverse_until(state, |state, undo| {
+            found = watchpoints
+                .iter()
+                .filter(|w| w.enabled && w.condition.as_ref().map_or(true, |c| c.holds(state)))
+                .find(|w| match w.trigger {
+                    Trigger::Execute(address) => address == undo.pc(),
+                    Trigger::Write { address, length } | Trigger::Access { address, length } => {
+                        undo.wrote_memory(address, length)
+                    }
+                    Trigger::Register(r) => undo.wrote_register(r),
+                    Trigger::Read { .. } | Trigger::Becomes(_) => false,
+                })
+                .map(|w| w.id);
+            found.is_some()
+        });
+        found
+    }
+}
@@ -0,0 +1,240 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use crate::{assembler::assembler::Endian, machine::device::Device};
+
+pub const PAGE_SIZE: u32 = 0x1000;
+
+/// An attached device's start, size and interrupt controller line, with the device.
+pub type AttachedDevice = (u32, u32, Option<u32>, Box<dyn Device>);
+
+/// Sparse byte-addressed memory. Pages are created when something is loaded or
+/// stored into them; reading an untouched address gives zero.
+///
+/// Devices can be attached over ranges of addresses. Stores there go to the device,
+/// and so do the program's loads through `read_device`; every other read only peeks.
+#[derive(Clone, Debug, Default)]
+pub struct Memory {
+    endian: Endian,
+    pages: HashMap<u32, Box<[u8]>>,
+    devices: Vec<Attached>,
+}
+
+#[derive(Clone, Debug)]
+struct Attached {
+    start: u32,
+    end: u32,
+    // loads only borrow the memory but can still change the device
+    device: RefCell<Box<dyn Device>>,
+    // the interrupt controller line it raises instead of a Cause bit
+    line: Option<u32>,
+}
+
+impl Memory {
+    pub fn new(endian: Endian) -> Memory {
+        Memory {
+            endian,
+            pages: HashMap::new(),
+            devices: Vec::new(),
+        }
+    }
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+    pub fn is_mapped(&self, address: u32) -> bool {
+        self.pages.contains_key(&(address / PAGE_SIZE))
+    }
+    pub fn map(&mut self, address: u32, len: u32) {
+        if len == 0 {
+            return;
+        }
+        let first = address / PAGE_SIZE;
+        let last = address.saturating_add(len - 1) / PAGE_SIZE;
+        for page in first..=last {
+            self.page_mut(page);
+        }
+    }
+    /// Every page something was loaded or stored into, by page number.
+    pub fn pages(&self) -> Vec<(u32, &[u8])> {
+        let mut pages: Vec<(u32, &[u8])> = self.pages.iter().map(|(n, p)| (*n, &p[..])).collect();
+        pages.sort_by_key(|(n, _)| *n);
+        pages
+    }
+    fn page_mut(&mut self, page: u32) -> &mut [u8] {
+        self.pages
+            .entry(page)
+            .or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice())
+    }
+    pub fn load(&mut self, address: u32, bytes: &[u8]) {
+        for (i, b) in bytes.iter().enumerate() {
+            self.write_u8(address.wrapping_add(i as u32), *b);
+        }
+    }
+    pub fn read_bytes(&self, address: u32, len: u32) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.read_u8(address.wrapping_add(i)))
+            .collect()
+    }
+    /// Puts `device` over the `size` bytes at `address`, which must be word aligned
+    /// and clear of other devices.
+    pub fn attach(
+        &mut self,
+        address: u32,
+        size: u32,
+        device: Box<dyn Device>,
+    ) -> Result<(), String> {
+        let end = address.wrapping_add(size);
+        if address % 4 != 0 || size == 0 || end < address {
+            return Err(format!("Can't attach a device at 0x{:08x}", address));
+        }
+        if self
+            .devices
+            .iter()
+            .any(|d| d.start < end && address < d.end)
+        {
+            return Err(format!("A device is already attached at 0x{:08x}", address));
+        }
+        self.devices.push(Attached {
+            start: address,
+            end,
+            device: RefCell::new(device),
+            line: None,
+        });
+        Ok(())
+    }
+    /// Sends the interrupts of the device at `address` to line `line` of the interrupt
+    /// controllers rather than straight to Cause.
+    pub fn connect(&mut self, address: u32, line: u32) -> Result<(), String> {
+        if line >= 32 {
+            return Err(format!("No interrupt line {}", line));
+        }
+        match self
+            .devices
+            .iter_mut()
+            .find(|d| address >= d.start && address < d.end)
+        {
+            Some(d) => {
+                d.line = Some(line);
+                Ok(())
+            }
+            None => Err(format!("No device at 0x{:08x}", address)),
+        }
+    }
+    /// Copies of the attached devices, in the order they went on.
+    pub fn devices(&self) -> Vec<AttachedDevice> {
+        self.devices
+            .iter()
+            .map(|d| (d.start, d.end - d.start, d.line, d.device.borrow().clone()))
+            .collect()
+    }
+    fn attached(&self, address: u32) -> Option<&Attached> {
+        self.devices
+            .iter()
+            .find(|d| address >= d.start && address < d.end)
+    }
+    pub fn is_device(&self, address: u32) -> bool {
+        self.attached(address).is_some()
+    }
+    pub fn device_mut(&mut self, address: u32) -> Option<&mut Box<dyn Device>> {
+        self.devices
+            .iter_mut()
+            .find(|d| address >= d.start && address < d.end)
+            .map(|d| d.device.get_mut())
+    }
+    /// Ticks every device and gathers what they printed.
+    pub fn tick_devices(&mut self) -> Vec<u8> {
+        let mut output = Vec::new();
+        for d in &mut self.devices {
+            let device = d.device.get_mut();
+            device.tick();
+            output.extend(device.take_output());
+        }
+        output
+    }
+    /// The Cause register bits raised by all the devices, after passing the ones that
+    /// are connected to lines on to the interrupt controllers.
+    pub fn interrupts(&self) -> u32 {
+        let lines = self.devices.iter().fold(0, |lines, d| match d.line {
+            Some(line) if d.device.borrow().interrupts() != 0 => lines | (1 << line),
+            _ => lines,
+        });
+        let mut bits = 0;
+        for d in self.devices.iter().filter(|d| d.line.is_none()) {
+            let mut device = d.device.borrow_mut();
+            device.route(lines);
+            bits |= device.interrupts();
+        }
+        bits
+    }
+    pub fn wants_input(&self) -> bool {
+        self.devices.iter().any(|d| d.device.borrow().wants_input())
+    }
+    /// A load of `size` bytes by the program, if `address` belongs to a device.
+    pub fn read_device(&self, address: u32, size: u32) -> Option<u32> {
+        let d = self.attached(address)?;
+        let offset = address - d.start;
+        let word = d.device.borrow_mut().read(offset & !3);
+        Some(self.part(word, offset, size))
+    }
+    // the `size` bytes at `offset` within a device register
+    fn part(&self, word: u32, offset: u32, size: u32) -> u32 {
+        let bytes = self.endian.u32_bytes(word);
+        let at = (offset % 4) as usize;
+        match size {
+            1 => u32::from(bytes[at]),
+            2 => u32::from(self.endian.read_u16(&bytes[at..])),
+            _ => word,
+        }
+    }
+    // stores through a device register, keeping the bytes not written
+    fn write_device(&mut self, address: u32, bytes: &[u8]) -> bool {
+        let endian = self.endian;
+        let d = match self.attached(address) {
+            Some(d) => d,
+            None => return false,
+        };
+        let offset = address - d.start;
+        let mut device = d.device.borrow_mut();
+        let mut word = endian.u32_bytes(device.peek(offset & !3));
+        let at = (offset % 4) as usize;
+        word[at..at + bytes.len()].copy_from_slice(bytes);
+        device.write(offset & !3, endian.read_u32(&word));
+        true
+    }
+    pub fn read_u8(&self, address: u32) -> u8 {
+        if let Some(d) = self.attached(address) {
+            let offset = address - d.start;
+            let word = self.endian.u32_bytes(d.device.borrow().peek(offset & !3));
+            return word[(offset % 4) as usize];
+        }
+        match self.pages.get(&(address / PAGE_SIZE)) {
+            Some(page) => page[(address % PAGE_SIZE) as usize],
+            None => 0,
+        }
+    }
+    pub fn write_u8(&mut self, address: u32, value: u8) {
+        if self.write_device(address, &[value]) {
+            return;
+        }
+        self.page_mut(address / PAGE_SIZE)[(address % PAGE_SIZE) as usize] = value;
+    }
+    pub fn read_u16(&self, address: u32) -> u16 {
+        let b = self.read_bytes(address, 2);
+        self.endian.read_u16(&b)
+    }
+    pub fn write_u16(&mut self, address: u32, value: u16) {
+        let b = self.endian.u16_bytes(value);
+        if !self.write_device(address, &b) {
+            self.load(address, &b);
+        }
+    }
+    pub fn read_u32(&self, address: u32) -> u32 {
+        let b = self.read_bytes(address, 4);
+        self.endian.read_u32(&b)
+    }
+    pub fn write_u32(&mut self, address: u32, value: u32) {
+        let b = self.endian.u32_bytes(value);
+        if !self.write_device(address, &b) {
+            self.load(address, &b);
+        }
+    }
+}
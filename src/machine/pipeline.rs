@@ -0,0 +1,412 @@
+use std::{collections::VecDeque, fmt::Write};
+
+use crate::machine::{
+    predictor::BranchPredictor,
+    state::{Exception, InstructionClass, State, Status},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Fetch,
+    Decode,
+    Execute,
+    Memory,
+    WriteBack,
+}
+
+impl From<&str> for Stage {
+    fn from(s: &str) -> Stage {
+        match s.to_lowercase().as_ref() {
+            "if" => Stage::Fetch,
+            "id" => Stage::Decode,
+            "ex" => Stage::Execute,
+            "mem" => Stage::Memory,
+            "wb" => Stage::WriteBack,
+            _ => panic!("No such pipeline stage: {}", s),
+        }
+    }
+}
+
+impl From<Stage> for String {
+    fn from(s: Stage) -> String {
+        match s {
+            Stage::Fetch => "IF",
+            Stage::Decode => "ID",
+            Stage::Execute => "EX",
+            Stage::Memory => "MEM",
+            Stage::WriteBack => "WB",
+        }
+        .to_owned()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PipelineOptions {
+    /// Results in the EX/MEM register go back to EX, and to ID for branches resolved there.
+    pub forward_ex_mem: bool,
+    /// Results in the MEM/WB register go back to EX and ID.
+    pub forward_mem_wb: bool,
+    /// Where conditional branches and `jr` find out where they go: ID, EX or MEM. `j`
+    /// and `jal` always know in ID.
+    pub branch_stage: Stage,
+    /// Instructions and data come through one memory port, so nothing is fetched while
+    /// a load or store is in MEM.
+    pub unified_memory: bool,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            forward_ex_mem: true,
+            forward_mem_wb: true,
+            branch_stage: Stage::Decode,
+            unified_memory: false,
+        }
+    }
+}
+
+/// The cycle an instruction entered each stage, counting from 1. It stays in a stage
+/// until it enters the next.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub fetch: u64,
+    pub decode: u64,
+    pub execute: u64,
+    pub memory: u64,
+    pub write_back: u64,
+}
+
+impl Timing {
+    pub fn stage(&self, cycle: u64) -> Option<Stage> {
+        if cycle < self.fetch || cycle > self.write_back {
+            None
+        } else if cycle < self.decode {
+            Some(Stage::Fetch)
+        } else if cycle < self.execute {
+            Some(Stage::Decode)
+        } else if cycle < self.memory {
+            Some(Stage::Execute)
+        } else if cycle < self.write_back {
+            Some(Stage::Memory)
+        } else {
+            Some(Stage::WriteBack)
+        }
+    }
+}
+
+/// Cycles lost, put down to the instruction that had to wait.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stalls {
+    /// Waiting on the result of a load.
+    pub load_use: u64,
+    /// Waiting on any other result.
+    pub data: u64,
+    /// Fetched late because a branch or jump went somewhere else.
+    pub control: u64,
+    /// Fetched late because a load or store had the memory.
+    pub structural: u64,
+}
+
+impl Stalls {
+    pub fn total(&self) -> u64 {
+        self.load_use + self.data + self.control + self.structural
+    }
+}
+
+// the last instruction to write a register
+#[derive(Copy, Clone, Debug)]
+struct Producer {
+    memory: u64,
+    write_back: u64,
+    // the value only exists at the end of MEM
+    load: bool,
+}
+
+/// Times the instructions a machine executes on a classic five stage pipeline.
+///
+/// Instructions are held in ID until what they need can be forwarded or read from the
+/// register file, which is written in the first half of a cycle and read in the second.
+/// Branches are assumed not taken unless there's a predictor, which gets the target
+/// straight away when it's right. Otherwise what was fetched before the branch was
+/// resolved is thrown away, less the delay slot when the machine has one.
+pub struct Pipeline {
+    options: PipelineOptions,
+    instructions: u64,
+    stalls: Stalls,
+    last: Option<Timing>,
+    registers: [Option<Producer>; 32],
+    // the earliest the next instruction can be fetched after a taken branch, and how
+    // many instructions go by before that applies
+    redirect: Option<(u64, u32)>,
+    // cycles where a load or store has the memory
+    busy: VecDeque<u64>,
+    diagram: Vec<(String, Timing)>,
+    diagram_limit: usize,
+    predictor: Option<BranchPredictor>,
+}
+
+impl Pipeline {
+    pub fn new(options: PipelineOptions) -> Pipeline {
+        Pipeline {
+            options,
+            instructions: 0,
+            stalls: Stalls::default(),
+            last: None,
+            registers: [None; 32],
+            redirect: None,
+            busy: VecDeque::new(),
+            diagram: Vec::new(),
+            diagram_limit: 0,
+            predictor: None,
+        }
+    }
+
+    pub fn predict_with(&mut self, predictor: BranchPredictor) {
+        self.predictor = Some(predictor);
+    }
+    pub fn predictor(&self) -> Option<&BranchPredictor> {
+        self.predictor.as_ref()
+    }
+
+    /// Keeps the timing of the first `n` instructions for `diagram`.
+    pub fn keep_diagram(&mut self, n: usize) {
+        self.diagram_limit = n;
+    }
+
+    // the first cycle from `at` on where a stage `distance` stages after ID can have the
+    // value `p` produces
+    fn available(&self, p: Producer, at: u64, distance: u64) -> u64 {
+        let from_register_file = (p.write_back + distance).max(at);
+        let forwarded = [
+            (self.options.forward_ex_mem && !p.load, p.memory),
+            (self.options.forward_mem_wb, p.write_back),
+        ];
+        forwarded
+            .iter()
+            .filter(|(on, cycle)| *on && *cycle >= at)
+            .map(|(_, cycle)| *cycle)
+            .fold(from_register_file, u64::min)
+    }
+
+    /// Times the instruction at `pc`, which `state` has just executed.
+    pub fn record(&mut self, pc: u32, state: &State) {
+        let inst = match State::parse_instruction(state.memory().read_u32(pc)) {
+            Ok(inst) => inst,
+            Err(_) => return,
+        };
+        let class = inst.class();
+        let operands = inst.operands();
+        // `jr`, like a conditional branch, needs a register to know where it goes
+        let resolves_late = match class {
+            InstructionClass::Branch => true,
+            InstructionClass::Jump => !operands.reads.is_empty(),
+            _ => false,
+        };
+
+        // an instruction moves into a stage as the one ahead of it moves out. Fetching
+        // late only costs anything if it holds up getting into ID.
+        let ahead_leaves_decode = self.last.map_or(0, |t| t.execute);
+        let decode_after = |fetch: u64| (fetch + 1).max(ahead_leaves_decode);
+        let mut fetch = self.last.map_or(1, |t| t.decode);
+        let mut decode = decode_after(fetch);
+        match self.redirect {
+            Some((cycle, 0)) => {
+                fetch = fetch.max(cycle);
+                self.redirect = None;
+            }
+            Some((cycle, n)) => self.redirect = Some((cycle, n - 1)),
+            None => (),
+        }
+        self.stalls.control += decode_after(fetch) - decode;
+        decode = decode_after(fetch);
+        if self.options.unified_memory {
+            while self.busy.front().is_some_and(|b| *b < fetch) {
+                self.busy.pop_front();
+            }
+            while self.busy.contains(&fetch) {
+                fetch += 1;
+            }
+        }
+        self.stalls.structural += decode_after(fetch) - decode;
+        let decode = decode_after(fetch);
+
+        // stages after ID count from it: branches resolved there compare in ID, other
+        // operands are used in EX, and the value a store writes only in MEM
+        let needed = if resolves_late && self.options.branch_stage == Stage::Decode {
+            0
+        } else {
+            1
+        };
+        let mut needs: Vec<(usize, u64)> = operands
+            .reads
+            .iter()
+            .map(|r| (u8::from(*r) as usize, needed))
+            .collect();
+        if let Some(r) = operands.stored {
+            needs.push((u8::from(r) as usize, 2));
+        }
+        let earliest = (decode + 1).max(self.last.map_or(0, |t| t.memory));
+        let mut execute = earliest;
+        let mut waited_on_load = false;
+        loop {
+            let mut later = false;
+            for (r, distance) in &needs {
+                let p = match self.registers[*r] {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let at = execute + distance - 1;
+                let required = self.available(p, at, *distance) + 1 - distance;
+                if required > execute {
+                    execute = required;
+                    waited_on_load = p.load;
+                    later = true;
+                }
+            }
+            if !later {
+                break;
+            }
+        }
+        if waited_on_load {
+            self.stalls.load_use += execute - earliest;
+        } else {
+            self.stalls.data += execute - earliest;
+        }
+        let timing = Timing {
+            fetch,
+            decode,
+            execute,
+            memory: execute + 1,
+            write_back: execute + 2,
+        };
+
+        let wrong_way = match class {
+            InstructionClass::Jump => true,
+            InstructionClass::Branch => match self.predictor {
+                Some(ref mut predictor) => predictor.record(pc, state) == Some(false),
+                None => inst.branch_taken(state) == Some(true),
+            },
+            _ => false,
+        };
+        if wrong_way {
+            let resolved = match self.options.branch_stage {
+                Stage::Execute if resolves_late => timing.execute,
+                Stage::Memory | Stage::WriteBack if resolves_late => timing.memory,
+                _ => timing.execute - 1,
+            };
+            let delay_slot = if state.delayed_branches { 1 } else { 0 };
+            self.redirect = Some((resolved + 1, delay_slot));
+        }
+        if let Some(r) = operands.writes {
+            self.registers[u8::from(r) as usize] = Some(Producer {
+                memory: timing.memory,
+                write_back: timing.write_back,
+                load: class == InstructionClass::Load || class == InstructionClass::Store,
+            });
+        }
+        if self.options.unified_memory
+            && (class == InstructionClass::Load || class == InstructionClass::Store)
+        {
+            self.busy.push_back(timing.memory);
+        }
+        if self.diagram.len() < self.diagram_limit {
+            self.diagram.push((inst.disassemble(pc), timing));
+        }
+        self.instructions += 1;
+        self.last = Some(timing);
+    }
+
+    pub fn step(&mut self, state: &mut State) -> Result<Status, Exception> {
+        let pc = state.read_pc();
+        let steps = state.steps();
+        let result = state.step();
+        if state.steps() > steps {
+            self.record(pc, state);
+        }
+        result
+    }
+
+    pub fn options(&self) -> PipelineOptions {
+        self.options
+    }
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    /// Until the last instruction so far leaves WB.
+    pub fn cycles(&self) -> u64 {
+        self.last.map_or(0, |t| t.write_back)
+    }
+    pub fn cpi(&self) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            self.cycles() as f64 / self.instructions as f64
+        }
+    }
+    pub fn stalls(&self) -> Stalls {
+        self.stalls
+    }
+    /// The timing of the instructions kept for the diagram, with their disassembly.
+    pub fn timings(&self) -> &[(String, Timing)] {
+        &self.diagram
+    }
+
+    pub fn report(&self) -> String {
+        let o = self.options;
+        let forwarding = match (o.forward_ex_mem, o.forward_mem_wb) {
+            (true, true) => "EX/MEM and MEM/WB",
+            (true, false) => "EX/MEM",
+            (false, true) => "MEM/WB",
+            (false, false) => "none",
+        };
+        let mut out = format!(
+            "pipeline: forwarding {}, branches resolved in {}, {} memory\n",
+            forwarding,
+            String::from(o.branch_stage),
+            if o.unified_memory { "unified" } else { "split" }
+        );
+        let mut row = |name: &str, value: String| {
+            writeln!(out, "  {:<18} {:>10}", name, value).unwrap();
+        };
+        row("cycles", self.cycles().to_string());
+        row("instructions", self.instructions.to_string());
+        row("CPI", format!("{:.3}", self.cpi()));
+        row("stalls", self.stalls.total().to_string());
+        row("load-use", self.stalls.load_use.to_string());
+        row("data", self.stalls.data.to_string());
+        row("control", self.stalls.control.to_string());
+        row("structural", self.stalls.structural.to_string());
+        out
+    }
+
+    /// Each kept instruction against the cycles it was in the pipeline for, a stage
+    /// repeating where it was held up.
+    pub fn diagram(&self) -> String {
+        let first = match self.diagram.first() {
+            Some((_, t)) => t.fetch,
+            None => return String::new(),
+        };
+        let last = self
+            .diagram
+            .iter()
+            .map(|(_, t)| t.write_back)
+            .max()
+            .unwrap();
+        let width = self.diagram.iter().map(|(a, _)| a.len()).max().unwrap();
+        let mut out = format!("{:width$}", "", width = width);
+        for cycle in first..=last {
+            write!(out, " {:<4}", cycle).unwrap();
+        }
+        out = out.trim_end().to_owned() + "\n";
+        for (asm, timing) in &self.diagram {
+            let mut line = format!("{:width$}", asm, width = width);
+            for cycle in first..=timing.write_back {
+                let stage = timing.stage(cycle).map_or(String::new(), String::from);
+                write!(line, " {:<4}", stage).unwrap();
+            }
+            out += line.trim_end();
+            out.push('\n');
+        }
+        out
+    }
+}
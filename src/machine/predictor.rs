@@ -0,0 +1,310 @@
+use std::{collections::BTreeMap, convert::TryFrom, fmt::Write};
+
+use crate::machine::state::State;
+
+/// Guesses which way conditional branches go, learning from what they did.
+pub trait Predictor {
+    fn predict(&mut self, pc: u32, target: u32) -> bool;
+    fn update(&mut self, pc: u32, target: u32, taken: bool);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PredictorKind {
+    Taken,
+    NotTaken,
+    /// Backward taken, forward not taken.
+    Btfn,
+    OneBit,
+    TwoBit,
+    Gshare,
+    Tournament,
+}
+
+pub const PREDICTOR_KINDS: [PredictorKind; 7] = [
+    PredictorKind::Taken,
+    PredictorKind::NotTaken,
+    PredictorKind::Btfn,
+    PredictorKind::OneBit,
+    PredictorKind::TwoBit,
+    PredictorKind::Gshare,
+    PredictorKind::Tournament,
+];
+
+impl TryFrom<&str> for PredictorKind {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let name = s.to_lowercase().replace("bit", "-bit").replace("--", "-");
+        PREDICTOR_KINDS
+            .iter()
+            .copied()
+            .find(|k| String::from(*k) == name)
+            .ok_or(format!("No branch predictor called {}", s))
+    }
+}
+
+impl From<PredictorKind> for String {
+    fn from(k: PredictorKind) -> String {
+        match k {
+            PredictorKind::Taken => "taken",
+            PredictorKind::NotTaken => "not-taken",
+            PredictorKind::Btfn => "btfn",
+            PredictorKind::OneBit => "1-bit",
+            PredictorKind::TwoBit => "2-bit",
+            PredictorKind::Gshare => "gshare",
+            PredictorKind::Tournament => "tournament",
+        }
+        .to_owned()
+    }
+}
+
+pub struct Static(pub bool);
+
+impl Predictor for Static {
+    fn predict(&mut self, _: u32, _: u32) -> bool {
+        self.0
+    }
+    fn update(&mut self, _: u32, _: u32, _: bool) {}
+}
+
+pub struct Btfn;
+
+impl Predictor for Btfn {
+    fn predict(&mut self, pc: u32, target: u32) -> bool {
+        target <= pc
+    }
+    fn update(&mut self, _: u32, _: u32, _: bool) {}
+}
+
+fn index(pc: u32, bits: u32) -> usize {
+    ((pc >> 2) & ((1 << bits) - 1)) as usize
+}
+
+/// Remembers which way each branch went last time.
+pub struct OneBit {
+    bits: u32,
+    table: Vec<bool>,
+}
+
+impl OneBit {
+    pub fn new(bits: u32) -> OneBit {
+        OneBit {
+            bits,
+            table: vec![false; 1 << bits],
+        }
+    }
+}
+
+impl Predictor for OneBit {
+    fn predict(&mut self, pc: u32, _: u32) -> bool {
+        self.table[index(pc, self.bits)]
+    }
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        self.table[index(pc, self.bits)] = taken;
+    }
+}
+
+// a two bit saturating counter, taken from 2 up
+fn train(counter: &mut u8, taken: bool) {
+    if taken {
+        *counter = (*counter + 1).min(3);
+    } else {
+        *counter = counter.saturating_sub(1);
+    }
+}
+
+/// A two bit saturating counter for each branch, starting weakly not taken.
+pub struct TwoBit {
+    bits: u32,
+    table: Vec<u8>,
+}
+
+impl TwoBit {
+    pub fn new(bits: u32) -> TwoBit {
+        TwoBit {
+            bits,
+            table: vec![1; 1 << bits],
+        }
+    }
+}
+
+impl Predictor for TwoBit {
+    fn predict(&mut self, pc: u32, _: u32) -> bool {
+        self.table[index(pc, self.bits)] >= 2
+    }
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        train(&mut self.table[index(pc, self.bits)], taken);
+    }
+}
+
+/// Two bit counters picked by the address xor the outcomes of the last branches.
+pub struct Gshare {
+    bits: u32,
+    history: u32,
+    table: Vec<u8>,
+}
+
+impl Gshare {
+    pub fn new(bits: u32) -> Gshare {
+        Gshare {
+            bits,
+            history: 0,
+            table: vec![1; 1 << bits],
+        }
+    }
+    fn slot(&self, pc: u32) -> usize {
+        index(pc ^ (self.history << 2), self.bits)
+    }
+}
+
+impl Predictor for Gshare {
+    fn predict(&mut self, pc: u32, _: u32) -> bool {
+        self.table[self.slot(pc)] >= 2
+    }
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        let slot = self.slot(pc);
+        train(&mut self.table[slot], taken);
+        self.history = ((self.history << 1) | taken as u32) & ((1 << self.bits) - 1);
+    }
+}
+
+/// Chooses between two bit counters per branch and gshare, going by which has been
+/// right more often for the branch.
+pub struct Tournament {
+    bits: u32,
+    local: TwoBit,
+    global: Gshare,
+    choice: Vec<u8>,
+}
+
+impl Tournament {
+    pub fn new(bits: u32) -> Tournament {
+        Tournament {
+            bits,
+            local: TwoBit::new(bits),
+            global: Gshare::new(bits),
+            choice: vec![1; 1 << bits],
+        }
+    }
+}
+
+impl Predictor for Tournament {
+    fn predict(&mut self, pc: u32, target: u32) -> bool {
+        if self.choice[index(pc, self.bits)] >= 2 {
+            self.global.predict(pc, target)
+        } else {
+            self.local.predict(pc, target)
+        }
+    }
+    fn update(&mut self, pc: u32, target: u32, taken: bool) {
+        let local = self.local.predict(pc, target) == taken;
+        let global = self.global.predict(pc, target) == taken;
+        if local != global {
+            train(&mut self.choice[index(pc, self.bits)], global);
+        }
+        self.local.update(pc, target, taken);
+        self.global.update(pc, target, taken);
+    }
+}
+
+/// How one branch went.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Site {
+    pub disassembly: String,
+    pub executed: u64,
+    pub taken: u64,
+    pub correct: u64,
+}
+
+/// Runs a predictor over the conditional branches a machine executes and keeps score
+/// for each one.
+pub struct BranchPredictor {
+    kind: PredictorKind,
+    predictor: Box<dyn Predictor>,
+    sites: BTreeMap<u32, Site>,
+}
+
+impl BranchPredictor {
+    /// Tables have `2^bits` entries, and gshare remembers `bits` branches.
+    pub fn new(kind: PredictorKind, bits: u32) -> BranchPredictor {
+        let predictor: Box<dyn Predictor> = match kind {
+            PredictorKind::Taken => Box::new(Static(true)),
+            PredictorKind::NotTaken => Box::new(Static(false)),
+            PredictorKind::Btfn => Box::new(Btfn),
+            PredictorKind::OneBit => Box::new(OneBit::new(bits)),
+            PredictorKind::TwoBit => Box::new(TwoBit::new(bits)),
+            PredictorKind::Gshare => Box::new(Gshare::new(bits)),
+            PredictorKind::Tournament => Box::new(Tournament::new(bits)),
+        };
+        BranchPredictor::with(kind, predictor)
+    }
+
+    /// Scores a predictor of your own. `kind` is only used to name it.
+    pub fn with(kind: PredictorKind, predictor: Box<dyn Predictor>) -> BranchPredictor {
+        BranchPredictor {
+            kind,
+            predictor,
+            sites: BTreeMap::new(),
+        }
+    }
+
+    /// Predicts the instruction at `pc`, which `state` has just executed, if it's a
+    /// conditional branch, and whether the prediction was right.
+    pub fn record(&mut self, pc: u32, state: &State) -> Option<bool> {
+        let inst = State::parse_instruction(state.memory().read_u32(pc)).ok()?;
+        let taken = inst.branch_taken(state)?;
+        let target = inst.branch_target(pc)?;
+        let correct = self.predictor.predict(pc, target) == taken;
+        self.predictor.update(pc, target, taken);
+        let site = self.sites.entry(pc).or_insert_with(|| Site {
+            disassembly: inst.disassemble(pc),
+            ..Site::default()
+        });
+        site.executed += 1;
+        site.taken += taken as u64;
+        site.correct += correct as u64;
+        Some(correct)
+    }
+
+    pub fn kind(&self) -> PredictorKind {
+        self.kind
+    }
+    pub fn sites(&self) -> &BTreeMap<u32, Site> {
+        &self.sites
+    }
+    pub fn branches(&self) -> u64 {
+        self.sites.values().map(|s| s.executed).sum()
+    }
+    pub fn correct(&self) -> u64 {
+        self.sites.values().map(|s| s.correct).sum()
+    }
+    pub fn accuracy(&self) -> f64 {
+        match self.branches() {
+            0 => 0.0,
+            n => self.correct() as f64 / n as f64,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = format!(
+            "branch predictor {}: {} of {} right, {:.2}%\n",
+            String::from(self.kind),
+            self.correct(),
+            self.branches(),
+            self.accuracy() * 100.0
+        );
+        for (pc, site) in &self.sites {
+            writeln!(
+                out,
+                "  0x{:08x} {:<30} {:>8} executed {:>6.2}% taken {:>6.2}% right",
+                pc,
+                site.disassembly,
+                site.executed,
+                site.taken as f64 * 100.0 / site.executed as f64,
+                site.correct as f64 * 100.0 / site.executed as f64
+            )
+            .unwrap();
+        }
+        out
+    }
+}
@@ -27,6 +27,9 @@ pub enum Reg {
     s5,
     s6,
     s7,
+    k0,
+    k1,
+    gp,
     sp,
     fp,
     ra,
@@ -61,8 +64,11 @@ impl From<&str> for Reg {
             "$s5" | "$21" => Reg::s5,
             "$s6" | "$22" => Reg::s6,
             "$s7" | "$23" => Reg::s7,
+            "$k0" | "$26" => Reg::k0,
+            "$k1" | "$27" => Reg::k1,
+            "$gp" | "$28" => Reg::gp,
             "$sp" | "$29" => Reg::sp,
-            "$fp" | "$30" => Reg::fp,
+            "$fp" | "$s8" | "$30" => Reg::fp,
             "$ra" | "$31" => Reg::ra,
             _ => panic!("No such register: {}", s),
         }
@@ -98,6 +104,9 @@ impl From<Reg> for String {
             Reg::s5 => "$s5",
             Reg::s6 => "$s6",
             Reg::s7 => "$s7",
+            Reg::k0 => "$k0",
+            Reg::k1 => "$k1",
+            Reg::gp => "$gp",
             Reg::sp => "$sp",
             Reg::fp => "$fp",
             Reg::ra => "$ra",
@@ -138,6 +147,9 @@ macro_rules! reg_map {
                     23 => Reg::s7,
                     24 => Reg::t8,
                     25 => Reg::t9,
+                    26 => Reg::k0,
+                    27 => Reg::k1,
+                    28 => Reg::gp,
                     29 => Reg::sp,
                     30 => Reg::fp,
                     31 => Reg::ra,
@@ -179,6 +191,9 @@ macro_rules! reg_inv_map {
                     Reg::s7 => 23,
                     Reg::t8 => 24,
                     Reg::t9 => 25,
+                    Reg::k0 => 26,
+                    Reg::k1 => 27,
+                    Reg::gp => 28,
                     Reg::sp => 29,
                     Reg::fp => 30,
                     Reg::ra => 31,
@@ -1,171 +1,561 @@
-use lazy_static::lazy_static;
-use regex::Regex;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    convert::TryFrom,
+    fmt,
+};
 
-use std::fmt;
+use crate::{
+    assembler::assembler::{Assembled, Endian},
+    instructions::{
+        cop0::C0Type,
+        itype::{IInst, ITypeImm},
+        jtype::{JInst, JType},
+        rtype::{RInst, RType},
+    },
+    machine::{
+        cp0::{self, ClockRate, Cp0},
+        memory::Memory,
+        register::Reg,
+    },
+};
 
-use crate::instructions::itype::IType;
-use crate::instructions::jtype::{JInst, JType};
-use crate::instructions::label::Label;
-use crate::instructions::rtype::RType;
+mod snapshot;
+
+const STACK_POINTER: u32 = 0x7FFF_EFFC;
+const GLOBAL_POINTER: u32 = 0x1000_8000;
+const HEAP_START: u32 = 0x1004_0000;
+
+#[derive(Clone, Debug)]
+pub enum InstType {
+    R(RType),
+    I(ITypeImm),
+    J(JType),
+    C0(C0Type),
+}
+
+impl InstType {
+    pub fn disassemble(&self, address: u32) -> String {
+        match self {
+            InstType::R(r) => r.disassemble(),
+            InstType::I(i) => i.disassemble(address),
+            InstType::J(j) => j.disassemble(address),
+            InstType::C0(c) => c.disassemble(),
+        }
+    }
+    pub fn class(&self) -> InstructionClass {
+        match self {
+            InstType::R(r) => r.class(),
+            InstType::I(i) => i.class(),
+            InstType::J(_) => InstructionClass::Jump,
+            InstType::C0(c) => c.class(),
+        }
+    }
+    pub fn operands(&self) -> Operands {
+        match self {
+            InstType::R(r) => r.operands(),
+            InstType::I(i) => i.operands(),
+            InstType::J(j) => match j.opcode() {
+                JInst::jal => Operands::new(&[], None, Some(Reg::ra)),
+                JInst::j => Operands::new(&[], None, None),
+            },
+            InstType::C0(c) => c.operands(),
+        }
+    }
+    pub fn mnemonic(&self) -> String {
+        match self {
+            InstType::R(r) => r.mnemonic(),
+            InstType::I(i) => i.mnemonic(),
+            InstType::J(j) => String::from(j.opcode()),
+            InstType::C0(c) => c.mnemonic(),
+        }
+    }
+    /// Whether a conditional branch goes to its target given the registers in `state`;
+    /// `None` for everything else.
+    pub fn branch_taken(&self, state: &State) -> Option<bool> {
+        match self {
+            InstType::I(i) => i.branch_taken(state),
+            _ => None,
+        }
+    }
+    /// Where a conditional branch at `address` goes if it's taken.
+    pub fn branch_target(&self, address: u32) -> Option<u32> {
+        match self {
+            InstType::I(i) => i.branch_target(address),
+            _ => None,
+        }
+    }
+}
+
+/// The general purpose registers an instruction uses. `stored` is the register a
+/// store puts in memory, which isn't needed until after the address is worked out.
+/// `$zero` is left out.
+#[derive(Clone, Debug, Default)]
+pub struct Operands {
+    pub reads: Vec<Reg>,
+    pub stored: Option<Reg>,
+    pub writes: Option<Reg>,
+}
+
+impl Operands {
+    pub fn new(reads: &[Reg], stored: Option<Reg>, writes: Option<Reg>) -> Operands {
+        let used = |r: &Reg| u8::from(*r) != 0;
+        Operands {
+            reads: reads.iter().copied().filter(used).collect(),
+            stored: stored.filter(used),
+            writes: writes.filter(used),
+        }
+    }
+}
+
+/// Broad groups of instructions, for filtering and counting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InstructionClass {
+    Arithmetic,
+    Logical,
+    Shift,
+    Load,
+    Store,
+    Branch,
+    Jump,
+    Syscall,
+}
+
+pub const INSTRUCTION_CLASSES: [InstructionClass; 8] = [
+    InstructionClass::Arithmetic,
+    InstructionClass::Logical,
+    InstructionClass::Shift,
+    InstructionClass::Load,
+    InstructionClass::Store,
+    InstructionClass::Branch,
+    InstructionClass::Jump,
+    InstructionClass::Syscall,
+];
+
+impl TryFrom<&str> for InstructionClass {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        INSTRUCTION_CLASSES
+            .iter()
+            .copied()
+            .find(|c| String::from(*c) == s.to_lowercase())
+            .ok_or(format!("No instruction class called {}", s))
+    }
+}
+
+impl From<InstructionClass> for String {
+    fn from(c: InstructionClass) -> String {
+        match c {
+            InstructionClass::Arithmetic => "arithmetic",
+            InstructionClass::Logical => "logical",
+            InstructionClass::Shift => "shift",
+            InstructionClass::Load => "load",
+            InstructionClass::Store => "store",
+            InstructionClass::Branch => "branch",
+            InstructionClass::Jump => "jump",
+            InstructionClass::Syscall => "syscall",
+        }
+        .to_owned()
+    }
+}
+
+/// Disassembles `word` as if it were at `address`; anything that doesn't decode is
+/// shown as data.
+pub fn disassemble(word: u32, address: u32) -> String {
+    match State::parse_instruction(word) {
+        Ok(inst) => inst.disassemble(address),
+        Err(_) => format!(".word 0x{:08x}", word),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub address: u32,
+    pub size: u32,
+    pub write: bool,
+    /// What was loaded or stored, or None for accesses wider than a word, like a
+    /// string read by a syscall.
+    pub value: Option<u32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    General(u8),
+    Hi,
+    Lo,
+}
+
+/// Something an instruction or syscall did, as seen by watchpoints.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Memory(Access),
+    Register {
+        register: Register,
+        old: u32,
+        new: u32,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum Change {
+    Register(Register, u32),
+    Memory(u32, Vec<u8>),
+    Input(u8),
+}
+
+/// What one step changed, kept so that it can be taken back with `State::undo`.
+#[derive(Clone, Debug)]
+pub struct Undo {
+    pc: u32,
+    delay_slot: Option<u32>,
+    heap: u32,
+    steps: u64,
+    status: Status,
+    output: usize,
+    cp0: Cp0,
+    changes: Vec<Change>,
+}
+
+impl Undo {
+    /// Where the undone instruction sits.
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+    pub fn wrote_register(&self, register: Register) -> bool {
+        self.changes
+            .iter()
+            .any(|c| matches!(c, Change::Register(r, _) if *r == register))
+    }
+    pub fn wrote_memory(&self, address: u32, length: u32) -> bool {
+        self.changes.iter().any(|c| match c {
+            Change::Memory(a, old) => {
+                *a < address.wrapping_add(length) && address < a.wrapping_add(old.len() as u32)
+            }
+            _ => false,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exception {
+    // misaligned access, or fetching from outside the text segments
+    AddressError(u32),
+    ReservedInstruction(u32),
+    ArithmeticOverflow,
+    UnknownSyscall(u32),
+    // sbrk asking for a negative amount or more than fits below the stack
+    BadHeapRequest(i32),
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exception::AddressError(a) => write!(f, "Address error at 0x{:08X}", a),
+            Exception::ReservedInstruction(w) => {
+                write!(f, "Reserved or unsupported instruction 0x{:08X}", w)
+            }
+            Exception::ArithmeticOverflow => write!(f, "Arithmetic overflow"),
+            Exception::UnknownSyscall(c) => write!(f, "Unknown syscall {}", c),
+            Exception::BadHeapRequest(n) if *n < 0 => {
+                write!(f, "Request ({}) is negative heap amount", n)
+            }
+            Exception::BadHeapRequest(n) => {
+                write!(f, "Request ({}) exceeds available heap storage", n)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Exited(i32),
+    // ran past the last instruction of a text segment, which MARS treats as a normal exit
+    DroppedOffBottom,
+}
 
 #[derive(Clone)]
 pub struct State {
     pc: u32,
+    next_pc: u32,
+    delay_slot: Option<u32>,
     registers: [u32; 32],
-    memory: [u32; std::u16::MAX as usize],
-    labels: Vec<Label>,
+    hi: u32,
+    lo: u32,
+    cp0: Cp0,
+    memory: Memory,
+    labels: BTreeMap<String, u32>,
+    text_ranges: Vec<(u32, u32)>,
+    heap: u32,
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    steps: u64,
+    status: Status,
+    // reads only borrow the state, so the log needs to be mutable behind `&self`
+    events: Option<RefCell<Vec<Event>>>,
+    // finished steps waiting for `take_undo`, and the one in progress
+    journal: Option<Vec<Undo>>,
+    in_progress: Option<Undo>,
+    pub delayed_branches: bool,
 }
 
-#[derive(Clone)]
-pub enum InstType {
-    R(RType),
-    I(IType),
-    J(JType),
+impl Default for State {
+    fn default() -> Self {
+        State::new(Endian::default())
+    }
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(endian: Endian) -> Self {
+        let mut registers = [0; 32];
+        registers[u8::from(Reg::sp) as usize] = STACK_POINTER;
+        registers[u8::from(Reg::gp) as usize] = GLOBAL_POINTER;
         State {
             pc: 0,
-            registers: [0; 32],
-            memory: [0; std::u16::MAX as usize],
-            labels: Vec::new(),
-        }
-    }
-    pub fn run(&mut self) {
-        /*
-         * main's return address will be 0x0, if we ever jump here the program is done
-         * 1. Set pc to 0x0 + 4
-         * 2. Set $ra to 0x0 (which it already is)
-         * 3. Begin executing code from memory
-         */
-        self.pc = 0;
-        loop {
-            self.pc += 4;
-            match State::parse_instruction(self.memory[self.pc as usize]) {
-                InstType::R(r) => {
-                    r.perform(self);
-                }
-                InstType::I(i) => {
-                    i.perform(self);
-                }
-                InstType::J(j) => {
-                    j.perform(self);
-                }
-            }
-            if self.pc == 0 {
-                break;
+            next_pc: 4,
+            delay_slot: None,
+            registers,
+            hi: 0,
+            lo: 0,
+            cp0: Cp0::default(),
+            memory: Memory::new(endian),
+            labels: BTreeMap::new(),
+            text_ranges: Vec::new(),
+            heap: HEAP_START,
+            input: VecDeque::new(),
+            output: Vec::new(),
+            steps: 0,
+            status: Status::Running,
+            events: None,
+            journal: None,
+            in_progress: None,
+            delayed_branches: false,
+        }
+    }
+    pub fn from_assembled(assembled: &Assembled) -> Self {
+        let mut state = State::new(assembled.endian);
+        for s in &assembled.segments {
+            state.load_segment(
+                s.start_address,
+                &s.bytes,
+                s.bytes.len() as u32,
+                s.kind.is_text(),
+            );
+        }
+        for (name, address) in &assembled.labels {
+            state.add_label(*address, name);
+        }
+        state.pc = assembled.entry_point();
+        state
+    }
+    /// Copies `bytes` to `address` and maps `size` bytes there, the rest zero filled.
+    pub fn load_segment(&mut self, address: u32, bytes: &[u8], size: u32, executable: bool) {
+        self.memory.map(address, size);
+        self.memory.load(address, bytes);
+        if executable && size > 0 {
+            self.text_ranges.push((address, address.wrapping_add(size)));
+        }
+        if !executable {
+            let end = address.saturating_add(size);
+            if end > self.heap && end < STACK_POINTER {
+                self.heap = (end + 7) & !7;
             }
         }
     }
-    pub fn parse_instruction<T>(inst: T) -> InstType
-    where
-        u32: From<T>,
-    {
-        let inst: u32 = inst.into();
-        let opcode: u32 = inst;
-        let opcode: u32 = opcode >> 26;
+    pub fn parse_instruction(inst: u32) -> Result<InstType, Exception> {
+        let opcode = inst >> 26;
         if opcode == 0 {
-            InstType::R(RType::from(inst))
-        } else if opcode == JInst::j.into() || opcode == JInst::jal.into() {
-            InstType::J(JType::from(inst))
+            RInst::decode(inst)
+                .map(|_| InstType::R(RType::from(inst)))
+                .ok_or(Exception::ReservedInstruction(inst))
+        } else if let Some(jinst) = JInst::decode(opcode) {
+            Ok(InstType::J(JType::decode(jinst, inst)))
+        } else if let Some(c) = C0Type::decode(inst) {
+            Ok(InstType::C0(c))
+        } else if IInst::decode(opcode).is_some() {
+            Ok(InstType::I(ITypeImm::from(inst)))
         } else {
-            InstType::I(IType::from(inst))
+            Err(Exception::ReservedInstruction(inst))
         }
     }
-    pub fn load_compiled_instructions<T>(&mut self, instructions: &[u32], start: Option<T>)
-    where
-        u32: From<T>,
-    {
-        let mut start: u32 = match start {
-            Some(s) => s.into(),
-            None => 0x4,
-        };
-        for inst in instructions {
-            self.memory[start as usize] = *inst;
-            start += 4;
+    pub fn step(&mut self) -> Result<Status, Exception> {
+        if self.status != Status::Running {
+            return Ok(self.status);
         }
-    }
-    pub fn load_parsed_instructions<T>(&mut self, instructions: &[InstType], start: Option<T>)
-    where
-        u32: From<T>,
-    {
-        let mut start: u32 = match start {
-            Some(s) => s.into(),
-            None => 0x4,
-        };
-        for inst in instructions {
-            self.memory[start as usize] = match *inst {
-                InstType::R(r) => r.into(),
-                InstType::I(i) => i.into(),
-                InstType::J(j) => j.into(),
-            };
-            start += 4;
+        let pc = self.pc;
+        let in_text = self.text_ranges.iter().any(|r| pc >= r.0 && pc < r.1);
+        if pc % 4 != 0 || !in_text {
+            if self.delay_slot.is_none() && self.text_ranges.iter().any(|r| r.1 == pc) {
+                self.status = Status::DroppedOffBottom;
+                return Ok(self.status);
+            }
+            return Err(Exception::AddressError(pc));
         }
-    }
-    pub fn load_text_instructions<T>(&mut self, instructions: &[&str], start: Option<T>)
-    where
-        u32: From<T>,
-    {
-        lazy_static! {
-            static ref LABEL_RE: Regex = Regex::new(r"^\s*(?P<label>\w+):\s*$").unwrap();
+        let inst = State::parse_instruction(self.memory.read_u32(pc))?;
+        if self.journal.is_some() {
+            self.in_progress = Some(Undo {
+                pc,
+                delay_slot: self.delay_slot,
+                heap: self.heap,
+                steps: self.steps,
+                status: self.status,
+                output: self.output.len(),
+                cp0: self.cp0.clone(),
+                changes: Vec::new(),
+            });
         }
-        let mut start: u32 = match start {
-            Some(s) => s.into(),
-            None => 0,
+        let pending = self.delay_slot.take();
+        self.next_pc = pending.unwrap_or_else(|| pc.wrapping_add(4));
+        let result = match inst {
+            InstType::R(r) => r.perform(self),
+            InstType::I(i) => i.perform(self),
+            InstType::J(j) => j.perform(self),
+            InstType::C0(c) => c.perform(self),
         };
-        let mut labels: Vec<u32> = Vec::new();
-        {
-            let mut count = start;
-            for line in instructions {
-                let trim = line.trim();
-                if trim == "" {
-                    continue;
-                }
-                for caps in LABEL_RE.captures_iter(trim) {
-                    self.add_label::<u32, &str>(Some(count), &caps["label"]);
-                    labels.push(count);
-                }
-                count += 4;
+        let undo = self.in_progress.take();
+        if let Err(e) = result {
+            // leave the machine pointing at the faulting instruction, as it was
+            if let Some(ref undo) = undo {
+                self.undo(undo);
             }
+            self.delay_slot = pending;
+            return Err(e);
+        }
+        self.pc = self.next_pc;
+        self.steps += 1;
+        let printed = self.memory.tick_devices();
+        self.output.extend(printed);
+        self.cp0.tick();
+        if self.status == Status::Running && self.cp0.interrupted(self.memory.interrupts()) {
+            // a branch whose delay slot hasn't run yet is gone back to
+            let (epc, in_delay_slot) = match self.delay_slot.take() {
+                Some(_) => (pc, true),
+                None => (self.pc, false),
+            };
+            self.cp0.enter(epc, in_delay_slot);
+            self.pc = cp0::EXCEPTION_VECTOR;
+        }
+        if let (Some(journal), Some(undo)) = (self.journal.as_mut(), undo) {
+            journal.push(undo);
         }
-        let mut iter = labels.into_iter().peekable();
-        for inst in instructions {
-            let inst = inst.trim();
-            if inst == "" {
-                continue;
+        Ok(self.status)
+    }
+    /// Starts or stops keeping an `Undo` for every step; see `take_undo`.
+    pub fn record_undo(&mut self, on: bool) {
+        self.journal = if on { Some(Vec::new()) } else { None };
+    }
+    pub fn is_recording_undo(&self) -> bool {
+        self.journal.is_some()
+    }
+    /// The steps taken since the last call, oldest first.
+    pub fn take_undo(&mut self) -> Vec<Undo> {
+        self.journal.as_mut().map_or(Vec::new(), std::mem::take)
+    }
+    /// Puts back what a step changed. Steps have to be undone newest first. Output the
+    /// step printed is dropped if it hasn't been taken yet.
+    pub fn undo(&mut self, undo: &Undo) {
+        for change in undo.changes.iter().rev() {
+            match change {
+                Change::Register(Register::General(r), old) => self.registers[*r as usize] = *old,
+                Change::Register(Register::Hi, old) => self.hi = *old,
+                Change::Register(Register::Lo, old) => self.lo = *old,
+                Change::Memory(address, old) => self.memory.load(*address, old),
+                Change::Input(b) => self.input.push_front(*b),
             }
-            if let Some(&i) = iter.peek() {
-                if i == start {
-                    iter.next();
-                    start += 4;
-                    continue;
-                }
+        }
+        self.pc = undo.pc;
+        self.delay_slot = undo.delay_slot;
+        self.heap = undo.heap;
+        self.steps = undo.steps;
+        self.status = undo.status;
+        self.cp0 = undo.cp0.clone();
+        self.output.truncate(undo.output);
+    }
+    fn journal_change(&mut self, change: Change) {
+        if let Some(ref mut undo) = self.in_progress {
+            undo.changes.push(change);
+        }
+    }
+    fn journal_memory(&mut self, address: u32, length: u32) {
+        // devices can't be wound back
+        if self.in_progress.is_some() && !self.memory.is_device(address) {
+            let old = self.memory.read_bytes(address, length);
+            self.journal_change(Change::Memory(address, old));
+        }
+    }
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<Status, Exception> {
+        let mut remaining = max_steps;
+        loop {
+            if remaining == Some(0) {
+                return Ok(self.status);
             }
-            if let Some(r) = RType::convert_from_string(inst, &self) {
-                self.memory[start as usize] = r.into();
-            } else if let Some(i) = IType::convert_from_string(inst, &self) {
-                self.memory[start as usize] = i.into();
-            } else if let Some(j) = JType::convert_from_string(inst, &self) {
-                self.memory[start as usize] = j.into();
-            } else {
-                panic!("Could not parse instruction: {}", inst);
+            let status = self.step()?;
+            if status != Status::Running {
+                return Ok(status);
             }
-            start += 4;
+            remaining = remaining.map(|r| r - 1);
         }
     }
+    pub fn status(&self) -> Status {
+        self.status
+    }
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
     pub fn read_pc(&self) -> u32 {
         self.pc
     }
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.delay_slot = None;
+    }
+    /// Address execution continues at after a call from the current instruction.
+    pub fn return_address(&self) -> u32 {
+        if self.delayed_branches {
+            self.pc.wrapping_add(8)
+        } else {
+            self.pc.wrapping_add(4)
+        }
+    }
+    /// Starts or stops logging memory accesses and register writes; see `take_events`.
+    pub fn record_events(&mut self, on: bool) {
+        self.events = if on { Some(RefCell::default()) } else { None };
+    }
+    pub fn is_recording_events(&self) -> bool {
+        self.events.is_some()
+    }
+    /// The events logged since they were last taken, leaving them there.
+    pub fn events(&self) -> Vec<Event> {
+        self.events
+            .as_ref()
+            .map_or(Vec::new(), |e| e.borrow().clone())
+    }
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events.as_mut().map_or(Vec::new(), |e| e.take())
+    }
+    fn record(&self, event: Event) {
+        if let Some(ref events) = self.events {
+            events.borrow_mut().push(event);
+        }
+    }
+    fn record_access(&self, address: u32, size: u32, write: bool, value: Option<u32>) {
+        self.record(Event::Memory(Access {
+            address,
+            size,
+            write,
+            value,
+        }));
+    }
     pub fn read_reg<T>(&self, r: T) -> u32
     where
         u8: From<T>,
     {
-        self.registers[u8::from(r) as usize]
+        self.registers[u8::from(r) as usize & 0x1F]
     }
     pub fn dump_reg(&self) -> [u32; 33] {
         let mut r: [u32; 33] = [0; 33];
-        r.clone_from_slice(&self.registers[..]);
+        r[..32].clone_from_slice(&self.registers[..]);
         r[32] = self.pc;
         r
     }
@@ -174,234 +564,266 @@ impl State {
         u8: From<T>,
         u32: From<U>,
     {
-        let reg = u8::from(r);
+        let reg = u8::from(r) & 0x1F;
         match reg {
             0 => (),
-            _ => self.registers[reg as usize] = u32::from(val),
+            _ => {
+                let new = u32::from(val);
+                let old = std::mem::replace(&mut self.registers[reg as usize], new);
+                self.journal_change(Change::Register(Register::General(reg), old));
+                self.record(Event::Register {
+                    register: Register::General(reg),
+                    old,
+                    new,
+                });
+            }
         };
     }
+    pub fn read_hi(&self) -> u32 {
+        self.hi
+    }
+    pub fn read_lo(&self) -> u32 {
+        self.lo
+    }
+    pub fn write_hi_lo(&mut self, hi: u32, lo: u32) {
+        self.record(Event::Register {
+            register: Register::Hi,
+            old: self.hi,
+            new: hi,
+        });
+        self.record(Event::Register {
+            register: Register::Lo,
+            old: self.lo,
+            new: lo,
+        });
+        self.journal_change(Change::Register(Register::Hi, self.hi));
+        self.journal_change(Change::Register(Register::Lo, self.lo));
+        self.hi = hi;
+        self.lo = lo;
+    }
     pub fn jump<T>(&mut self, dest: T)
     where
         u32: From<T>,
     {
-        self.pc = u32::from(dest);
+        let dest = u32::from(dest);
+        if self.delayed_branches {
+            self.delay_slot = Some(dest);
+        } else {
+            self.next_pc = dest;
+        }
     }
-    pub fn write_mem<T, U>(&mut self, addr: T, val: U)
-    where
-        u32: From<T> + From<U>,
-    {
-        self.memory[u32::from(addr) as usize] = u32::from(val);
+    pub fn cp0(&self) -> &Cp0 {
+        &self.cp0
     }
-    pub fn read_mem<T>(&self, addr: T) -> u32
-    where
-        u32: From<T>,
-    {
-        self.memory[u32::from(addr) as usize]
+    /// Coprocessor 0 register `n`, with the interrupts devices are raising in Cause.
+    pub fn read_cp0(&self, n: u8) -> u32 {
+        match n {
+            cp0::CAUSE => self.cp0.read(n) | self.memory.interrupts(),
+            _ => self.cp0.read(n),
+        }
+    }
+    pub fn write_cp0(&mut self, n: u8, value: u32) {
+        self.cp0.write(n, value);
     }
-    pub fn find_label_by_addr<T>(&self, addr: T) -> Option<String>
+    pub fn cp0_mut(&mut self) -> &mut Cp0 {
+        &mut self.cp0
+    }
+    pub fn set_clock(&mut self, rate: ClockRate) {
+        self.cp0.set_clock(rate);
+    }
+    pub fn eret(&mut self) {
+        self.next_pc = self.cp0.eret();
+        self.delay_slot = None;
+    }
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+    pub fn read_mem<T>(&self, addr: T) -> Result<u32, Exception>
     where
         u32: From<T>,
     {
-        let x = u32::from(addr);
-        for p in &self.labels {
-            match p.addr {
-                Some(a) => {
-                    if a == x {
-                        return Some(p.label.clone());
-                    }
-                }
-                None => (),
-            }
+        let addr = u32::from(addr);
+        if addr % 4 != 0 {
+            return Err(Exception::AddressError(addr));
         }
-        None
+        let value = self
+            .memory
+            .read_device(addr, 4)
+            .unwrap_or_else(|| self.memory.read_u32(addr));
+        self.record_access(addr, 4, false, Some(value));
+        Ok(value)
     }
-    pub fn find_label_by_name<T>(&self, name: T) -> Option<u32>
+    pub fn write_mem<T, U>(&mut self, addr: T, val: U) -> Result<(), Exception>
     where
-        String: From<T>,
+        u32: From<T> + From<U>,
     {
-        let x = String::from(name);
-        for p in &self.labels {
-            if p.label == x {
-                return p.addr;
-            }
+        let addr = u32::from(addr);
+        if addr % 4 != 0 {
+            return Err(Exception::AddressError(addr));
         }
-        None
+        let val = u32::from(val);
+        self.record_access(addr, 4, true, Some(val));
+        self.journal_memory(addr, 4);
+        self.memory.write_u32(addr, val);
+        Ok(())
     }
-    pub fn add_label<T, U>(&mut self, addr: Option<T>, label: U)
-    where
-        u32: From<T>,
-        String: From<U>,
-        U: Clone,
-    {
-        let addr: Option<u32> = match addr {
-            Some(a) => Some(a.into()),
-            None => None,
-        };
-        let label = String::from(label);
-        for p in &mut self.labels {
-            if p.label == label {
-                match p.addr {
-                    Some(_) => {
-                        return;
+    pub fn read_half(&self, addr: u32) -> Result<u16, Exception> {
+        if addr % 2 != 0 {
+            return Err(Exception::AddressError(addr));
+        }
+        let value = self
+            .memory
+            .read_device(addr, 2)
+            .map_or_else(|| self.memory.read_u16(addr), |v| v as u16);
+        self.record_access(addr, 2, false, Some(u32::from(value)));
+        Ok(value)
+    }
+    pub fn write_half(&mut self, addr: u32, val: u16) -> Result<(), Exception> {
+        if addr % 2 != 0 {
+            return Err(Exception::AddressError(addr));
+        }
+        self.record_access(addr, 2, true, Some(u32::from(val)));
+        self.journal_memory(addr, 2);
+        self.memory.write_u16(addr, val);
+        Ok(())
+    }
+    pub fn read_byte(&self, addr: u32) -> u8 {
+        let value = self
+            .memory
+            .read_device(addr, 1)
+            .map_or_else(|| self.memory.read_u8(addr), |v| v as u8);
+        self.record_access(addr, 1, false, Some(u32::from(value)));
+        value
+    }
+    pub fn write_byte(&mut self, addr: u32, val: u8) {
+        self.record_access(addr, 1, true, Some(u32::from(val)));
+        self.journal_memory(addr, 1);
+        self.memory.write_u8(addr, val);
+    }
+    pub fn text_ranges(&self) -> &[(u32, u32)] {
+        &self.text_ranges
+    }
+    pub fn labels(&self) -> &BTreeMap<String, u32> {
+        &self.labels
+    }
+    pub fn find_label_by_addr(&self, addr: u32) -> Option<String> {
+        self.labels
+            .iter()
+            .find(|(_, a)| **a == addr)
+            .map(|(l, _)| l.clone())
+    }
+    pub fn find_label_by_name(&self, name: &str) -> Option<u32> {
+        self.labels.get(name).copied()
+    }
+    pub fn add_label(&mut self, addr: u32, label: &str) {
+        self.labels.entry(label.to_owned()).or_insert(addr);
+    }
+    pub fn has_input(&self) -> bool {
+        !self.input.is_empty()
+    }
+    pub fn feed_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn read_line(&mut self, max: usize) -> Vec<u8> {
+        let mut line = Vec::new();
+        while line.len() < max {
+            match self.input.pop_front() {
+                Some(b) => {
+                    self.journal_change(Change::Input(b));
+                    line.push(b);
+                    if b == b'\n' {
+                        break;
                     }
-                    None => {
-                        p.addr = addr;
-                        return;
+                }
+                None => break,
+            }
+        }
+        line
+    }
+
+    // The SPIM services plus MARS's hex and unsigned printing.
+    pub fn syscall(&mut self) -> Result<(), Exception> {
+        let code = self.read_reg(Reg::v0);
+        let a0 = self.read_reg(Reg::a0);
+        match code {
+            1 => self.output.extend(format!("{}", a0 as i32).bytes()),
+            4 => {
+                let mut address = a0;
+                loop {
+                    let b = self.memory.read_u8(address);
+                    if b == 0 {
+                        break;
                     }
+                    self.output.push(b);
+                    address = address.wrapping_add(1);
                 }
+                self.record_access(a0, address.wrapping_sub(a0).wrapping_add(1), false, None);
+            }
+            5 => {
+                let line = self.read_line(usize::MAX);
+                let value = String::from_utf8_lossy(&line).trim().parse::<i32>();
+                self.write_reg(Reg::v0, value.unwrap_or(0) as u32);
             }
+            8 => {
+                let max = self.read_reg(Reg::a1) as usize;
+                if max == 0 {
+                    return Ok(());
+                }
+                let line = self.read_line(max - 1);
+                self.journal_memory(a0, line.len() as u32 + 1);
+                self.memory.load(a0, &line);
+                self.memory.write_u8(a0.wrapping_add(line.len() as u32), 0);
+                self.record_access(a0, line.len() as u32 + 1, true, None);
+            }
+            9 => {
+                let request = a0 as i32;
+                let end = u32::try_from(request)
+                    .ok()
+                    .and_then(|n| self.heap.checked_add((n + 3) & !3))
+                    .filter(|end| *end <= STACK_POINTER)
+                    .ok_or(Exception::BadHeapRequest(request))?;
+                self.write_reg(Reg::v0, self.heap);
+                self.heap = end;
+            }
+            10 => self.status = Status::Exited(0),
+            11 => self.output.push(a0 as u8),
+            12 => {
+                let c = self.input.pop_front();
+                if let Some(c) = c {
+                    self.journal_change(Change::Input(c));
+                }
+                let c = c.unwrap_or(0);
+                self.write_reg(Reg::v0, u32::from(c));
+            }
+            17 => self.status = Status::Exited(a0 as i32),
+            34 => self.output.extend(format!("0x{:08x}", a0).bytes()),
+            36 => self.output.extend(format!("{}", a0).bytes()),
+            _ => return Err(Exception::UnknownSyscall(code)),
         }
-        self.labels.push(Label::new::<u32, String>(addr, label))
+        Ok(())
     }
 }
 
 impl fmt::Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "$pc: 0x{:08X} == {}\n", self.pc, self.pc)?;
-        write!(
-            f,
-            "$0 : 0x{:08X} == {}\n",
-            self.registers[0], self.registers[0]
-        )?;
-        write!(
-            f,
-            "$at: 0x{:08X} == {}\n",
-            self.registers[1], self.registers[1]
-        )?;
-        write!(
-            f,
-            "$v0: 0x{:08X} == {}\n",
-            self.registers[2], self.registers[2]
-        )?;
-        write!(
-            f,
-            "$v1: 0x{:08X} == {}\n",
-            self.registers[3], self.registers[3]
-        )?;
-        write!(
-            f,
-            "$a0: 0x{:08X} == {}\n",
-            self.registers[4], self.registers[4]
-        )?;
-        write!(
-            f,
-            "$a1: 0x{:08X} == {}\n",
-            self.registers[5], self.registers[5]
-        )?;
-        write!(
-            f,
-            "$a2: 0x{:08X} == {}\n",
-            self.registers[6], self.registers[6]
-        )?;
-        write!(
-            f,
-            "$a3: 0x{:08X} == {}\n",
-            self.registers[7], self.registers[7]
-        )?;
-        write!(
-            f,
-            "$t0: 0x{:08X} == {}\n",
-            self.registers[8], self.registers[8]
-        )?;
-        write!(
-            f,
-            "$t1: 0x{:08X} == {}\n",
-            self.registers[9], self.registers[9]
-        )?;
-        write!(
-            f,
-            "$t2: 0x{:08X} == {}\n",
-            self.registers[10], self.registers[10]
-        )?;
-        write!(
-            f,
-            "$t3: 0x{:08X} == {}\n",
-            self.registers[11], self.registers[11]
-        )?;
-        write!(
-            f,
-            "$t4: 0x{:08X} == {}\n",
-            self.registers[12], self.registers[12]
-        )?;
-        write!(
-            f,
-            "$t5: 0x{:08X} == {}\n",
-            self.registers[13], self.registers[13]
-        )?;
-        write!(
-            f,
-            "$t6: 0x{:08X} == {}\n",
-            self.registers[14], self.registers[14]
-        )?;
-        write!(
-            f,
-            "$t7: 0x{:08X} == {}\n",
-            self.registers[15], self.registers[15]
-        )?;
-        write!(
-            f,
-            "$s0; 0x{:08X} == {}\n",
-            self.registers[16], self.registers[16]
-        )?;
-        write!(
-            f,
-            "$s1: 0x{:08X} == {}\n",
-            self.registers[17], self.registers[17]
-        )?;
-        write!(
-            f,
-            "$s2: 0x{:08X} == {}\n",
-            self.registers[18], self.registers[18]
-        )?;
-        write!(
-            f,
-            "$s3: 0x{:08X} == {}\n",
-            self.registers[19], self.registers[19]
-        )?;
-        write!(
-            f,
-            "$s4: 0x{:08X} == {}\n",
-            self.registers[20], self.registers[20]
-        )?;
-        write!(
-            f,
-            "$s5: 0x{:08X} == {}\n",
-            self.registers[21], self.registers[21]
-        )?;
-        write!(
-            f,
-            "$s6: 0x{:08X} == {}\n",
-            self.registers[22], self.registers[22]
-        )?;
-        write!(
-            f,
-            "$s7: 0x{:08X} == {}\n",
-            self.registers[23], self.registers[23]
-        )?;
-        write!(
-            f,
-            "$t8: 0x{:08X} == {}\n",
-            self.registers[24], self.registers[24]
-        )?;
-        write!(
-            f,
-            "$t9: 0x{:08X} == {}\n",
-            self.registers[25], self.registers[25]
-        )?;
-        write!(
-            f,
-            "$sp: 0x{:08X} == {}\n",
-            self.registers[29], self.registers[29]
-        )?;
-        write!(
-            f,
-            "$fp: 0x{:08X} == {}\n",
-            self.registers[30], self.registers[30]
-        )?;
-        write!(
-            f,
-            "$ra: 0x{:08X} == {}",
-            self.registers[31], self.registers[31]
-        )
+        writeln!(f, "$pc: 0x{:08X} == {}", self.pc, self.pc)?;
+        for (i, r) in self.registers.iter().enumerate() {
+            writeln!(
+                f,
+                "{}: 0x{:08X} == {}",
+                String::from(Reg::from(i as u8)),
+                r,
+                r
+            )?;
+        }
+        writeln!(f, "$hi: 0x{:08X} == {}", self.hi, self.hi)?;
+        write!(f, "$lo: 0x{:08X} == {}", self.lo, self.lo)
     }
 }
@@ -0,0 +1,332 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    assembler::assembler::Endian,
+    machine::{
+        cp0::{self, ClockRate},
+        device,
+        memory::{Memory, PAGE_SIZE},
+        state::{State, Status},
+    },
+};
+
+const MAGIC: &[u8; 8] = b"MIPSSNAP";
+const VERSION: u16 = 2;
+
+// Sections are a four byte tag and a length, so a reader skips what it doesn't know and
+// newer parts of the machine can be added without breaking older snapshots.
+const CPU: &[u8; 4] = b"CPU ";
+const MEMORY: &[u8; 4] = b"MEM ";
+const TEXT: &[u8; 4] = b"TEXT";
+const LABELS: &[u8; 4] = b"SYMS";
+const IO: &[u8; 4] = b"IO  ";
+const CP0: &[u8; 4] = b"CP0 ";
+const DEVICES: &[u8; 4] = b"DEV ";
+const CP0_REGISTERS: [u8; 6] = [
+    cp0::BAD_VADDR,
+    cp0::COUNT,
+    cp0::COMPARE,
+    cp0::STATUS,
+    cp0::CAUSE,
+    cp0::EPC,
+];
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    fn blob(&mut self, b: &[u8]) {
+        self.u32(b.len() as u32);
+        self.bytes.extend_from_slice(b);
+    }
+    fn section(&mut self, tag: &[u8; 4], contents: Writer) {
+        self.bytes.extend_from_slice(tag);
+        self.blob(&contents.bytes);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < n {
+            return Err("Snapshot is truncated".to_owned());
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from(self.u32()?) | u64::from(self.u32()?) << 32)
+    }
+    fn blob(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+impl State {
+    /// Everything needed to carry on exactly where the machine is, in a versioned
+    /// binary format, including the attached devices and what they're in the middle
+    /// of. Debugging aids like watch and undo logs aren't included. The machine has no
+    /// file or random number syscalls, so there are no handles or seeds to save.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = Writer::default();
+        out.bytes.extend_from_slice(MAGIC);
+        out.u16(VERSION);
+
+        let mut cpu = Writer::default();
+        cpu.u8(match self.memory.endian() {
+            Endian::Little => 0,
+            Endian::Big => 1,
+        });
+        cpu.u32(self.pc);
+        match self.delay_slot {
+            Some(target) => {
+                cpu.u8(1);
+                cpu.u32(target);
+            }
+            None => cpu.u8(0),
+        }
+        for r in &self.registers {
+            cpu.u32(*r);
+        }
+        cpu.u32(self.hi);
+        cpu.u32(self.lo);
+        cpu.u32(self.heap);
+        cpu.u64(self.steps);
+        match self.status {
+            Status::Running => cpu.u8(0),
+            Status::Exited(code) => {
+                cpu.u8(1);
+                cpu.u32(code as u32);
+            }
+            Status::DroppedOffBottom => cpu.u8(2),
+        }
+        cpu.u8(self.delayed_branches as u8);
+        out.section(CPU, cpu);
+
+        // trailing zeros are left off each page
+        let mut memory = Writer::default();
+        let pages = self.memory.pages();
+        memory.u32(pages.len() as u32);
+        for (number, page) in pages {
+            let used = page.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+            memory.u32(number);
+            memory.blob(&page[..used]);
+        }
+        out.section(MEMORY, memory);
+
+        let mut text = Writer::default();
+        text.u32(self.text_ranges.len() as u32);
+        for (start, end) in &self.text_ranges {
+            text.u32(*start);
+            text.u32(*end);
+        }
+        out.section(TEXT, text);
+
+        let mut labels = Writer::default();
+        labels.u32(self.labels.len() as u32);
+        for (name, address) in &self.labels {
+            labels.u32(*address);
+            labels.u16(name.len() as u16);
+            labels.bytes.extend_from_slice(name.as_bytes());
+        }
+        out.section(LABELS, labels);
+
+        let mut io = Writer::default();
+        io.blob(&self.input.iter().copied().collect::<Vec<u8>>());
+        io.blob(&self.output);
+        out.section(IO, io);
+
+        let mut registers = Writer::default();
+        for r in CP0_REGISTERS.iter() {
+            registers.u32(self.cp0.read(*r));
+        }
+        registers.u32(match self.cp0.clock() {
+            ClockRate::Instructions => 0,
+            ClockRate::Hertz(hz) => hz,
+        });
+        out.section(CP0, registers);
+
+        let mut devices = Writer::default();
+        let attached = self.memory.devices();
+        devices.u32(attached.len() as u32);
+        for (start, size, line, device) in attached {
+            devices.u32(start);
+            devices.u32(size);
+            match line {
+                Some(line) => {
+                    devices.u8(1);
+                    devices.u32(line);
+                }
+                None => devices.u8(0),
+            }
+            let (name, words) = device.save();
+            devices.u16(name.len() as u16);
+            devices.bytes.extend_from_slice(name.as_bytes());
+            devices.u32(words.len() as u32);
+            for word in words {
+                devices.u32(word);
+            }
+        }
+        out.section(DEVICES, devices);
+        out.bytes
+    }
+
+    pub fn is_snapshot(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn from_snapshot(bytes: &[u8]) -> Result<State, String> {
+        if !State::is_snapshot(bytes) {
+            return Err("Not a mips-rs snapshot".to_owned());
+        }
+        let mut reader = Reader {
+            bytes: &bytes[MAGIC.len()..],
+        };
+        let version = reader.u16()?;
+        if version > VERSION {
+            return Err(format!(
+                "Snapshot version {} is newer than this build understands ({})",
+                version, VERSION
+            ));
+        }
+        let mut state = State::default();
+        let mut seen_cpu = false;
+        while !reader.bytes.is_empty() {
+            let tag = reader.take(4)?;
+            let mut section = Reader {
+                bytes: reader.blob()?,
+            };
+            match tag {
+                t if t == CPU => {
+                    let endian = match section.u8()? {
+                        0 => Endian::Little,
+                        1 => Endian::Big,
+                        e => return Err(format!("Unknown byte order {} in snapshot", e)),
+                    };
+                    state.memory = Memory::new(endian);
+                    state.pc = section.u32()?;
+                    state.delay_slot = match section.u8()? {
+                        0 => None,
+                        _ => Some(section.u32()?),
+                    };
+                    for r in state.registers.iter_mut() {
+                        *r = section.u32()?;
+                    }
+                    state.hi = section.u32()?;
+                    state.lo = section.u32()?;
+                    state.heap = section.u32()?;
+                    state.steps = section.u64()?;
+                    state.status = match section.u8()? {
+                        0 => Status::Running,
+                        1 => Status::Exited(section.u32()? as i32),
+                        2 => Status::DroppedOffBottom,
+                        s => return Err(format!("Unknown status {} in snapshot", s)),
+                    };
+                    state.delayed_branches = section.u8()? != 0;
+                    seen_cpu = true;
+                }
+                t if t == MEMORY => {
+                    if !seen_cpu {
+                        return Err("Snapshot has memory before the CPU section".to_owned());
+                    }
+                    for _ in 0..section.u32()? {
+                        let address = section.u32()?.wrapping_mul(PAGE_SIZE);
+                        let contents = section.blob()?;
+                        state.memory.map(address, PAGE_SIZE);
+                        state.memory.load(address, contents);
+                    }
+                }
+                t if t == TEXT => {
+                    state.text_ranges = (0..section.u32()?)
+                        .map(|_| Ok((section.u32()?, section.u32()?)))
+                        .collect::<Result<_, String>>()?;
+                }
+                t if t == LABELS => {
+                    let mut labels = BTreeMap::new();
+                    for _ in 0..section.u32()? {
+                        let address = section.u32()?;
+                        let len = section.u16()? as usize;
+                        let name = String::from_utf8_lossy(section.take(len)?).into_owned();
+                        labels.insert(name, address);
+                    }
+                    state.labels = labels;
+                }
+                t if t == IO => {
+                    state.input = section.blob()?.iter().copied().collect::<VecDeque<u8>>();
+                    state.output = section.blob()?.to_vec();
+                }
+                t if t == CP0 => {
+                    for r in CP0_REGISTERS.iter() {
+                        state.cp0.set(*r, section.u32()?);
+                    }
+                    // version 1 didn't have the clock
+                    if !section.bytes.is_empty() {
+                        state.cp0.set_clock(match section.u32()? {
+                            0 => ClockRate::Instructions,
+                            hz => ClockRate::Hertz(hz),
+                        });
+                    }
+                }
+                t if t == DEVICES => {
+                    if !seen_cpu {
+                        return Err("Snapshot has devices before the CPU section".to_owned());
+                    }
+                    for _ in 0..section.u32()? {
+                        let start = section.u32()?;
+                        let size = section.u32()?;
+                        let line = match section.u8()? {
+                            0 => None,
+                            _ => Some(section.u32()?),
+                        };
+                        let len = section.u16()? as usize;
+                        let name = String::from_utf8_lossy(section.take(len)?).into_owned();
+                        let words = (0..section.u32()?)
+                            .map(|_| section.u32())
+                            .collect::<Result<Vec<u32>, String>>()?;
+                        let memory = &mut state.memory;
+                        memory.attach(start, size, device::restore(&name, &words)?)?;
+                        if let Some(line) = line {
+                            memory.connect(start, line)?;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        if !seen_cpu {
+            return Err("Snapshot has no CPU section".to_owned());
+        }
+        state.next_pc = state.pc.wrapping_add(4);
+        Ok(state)
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::machine::state::{Exception, InstructionClass, State, Status, INSTRUCTION_CLASSES};
+
+/// Counts of what a program executed: by class, by mnemonic and by the label each
+/// instruction falls under, with conditional branches split into taken and not taken.
+#[derive(Clone, Debug, Default)]
+pub struct Statistics {
+    instructions: u64,
+    classes: BTreeMap<InstructionClass, u64>,
+    taken: u64,
+    not_taken: u64,
+    mnemonics: BTreeMap<String, u64>,
+    labels: BTreeMap<String, u64>,
+    // labels in text segments, sorted by address
+    functions: Vec<(u32, String)>,
+}
+
+impl Statistics {
+    pub fn new(state: &State) -> Statistics {
+        let mut functions: Vec<(u32, String)> = state
+            .labels()
+            .iter()
+            .filter(|(_, a)| {
+                state
+                    .text_ranges()
+                    .iter()
+                    .any(|(s, e)| **a >= *s && **a < *e)
+            })
+            .map(|(name, a)| (*a, name.clone()))
+            .collect();
+        // where labels share an address the alphabetically first one wins
+        functions.sort();
+        functions.dedup_by_key(|(a, _)| *a);
+        Statistics {
+            functions,
+            ..Statistics::default()
+        }
+    }
+
+    /// Counts the instruction at `pc`, which `state` has just executed.
+    pub fn record(&mut self, pc: u32, state: &State) {
+        let inst = match State::parse_instruction(state.memory().read_u32(pc)) {
+            Ok(inst) => inst,
+            Err(_) => return,
+        };
+        self.instructions += 1;
+        *self.classes.entry(inst.class()).or_insert(0) += 1;
+        match inst.branch_taken(state) {
+            Some(true) => self.taken += 1,
+            Some(false) => self.not_taken += 1,
+            None => (),
+        }
+        *self.mnemonics.entry(inst.mnemonic()).or_insert(0) += 1;
+        if let Some(label) = self.function(pc) {
+            *self.labels.entry(label.to_owned()).or_insert(0) += 1;
+        }
+    }
+
+    pub fn step(&mut self, state: &mut State) -> Result<Status, Exception> {
+        let pc = state.read_pc();
+        let steps = state.steps();
+        let result = state.step();
+        if state.steps() > steps {
+            self.record(pc, state);
+        }
+        result
+    }
+
+    /// The nearest text label at or before `pc`.
+    pub fn function(&self, pc: u32) -> Option<&str> {
+        match self.functions.binary_search_by_key(&pc, |(a, _)| *a) {
+            Ok(i) => Some(&self.functions[i].1),
+            Err(0) => None,
+            Err(i) => Some(&self.functions[i - 1].1),
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    pub fn class(&self, class: InstructionClass) -> u64 {
+        self.classes.get(&class).copied().unwrap_or(0)
+    }
+    /// Arithmetic, logical and shift instructions together.
+    pub fn alu(&self) -> u64 {
+        self.class(InstructionClass::Arithmetic)
+            + self.class(InstructionClass::Logical)
+            + self.class(InstructionClass::Shift)
+    }
+    pub fn branches_taken(&self) -> u64 {
+        self.taken
+    }
+    pub fn branches_not_taken(&self) -> u64 {
+        self.not_taken
+    }
+    pub fn mnemonics(&self) -> &BTreeMap<String, u64> {
+        &self.mnemonics
+    }
+    pub fn labels(&self) -> &BTreeMap<String, u64> {
+        &self.labels
+    }
+    /// `count` as a percentage of all instructions executed.
+    pub fn percent(&self, count: u64) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.instructions as f64
+        }
+    }
+    /// The share of each class in the dynamic instruction mix, in percent.
+    pub fn mix(&self) -> Vec<(InstructionClass, f64)> {
+        INSTRUCTION_CLASSES
+            .iter()
+            .map(|c| (*c, self.percent(self.class(*c))))
+            .collect()
+    }
+
+    fn row(&self, out: &mut String, name: &str, count: u64) {
+        writeln!(
+            out,
+            "  {:<18} {:>10} {:>6.2}%",
+            name,
+            count,
+            self.percent(count)
+        )
+        .unwrap();
+    }
+
+    /// A summary for printing when the program stops.
+    pub fn report(&self) -> String {
+        let mut out = format!("instructions {}\n", self.instructions);
+        self.row(&mut out, "alu", self.alu());
+        for class in INSTRUCTION_CLASSES.iter() {
+            self.row(&mut out, &String::from(*class), self.class(*class));
+        }
+        self.row(&mut out, "branch taken", self.taken);
+        self.row(&mut out, "branch not taken", self.not_taken);
+        for (title, counts) in [("by mnemonic", &self.mnemonics), ("by label", &self.labels)].iter()
+        {
+            writeln!(out, "{}", title).unwrap();
+            // most frequent first
+            let mut counts: Vec<(&String, &u64)> = counts.iter().collect();
+            counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (name, count) in counts {
+                self.row(&mut out, name, *count);
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use crate::machine::{
+    register::Reg,
+    state::{Event, Exception, InstructionClass, Register, State, Status},
+};
+
+/// Starts a binary trace; the byte after it is the format version.
+pub const TRACE_MAGIC: &[u8; 7] = b"MIPSTRC";
+const TRACE_VERSION: u8 = 2;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    #[default]
+    JsonLines,
+    Binary,
+}
+
+impl From<&str> for TraceFormat {
+    fn from(s: &str) -> TraceFormat {
+        match s.to_lowercase().as_ref() {
+            "jsonl" | "json" => TraceFormat::JsonLines,
+            "bin" | "binary" => TraceFormat::Binary,
+            _ => panic!("No such trace format: {}", s),
+        }
+    }
+}
+
+impl From<TraceFormat> for String {
+    fn from(f: TraceFormat) -> String {
+        match f {
+            TraceFormat::JsonLines => "jsonl",
+            TraceFormat::Binary => "binary",
+        }
+        .to_owned()
+    }
+}
+
+/// Which instructions make it into the trace. Empty lists let everything through.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// Half-open `(start, end)` ranges the pc has to be in.
+    pub ranges: Vec<(u32, u32)>,
+    pub classes: Vec<InstructionClass>,
+}
+
+impl TraceFilter {
+    pub fn accepts(&self, pc: u32, class: Option<InstructionClass>) -> bool {
+        let in_range =
+            self.ranges.is_empty() || self.ranges.iter().any(|(s, e)| pc >= *s && pc < *e);
+        let in_class = self.classes.is_empty() || class.is_some_and(|c| self.classes.contains(&c));
+        in_range && in_class
+    }
+}
+
+// numbering used by the binary format
+fn register_number(r: Register) -> u8 {
+    match r {
+        Register::General(n) => n,
+        Register::Hi => 32,
+        Register::Lo => 33,
+    }
+}
+
+fn register_name(r: Register) -> String {
+    match r {
+        Register::General(n) => String::from(Reg::from(n)),
+        Register::Hi => "$hi".to_owned(),
+        Register::Lo => "$lo".to_owned(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Writes a record for every instruction it executes.
+///
+/// A JSON Lines record looks like
+/// `{"step":7,"pc":4194332,"word":2886205440,"asm":"sw $t0, 0($t1)","class":"store",
+/// "regs":[],"mem":[{"op":"write","addr":268500996,"size":4,"value":2}]}`, with
+/// `{"reg":"$t0","old":1,"new":2}` entries in `regs`.
+///
+/// The binary form starts with `TRACE_MAGIC` and a version byte. Each record is then,
+/// little-endian: step (u64), pc, word, register count, access count (u32), per
+/// register its number (u8, 32 is hi and 33 lo), old and new value (u32), and per
+/// access a flags byte (1 = write, 2 = value follows), address and size (u32) and the
+/// value (u32) when there is one. The value is what the program loaded or stored, even
+/// from a device. Accesses wider than a word, like a string printed by a syscall, have
+/// no value.
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    filter: TraceFilter,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(mut out: W, format: TraceFormat, filter: TraceFilter) -> io::Result<Tracer<W>> {
+        if format == TraceFormat::Binary {
+            out.write_all(TRACE_MAGIC)?;
+            out.write_all(&[TRACE_VERSION])?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            filter,
+            error: None,
+        })
+    }
+
+    /// Executes one instruction and traces it if the filter lets it through. Events the
+    /// machine was already recording are left for the caller.
+    pub fn step(&mut self, state: &mut State) -> Result<Status, Exception> {
+        let pc = state.read_pc();
+        let steps = state.steps();
+        let recording = state.is_recording_events();
+        let earlier = if recording {
+            state.events().len()
+        } else {
+            state.record_events(true);
+            0
+        };
+        let result = state.step();
+        let events = if recording {
+            state.events().split_off(earlier)
+        } else {
+            let events = state.take_events();
+            state.record_events(false);
+            events
+        };
+        if state.steps() > steps {
+            self.record(pc, state, &events);
+        }
+        result
+    }
+
+    /// Traces the instruction at `pc`, which `state` has just executed with `events`
+    /// recorded. A failed write stops tracing and is reported by `finish`.
+    pub fn record(&mut self, pc: u32, state: &State, events: &[Event]) {
+        let word = state.memory().read_u32(pc);
+        let inst = match State::parse_instruction(word) {
+            Ok(inst) => inst,
+            Err(_) => return,
+        };
+        if self.error.is_some() || !self.filter.accepts(pc, Some(inst.class())) {
+            return;
+        }
+        let step = state.steps() - 1;
+        let written = match self.format {
+            TraceFormat::JsonLines => {
+                let asm = inst.disassemble(pc);
+                let line = self.json(step, pc, word, &asm, inst.class(), events);
+                writeln!(self.out, "{}", line)
+            }
+            TraceFormat::Binary => self.binary(step, pc, word, events),
+        };
+        if let Err(e) = written {
+            self.error = Some(e);
+        }
+    }
+
+    fn json(
+        &self,
+        step: u64,
+        pc: u32,
+        word: u32,
+        asm: &str,
+        class: InstructionClass,
+        events: &[Event],
+    ) -> String {
+        let mut regs = Vec::new();
+        let mut mem = Vec::new();
+        for event in events {
+            match *event {
+                Event::Register { register, old, new } => regs.push(format!(
+                    "{{\"reg\":{},\"old\":{},\"new\":{}}}",
+                    json_string(&register_name(register)),
+                    old,
+                    new
+                )),
+                Event::Memory(a) => {
+                    let mut access = format!(
+                        "{{\"op\":\"{}\",\"addr\":{},\"size\":{}",
+                        if a.write { "write" } else { "read" },
+                        a.address,
+                        a.size
+                    );
+                    if let Some(value) = a.value {
+                        write!(access, ",\"value\":{}", value).unwrap();
+                    }
+                    access.push('}');
+                    mem.push(access);
+                }
+            }
+        }
+        format!(
+            "{{\"step\":{},\"pc\":{},\"word\":{},\"asm\":{},\"class\":\"{}\",\"regs\":[{}],\"mem\":[{}]}}",
+            step,
+            pc,
+            word,
+            json_string(asm),
+            String::from(class),
+            regs.join(","),
+            mem.join(",")
+        )
+    }
+
+    fn binary(&mut self, step: u64, pc: u32, word: u32, events: &[Event]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(32);
+        record.extend_from_slice(&step.to_le_bytes());
+        record.extend_from_slice(&pc.to_le_bytes());
+        record.extend_from_slice(&word.to_le_bytes());
+        let registers = events
+            .iter()
+            .filter(|e| matches!(e, Event::Register { .. }))
+            .count();
+        record.extend_from_slice(&(registers as u32).to_le_bytes());
+        record.extend_from_slice(&((events.len() - registers) as u32).to_le_bytes());
+        for event in events {
+            if let Event::Register { register, old, new } = *event {
+                record.push(register_number(register));
+                record.extend_from_slice(&old.to_le_bytes());
+                record.extend_from_slice(&new.to_le_bytes());
+            }
+        }
+        for event in events {
+            if let Event::Memory(a) = *event {
+                record.push(a.write as u8 | if a.value.is_some() { 2 } else { 0 });
+                record.extend_from_slice(&a.address.to_le_bytes());
+                record.extend_from_slice(&a.size.to_le_bytes());
+                if let Some(value) = a.value {
+                    record.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        self.out.write_all(&record)
+    }
+
+    /// Flushes the trace, handing back the writer or the first error hit on the way.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
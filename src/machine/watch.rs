@@ -0,0 +1,497 @@
+use std::{convert::TryFrom, fmt};
+
+use crate::machine::{
+    register::Reg,
+    state::{Event, Exception, Register, State, Status},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Number(u32),
+    Register(Register),
+    Pc,
+    Label(String),
+    // `mem[`, `memh[` or `memb[` with the access size
+    Memory(u32),
+    Op(&'static str),
+    Open,
+    Close,
+    CloseBracket,
+}
+
+// longest first, so `<=` isn't read as `<`
+const OPERATORS: [&str; 19] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "|", "^", "&", "+", "-", "*", "/",
+    "%", "!",
+];
+
+fn register(name: &str) -> Option<Register> {
+    match name {
+        "hi" => return Some(Register::Hi),
+        "lo" => return Some(Register::Lo),
+        _ => (),
+    }
+    let dollar = format!("${}", name);
+    let known = name.parse::<u8>().is_ok_and(|n| n < 32)
+        || dollar == "$s8"
+        || (0..32u8).any(|r| String::from(Reg::from(r)) == dollar);
+    if known {
+        Some(Register::General(u8::from(Reg::from(dollar.as_str()))))
+    } else {
+        None
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let word_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        let (token, len) = if c == '$' {
+            let name_len = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len() - 1);
+            let name = rest[1..1 + name_len].to_lowercase();
+            let token = match name.as_str() {
+                "pc" => Token::Pc,
+                _ => Token::Register(register(&name).ok_or(format!("No register ${}", name))?),
+            };
+            (token, 1 + name_len)
+        } else if c.is_ascii_digit() {
+            let word = &rest[..word_len];
+            let n = match word.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => word.parse(),
+            };
+            (
+                Token::Number(n.map_err(|_| format!("Bad number {}", word))?),
+                word_len,
+            )
+        } else if word_len > 0 {
+            let word = &rest[..word_len];
+            match (word, rest[word_len..].starts_with('[')) {
+                ("mem", true) => (Token::Memory(4), word_len + 1),
+                ("memh", true) => (Token::Memory(2), word_len + 1),
+                ("memb", true) => (Token::Memory(1), word_len + 1),
+                _ => (Token::Label(word.to_owned()), word_len),
+            }
+        } else if c == '(' {
+            (Token::Open, 1)
+        } else if c == ')' {
+            (Token::Close, 1)
+        } else if c == ']' {
+            (Token::CloseBracket, 1)
+        } else if c == '~' {
+            (Token::Op("~"), 1)
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or(format!("Unexpected `{}`", c))?;
+            (Token::Op(op), op.len())
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(u32),
+    Register(Register),
+    Pc,
+    Label(String),
+    Memory(u32, Box<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+// binding strength of each binary operator, loosest first
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | "<=" | ">" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    })
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            _ => Err(format!("Expected {:?}", token)),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        Ok(match self.next() {
+            Some(Token::Number(n)) => Expr::Number(n),
+            Some(Token::Register(r)) => Expr::Register(r),
+            Some(Token::Pc) => Expr::Pc,
+            Some(Token::Label(l)) => Expr::Label(l),
+            Some(Token::Memory(size)) => {
+                let address = self.binary(0)?;
+                self.expect(Token::CloseBracket)?;
+                Expr::Memory(size, Box::new(address))
+            }
+            Some(Token::Op(op)) if op == "-" || op == "!" || op == "~" => {
+                Expr::Unary(op, Box::new(self.primary()?))
+            }
+            Some(Token::Open) => {
+                let inner = self.binary(0)?;
+                self.expect(Token::Close)?;
+                inner
+            }
+            Some(t) => return Err(format!("Unexpected {:?}", t)),
+            None => return Err("Expression ends too soon".to_owned()),
+        })
+    }
+
+    fn binary(&mut self, min: u8) -> Result<Expr, String> {
+        let mut left = self.primary()?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.position).cloned() {
+            let level = match precedence(op) {
+                Some(level) if level > min => level,
+                _ => break,
+            };
+            self.position += 1;
+            let right = self.binary(level)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+}
+
+impl Expr {
+    fn evaluate(&self, state: &State) -> Result<u32, String> {
+        let truth = |b: bool| b as u32;
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Register(Register::General(r)) => state.read_reg(*r),
+            Expr::Register(Register::Hi) => state.read_hi(),
+            Expr::Register(Register::Lo) => state.read_lo(),
+            Expr::Pc => state.read_pc(),
+            Expr::Label(l) => state
+                .find_label_by_name(l)
+                .ok_or(format!("No label called {}", l))?,
+            Expr::Memory(size, address) => {
+                let address = address.evaluate(state)?;
+                let memory = state.memory();
+                match size {
+                    1 => u32::from(memory.read_u8(address)),
+                    2 => u32::from(memory.read_u16(address)),
+                    _ => memory.read_u32(address),
+                }
+            }
+            Expr::Unary(op, e) => {
+                let v = e.evaluate(state)?;
+                match *op {
+                    "-" => v.wrapping_neg(),
+                    "!" => truth(v == 0),
+                    _ => !v,
+                }
+            }
+            // both sides of && and || are evaluated; none of them have side effects
+            Expr::Binary(op, l, r) => {
+                let (l, r) = (l.evaluate(state)?, r.evaluate(state)?);
+                let (sl, sr) = (l as i32, r as i32);
+                match *op {
+                    "||" => truth(l != 0 || r != 0),
+                    "&&" => truth(l != 0 && r != 0),
+                    "|" => l | r,
+                    "^" => l ^ r,
+                    "&" => l & r,
+                    "==" => truth(l == r),
+                    "!=" => truth(l != r),
+                    "<" => truth(sl < sr),
+                    "<=" => truth(sl <= sr),
+                    ">" => truth(sl > sr),
+                    ">=" => truth(sl >= sr),
+                    "<<" => l.wrapping_shl(r),
+                    ">>" => l.wrapping_shr(r),
+                    "+" => l.wrapping_add(r),
+                    "-" => l.wrapping_sub(r),
+                    "*" => l.wrapping_mul(r),
+                    "/" | "%" if r == 0 => return Err("Division by zero".to_owned()),
+                    "/" => sl.wrapping_div(sr) as u32,
+                    _ => sl.wrapping_rem(sr) as u32,
+                }
+            }
+        })
+    }
+}
+
+/// An expression over registers and memory, such as `$t0 == 5 && mem[0x10010000] > 3`.
+///
+/// Values are 32-bit words; comparisons, division and remainder treat them as signed.
+/// `mem[a]`, `memh[a]` and `memb[a]` read a word, halfword or byte, labels stand for
+/// their address and `$pc`, `$hi` and `$lo` work like the general registers.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl TryFrom<&str> for Condition {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let expr = parser.binary(0)?;
+        if let Some(t) = parser.next() {
+            return Err(format!("Unexpected {:?}", t));
+        }
+        Ok(Condition {
+            source: s.trim().to_owned(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Condition {
+    pub fn evaluate(&self, state: &State) -> Result<u32, String> {
+        self.expr.evaluate(state)
+    }
+    // an expression that can't be evaluated, say after dividing by zero, isn't true
+    pub fn holds(&self, state: &State) -> bool {
+        self.evaluate(state).is_ok_and(|v| v != 0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Trigger {
+    /// About to execute the instruction at this address.
+    Execute(u32),
+    /// A load, or a syscall reading memory, touches the range at `address`.
+    Read {
+        address: u32,
+        length: u32,
+    },
+    Write {
+        address: u32,
+        length: u32,
+    },
+    Access {
+        address: u32,
+        length: u32,
+    },
+    /// An instruction leaves the register with a different value.
+    Register(Register),
+    /// The expression goes from false to true.
+    Becomes(Condition),
+}
+
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    pub id: u32,
+    pub trigger: Trigger,
+    /// Checked after the trigger fires; the watchpoint only counts a hit when it holds.
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    pub hits: u64,
+    /// How many more hits to let through without stopping.
+    pub ignore: u64,
+    // for `Becomes`, whether the expression was true after the last step
+    was_true: Option<bool>,
+}
+
+/// A watchpoint that stopped execution, with the access or write that set it off.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hit {
+    pub id: u32,
+    pub event: Option<Event>,
+}
+
+fn overlaps(address: u32, length: u32, event: &Event, write: Option<bool>) -> bool {
+    match event {
+        Event::Memory(a) => {
+            write.map_or(true, |w| w == a.write)
+                && a.address < address.wrapping_add(length)
+                && address < a.address.wrapping_add(a.size)
+        }
+        Event::Register { .. } => false,
+    }
+}
+
+impl Watchpoint {
+    fn fired_by(&self, event: &Event) -> bool {
+        match self.trigger {
+            Trigger::Read { address, length } => overlaps(address, length, event, Some(false)),
+            Trigger::Write { address, length } => overlaps(address, length, event, Some(true)),
+            Trigger::Access { address, length } => overlaps(address, length, event, None),
+            Trigger::Register(r) => match event {
+                Event::Register { register, old, new } => *register == r && old != new,
+                Event::Memory(_) => false,
+            },
+            Trigger::Execute(_) | Trigger::Becomes(_) => false,
+        }
+    }
+
+    // counts the hit and says whether to stop for it
+    fn hit(&mut self, state: &State) -> bool {
+        if self.condition.as_ref().is_some_and(|c| !c.holds(state)) {
+            return false;
+        }
+        self.hits += 1;
+        if self.ignore > 0 {
+            self.ignore -= 1;
+            return false;
+        }
+        true
+    }
+}
+
+/// Breakpoints, watchpoints and conditions over a running `State`, numbered from 1.
+#[derive(Clone, Debug)]
+pub struct Watchpoints {
+    points: Vec<Watchpoint>,
+    next_id: u32,
+}
+
+impl Default for Watchpoints {
+    fn default() -> Self {
+        Watchpoints {
+            points: Vec::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints::default()
+    }
+    pub fn add(&mut self, trigger: Trigger, condition: Option<Condition>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.push(Watchpoint {
+            id,
+            trigger,
+            condition,
+            enabled: true,
+            hits: 0,
+            ignore: 0,
+            was_true: None,
+        });
+        id
+    }
+    pub fn remove(&mut self, id: u32) -> bool {
+        let before = self.points.len();
+        self.points.retain(|w| w.id != id);
+        self.points.len() != before
+    }
+    pub fn get(&self, id: u32) -> Option<&Watchpoint> {
+        self.points.iter().find(|w| w.id == id)
+    }
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Watchpoint> {
+        self.points.iter_mut().find(|w| w.id == id)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.points.iter()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Checks `Execute` triggers against the instruction at the pc, counting hits.
+    pub fn breakpoint(&mut self, state: &State) -> Option<u32> {
+        let pc = state.read_pc();
+        let mut stop = None;
+        for w in self.points.iter_mut().filter(|w| w.enabled) {
+            if matches!(w.trigger, Trigger::Execute(a) if a == pc) && w.hit(state) {
+                stop = stop.or(Some(w.id));
+            }
+        }
+        stop
+    }
+
+    /// Executes one instruction, reporting the first watchpoint it sets off. Every
+    /// watchpoint that fires has its hit counted, even when another one stops first.
+    pub fn step(&mut self, state: &mut State) -> Result<Option<Hit>, Exception> {
+        for w in self.points.iter_mut().filter(|w| w.enabled) {
+            if let (Trigger::Becomes(ref c), None) = (&w.trigger, w.was_true) {
+                w.was_true = Some(c.holds(state));
+            }
+        }
+        let recording = !self.points.is_empty();
+        state.record_events(recording);
+        let result = state.step();
+        let events = state.take_events();
+        state.record_events(false);
+        result?;
+
+        let mut stop = None;
+        for w in self.points.iter_mut().filter(|w| w.enabled) {
+            let fired = match w.trigger {
+                Trigger::Becomes(ref c) => {
+                    let now = c.holds(state);
+                    let rose = now && w.was_true == Some(false);
+                    w.was_true = Some(now);
+                    rose.then_some(None)
+                }
+                _ => events.iter().find(|e| w.fired_by(e)).map(|e| Some(*e)),
+            };
+            if let Some(event) = fired {
+                if w.hit(state) && stop.is_none() {
+                    stop = Some(Hit { id: w.id, event });
+                }
+            }
+        }
+        Ok(stop)
+    }
+
+    /// Runs until a watchpoint or breakpoint stops the program, it finishes, or
+    /// `max_steps` instructions have run. A breakpoint on the first instruction is
+    /// passed over, so this can resume from one.
+    pub fn run(
+        &mut self,
+        state: &mut State,
+        max_steps: Option<u64>,
+    ) -> Result<Option<Hit>, Exception> {
+        let mut remaining = max_steps;
+        while remaining != Some(0) {
+            if let Some(hit) = self.step(state)? {
+                return Ok(Some(hit));
+            }
+            if state.status() != Status::Running {
+                break;
+            }
+            if let Some(id) = self.breakpoint(state) {
+                return Ok(Some(Hit { id, event: None }));
+            }
+            remaining = remaining.map(|r| r - 1);
+        }
+        Ok(None)
+    }
+}
@@ -1,14 +1,818 @@
-use std::{env, path};
+use std::{
+    convert::TryFrom,
+    env, fs,
+    io::{self, BufRead, Write},
+    panic, process,
+};
 
-use mips_rs::parser::parser::*;
-use mips_rs::*;
+use mips_rs::assembler::{
+    assembler::{assemble, Assembled, AssemblerOptions, Endian, Layout},
+    dump::DumpFormat,
+    elf, listing,
+    output::{write_images, OutputFormat, OutputOptions},
+};
+use mips_rs::debugger::Debugger;
+use mips_rs::gdb::{self, GdbServer};
+use mips_rs::machine::{
+    bitmap::{self, BitmapConfig, ImageFormat},
+    cache::{Cache, CacheConfig, Caches},
+    cp0::ClockRate,
+    device::{
+        InterruptController, Terminal, Timer, CONTROLLER_ADDRESS, CONTROLLER_SIZE,
+        TERMINAL_ADDRESS, TERMINAL_SIZE, TIMER_ADDRESS, TIMER_SIZE,
+    },
+    dump::dump_memory,
+    elf::load_elf,
+    pipeline::{Pipeline, PipelineOptions, Stage},
+    predictor::{BranchPredictor, PredictorKind},
+    register::Reg,
+    state::{disassemble, InstructionClass, State, Status},
+    stats::Statistics,
+    trace::{TraceFilter, TraceFormat, Tracer},
+};
+use mips_rs::parser::{
+    parser::*,
+    span::{FileId, SourceMap},
+};
+
+// exit codes; a program that exits through syscall 17 uses its own
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_EXCEPTION: i32 = 3;
+const EXIT_STEP_LIMIT: i32 = 4;
+
+const USAGE: &str = "Usage: mips-rs <command> [options] <file>
+
+Commands:
+  parse <file.s>      print the parse tree
+  assemble <file.s>   assemble, see --format
+  run <file>          run a source file, ELF executable or snapshot
+  disasm <file>       disassemble an ELF executable or raw binary
+  debug <file>        debug a source file, ELF executable or snapshot
+  gdbserver <file>    serve a source file, ELF executable or snapshot to GDB
+
+Options:
+  --layout mars|spim|compact   segment addresses (default mars)
+  --endian little|big          byte order (default little)
+  --delay-slots                execute the instruction after each branch and jump
+  -o, --output <file>          where to write results, or program output for run
+  -i, --input <file>           program input for run and debug, instead of stdin
+  --mmio                       put the SPIM/MARS keyboard and display at 0xffff0000
+  --keyboard <file>            type this file at the keyboard instead of stdin
+                               (implies --mmio)
+  --display-delay <n>          instructions the display takes per character (default 5)
+  --timer <n>                  put a timer expiring every n ticks at 0xffff0010, on line
+                               0 of an interrupt controller at 0xffff0020
+  --bitmap <display>           show memory as a bitmap display, described like
+                               512x256,unit=8,base=0x10010000 (base can also be global,
+                               gp, data, heap or mmio)
+  --bitmap-out <file>          run: save the display as a .png or .ppm image at exit
+  --bitmap-every <n>           run: also save it every n instructions, numbering the
+                               files by step: out-00001000.png
+  --clock instructions|<hz>    what Count and the timer count: instructions executed,
+                               the default, or ticks per second of real time
+  -f, --format <format>        assemble: elf, obj, bin, ihex, srec, readmemh, readmemb,
+                               logisim, lst or xref (default elf)
+  --merge                      assemble: one image for all segments
+  --fill <byte>                assemble: gap filler for merged images
+  --base <address>             assemble: image base; disasm: load address of raw binaries
+  --max-steps <n>              run: stop after n instructions
+  --dump-regs                  run: print the registers when the program stops
+  --dump-mem <start>-<end>     run: print memory as hex words when the program stops
+  --save-snapshot <file>       run: save the machine when the program stops
+  --stats                      run: print instruction counts when the program stops
+  --pipeline                   run: time the program on a five-stage pipeline
+  --forwarding all|ex|mem|none run: pipeline forwarding from EX/MEM, MEM/WB, both or
+                               neither (default all)
+  --branch-stage id|ex|mem     run: where the pipeline resolves branches (default id)
+  --unified-memory             run: the pipeline fetches through the data memory port
+  --pipeline-diagram <n>       run: draw the pipeline for the first n instructions
+  --predictor <kind>           run: score a branch predictor: taken, not-taken, btfn,
+                               1-bit, 2-bit, gshare or tournament; with --pipeline
+                               it fetches down the predicted path
+  --predictor-bits <n>         run: predictor tables have 2^n entries (default 10)
+  --icache <cache>             run: simulate an instruction cache, described like
+                               8k,block=32,ways=2,lru,write-back,write-allocate
+                               (ways can be direct or full; fifo and random replace;
+                               write-through and no-write-allocate change writes)
+  --dcache <cache>             run: simulate a data cache
+  --l2 <cache>                 run: simulate a second level under both
+  --trace <file>               run: record every instruction executed
+  --trace-format jsonl|binary  run: how to write the trace (default jsonl)
+  --trace-range <start>-<end>  run: only trace instructions in this address range
+  --trace-class <c,...>        run: only trace arithmetic, logical, shift, load, store,
+                               branch, jump or syscall instructions
+  --script <file>              debug: read commands from a file
+  --listen <host:port>         gdbserver: TCP address (default 127.0.0.1:1234)
+  --socket <path>              gdbserver: listen on a Unix socket instead
+";
+
+fn fail(code: i32, message: &str) -> ! {
+    eprintln!("mips-rs: {}", message);
+    process::exit(code)
+}
+
+fn number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    command: String,
+    file: String,
+    layout: Layout,
+    endian: Endian,
+    delay_slots: bool,
+    output: Option<String>,
+    input: Option<String>,
+    mmio: bool,
+    keyboard: Option<String>,
+    display_delay: u32,
+    timer: Option<u32>,
+    bitmap: Option<BitmapConfig>,
+    bitmap_out: Option<String>,
+    bitmap_every: Option<u64>,
+    clock: ClockRate,
+    format: Option<String>,
+    merge: bool,
+    fill: u8,
+    base: Option<u32>,
+    max_steps: Option<u64>,
+    dump_regs: bool,
+    dump_mem: Vec<(u32, u32)>,
+    script: Option<String>,
+    listen: Option<String>,
+    socket: Option<String>,
+    save_snapshot: Option<String>,
+    stats: bool,
+    pipeline: Option<PipelineOptions>,
+    pipeline_diagram: usize,
+    predictor: Option<PredictorKind>,
+    predictor_bits: u32,
+    icache: Option<CacheConfig>,
+    dcache: Option<CacheConfig>,
+    l2: Option<CacheConfig>,
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+}
+
+// `start-end`, where the end is excluded
+fn range(v: &str) -> (u32, u32) {
+    let range = v
+        .split_once('-')
+        .and_then(|(s, e)| Some((number(s)?, number(e)?)));
+    range.unwrap_or_else(|| fail(EXIT_USAGE, &format!("Bad address range {}", v)))
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut options = Options {
+        predictor_bits: 10,
+        display_delay: 5,
+        ..Options::default()
+    };
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(v) => v.clone(),
+            None => fail(EXIT_USAGE, &format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "--layout" => {
+                let name = value();
+                options.layout = Layout::named(&name)
+                    .unwrap_or_else(|| fail(EXIT_USAGE, &format!("Unknown layout {}", name)))
+            }
+            "--endian" => {
+                options.endian = match value().as_str() {
+                    "little" | "el" => Endian::Little,
+                    "big" | "eb" => Endian::Big,
+                    e => fail(EXIT_USAGE, &format!("Unknown endianness {}", e)),
+                }
+            }
+            "--delay-slots" => options.delay_slots = true,
+            "-o" | "--output" => options.output = Some(value()),
+            "-i" | "--input" => options.input = Some(value()),
+            "--mmio" => options.mmio = true,
+            "--keyboard" => {
+                options.keyboard = Some(value());
+                options.mmio = true;
+            }
+            "--bitmap" => {
+                options.bitmap = Some(
+                    BitmapConfig::try_from(value().as_str())
+                        .unwrap_or_else(|e| fail(EXIT_USAGE, &e)),
+                )
+            }
+            "--bitmap-out" => {
+                let file = value();
+                if ImageFormat::from_path(&file).is_none() {
+                    fail(EXIT_USAGE, &format!("Not a .png or .ppm file: {}", file))
+                }
+                options.bitmap_out = Some(file);
+                options.bitmap.get_or_insert_with(BitmapConfig::default);
+            }
+            "--bitmap-every" => {
+                let v = value();
+                options.bitmap_every = Some(
+                    v.parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .unwrap_or_else(|| fail(EXIT_USAGE, &format!("Bad step count {}", v))),
+                );
+            }
+            "--timer" => {
+                let v = value();
+                options.timer = Some(
+                    v.parse()
+                        .unwrap_or_else(|_| fail(EXIT_USAGE, &format!("Bad interval {}", v))),
+                );
+            }
+            "--clock" => {
+                options.clock =
+                    ClockRate::try_from(value().as_str()).unwrap_or_else(|e| fail(EXIT_USAGE, &e))
+            }
+            "--display-delay" => {
+                let v = value();
+                options.display_delay = v
+                    .parse()
+                    .unwrap_or_else(|_| fail(EXIT_USAGE, &format!("Bad delay {}", v)));
+            }
+            "-f" | "--format" => options.format = Some(value()),
+            "--merge" => options.merge = true,
+            "--fill" => {
+                let v = value();
+                options.fill = match number(&v) {
+                    Some(n) if n <= 0xFF => n as u8,
+                    _ => fail(EXIT_USAGE, &format!("Fill must be a byte, not {}", v)),
+                }
+            }
+            "--base" => {
+                let v = value();
+                options.base = Some(
+                    number(&v).unwrap_or_else(|| fail(EXIT_USAGE, &format!("Bad address {}", v))),
+                )
+            }
+            "--max-steps" => {
+                let v = value();
+                options.max_steps = Some(
+                    v.parse()
+                        .unwrap_or_else(|_| fail(EXIT_USAGE, &format!("Bad step count {}", v))),
+                )
+            }
+            "--dump-regs" => options.dump_regs = true,
+            "--stats" => options.stats = true,
+            "--pipeline" => {
+                options
+                    .pipeline
+                    .get_or_insert_with(PipelineOptions::default);
+            }
+            "--forwarding" => {
+                let name = value();
+                let (ex_mem, mem_wb) = match name.as_str() {
+                    "all" => (true, true),
+                    "ex" => (true, false),
+                    "mem" => (false, true),
+                    "none" => (false, false),
+                    _ => fail(EXIT_USAGE, &format!("Unknown forwarding {}", name)),
+                };
+                let pipeline = options
+                    .pipeline
+                    .get_or_insert_with(PipelineOptions::default);
+                pipeline.forward_ex_mem = ex_mem;
+                pipeline.forward_mem_wb = mem_wb;
+            }
+            "--branch-stage" => {
+                let name = value();
+                if !matches!(name.as_str(), "id" | "ex" | "mem") {
+                    fail(
+                        EXIT_USAGE,
+                        &format!("Branches can't be resolved in {}", name),
+                    )
+                }
+                options
+                    .pipeline
+                    .get_or_insert_with(PipelineOptions::default)
+                    .branch_stage = Stage::from(name.as_str());
+            }
+            "--unified-memory" => {
+                options
+                    .pipeline
+                    .get_or_insert_with(PipelineOptions::default)
+                    .unified_memory = true;
+            }
+            "--icache" | "--dcache" | "--l2" => {
+                let config = CacheConfig::try_from(value().as_str())
+                    .unwrap_or_else(|e| fail(EXIT_USAGE, &e));
+                match arg.as_str() {
+                    "--icache" => options.icache = Some(config),
+                    "--dcache" => options.dcache = Some(config),
+                    _ => options.l2 = Some(config),
+                }
+            }
+            "--pipeline-diagram" => {
+                let v = value();
+                options.pipeline_diagram = v
+                    .parse()
+                    .unwrap_or_else(|_| fail(EXIT_USAGE, &format!("Bad instruction count {}", v)));
+                options
+                    .pipeline
+                    .get_or_insert_with(PipelineOptions::default);
+            }
+            "--predictor" => {
+                options.predictor = Some(
+                    PredictorKind::try_from(value().as_str())
+                        .unwrap_or_else(|e| fail(EXIT_USAGE, &e)),
+                )
+            }
+            "--predictor-bits" => {
+                let v = value();
+                options.predictor_bits = v
+                    .parse()
+                    .ok()
+                    .filter(|b| (1..=24).contains(b))
+                    .unwrap_or_else(|| fail(EXIT_USAGE, &format!("Bad predictor size {}", v)));
+            }
+            "--dump-mem" => options.dump_mem.push(range(&value())),
+            "--trace" => options.trace = Some(value()),
+            "--trace-format" => {
+                let name = value();
+                if !matches!(name.as_str(), "jsonl" | "json" | "bin" | "binary") {
+                    fail(EXIT_USAGE, &format!("Unknown trace format {}", name))
+                }
+                options.trace_format = TraceFormat::from(name.as_str());
+            }
+            "--trace-range" => options.trace_filter.ranges.push(range(&value())),
+            "--trace-class" => {
+                for name in value().split(',') {
+                    match InstructionClass::try_from(name) {
+                        Ok(class) => options.trace_filter.classes.push(class),
+                        Err(e) => fail(EXIT_USAGE, &e),
+                    }
+                }
+            }
+            "--script" => options.script = Some(value()),
+            "--listen" => options.listen = Some(value()),
+            "--save-snapshot" => options.save_snapshot = Some(value()),
+            "--socket" => options.socket = Some(value()),
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0)
+            }
+            a if a.starts_with('-') && a.len() > 1 => {
+                fail(EXIT_USAGE, &format!("Unknown option {}", a))
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    match positional.as_slice() {
+        [command, file] => {
+            options.command = command.clone();
+            options.file = file.clone();
+        }
+        _ => {
+            eprint!("{}", USAGE);
+            process::exit(EXIT_USAGE)
+        }
+    }
+    if options.bitmap_every.is_some() && options.bitmap_out.is_none() {
+        fail(EXIT_USAGE, "--bitmap-every needs --bitmap-out")
+    }
+    options
+}
+
+fn read_bytes(file: &str) -> Vec<u8> {
+    fs::read(file).unwrap_or_else(|e| fail(EXIT_ERROR, &format!("{}: {}", file, e)))
+}
+
+fn read_source(file: &str) -> String {
+    String::from_utf8(read_bytes(file))
+        .unwrap_or_else(|_| fail(EXIT_ERROR, &format!("{}: not a UTF-8 text file", file)))
+}
+
+fn write_output(options: &Options, bytes: &[u8]) {
+    let result = match options.output {
+        Some(ref file) => fs::write(file, bytes),
+        None => io::stdout().write_all(bytes),
+    };
+    if let Err(e) = result {
+        fail(EXIT_ERROR, &e.to_string())
+    }
+}
+
+// the parser reports malformed lines by panicking
+fn parse_source(source: &str, file: FileId) -> Parsed {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|info| {
+        let message = match info.payload().downcast_ref::<String>() {
+            Some(message) => message.as_str(),
+            None => info
+                .payload()
+                .downcast_ref::<&str>()
+                .copied()
+                .unwrap_or("parse error"),
+        };
+        eprintln!("mips-rs: {}", message);
+    }));
+    let parsed = panic::catch_unwind(|| parse_file(source, file));
+    panic::set_hook(hook);
+    parsed.unwrap_or_else(|_| process::exit(EXIT_ERROR))
+}
+
+fn assemble_file(options: &Options, sources: &mut SourceMap) -> Assembled {
+    let source = read_source(&options.file);
+    let id = sources.add(&options.file, &source);
+    let parsed = parse_source(&source, id);
+    let assembler_options = AssemblerOptions {
+        layout: options.layout,
+        endian: options.endian,
+        relocatable: options.format.as_deref() == Some("obj"),
+    };
+    let assembled = assemble(&parsed, &assembler_options).unwrap_or_else(|e| {
+        let at = e.span.map_or(options.file.clone(), |s| sources.locate(s));
+        fail(EXIT_ERROR, &format!("{}: {}", at, e.message))
+    });
+    for warning in assembled.symbols.warnings() {
+        eprintln!(
+            "{}: warning: {}",
+            sources.locate(warning.span),
+            warning.message
+        );
+    }
+    assembled
+}
+
+// source files also give back what the debugger needs to show source lines
+fn load_program(options: &Options) -> (State, Option<(Assembled, SourceMap)>) {
+    let bytes = read_bytes(&options.file);
+    let (mut state, program) = if bytes.starts_with(b"\x7FELF") {
+        (
+            load_elf(&bytes).unwrap_or_else(|e| fail(EXIT_ERROR, &e)),
+            None,
+        )
+    } else if State::is_snapshot(&bytes) {
+        (
+            State::from_snapshot(&bytes).unwrap_or_else(|e| fail(EXIT_ERROR, &e)),
+            None,
+        )
+    } else {
+        let mut sources = SourceMap::new();
+        let assembled = assemble_file(options, &mut sources);
+        (
+            State::from_assembled(&assembled),
+            Some((assembled, sources)),
+        )
+    };
+    // a snapshot remembers whether it was taken with delay slots
+    state.delayed_branches |= options.delay_slots;
+    if let Some(ref input) = options.input {
+        state.feed_input(&read_bytes(input));
+    }
+    // a snapshot brings back the devices it was taken with
+    if options.mmio {
+        let keys = options
+            .keyboard
+            .as_ref()
+            .map_or_else(Vec::new, |k| read_bytes(k));
+        match state.memory_mut().device_mut(TERMINAL_ADDRESS) {
+            Some(terminal) => terminal.feed(&keys),
+            None => {
+                let mut terminal = Terminal::new(options.display_delay);
+                terminal.type_at(0, &keys);
+                state
+                    .memory_mut()
+                    .attach(TERMINAL_ADDRESS, TERMINAL_SIZE, Box::new(terminal))
+                    .unwrap_or_else(|e| fail(EXIT_ERROR, &e));
+            }
+        }
+    }
+    state.set_clock(options.clock);
+    let restored_timer = state.memory().is_device(TIMER_ADDRESS);
+    if let Some(interval) = options.timer.filter(|_| !restored_timer) {
+        let memory = state.memory_mut();
+        memory
+            .attach(
+                TIMER_ADDRESS,
+                TIMER_SIZE,
+                Box::new(Timer::new(interval, options.clock)),
+            )
+            .and_then(|_| {
+                memory.attach(
+                    CONTROLLER_ADDRESS,
+                    CONTROLLER_SIZE,
+                    Box::new(InterruptController::default()),
+                )
+            })
+            .and_then(|_| memory.connect(TIMER_ADDRESS, 0))
+            .unwrap_or_else(|e| fail(EXIT_ERROR, &e));
+    }
+    (state, program)
+}
+
+fn command_assemble(options: &Options) {
+    let mut sources = SourceMap::new();
+    let assembled = assemble_file(options, &mut sources);
+    let format = options.format.as_deref().unwrap_or("elf");
+    let bytes = match format {
+        "elf" => elf::write_executable(&assembled).unwrap_or_else(|e| fail(EXIT_ERROR, &e)),
+        "obj" => elf::write_object(&assembled),
+        "lst" => listing::write_listing(&assembled, &sources).into_bytes(),
+        "xref" => assembled.symbols.cross_reference(&sources).into_bytes(),
+        "bin" | "ihex" | "srec" | "readmemh" | "readmemb" | "logisim" => {
+            let output = OutputOptions {
+                format: OutputFormat::from(format),
+                endian: None,
+                fill: options.fill,
+                base: options.base,
+                merge: options.merge,
+            };
+            let images = write_images(&assembled, &output).unwrap_or_else(|e| fail(EXIT_ERROR, &e));
+            if images.len() == 1 || options.output.is_none() {
+                images.into_iter().flat_map(|(_, image)| image).collect()
+            } else {
+                // one file per segment: out.bin becomes out.text.bin, out.data.bin, ...
+                let output = options.output.as_deref().unwrap_or_default();
+                let (stem, extension) = output.rsplit_once('.').unwrap_or((output, format));
+                for (name, image) in images {
+                    let file = format!("{}{}.{}", stem, name, extension);
+                    if let Err(e) = fs::write(&file, image) {
+                        fail(EXIT_ERROR, &format!("{}: {}", file, e))
+                    }
+                }
+                return;
+            }
+        }
+        f => fail(EXIT_USAGE, &format!("Unknown output format {}", f)),
+    };
+    write_output(options, &bytes);
+}
+
+fn save_bitmap(state: &State, config: &BitmapConfig, file: &str) {
+    // the extension was checked with the options
+    let format = ImageFormat::from_path(file).unwrap();
+    let image =
+        bitmap::encode(state.memory(), config, format).unwrap_or_else(|e| fail(EXIT_ERROR, &e));
+    fs::write(file, image).unwrap_or_else(|e| fail(EXIT_ERROR, &format!("{}: {}", file, e)));
+}
+
+// what the program has printed so far; a closed pipe ends the run like other I/O errors
+fn print_output(out: &mut dyn Write, state: &mut State) {
+    if let Err(e) = out
+        .write_all(&state.take_output())
+        .and_then(|_| out.flush())
+    {
+        fail(EXIT_ERROR, &e.to_string())
+    }
+}
+
+// feeds stdin a line at a time, only when the program is about to read
+fn wants_input(state: &State) -> bool {
+    const SYSCALL: u32 = 0x0000_000C;
+    state.memory().read_u32(state.read_pc()) == SYSCALL
+        && matches!(state.read_reg(Reg::v0), 5 | 8 | 12)
+        && !state.has_input()
+}
+
+fn command_run(options: &Options) -> i32 {
+    let (mut state, _) = load_program(options);
+    let mut out: Box<dyn Write> = match options.output {
+        Some(ref file) => Box::new(
+            fs::File::create(file)
+                .unwrap_or_else(|e| fail(EXIT_ERROR, &format!("{}: {}", file, e))),
+        ),
+        None => Box::new(io::stdout()),
+    };
+    let stdin = io::stdin();
+    let mut tracer = options.trace.as_ref().map(|file| {
+        let out = fs::File::create(file)
+            .map(io::BufWriter::new)
+            .unwrap_or_else(|e| fail(EXIT_ERROR, &format!("{}: {}", file, e)));
+        Tracer::new(out, options.trace_format, options.trace_filter.clone())
+            .unwrap_or_else(|e| fail(EXIT_ERROR, &format!("{}: {}", file, e)))
+    });
+    let mut stats = if options.stats {
+        Some(Statistics::new(&state))
+    } else {
+        None
+    };
+    let mut predictor = options
+        .predictor
+        .map(|k| BranchPredictor::new(k, options.predictor_bits));
+    let mut pipeline = options.pipeline.map(|o| {
+        let mut pipeline = Pipeline::new(o);
+        pipeline.keep_diagram(options.pipeline_diagram);
+        // the pipeline takes over the predictor so that mispredictions cost cycles
+        if let Some(predictor) = predictor.take() {
+            pipeline.predict_with(predictor);
+        }
+        pipeline
+    });
+    let mut caches = if options.icache.is_some() || options.dcache.is_some() || options.l2.is_some()
+    {
+        Some(Caches {
+            instruction: options.icache.map(Cache::new),
+            data: options.dcache.map(Cache::new),
+            second_level: options.l2.map(Cache::new),
+        })
+    } else {
+        None
+    };
+    state.record_events(tracer.is_some() || caches.is_some());
+    let mut remaining = options.max_steps;
+    let code = loop {
+        if remaining == Some(0) {
+            eprintln!("mips-rs: stopped after {} steps", state.steps());
+            break EXIT_STEP_LIMIT;
+        }
+        remaining = remaining.map(|r| r - 1);
+        if options.input.is_none() && wants_input(&state) {
+            print_output(&mut out, &mut state);
+            let mut line = String::new();
+            stdin.lock().read_line(&mut line).unwrap_or(0);
+            state.feed_input(line.as_bytes());
+        }
+        if options.mmio && options.keyboard.is_none() && state.memory().wants_input() {
+            print_output(&mut out, &mut state);
+            let mut line = String::new();
+            stdin.lock().read_line(&mut line).unwrap_or(0);
+            if let Some(keyboard) = state.memory_mut().device_mut(TERMINAL_ADDRESS) {
+                keyboard.feed(line.as_bytes());
+            }
+        }
+        let (pc, steps) = (state.read_pc(), state.steps());
+        let stepped = state.step();
+        let events = state.take_events();
+        if state.steps() > steps {
+            if let Some(ref mut tracer) = tracer {
+                tracer.record(pc, &state, &events);
+            }
+            if let Some(ref mut caches) = caches {
+                caches.record(pc, &events);
+            }
+            if let Some(ref mut stats) = stats {
+                stats.record(pc, &state);
+            }
+            if let Some(ref mut pipeline) = pipeline {
+                pipeline.record(pc, &state);
+            }
+            if let Some(ref mut predictor) = predictor {
+                predictor.record(pc, &state);
+            }
+            if let (Some(every), Some(config), Some(file)) =
+                (options.bitmap_every, &options.bitmap, &options.bitmap_out)
+            {
+                if state.steps() % every == 0 {
+                    let (stem, extension) = file.rsplit_once('.').unwrap();
+                    let frame = format!("{}-{:08}.{}", stem, state.steps(), extension);
+                    save_bitmap(&state, config, &frame);
+                }
+            }
+        }
+        match stepped {
+            Ok(Status::Running) => (),
+            Ok(Status::Exited(code)) => break code,
+            Ok(Status::DroppedOffBottom) => break 0,
+            Err(e) => {
+                print_output(&mut out, &mut state);
+                eprintln!("mips-rs: {} at pc 0x{:08x}", e, state.read_pc());
+                break EXIT_EXCEPTION;
+            }
+        }
+    };
+    print_output(&mut out, &mut state);
+    if let Some(tracer) = tracer {
+        if let Err(e) = tracer.finish() {
+            fail(
+                EXIT_ERROR,
+                &format!("{}: {}", options.trace.as_deref().unwrap_or(""), e),
+            )
+        }
+    }
+    if let (Some(config), Some(file)) = (&options.bitmap, &options.bitmap_out) {
+        save_bitmap(&state, config, file);
+    }
+    if let Some(ref file) = options.save_snapshot {
+        fs::write(file, state.snapshot())
+            .unwrap_or_else(|e| fail(EXIT_ERROR, &format!("{}: {}", file, e)));
+    }
+    if options.dump_regs {
+        let registers = state.dump_reg();
+        for (r, value) in registers[..32].iter().enumerate() {
+            eprintln!("{:>5} 0x{:08x}", String::from(Reg::from(r as u8)), value);
+        }
+        eprintln!("{:>5} 0x{:08x}", "pc", state.read_pc());
+        eprintln!("{:>5} 0x{:08x}", "hi", state.read_hi());
+        eprintln!("{:>5} 0x{:08x}", "lo", state.read_lo());
+    }
+    for (start, end) in &options.dump_mem {
+        let dump = dump_memory(&state, *start, *end, DumpFormat::HexTextWithAddresses);
+        eprint!("{}", String::from_utf8_lossy(&dump));
+    }
+    if let Some(stats) = stats {
+        eprint!("{}", stats.report());
+    }
+    if let Some(pipeline) = pipeline {
+        eprint!("{}{}", pipeline.diagram(), pipeline.report());
+        if let Some(predictor) = pipeline.predictor() {
+            eprint!("{}", predictor.report());
+        }
+    }
+    if let Some(predictor) = predictor {
+        eprint!("{}", predictor.report());
+    }
+    if let Some(caches) = caches {
+        eprint!("{}", caches.report());
+    }
+    code
+}
+
+fn command_disasm(options: &Options) {
+    let bytes = read_bytes(&options.file);
+    let state = if bytes.starts_with(b"\x7FELF") {
+        load_elf(&bytes).unwrap_or_else(|e| fail(EXIT_ERROR, &e))
+    } else {
+        let base = options.base.unwrap_or(options.layout.text);
+        let mut state = State::new(options.endian);
+        state.load_segment(base, &bytes, bytes.len() as u32, true);
+        state
+    };
+    let mut text = String::new();
+    for &(start, end) in state.text_ranges() {
+        for address in (start..end).step_by(4) {
+            if let Some(label) = state.find_label_by_addr(address) {
+                text.push_str(&format!("{}:\n", label));
+            }
+            let word = state.memory().read_u32(address);
+            text.push_str(&format!(
+                "  0x{:08x}: {:08x}  {}\n",
+                address,
+                word,
+                disassemble(word, address)
+            ));
+        }
+    }
+    write_output(options, text.as_bytes());
+}
+
+fn command_debug(options: &Options) {
+    let mut debugger = match load_program(options) {
+        (state, Some((assembled, sources))) => Debugger::with_source(state, assembled, sources),
+        (state, None) => Debugger::new(state),
+    };
+    debugger.bitmap = options.bitmap;
+    let stdout = io::stdout();
+    let result = match options.script {
+        Some(ref script) => {
+            let file = fs::File::open(script)
+                .unwrap_or_else(|e| fail(EXIT_ERROR, &format!("{}: {}", script, e)));
+            debugger.repl(&mut io::BufReader::new(file), &mut stdout.lock(), true)
+        }
+        None => debugger.repl(&mut io::stdin().lock(), &mut stdout.lock(), false),
+    };
+    if let Err(e) = result {
+        fail(EXIT_ERROR, &e.to_string())
+    }
+}
+
+fn command_gdbserver(options: &Options) {
+    let (state, _) = load_program(options);
+    let mut server = GdbServer::new(state);
+    let result = match options.socket {
+        #[cfg(unix)]
+        Some(ref path) => {
+            eprintln!("Waiting for GDB on {}", path);
+            gdb::accept_unix(path).and_then(|mut conn| server.serve(&mut conn))
+        }
+        #[cfg(not(unix))]
+        Some(_) => fail(EXIT_USAGE, "Unix sockets aren't available on this platform"),
+        None => {
+            let address = options.listen.as_deref().unwrap_or("127.0.0.1:1234");
+            eprintln!("Waiting for GDB on {}", address);
+            gdb::accept_tcp(address).and_then(|mut conn| server.serve(&mut conn))
+        }
+    };
+    if let Err(e) = result {
+        fail(EXIT_ERROR, &e.to_string())
+    }
+}
 
 pub fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <mips_file>", args[0]);
-        return;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_args(&args);
+    match options.command.as_str() {
+        "parse" => {
+            let source = read_source(&options.file);
+            println!("{:#?}", parse_source(&source, FileId::default()));
+        }
+        "assemble" => command_assemble(&options),
+        "run" => process::exit(command_run(&options)),
+        "disasm" => command_disasm(&options),
+        "debug" => command_debug(&options),
+        "gdbserver" => command_gdbserver(&options),
+        c => fail(EXIT_USAGE, &format!("Unknown command {}\n\n{}", c, USAGE)),
     }
-    let s = load_file(path::Path::new(&args[1]));
-    println!("{:#?}", parse(&s));
 }
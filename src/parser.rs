@@ -1,2 +1,5 @@
+pub mod expression;
+#[allow(clippy::module_inception)]
 pub mod parser;
 mod parsing_functions;
+pub mod span;
@@ -0,0 +1,133 @@
+use crate::machine::address::Address;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(i64),
+    Symbol(Address),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn symbols(&self) -> Vec<&str> {
+        let mut symbols = Vec::new();
+        self.collect_symbols(&mut symbols);
+        symbols
+    }
+
+    fn collect_symbols<'a>(&'a self, symbols: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => (),
+            Expr::Symbol(a) => {
+                if let Some(ref l) = a.label {
+                    symbols.push(l);
+                }
+            }
+            Expr::Unary(_, e) => e.collect_symbols(symbols),
+            Expr::Binary(_, l, r) => {
+                l.collect_symbols(symbols);
+                r.collect_symbols(symbols);
+            }
+        }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.symbols().is_empty()
+    }
+
+    pub fn evaluate<F>(&self, lookup: &F) -> Result<i64, String>
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Symbol(a) => match (&a.label, a.numeric) {
+                (Some(l), _) => match lookup(l) {
+                    Some(v) => Ok(i64::from(v)),
+                    None => Err(format!("Undefined label: {}", l)),
+                },
+                (None, Some(n)) => Ok(i64::from(n.get())),
+                (None, None) => Ok(0),
+            },
+            Expr::Unary(op, e) => {
+                let v = e.evaluate(lookup)?;
+                Ok(match op {
+                    UnOp::Neg => v.wrapping_neg(),
+                    UnOp::Not => !v,
+                })
+            }
+            Expr::Binary(op, l, r) => {
+                let l = l.evaluate(lookup)?;
+                let r = r.evaluate(lookup)?;
+                match op {
+                    BinOp::Add => Ok(l.wrapping_add(r)),
+                    BinOp::Sub => Ok(l.wrapping_sub(r)),
+                    BinOp::Mul => Ok(l.wrapping_mul(r)),
+                    BinOp::Div | BinOp::Rem if r == 0 => Err("Division by zero".to_owned()),
+                    BinOp::Div => Ok(l.wrapping_div(r)),
+                    BinOp::Rem => Ok(l.wrapping_rem(r)),
+                    BinOp::Shl => Ok(l.wrapping_shl(r as u32)),
+                    BinOp::Shr => Ok(l.wrapping_shr(r as u32)),
+                    BinOp::And => Ok(l & r),
+                    BinOp::Or => Ok(l | r),
+                    BinOp::Xor => Ok(l ^ r),
+                }
+            }
+        }
+    }
+
+    /// Splits the expression into `symbol + addend` when it has that shape, which is
+    /// what a relocation can describe. Constants come back without a symbol.
+    pub fn symbol_offset(&self) -> Option<(Option<&str>, i64)> {
+        let no_symbols = |_: &str| None;
+        match self {
+            Expr::Number(n) => Some((None, *n)),
+            Expr::Symbol(a) => match a.label {
+                Some(ref l) => Some((Some(l), 0)),
+                None => Some((None, a.numeric.map_or(0, |n| i64::from(n.get())))),
+            },
+            Expr::Binary(BinOp::Add, l, r) => match (l.symbol_offset()?, r.symbol_offset()?) {
+                ((Some(_), _), (Some(_), _)) => None,
+                ((s, a), (None, b)) | ((None, a), (s, b)) => Some((s, a.wrapping_add(b))),
+            },
+            Expr::Binary(BinOp::Sub, l, r) => match (l.symbol_offset()?, r.symbol_offset()?) {
+                ((s, a), (None, b)) => Some((s, a.wrapping_sub(b))),
+                _ => None,
+            },
+            _ if self.is_constant() => self.evaluate(&no_symbols).ok().map(|v| (None, v)),
+            _ => None,
+        }
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(n: i64) -> Self {
+        Expr::Number(n)
+    }
+}
+
+impl From<Address> for Expr {
+    fn from(a: Address) -> Self {
+        Expr::Symbol(a)
+    }
+}
@@ -3,21 +3,30 @@
 use nom::Err;
 
 use std::{
-    iter::FromIterator,
+    iter::Enumerate,
     num::{NonZeroU32, ParseIntError},
     str::Lines,
     vec::Vec,
 };
 
 use crate::{
-    instructions::{itype::*, jtype::*, rtype::*, Inst},
+    instructions::{cop0::*, itype::*, jtype::*, pseudo::*, rtype::*, Inst},
     machine::{address::Address, register::Reg},
-    parser::parsing_functions::*,
+    parser::{
+        expression::Expr,
+        parsing_functions::*,
+        span::{FileId, Span, Spanned},
+    },
 };
 
-#[derive(Clone, Debug)]
+pub type Labels = Option<Vec<Spanned<Address>>>;
+
+#[derive(Clone, Debug, Default)]
 pub struct TextSegment {
-    pub instructions: Vec<(Option<Vec<Address>>, Inst)>,
+    pub instructions: Vec<(Labels, Spanned<Inst>)>,
+    // applied before the instruction at the given index
+    pub alignments: Vec<(usize, Spanned<DataAlignment>)>,
+    pub trailing_labels: Labels,
     pub start_address: Option<Address>,
 }
 
@@ -25,6 +34,8 @@ impl TextSegment {
     pub fn new() -> TextSegment {
         TextSegment {
             instructions: Vec::new(),
+            alignments: Vec::new(),
+            trailing_labels: None,
             start_address: None,
         }
     }
@@ -32,7 +43,9 @@ impl TextSegment {
 
 #[derive(Clone, Debug)]
 pub struct KTextSegment {
-    pub instructions: Vec<(Option<Vec<Address>>, Inst)>,
+    pub instructions: Vec<(Labels, Spanned<Inst>)>,
+    pub alignments: Vec<(usize, Spanned<DataAlignment>)>,
+    pub trailing_labels: Labels,
     pub start_address: Option<Address>,
 }
 
@@ -40,6 +53,8 @@ impl From<TextSegment> for KTextSegment {
     fn from(t: TextSegment) -> Self {
         KTextSegment {
             instructions: t.instructions,
+            alignments: t.alignments,
+            trailing_labels: t.trailing_labels,
             start_address: t.start_address,
         }
     }
@@ -52,28 +67,39 @@ pub struct DataAlignment {
 
 #[derive(Clone, Debug)]
 pub struct DataCString {
-    pub chars: (Option<Vec<Address>>, Vec<u8>),
+    pub chars: (Labels, Vec<u8>),
     pub null_terminated: bool,
 }
 
+#[derive(Clone, Debug)]
+pub enum DataValue<T> {
+    Literal(T),
+    Expression(Expr),
+}
+
 #[derive(Clone, Debug)]
 pub struct DataBytes {
-    pub bytes: (Option<Vec<Address>>, Vec<u8>),
+    pub bytes: (Labels, Vec<DataValue<u8>>),
 }
 
 #[derive(Clone, Debug)]
 pub struct DataHalfs {
-    pub halfs: (Option<Vec<Address>>, Vec<u16>),
+    pub halfs: (Labels, Vec<DataValue<u16>>),
 }
 
 #[derive(Clone, Debug)]
 pub struct DataWords {
-    pub words: (Option<Vec<Address>>, Vec<u32>),
+    pub words: (Labels, Vec<DataValue<u32>>),
+}
+
+#[derive(Clone, Debug)]
+pub struct DataDWords {
+    pub dwords: (Labels, Vec<DataValue<u64>>),
 }
 
 #[derive(Clone, Debug)]
 pub struct DataSpace {
-    pub spaces: (Option<Vec<Address>>, Vec<u8>),
+    pub spaces: (Labels, Vec<u8>),
 }
 
 #[derive(Clone, Debug)]
@@ -83,12 +109,14 @@ pub enum DataEntry {
     Bytes(DataBytes),
     Halfs(DataHalfs),
     Words(DataWords),
+    DWords(DataDWords),
     Space(DataSpace),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct DataSegment {
-    pub data_entries: Vec<DataEntry>,
+    pub data_entries: Vec<Spanned<DataEntry>>,
+    pub trailing_labels: Labels,
     pub start_address: Option<Address>,
 }
 
@@ -96,6 +124,7 @@ impl DataSegment {
     pub fn new() -> DataSegment {
         DataSegment {
             data_entries: Vec::new(),
+            trailing_labels: None,
             start_address: None,
         }
     }
@@ -103,7 +132,8 @@ impl DataSegment {
 
 #[derive(Clone, Debug)]
 pub struct KDataSegment {
-    pub data_entries: Vec<DataEntry>,
+    pub data_entries: Vec<Spanned<DataEntry>>,
+    pub trailing_labels: Labels,
     pub start_address: Option<Address>,
 }
 
@@ -111,17 +141,28 @@ impl From<DataSegment> for KDataSegment {
     fn from(d: DataSegment) -> Self {
         KDataSegment {
             data_entries: d.data_entries,
+            trailing_labels: d.trailing_labels,
             start_address: d.start_address,
         }
     }
 }
 
+/// A `.extern name size` declaration. The assembler reserves `size` bytes for any
+/// that the program doesn't define itself.
+#[derive(Clone, Debug)]
+pub struct ExternSymbol {
+    pub name: String,
+    pub size: Option<u32>,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Parsed {
     pub text_segment: Vec<TextSegment>,
     pub ktext_segment: Vec<KTextSegment>,
     pub data_segment: Vec<DataSegment>,
     pub kdata_segment: Vec<KDataSegment>,
+    pub globals: Vec<Spanned<String>>,
+    pub externs: Vec<Spanned<ExternSymbol>>,
 }
 
 fn i_extract_imm<T>(imm: (Option<&str>, Result<T, ParseIntError>)) -> Option<T>
@@ -140,460 +181,829 @@ where
     Some(imm_int)
 }
 
-fn parse_label(
-    current_line: &str,
-) -> Result<(String, Option<&str>), Err<(&str, nom::error::ErrorKind)>> {
-    let (rest, l) = new_label(current_line)?;
-    let l = String::from_iter(l.into_iter());
+#[derive(Copy, Clone, Debug)]
+struct SourceLine<'a> {
+    file: FileId,
+    number: u32,
+    raw: &'a str,
+    text: &'a str,
+}
+
+impl<'a> SourceLine<'a> {
+    fn with_text(self, text: &'a str) -> SourceLine<'a> {
+        SourceLine { text, ..self }
+    }
+    fn column(&self, part: &str) -> u32 {
+        (part.as_ptr() as usize - self.raw.as_ptr() as usize) as u32 + 1
+    }
+    // `rest` is whatever nom left over after consuming from the start of `self.text`
+    fn span_until(&self, rest: &str) -> Span {
+        Span::new(
+            self.file,
+            self.number,
+            self.column(self.text),
+            self.column(rest),
+        )
+    }
+    fn span_of(&self, part: &str) -> Span {
+        let start = self.column(part);
+        Span::new(self.file, self.number, start, start + part.len() as u32)
+    }
+}
+
+struct SourceLines<'a> {
+    file: FileId,
+    lines: Enumerate<Lines<'a>>,
+    pending: Vec<SourceLine<'a>>,
+}
+
+impl<'a> SourceLines<'a> {
+    fn new(program: &'a str, file: FileId) -> SourceLines<'a> {
+        SourceLines {
+            file,
+            lines: program.lines().enumerate(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Iterator for SourceLines<'a> {
+    type Item = SourceLine<'a>;
+
+    fn next(&mut self) -> Option<SourceLine<'a>> {
+        if let Some(statement) = self.pending.pop() {
+            return Some(statement);
+        }
+        let (i, raw) = self.lines.next()?;
+        let file = self.file;
+        self.pending = split_statements(raw)
+            .into_iter()
+            .rev()
+            .map(|text| SourceLine {
+                file,
+                number: i as u32 + 1,
+                raw,
+                text: text.trim(),
+            })
+            .collect();
+        self.pending.pop()
+    }
+}
+
+// Blanks out `#`, `//` and `/* */` comments in place so columns still line up with the source.
+fn strip_comments(program: &str, file: FileId) -> String {
+    enum Scan {
+        Code,
+        Quoted(u8),
+        Escaped(u8),
+        LineComment,
+        BlockComment,
+    }
+    let bytes = program.as_bytes();
+    let mut out = bytes.to_vec();
+    let mut state = Scan::Code;
+    let mut line = 1;
+    let mut line_start = 0;
+    let mut block_start = Span::default();
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let next = bytes.get(i + 1).copied();
+        state = match state {
+            Scan::Code => match (b, next) {
+                (b'"', _) | (b'\'', _) => Scan::Quoted(b),
+                (b'#', _) | (b'/', Some(b'/')) => {
+                    out[i] = b' ';
+                    Scan::LineComment
+                }
+                (b'/', Some(b'*')) => {
+                    let column = (i - line_start) as u32 + 1;
+                    block_start = Span::new(file, line, column, column + 2);
+                    out[i] = b' ';
+                    out[i + 1] = b' ';
+                    i += 1;
+                    Scan::BlockComment
+                }
+                _ => Scan::Code,
+            },
+            Scan::Quoted(q) => match b {
+                b'\\' => Scan::Escaped(q),
+                b'\n' => Scan::Code,
+                _ if b == q => Scan::Code,
+                _ => Scan::Quoted(q),
+            },
+            Scan::Escaped(q) => match b {
+                b'\n' => Scan::Code,
+                _ => Scan::Quoted(q),
+            },
+            Scan::LineComment => match b {
+                b'\n' => Scan::Code,
+                _ => {
+                    out[i] = b' ';
+                    Scan::LineComment
+                }
+            },
+            Scan::BlockComment => match (b, next) {
+                (b'*', Some(b'/')) => {
+                    out[i] = b' ';
+                    out[i + 1] = b' ';
+                    i += 1;
+                    Scan::Code
+                }
+                (b'\n', _) => Scan::BlockComment,
+                _ => {
+                    out[i] = b' ';
+                    Scan::BlockComment
+                }
+            },
+        };
+        if b == b'\n' {
+            line += 1;
+            line_start = i + 1;
+        }
+        i += 1;
+    }
+    if let Scan::BlockComment = state {
+        panic!("Unterminated comment starting at {}", block_start);
+    }
+    // only whole characters inside comments were replaced, so this is still valid UTF-8
+    String::from_utf8(out).unwrap()
+}
+
+fn split_statements(line: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut quote: Option<u8> = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, b) in line.bytes().enumerate() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if b == b'\\' => escaped = true,
+            Some(q) if b == q => quote = None,
+            Some(_) => (),
+            None if b == b'"' || b == b'\'' => quote = Some(b),
+            None if b == b';' => {
+                statements.push(&line[start..i]);
+                start = i + 1;
+            }
+            None => (),
+        }
+    }
+    statements.push(&line[start..]);
+    statements
+}
+
+// Every statement has to be consumed entirely, anything left over is an error.
+fn finish(line: &SourceLine, rest: &str) -> Span {
+    let junk = rest.trim();
+    if !junk.is_empty() {
+        panic!(
+            "Unexpected trailing characters at {}: {}",
+            line.span_of(junk),
+            junk
+        );
+    }
+    line.span_until(rest)
+}
+
+type LabelResult<'a> =
+    Result<(Spanned<Address>, Option<SourceLine<'a>>), Err<(&'a str, nom::error::ErrorKind)>>;
+
+fn parse_label(line: SourceLine) -> LabelResult {
+    let (rest, l) = new_label(line.text)?;
+    let label = Spanned::new(Address::from(l.to_owned()), line.span_of(l));
     let rest = rest.trim();
-    if !rest.is_empty() && !entire_line_is_comment(rest) {
-        Ok((l, Some(rest)))
+    if !rest.is_empty() {
+        Ok((label, Some(line.with_text(rest))))
     } else {
-        Ok((l, None))
+        Ok((label, None))
     }
 }
 
-fn parse_directive<'a>(current_line: &'a str, lines: &'a mut Lines) -> Option<ParsedDirective<'a>> {
+fn take_labels(current_labels: &mut Labels) -> Labels {
+    current_labels.take()
+}
+
+fn push_label(current_labels: &mut Labels, label: Spanned<Address>) {
+    match current_labels {
+        Some(ref mut v) => v.push(label),
+        None => *current_labels = Some(vec![label]),
+    };
+}
+
+// Directives that may appear anywhere and don't emit anything into a segment.
+fn parse_symbol_directive(line: &SourceLine, parsed: &mut Parsed) -> bool {
+    let text = line.text;
+    if let Ok((rest, names)) = directive_globl(text) {
+        finish(line, rest);
+        for name in names {
+            parsed
+                .globals
+                .push(Spanned::new(name.to_owned(), line.span_of(name)));
+        }
+        return true;
+    }
+    if let Ok((rest, (name, size))) = directive_extern(text) {
+        let span = finish(line, rest);
+        let size = size.map(|imm| match i_extract_imm(imm) {
+            Some(i) if i >= 0 => i as u32,
+            _ => panic!("Invalid size for extern directive at {}: {}", span, text),
+        });
+        let symbol = ExternSymbol {
+            name: name.to_owned(),
+            size,
+        };
+        parsed.externs.push(Spanned::new(symbol, span));
+        return true;
+    }
+    // `.set noreorder` and friends: we never reorder or fill delay slots anyway
+    if let Ok((rest, _)) = directive_set(text) {
+        finish(line, rest);
+        return true;
+    }
+    if let Ok((rest, _)) = directive_ent(text) {
+        finish(line, rest);
+        return true;
+    }
+    directive_annotation(text).is_ok()
+}
+
+fn parse_directive<'a>(
+    current_line: SourceLine<'a>,
+    lines: &mut SourceLines<'a>,
+    parsed: &mut Parsed,
+) -> Option<(SourceLine<'a>, ParsedDirective<'a>)> {
     let mut first = Some(current_line);
-    for line in lines {
-        let line = match first {
-            Some(f) => {
-                first = None;
-                f.trim()
-            }
-            None => line.trim(),
+    loop {
+        let line = match first.take() {
+            Some(f) => f,
+            None => lines.next()?,
         };
-        if line.is_empty() || entire_line_is_comment(line) {
+        let text = line.text;
+        if text.is_empty() || parse_symbol_directive(&line, parsed) {
             continue;
         }
-        if let Ok(align) = directive_align(line) {
-            return Some(ParsedDirective::Align(Ok(align)));
+        if let Ok(align) = directive_align(text) {
+            return Some((line, ParsedDirective::Align(Ok(align))));
+        }
+        if let Ok(ascii) = directive_ascii(text) {
+            return Some((line, ParsedDirective::Ascii(Ok(ascii))));
+        }
+        if let Ok(asciiz) = directive_asciiz(text) {
+            return Some((line, ParsedDirective::Asciiz(Ok(asciiz))));
         }
-        if let Ok(ascii) = directive_ascii(line) {
-            return Some(ParsedDirective::Ascii(Ok(ascii)));
+        if let Ok(byte) = directive_byte(text) {
+            return Some((line, ParsedDirective::Byte(Ok(byte))));
         }
-        if let Ok(asciiz) = directive_asciiz(line) {
-            return Some(ParsedDirective::Asciiz(Ok(asciiz)));
+        if let Ok(data) = directive_data(text) {
+            return Some((line, ParsedDirective::Data(Ok(data))));
         }
-        if let Ok(byte) = directive_byte(line) {
-            return Some(ParsedDirective::Byte(Ok(byte)));
+        if let Ok(dword) = directive_dword(text) {
+            return Some((line, ParsedDirective::DWord(Ok(dword))));
         }
-        if let Ok(data) = directive_data(line) {
-            return Some(ParsedDirective::Data(Ok(data)));
+        if let Ok(half) = directive_half(text) {
+            return Some((line, ParsedDirective::Half(Ok(half))));
         }
-        if let Ok(half) = directive_half(line) {
-            return Some(ParsedDirective::Half(Ok(half)));
+        if let Ok(kdata) = directive_kdata(text) {
+            return Some((line, ParsedDirective::KData(Ok(kdata))));
         }
-        if let Ok(kdata) = directive_kdata(line) {
-            return Some(ParsedDirective::KData(Ok(kdata)));
+        if let Ok(ktext) = directive_ktext(text) {
+            return Some((line, ParsedDirective::KText(Ok(ktext))));
         }
-        if let Ok(ktext) = directive_ktext(line) {
-            return Some(ParsedDirective::KText(Ok(ktext)));
+        if let Ok(space) = directive_space(text) {
+            return Some((line, ParsedDirective::Space(Ok(space))));
         }
-        if let Ok(space) = directive_space(line) {
-            return Some(ParsedDirective::Space(Ok(space)));
+        if let Ok(text_directive) = directive_text(text) {
+            return Some((line, ParsedDirective::Text(Ok(text_directive))));
         }
-        if let Ok(text) = directive_text(line) {
-            return Some(ParsedDirective::Text(Ok(text)));
+        if let Ok(word) = directive_word(text) {
+            return Some((line, ParsedDirective::Word(Ok(word))));
         }
-        if let Ok(word) = directive_word(line) {
-            return Some(ParsedDirective::Word(Ok(word)));
+        match directive(text) {
+            Ok((_, name)) => panic!("Unknown directive at {}: .{}", line.span_of(text), name),
+            Err(_) => panic!("Unrecognized statement at {}: {}", line.span_of(text), text),
         }
-        return None;
     }
-    None
 }
 
-fn parse_text_segment(lines: &mut Lines, text_segment: &mut TextSegment) -> Option<String> {
-    let mut current_labels: Option<Vec<String>> = None;
-    for line in lines {
-        let mut line = line.trim();
-        if line.is_empty() || entire_line_is_comment(line) {
+// `.align n` aligns to 2^n bytes as in SPIM, 0 meaning no alignment.
+fn parse_alignment(line: &SourceLine, rest: &str, imm: Imm<'_, i64>) -> (Span, u32) {
+    let span = finish(line, rest);
+    match i_extract_imm(imm) {
+        Some(i) if i < 0 => panic!("Cannot have negative alignment at {}: {}", span, line.text),
+        Some(i) if i > 16 => panic!("Alignment too large at {}: {}", span, line.text),
+        Some(i) => (span, i as u32),
+        None => panic!(
+            "Unable to parse immediate for align directive at {}: {}",
+            span, line.text
+        ),
+    }
+}
+
+// Immediates are 16 bits, sign extended except for the logical instructions.
+fn immediate(line: &SourceLine, rest: &str, value: i64, unsigned: bool) -> u16 {
+    let (low, high) = if unsigned {
+        (0, 0xffff)
+    } else {
+        (-0x8000, 0x7fff)
+    };
+    if value < low || value > high {
+        panic!(
+            "Immediate must be from {} to {} at {}: {}",
+            low,
+            high,
+            finish(line, rest),
+            line.text
+        );
+    }
+    value as u16
+}
+
+fn parse_text_segment<'a>(
+    lines: &mut SourceLines<'a>,
+    text_segment: &mut TextSegment,
+    parsed: &mut Parsed,
+) -> Option<SourceLine<'a>> {
+    let mut current_labels: Labels = None;
+    for mut line in lines {
+        if line.text.is_empty() {
             continue;
         }
         if let Ok((l, rest)) = parse_label(line) {
-            match current_labels {
-                Some(ref mut v) => v.push(l),
-                None => current_labels = Some(vec![l]),
-            };
+            push_label(&mut current_labels, l);
             if let Some(rest) = rest {
                 line = rest;
             } else {
                 continue;
             }
         }
-        if let Ok((_, (inst, rd, rs, rt))) = r_arithmetic(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        let text = line.text;
+        if parse_symbol_directive(&line, parsed) {
+            continue;
+        }
+        if let Ok((rest, imm)) = directive_align(text) {
+            let (span, power) = parse_alignment(&line, rest, imm);
+            if power > 0 {
+                let alignment = DataAlignment {
+                    alignment: 1 << power,
+                };
+                text_segment.alignments.push((
+                    text_segment.instructions.len(),
+                    Spanned::new(alignment, span),
+                ));
+            }
+            continue;
+        }
+        if let Ok((rest, (inst, rd, rs, rt))) = r_arithmetic(text) {
             text_segment.instructions.push((
-                addr,
-                RType::new(
-                    RInst::from(inst),
-                    Reg::from(rs),
-                    Reg::from(rt),
-                    Reg::from(rd),
-                    0,
-                )
-                .into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    RType::new(
+                        RInst::from(inst),
+                        Reg::from(rs),
+                        Reg::from(rt),
+                        Reg::from(rd),
+                        0,
+                    )
+                    .into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rd, rt, shamt))) = r_shift(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, rd, rt, shamt))) = r_shift(text) {
             if let Some(sign) = shamt.0 {
                 if sign == "-" {
-                    panic!("Cannot have negative shift amount: {}", line);
+                    panic!(
+                        "Cannot have negative shift amount at {}: {}",
+                        finish(&line, rest),
+                        text
+                    );
                 }
             }
             let shamt_int = match shamt.1 {
-                Ok(i) => i as u8,
-                Err(p) => panic!("Unable to parse shift amount: {} because {}", line, p),
+                Ok(i) if (0..32).contains(&i) => i as u8,
+                Ok(_) => panic!(
+                    "Shift amount must be from 0 to 31 at {}: {}",
+                    finish(&line, rest),
+                    text
+                ),
+                Err(p) => panic!(
+                    "Unable to parse shift amount at {}: {} because {}",
+                    finish(&line, rest),
+                    text,
+                    p
+                ),
             };
             text_segment.instructions.push((
-                addr,
-                RType::new(
-                    RInst::from(inst),
-                    Reg::zero,
-                    Reg::from(rt),
-                    Reg::from(rd),
-                    shamt_int,
-                )
-                .into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    RType::new(
+                        RInst::from(inst),
+                        Reg::zero,
+                        Reg::from(rt),
+                        Reg::from(rd),
+                        shamt_int,
+                    )
+                    .into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rs))) = r_jump(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, inst)) = r_syscall(text) {
             text_segment.instructions.push((
-                addr,
-                RType::new(RInst::from(inst), Reg::from(rs), Reg::zero, Reg::zero, 0).into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    RType::new(RInst::from(inst), Reg::zero, Reg::zero, Reg::zero, 0).into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rt, rs, imm))) = i_arith(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, rs))) = r_jump(text) {
+            text_segment.instructions.push((
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    RType::new(RInst::from(inst), Reg::from(rs), Reg::zero, Reg::zero, 0).into(),
+                    finish(&line, rest),
+                ),
+            ));
+            continue;
+        }
+        if let Ok((rest, (inst, rt, rd))) = c0_move(text) {
+            text_segment.instructions.push((
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    C0Type::new(C0Inst::from(inst), Reg::from(rt), u8::from(Reg::from(rd))).into(),
+                    finish(&line, rest),
+                ),
+            ));
+            continue;
+        }
+        if let Ok((rest, inst)) = c0_eret(text) {
+            text_segment.instructions.push((
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    C0Type::new(C0Inst::from(inst), Reg::zero, 0).into(),
+                    finish(&line, rest),
+                ),
+            ));
+            continue;
+        }
+        if let Ok((rest, (inst, rt, rs, imm))) = i_arith(text) {
+            let inst = IInst::from(inst);
+            let logical = matches!(inst, IInst::andi | IInst::ori);
             let imm_int = match i_extract_imm(imm) {
-                Some(i) => i as u16,
-                None => panic!("Unable to parse immediate: {}", line),
+                Some(i) => immediate(&line, rest, i, logical),
+                None => panic!(
+                    "Unable to parse immediate at {}: {}",
+                    finish(&line, rest),
+                    text
+                ),
             };
             text_segment.instructions.push((
-                addr,
-                ITypeImm::new(IInst::from(inst), Reg::from(rs), Reg::from(rt), imm_int).into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    ITypeImm::new(inst, Reg::from(rs), Reg::from(rt), imm_int).into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rt, rs, imm))) = i_branch_imm(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, rs, rt, imm))) = i_branch_imm(text) {
             let imm_int = match i_extract_imm(imm) {
-                Some(i) => i as u16,
-                None => panic!("Unable to parse immediate: {}", line),
+                Some(i) => immediate(&line, rest, i, false),
+                None => panic!(
+                    "Unable to parse immediate at {}: {}",
+                    finish(&line, rest),
+                    text
+                ),
             };
             text_segment.instructions.push((
-                addr,
-                ITypeImm::new(IInst::from(inst), Reg::from(rs), Reg::from(rt), imm_int).into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    ITypeImm::new(IInst::from(inst), Reg::from(rs), Reg::from(rt), imm_int).into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rt, rs, label))) = i_branch_label(line) {
-            let label = String::from_iter(label.into_iter());
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, rs, rt, label))) = i_branch_label(text) {
+            let label = label.to_owned();
             text_segment.instructions.push((
-                addr,
-                ITypeLabel::new(
-                    IInst::from(inst),
-                    Reg::from(rs),
-                    Reg::from(rt),
-                    Address::new(None, Some(label)),
-                )
-                .into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    ITypeLabel::new(
+                        IInst::from(inst),
+                        Reg::from(rs),
+                        Reg::from(rt),
+                        Address::new(None, Some(label)),
+                    )
+                    .into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rt, imm, rs))) = i_mem_imm(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
-            let imm_int = match i_extract_imm(imm) {
-                Some(i) => i as u16,
-                None => panic!("Unable to parse immediate: {}", line),
+        if let Ok((rest, (inst, rt, imm, rs))) = i_mem_imm(text) {
+            let imm_int = match imm.map_or(Some(0), i_extract_imm) {
+                Some(i) => immediate(&line, rest, i, false),
+                None => panic!(
+                    "Unable to parse immediate at {}: {}",
+                    finish(&line, rest),
+                    text
+                ),
             };
             text_segment.instructions.push((
-                addr,
-                ITypeImm::new(IInst::from(inst), Reg::from(rs), Reg::from(rt), imm_int).into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    ITypeImm::new(IInst::from(inst), Reg::from(rs), Reg::from(rt), imm_int).into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rt, label, rs))) = i_mem_label(line) {
-            let label = String::from_iter(label.into_iter());
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, rt, label, rs))) = i_mem_label(text) {
+            let label = label.to_owned();
             text_segment.instructions.push((
-                addr,
-                ITypeLabel::new(
-                    IInst::from(inst),
-                    Reg::from(rs),
-                    Reg::from(rt),
-                    Address::new(None, Some(label)),
-                )
-                .into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    ITypeLabel::new(
+                        IInst::from(inst),
+                        Reg::from(rs),
+                        Reg::from(rt),
+                        Address::new(None, Some(label)),
+                    )
+                    .into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
-        if let Ok((_, (inst, rt, imm))) = i_load_imm(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, rt, imm))) = i_load_imm(text) {
             let imm_int = match i_extract_imm(imm) {
-                Some(i) => i as u16,
-                None => panic!("Unable to parse immediate: {}", line),
+                Some(i) => i,
+                None => panic!(
+                    "Unable to parse immediate at {}: {}",
+                    finish(&line, rest),
+                    text
+                ),
+            };
+            let inst: Inst = if inst.eq_ignore_ascii_case("lui") {
+                let imm_int = immediate(&line, rest, imm_int, true);
+                ITypeImm::new(IInst::lui, Reg::zero, Reg::from(rt), imm_int).into()
+            } else {
+                PseudoImm::new(PInst::from(inst), Reg::from(rt), imm_int as u32).into()
             };
             text_segment.instructions.push((
-                addr,
-                ITypeImm::new(IInst::from(inst), Reg::zero, Reg::from(rt), imm_int).into(),
+                take_labels(&mut current_labels),
+                Spanned::new(inst, finish(&line, rest)),
             ));
             continue;
         }
-        if let Ok((_, (inst, rt, label))) = i_load_label(line) {
-            let label = String::from_iter(label.into_iter());
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, rt, label))) = i_load_label(text) {
+            let label = Address::new(None, Some(label.to_owned()));
+            let inst: Inst = if inst.eq_ignore_ascii_case("lui") {
+                ITypeLabel::new(IInst::lui, Reg::zero, Reg::from(rt), label).into()
+            } else {
+                PseudoLabel::new(PInst::from(inst), Reg::from(rt), label).into()
+            };
             text_segment.instructions.push((
-                addr,
-                ITypeLabel::new(
-                    IInst::from(inst),
-                    Reg::zero,
-                    Reg::from(rt),
-                    Address::new(None, Some(label)),
-                )
-                .into(),
+                take_labels(&mut current_labels),
+                Spanned::new(inst, finish(&line, rest)),
             ));
             continue;
         }
-        if let Ok((_, (inst, label))) = j_label(line) {
-            let label = String::from_iter(label.into_iter());
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (inst, label))) = j_label(text) {
+            let label = label.to_owned();
             text_segment.instructions.push((
-                addr,
-                JType::new(JInst::from(inst), Address::new(None, Some(label))).into(),
+                take_labels(&mut current_labels),
+                Spanned::new(
+                    JType::new(JInst::from(inst), Address::new(None, Some(label))).into(),
+                    finish(&line, rest),
+                ),
             ));
             continue;
         }
         // it may be a new directive
-        return Some(line.to_owned());
+        text_segment.trailing_labels = current_labels;
+        return Some(line);
     }
+    text_segment.trailing_labels = current_labels;
     None
 }
 
-fn parse_data_segment(lines: &mut Lines, data_segment: &mut DataSegment) -> Option<String> {
-    let mut current_labels: Option<Vec<String>> = None;
-    for line in lines {
-        let mut line = line.trim();
-        if line.is_empty() || entire_line_is_comment(line) {
+// Constant expressions are folded here, anything naming a label waits for the assembler.
+fn data_values<T, F>(exprs: Vec<Expr>, span: Span, truncate: F) -> Vec<DataValue<T>>
+where
+    F: Fn(i64) -> T,
+{
+    exprs
+        .into_iter()
+        .map(|e| {
+            if e.is_constant() {
+                match e.evaluate(&|_| None) {
+                    Ok(v) => DataValue::Literal(truncate(v)),
+                    Err(err) => panic!("{} at {}", err, span),
+                }
+            } else {
+                DataValue::Expression(e)
+            }
+        })
+        .collect()
+}
+
+// Pads to the natural alignment of the entry that follows, the labels pending for
+// that entry stay with it and so land on the aligned address.
+fn auto_align(data_segment: &mut DataSegment, enabled: bool, alignment: u32, span: Span) {
+    if enabled {
+        data_segment.data_entries.push(Spanned::new(
+            DataEntry::Alignment(DataAlignment { alignment }),
+            span,
+        ));
+    }
+}
+
+fn parse_data_segment<'a>(
+    lines: &mut SourceLines<'a>,
+    data_segment: &mut DataSegment,
+    parsed: &mut Parsed,
+) -> Option<SourceLine<'a>> {
+    let mut current_labels: Labels = None;
+    let mut auto_alignment = true;
+    for mut line in lines {
+        if line.text.is_empty() {
             continue;
         }
         if let Ok((l, rest)) = parse_label(line) {
-            match current_labels {
-                Some(ref mut v) => v.push(l),
-                None => current_labels = Some(vec![l]),
-            };
+            push_label(&mut current_labels, l);
             if let Some(rest) = rest {
                 line = rest;
             } else {
                 continue;
             }
         }
-        if let Ok((_, imm)) = directive_align(line) {
-            let imm_int = match i_extract_imm(imm) {
-                Some(i) => i,
-                None => panic!("Unable to parse immediate for align directive: {}", line),
-            };
-            if imm_int < 0 {
-                panic!("Cannot have negative aligment: {}", line);
-            }
-            let imm_int = imm_int as u32;
-            if imm_int & (imm_int - 1) != 0 {
-                panic!("Alignment must be power of 2: {}", line);
+        let text = line.text;
+        if parse_symbol_directive(&line, parsed) {
+            continue;
+        }
+        if let Ok((rest, imm)) = directive_align(text) {
+            let (span, power) = parse_alignment(&line, rest, imm);
+            // `.align 0` switches off automatic alignment for the rest of the segment
+            if power == 0 {
+                auto_alignment = false;
+                continue;
             }
-            let alignment = DataAlignment { alignment: imm_int };
+            let alignment = DataAlignment {
+                alignment: 1 << power,
+            };
             data_segment
                 .data_entries
-                .push(DataEntry::Alignment(alignment));
+                .push(Spanned::new(DataEntry::Alignment(alignment), span));
             continue;
         }
-        if let Ok((_, s)) = directive_ascii(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, strings)) = directive_ascii(text) {
             let cstring = DataCString {
-                chars: (addr, s.as_bytes().to_vec()), // Rust strings aren't null terminated
+                chars: (take_labels(&mut current_labels), strings.concat()),
                 null_terminated: false,
             };
-            data_segment.data_entries.push(DataEntry::CString(cstring));
+            data_segment.data_entries.push(Spanned::new(
+                DataEntry::CString(cstring),
+                finish(&line, rest),
+            ));
             continue;
         }
-        if let Ok((_, s)) = directive_asciiz(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
-            let mut cstring = DataCString {
-                chars: (addr, s.as_bytes().to_vec()), // Rust strings aren't null terminated
+        if let Ok((rest, strings)) = directive_asciiz(text) {
+            let mut chars = Vec::new();
+            for s in strings {
+                chars.extend(s);
+                chars.push(0); // each string gets its own terminator, like GNU as
+            }
+            let cstring = DataCString {
+                chars: (take_labels(&mut current_labels), chars),
                 null_terminated: true,
             };
-            cstring.chars.1.push(0);
-            data_segment.data_entries.push(DataEntry::CString(cstring));
+            data_segment.data_entries.push(Spanned::new(
+                DataEntry::CString(cstring),
+                finish(&line, rest),
+            ));
             continue;
         }
-        if let Ok((_, bytes)) = directive_byte(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
-            let mut byte_vec = Vec::new();
-            for entry in bytes {
-                let imm = match i_extract_imm(entry) {
-                    Some(b) => b as u8,
-                    None => panic!("Syntax error in byte directive: {}", line),
-                };
-                byte_vec.push(imm);
-            }
+        if let Ok((rest, bytes)) = directive_byte(text) {
+            let span = finish(&line, rest);
             let data_bytes = DataBytes {
-                bytes: (addr, byte_vec),
+                bytes: (
+                    take_labels(&mut current_labels),
+                    data_values(bytes, span, |v| v as u8),
+                ),
             };
-            data_segment.data_entries.push(DataEntry::Bytes(data_bytes));
+            data_segment
+                .data_entries
+                .push(Spanned::new(DataEntry::Bytes(data_bytes), span));
             continue;
         }
-        if let Ok((_, halfs)) = directive_half(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
-            let mut half_vec = Vec::new();
-            for entry in halfs {
-                let imm = match i_extract_imm(entry) {
-                    Some(b) => b as u16,
-                    None => panic!("Syntax error in half directive: {}", line),
-                };
-                half_vec.push(imm);
-            }
+        if let Ok((rest, halfs)) = directive_half(text) {
+            let span = finish(&line, rest);
+            auto_align(data_segment, auto_alignment, 2, span);
             let data_halfs = DataHalfs {
-                halfs: (addr, half_vec),
+                halfs: (
+                    take_labels(&mut current_labels),
+                    data_values(halfs, span, |v| v as u16),
+                ),
             };
-            data_segment.data_entries.push(DataEntry::Halfs(data_halfs));
+            data_segment
+                .data_entries
+                .push(Spanned::new(DataEntry::Halfs(data_halfs), span));
             continue;
         }
-        if let Ok((_, words)) = directive_word(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
-            let mut word_vec = Vec::new();
-            for entry in words {
-                let imm = match i_extract_imm(entry) {
-                    Some(b) => b as u32,
-                    None => panic!("Syntax error in word directive: {}", line),
-                };
-                word_vec.push(imm);
-            }
+        if let Ok((rest, words)) = directive_word(text) {
+            let span = finish(&line, rest);
+            auto_align(data_segment, auto_alignment, 4, span);
             let data_words = DataWords {
-                words: (addr, word_vec),
+                words: (
+                    take_labels(&mut current_labels),
+                    data_values(words, span, |v| v as u32),
+                ),
+            };
+            data_segment
+                .data_entries
+                .push(Spanned::new(DataEntry::Words(data_words), span));
+            continue;
+        }
+        if let Ok((rest, dwords)) = directive_dword(text) {
+            let span = finish(&line, rest);
+            auto_align(data_segment, auto_alignment, 8, span);
+            let data_dwords = DataDWords {
+                dwords: (
+                    take_labels(&mut current_labels),
+                    data_values(dwords, span, |v| v as u64),
+                ),
             };
-            data_segment.data_entries.push(DataEntry::Words(data_words));
+            data_segment
+                .data_entries
+                .push(Spanned::new(DataEntry::DWords(data_dwords), span));
             continue;
         }
-        if let Ok((_, imm)) = directive_space(line) {
-            let addr = current_labels.map_or_else(
-                || None,
-                |v| Some(v.into_iter().map(|s| Address::from(s)).collect()),
-            );
-            current_labels = None;
+        if let Ok((rest, (imm, fill))) = directive_space(text) {
+            let span = finish(&line, rest);
             let imm = match i_extract_imm(imm) {
-                Some(i) => i as u32,
-                None => panic!("Expected amount of space after space directive: {}", line),
+                Some(i) if i >= 0 => i as u32,
+                _ => panic!(
+                    "Expected amount of space after space directive at {}: {}",
+                    span, text
+                ),
+            };
+            let fill = match fill.map(i_extract_imm) {
+                Some(Some(f)) => f as u8,
+                Some(None) => panic!("Unable to parse fill value at {}: {}", span, text),
+                None => 0,
             };
             let data_space = DataSpace {
-                spaces: (addr, vec![0; imm as usize]),
+                spaces: (take_labels(&mut current_labels), vec![fill; imm as usize]),
             };
-            data_segment.data_entries.push(DataEntry::Space(data_space));
+            data_segment
+                .data_entries
+                .push(Spanned::new(DataEntry::Space(data_space), span));
             continue;
         }
         // it may be a new directive
-        return Some(line.to_owned());
+        data_segment.trailing_labels = current_labels;
+        return Some(line);
     }
+    data_segment.trailing_labels = current_labels;
     None
 }
 
+fn segment_start(imm: Option<(Option<&str>, Result<i64, ParseIntError>)>) -> Option<Address> {
+    imm.and_then(i_extract_imm)
+        .map(|i| Address::new(NonZeroU32::new(i as u32), None))
+}
+
 pub fn parse(program: &str) -> Parsed {
+    parse_file(program, FileId::default())
+}
+
+pub fn parse_file(program: &str, file: FileId) -> Parsed {
     let mut parsed = Parsed::default();
-    let mut lines = program.lines();
+    let program = strip_comments(program, file);
+    let mut lines = SourceLines::new(&program, file);
 
-    let mut line: String;
-    match lines.next() {
-        Some(l) => line = l.to_string(),
+    let mut line = match lines.next() {
+        Some(l) => l,
         None => return parsed,
-    }
+    };
 
     loop {
-        match parse_directive(&line, &mut lines) {
-            Some(ParsedDirective::Text(Ok((_, imm)))) => {
+        match parse_directive(line, &mut lines, &mut parsed) {
+            Some((l, ParsedDirective::Text(Ok((rest, imm))))) => {
+                finish(&l, rest);
                 let mut text_segment = TextSegment::new();
+                text_segment.start_address = segment_start(imm);
 
-                if let Some(imm) = imm {
-                    match i_extract_imm(imm) {
-                        Some(i) => {
-                            text_segment.start_address =
-                                Some(Address::new(NonZeroU32::new(i as u32), None))
-                        }
-                        None => (),
-                    }
-                }
+                let more_lines = parse_text_segment(&mut lines, &mut text_segment, &mut parsed);
 
-                let more_lines = parse_text_segment(&mut lines, &mut text_segment);
-
-                if text_segment.instructions.len() > 0 {
+                if !text_segment.instructions.is_empty() || text_segment.trailing_labels.is_some() {
                     parsed.text_segment.push(text_segment);
                 }
 
@@ -602,22 +1012,14 @@ pub fn parse(program: &str) -> Parsed {
                     None => break,
                 }
             }
-            Some(ParsedDirective::KText(Ok((_, imm)))) => {
+            Some((l, ParsedDirective::KText(Ok((rest, imm))))) => {
+                finish(&l, rest);
                 let mut text_segment = TextSegment::new();
+                text_segment.start_address = segment_start(imm);
 
-                if let Some(imm) = imm {
-                    match i_extract_imm(imm) {
-                        Some(i) => {
-                            text_segment.start_address =
-                                Some(Address::new(NonZeroU32::new(i as u32), None))
-                        }
-                        None => (),
-                    }
-                }
-
-                let more_lines = parse_text_segment(&mut lines, &mut text_segment);
+                let more_lines = parse_text_segment(&mut lines, &mut text_segment, &mut parsed);
 
-                if text_segment.instructions.len() > 0 {
+                if !text_segment.instructions.is_empty() || text_segment.trailing_labels.is_some() {
                     parsed.ktext_segment.push(KTextSegment::from(text_segment));
                 }
 
@@ -626,22 +1028,14 @@ pub fn parse(program: &str) -> Parsed {
                     None => break,
                 }
             }
-            Some(ParsedDirective::Data(Ok((_, imm)))) => {
+            Some((l, ParsedDirective::Data(Ok((rest, imm))))) => {
+                finish(&l, rest);
                 let mut data_segment = DataSegment::new();
+                data_segment.start_address = segment_start(imm);
 
-                if let Some(imm) = imm {
-                    match i_extract_imm(imm) {
-                        Some(i) => {
-                            data_segment.start_address =
-                                Some(Address::new(NonZeroU32::new(i as u32), None))
-                        }
-                        None => (),
-                    }
-                }
-
-                let more_lines = parse_data_segment(&mut lines, &mut data_segment);
+                let more_lines = parse_data_segment(&mut lines, &mut data_segment, &mut parsed);
 
-                if data_segment.data_entries.len() > 0 {
+                if !data_segment.data_entries.is_empty() || data_segment.trailing_labels.is_some() {
                     parsed.data_segment.push(data_segment);
                 }
 
@@ -650,22 +1044,14 @@ pub fn parse(program: &str) -> Parsed {
                     None => break,
                 }
             }
-            Some(ParsedDirective::KData(Ok((_, imm)))) => {
+            Some((l, ParsedDirective::KData(Ok((rest, imm))))) => {
+                finish(&l, rest);
                 let mut data_segment = DataSegment::new();
+                data_segment.start_address = segment_start(imm);
 
-                if let Some(imm) = imm {
-                    match i_extract_imm(imm) {
-                        Some(i) => {
-                            data_segment.start_address =
-                                Some(Address::new(NonZeroU32::new(i as u32), None))
-                        }
-                        None => (),
-                    }
-                }
-
-                let more_lines = parse_data_segment(&mut lines, &mut data_segment);
+                let more_lines = parse_data_segment(&mut lines, &mut data_segment, &mut parsed);
 
-                if data_segment.data_entries.len() > 0 {
+                if !data_segment.data_entries.is_empty() || data_segment.trailing_labels.is_some() {
                     parsed.kdata_segment.push(KDataSegment::from(data_segment));
                 }
 
@@ -674,7 +1060,7 @@ pub fn parse(program: &str) -> Parsed {
                     None => break,
                 }
             }
-            Some(_) => panic!("Unexpected Directive: {}", line),
+            Some((l, _)) => panic!("Unexpected Directive at {}: {}", l.span_of(l.text), l.text),
             None => break,
         }
     }
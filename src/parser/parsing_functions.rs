@@ -2,28 +2,39 @@
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{alphanumeric1, digit1, hex_digit1, not_line_ending, space0, space1},
-    combinator::{map, opt, recognize},
-    multi::{many1, separated_nonempty_list},
-    sequence::{pair, preceded, terminated, tuple},
-    IResult,
+    bytes::complete::{tag, tag_no_case},
+    character::complete::{alpha1, alphanumeric1, char, digit1, hex_digit1, space0, space1},
+    combinator::{map, map_res, opt, recognize, rest, verify},
+    error::ErrorKind,
+    multi::{many0, separated_nonempty_list},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    Err, IResult,
 };
 
 use std::num::ParseIntError;
 use std::str::FromStr;
 
+use crate::{
+    machine::address::Address,
+    parser::expression::{BinOp, Expr, UnOp},
+};
+
 pub fn sign(input: &str) -> IResult<&str, &str> {
     alt((tag("+"), tag("-")))(input)
 }
 
-pub fn identifier(input: &str) -> IResult<&str, Vec<&str>> {
-    many1(alt((alphanumeric1, tag("_"))))(input)
+pub type Imm<'a, T> = (Option<&'a str>, Result<T, ParseIntError>);
+
+pub fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"), tag("."))),
+        many0(alt((alphanumeric1, tag("_"), tag(".")))),
+    ))(input)
 }
 
 macro_rules! gen_nom_ints_dec {
     ($name: ident, $type: ty) => {
-        pub fn $name(input: &str) -> IResult<&str, (Option<&str>, Result<$type, ParseIntError>)> {
+        pub fn $name(input: &str) -> IResult<&str, Imm<'_, $type>> {
             pair(opt(sign), map(digit1, |s: &str| FromStr::from_str(s)))(input)
         }
     };
@@ -31,11 +42,11 @@ macro_rules! gen_nom_ints_dec {
 
 macro_rules! gen_nom_ints_hex {
     ($name: ident, $type: ty) => {
-        pub fn $name(input: &str) -> IResult<&str, (Option<&str>, Result<$type, ParseIntError>)> {
+        pub fn $name(input: &str) -> IResult<&str, Imm<'_, $type>> {
             pair(
                 opt(sign),
                 preceded(
-                    tag("0x"),
+                    tag_no_case("0x"),
                     map(hex_digit1, |s: &str| <$type>::from_str_radix(s, 16)),
                 ),
             )(input)
@@ -54,459 +65,584 @@ gen_nom_ints_hex!(parse_hex_int32, i32);
 gen_nom_ints_hex!(parse_hex_int64, i64);
 gen_nom_ints_hex!(parse_hex_int128, i128);
 
-pub fn v_reg_name(input: &str) -> IResult<&str, &str> {
-    preceded(tag("v"), alt((tag("0"), tag("1"))))(input)
+const REGISTER_NAMES: [&str; 33] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+    "t7", "t8", "t9", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "k0", "k1", "gp", "sp",
+    "fp", "ra",
+];
+
+fn is_register(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    match name.parse::<u8>() {
+        Ok(n) => n < 32 && n.to_string() == name,
+        Err(_) => REGISTER_NAMES.contains(&name.as_str()),
+    }
 }
 
-pub fn v_reg_num(input: &str) -> IResult<&str, &str> {
-    alt((tag("2"), tag("3")))(input)
+pub fn register(input: &str) -> IResult<&str, &str> {
+    verify(recognize(preceded(char('$'), alphanumeric1)), |s: &str| {
+        is_register(&s[1..])
+    })(input)
 }
 
-pub fn a_reg_name(input: &str) -> IResult<&str, &str> {
-    preceded(tag("a"), alt((tag("0"), tag("1"), tag("2"), tag("3"))))(input)
+pub fn new_label(input: &str) -> IResult<&str, &str> {
+    terminated(identifier, preceded(space0, char(':')))(input)
 }
 
-pub fn a_reg_num(input: &str) -> IResult<&str, &str> {
-    alt((tag("4"), tag("5"), tag("6"), tag("7")))(input)
+pub fn directive(input: &str) -> IResult<&str, &str> {
+    preceded(char('.'), identifier)(input)
 }
 
-pub fn t_reg_name(input: &str) -> IResult<&str, &str> {
+pub fn operand_separator(input: &str) -> IResult<&str, &str> {
+    alt((recognize(tuple((space0, char(','), space0))), space1))(input)
+}
+
+fn mnemonic<'a>(names: &'static [&'static str]) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
+    verify(alphanumeric1, move |s: &str| {
+        names.iter().any(|n| n.eq_ignore_ascii_case(s))
+    })
+}
+
+fn directive_name<'a>(name: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
     preceded(
-        tag("t"),
-        alt((
-            tag("0"),
-            tag("1"),
-            tag("2"),
-            tag("3"),
-            tag("4"),
-            tag("5"),
-            tag("6"),
-            tag("7"),
-            tag("8"),
-            tag("9"),
-        )),
-    )(input)
+        char('.'),
+        verify(identifier, move |s: &str| s.eq_ignore_ascii_case(name)),
+    )
 }
 
-pub fn t_reg_num(input: &str) -> IResult<&str, &str> {
+fn integer64(input: &str) -> IResult<&str, Imm<'_, i64>> {
+    alt((parse_hex_int64, parse_dec_int64))(input)
+}
+
+fn memory_operand(input: &str) -> IResult<&str, &str> {
+    delimited(pair(char('('), space0), register, pair(space0, char(')')))(input)
+}
+
+fn escape(input: &str) -> IResult<&str, u8> {
+    let mut chars = input.chars();
+    let c = match chars.next() {
+        Some(c) => c,
+        None => return Err(Err::Error((input, ErrorKind::Escaped))),
+    };
+    let rest = &input[c.len_utf8()..];
+    let simple = match c {
+        'n' => Some(b'\n'),
+        't' => Some(b'\t'),
+        'r' => Some(b'\r'),
+        'a' => Some(0x07),
+        'b' => Some(0x08),
+        'f' => Some(0x0C),
+        'v' => Some(0x0B),
+        '\\' => Some(b'\\'),
+        '"' => Some(b'"'),
+        '\'' => Some(b'\''),
+        _ => None,
+    };
+    if let Some(b) = simple {
+        return Ok((rest, b));
+    }
+    if c == 'x' || c == 'X' {
+        let digits: String = rest
+            .chars()
+            .take_while(|c| c.is_ascii_hexdigit())
+            .take(2)
+            .collect();
+        return match u8::from_str_radix(&digits, 16) {
+            Ok(b) => Ok((&rest[digits.len()..], b)),
+            Err(_) => Err(Err::Failure((input, ErrorKind::Escaped))),
+        };
+    }
+    if c.is_digit(8) {
+        let digits: String = input
+            .chars()
+            .take_while(|c| c.is_digit(8))
+            .take(3)
+            .collect();
+        return match u8::from_str_radix(&digits, 8) {
+            Ok(b) => Ok((&input[digits.len()..], b)),
+            Err(_) => Err(Err::Failure((input, ErrorKind::Escaped))),
+        };
+    }
+    Err(Err::Failure((input, ErrorKind::Escaped)))
+}
+
+pub fn string_literal(input: &str) -> IResult<&str, Vec<u8>> {
+    let (mut rest, _) = char('"')(input)?;
+    let mut bytes = Vec::new();
+    loop {
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Err(Err::Failure((input, ErrorKind::Char))),
+        };
+        match c {
+            '"' => return Ok((&rest[1..], bytes)),
+            '\\' => {
+                let (r, b) = escape(&rest[1..])?;
+                bytes.push(b);
+                rest = r;
+            }
+            _ => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+}
+
+fn unsigned_integer(input: &str) -> IResult<&str, i64> {
     alt((
-        tag("8"),
-        tag("9"),
-        tag("10"),
-        tag("11"),
-        tag("12"),
-        tag("13"),
-        tag("14"),
-        tag("15"),
-        tag("24"),
-        tag("25"),
+        map_res(preceded(tag_no_case("0x"), hex_digit1), |s: &str| {
+            i64::from_str_radix(s, 16)
+        }),
+        map_res(digit1, |s: &str| s.parse::<i64>()),
     ))(input)
 }
 
-pub fn s_reg_name(input: &str) -> IResult<&str, &str> {
-    preceded(
-        tag("s"),
-        alt((
-            tag("0"),
-            tag("1"),
-            tag("2"),
-            tag("3"),
-            tag("4"),
-            tag("5"),
-            tag("6"),
-            tag("7"),
-        )),
-    )(input)
+pub fn char_literal(input: &str) -> IResult<&str, u8> {
+    let (after, _) = char('\'')(input)?;
+    let (after, c) = match after.chars().next() {
+        Some('\\') => escape(&after[1..])?,
+        Some(c) if c != '\'' && c.is_ascii() => (&after[1..], c as u8),
+        _ => return Err(Err::Error((input, ErrorKind::Char))),
+    };
+    let (after, _) = char('\'')(after)?;
+    Ok((after, c))
 }
 
-pub fn s_reg_num(input: &str) -> IResult<&str, &str> {
+fn primary_expression(input: &str) -> IResult<&str, Expr> {
     alt((
-        tag("16"),
-        tag("17"),
-        tag("18"),
-        tag("19"),
-        tag("20"),
-        tag("21"),
-        tag("22"),
-        tag("23"),
+        map(unsigned_integer, Expr::Number),
+        map(char_literal, |c| Expr::Number(i64::from(c))),
+        map(identifier, |s: &str| {
+            Expr::Symbol(Address::new(None, Some(s.to_owned())))
+        }),
+        delimited(pair(char('('), space0), expression, pair(space0, char(')'))),
     ))(input)
 }
 
-pub fn register_named(input: &str) -> IResult<&str, &str> {
-    recognize(preceded(
-        tag("$"),
-        alt((
-            tag("zero"),
-            tag("at"),
-            tag("sp"),
-            tag("fp"),
-            tag("ra"),
-            v_reg_name,
-            a_reg_name,
-            t_reg_name,
-            s_reg_name,
-        )),
+fn unary_expression(input: &str) -> IResult<&str, Expr> {
+    alt((
+        map(
+            preceded(pair(char('-'), space0), unary_expression),
+            |e| match e {
+                Expr::Number(n) => Expr::Number(n.wrapping_neg()),
+                e => Expr::Unary(UnOp::Neg, Box::new(e)),
+            },
+        ),
+        map(preceded(pair(char('~'), space0), unary_expression), |e| {
+            Expr::Unary(UnOp::Not, Box::new(e))
+        }),
+        preceded(pair(char('+'), space0), unary_expression),
+        primary_expression,
     ))(input)
 }
 
-pub fn register_numbered(input: &str) -> IResult<&str, &str> {
-    recognize(preceded(
-        tag("$"),
-        alt((
-            tag("0"),
-            tag("1"),
-            tag("29"),
-            tag("30"),
-            tag("31"),
-            v_reg_num,
-            a_reg_num,
-            t_reg_num,
-            s_reg_num,
-        )),
-    ))(input)
+fn binary_expression<'a>(
+    input: &'a str,
+    operand: fn(&'a str) -> IResult<&'a str, Expr>,
+    operators: &[(&str, BinOp)],
+) -> IResult<&'a str, Expr> {
+    let (mut rest, mut lhs) = operand(input)?;
+    loop {
+        let trimmed = rest.trim_start();
+        let (token, op) = match operators.iter().find(|(t, _)| trimmed.starts_with(t)) {
+            Some(&(t, op)) => (t, op),
+            None => return Ok((rest, lhs)),
+        };
+        let after = &trimmed[token.len()..];
+        // `.word 1 -2` is two values in SPIM, so a sign glued to the next operand after
+        // whitespace starts a new list element rather than continuing this expression
+        if (op == BinOp::Add || op == BinOp::Sub)
+            && trimmed.len() != rest.len()
+            && !after.starts_with(|c: char| c.is_whitespace())
+        {
+            return Ok((rest, lhs));
+        }
+        match operand(after.trim_start()) {
+            Ok((r, rhs)) => {
+                lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                rest = r;
+            }
+            Err(_) => return Ok((rest, lhs)),
+        }
+    }
 }
 
-pub fn register(input: &str) -> IResult<&str, &str> {
-    alt((register_named, register_numbered))(input)
+fn multiplicative_expression(input: &str) -> IResult<&str, Expr> {
+    binary_expression(
+        input,
+        unary_expression,
+        &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+    )
 }
 
-pub fn single_line_comment(input: &str) -> IResult<&str, &str> {
-    preceded(tag("#"), not_line_ending)(input)
+fn additive_expression(input: &str) -> IResult<&str, Expr> {
+    binary_expression(
+        input,
+        multiplicative_expression,
+        &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    )
 }
 
-pub fn entire_line_is_comment(input: &str) -> bool {
-    match input.get(0..1) {
-        Some(c) => c == "#",
-        None => false,
-    }
+fn shift_expression(input: &str) -> IResult<&str, Expr> {
+    binary_expression(
+        input,
+        additive_expression,
+        &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    )
 }
 
-pub fn new_label(input: &str) -> IResult<&str, Vec<&str>> {
-    terminated(identifier, tag(":"))(input)
+fn and_expression(input: &str) -> IResult<&str, Expr> {
+    binary_expression(input, shift_expression, &[("&", BinOp::And)])
 }
 
-pub fn directive(input: &str) -> IResult<&str, Vec<&str>> {
-    preceded(tag("."), identifier)(input)
+fn xor_expression(input: &str) -> IResult<&str, Expr> {
+    binary_expression(input, and_expression, &[("^", BinOp::Xor)])
 }
 
-pub fn comma_space(input: &str) -> IResult<&str, &str> {
-    preceded(tag(","), space0)(input)
+pub fn expression(input: &str) -> IResult<&str, Expr> {
+    binary_expression(input, xor_expression, &[("|", BinOp::Or)])
 }
 
 pub fn r_arithmetic_mnemonic(input: &str) -> IResult<&str, &str> {
-    alt((
-        tag("add"),
-        tag("addu"),
-        tag("and"),
-        tag("nor"),
-        tag("or"),
-        tag("slt"),
-        tag("sltu"),
-        tag("sub"),
-        tag("subu"),
-        tag("div"),
-        tag("divu"),
-    ))(input)
+    mnemonic(&[
+        "add", "addu", "and", "nor", "or", "slt", "sltu", "sub", "subu", "div", "divu",
+    ])(input)
 }
 
 pub fn r_shift_mnemonic(input: &str) -> IResult<&str, &str> {
-    alt((tag("sll"), tag("srl")))(input)
+    mnemonic(&["sll", "srl"])(input)
 }
 
 pub fn r_jump_mnemonic(input: &str) -> IResult<&str, &str> {
-    tag("jr")(input)
+    mnemonic(&["jr"])(input)
+}
+
+pub fn r_syscall_mnemonic(input: &str) -> IResult<&str, &str> {
+    mnemonic(&["syscall"])(input)
+}
+
+pub fn c0_move_mnemonic(input: &str) -> IResult<&str, &str> {
+    mnemonic(&["mfc0", "mtc0"])(input)
+}
+
+pub fn c0_eret_mnemonic(input: &str) -> IResult<&str, &str> {
+    mnemonic(&["eret"])(input)
 }
 
 pub fn i_arith_mnemonic(input: &str) -> IResult<&str, &str> {
-    alt((
-        tag("addi"),
-        tag("addiu"),
-        tag("andi"),
-        tag("ori"),
-        tag("slti"),
-        tag("sltiu"),
-    ))(input)
+    mnemonic(&["addi", "addiu", "andi", "ori", "slti", "sltiu"])(input)
 }
 
 pub fn i_branch_mnemonic(input: &str) -> IResult<&str, &str> {
-    alt((tag("beq"), tag("bne")))(input)
+    mnemonic(&["beq", "bne"])(input)
 }
 
 pub fn i_mem_mnemonic(input: &str) -> IResult<&str, &str> {
-    alt((
-        tag("lbu"),
-        tag("lhu"),
-        tag("ll"),
-        tag("lw"),
-        tag("sb"),
-        tag("sc"),
-        tag("sh"),
-        tag("sw"),
-    ))(input)
+    mnemonic(&["lbu", "lhu", "ll", "lw", "sb", "sc", "sh", "sw"])(input)
 }
 
 pub fn i_load_mnemonic(input: &str) -> IResult<&str, &str> {
-    alt((tag("li"), tag("lui"), tag("la")))(input)
+    mnemonic(&["li", "lui", "la"])(input)
 }
 
 pub fn j_mnemonic(input: &str) -> IResult<&str, &str> {
-    alt((tag("j"), tag("jal")))(input)
+    mnemonic(&["j", "jal"])(input)
 }
 
 pub fn r_arithmetic(input: &str) -> IResult<&str, (&str, &str, &str, &str)> {
     tuple((
         terminated(r_arithmetic_mnemonic, space1),
-        terminated(register, comma_space),
-        terminated(register, comma_space),
+        terminated(register, operand_separator),
+        terminated(register, operand_separator),
         register,
     ))(input)
 }
 
-pub fn r_shift(
-    input: &str,
-) -> IResult<&str, (&str, &str, &str, (Option<&str>, Result<i8, ParseIntError>))> {
+pub fn r_shift(input: &str) -> IResult<&str, (&str, &str, &str, Imm<'_, i8>)> {
     tuple((
         terminated(r_shift_mnemonic, space1),
-        terminated(register, comma_space),
-        terminated(register, comma_space),
+        terminated(register, operand_separator),
+        terminated(register, operand_separator),
         alt((parse_hex_int8, parse_dec_int8)),
     ))(input)
 }
 
+pub fn r_syscall(input: &str) -> IResult<&str, &str> {
+    r_syscall_mnemonic(input)
+}
+
 pub fn r_jump(input: &str) -> IResult<&str, (&str, &str)> {
     pair(terminated(r_jump_mnemonic, space1), register)(input)
 }
 
-pub fn i_arith(
-    input: &str,
-) -> IResult<&str, (&str, &str, &str, (Option<&str>, Result<i64, ParseIntError>))> {
+// the second register is coprocessor 0's, written like a general one: `mfc0 $k0, $13`
+pub fn c0_move(input: &str) -> IResult<&str, (&str, &str, &str)> {
+    tuple((
+        terminated(c0_move_mnemonic, space1),
+        terminated(register, operand_separator),
+        register,
+    ))(input)
+}
+
+pub fn c0_eret(input: &str) -> IResult<&str, &str> {
+    c0_eret_mnemonic(input)
+}
+
+pub fn i_arith(input: &str) -> IResult<&str, (&str, &str, &str, Imm<'_, i64>)> {
     tuple((
         terminated(i_arith_mnemonic, space1),
-        terminated(register, comma_space),
-        terminated(register, comma_space),
-        alt((parse_hex_int64, parse_dec_int64)),
+        terminated(register, operand_separator),
+        terminated(register, operand_separator),
+        integer64,
     ))(input)
 }
 
-pub fn i_branch_imm(
-    input: &str,
-) -> IResult<&str, (&str, &str, &str, (Option<&str>, Result<i64, ParseIntError>))> {
+pub fn i_branch_imm(input: &str) -> IResult<&str, (&str, &str, &str, Imm<'_, i64>)> {
     tuple((
         terminated(i_branch_mnemonic, space1),
-        terminated(register, comma_space),
-        terminated(register, comma_space),
-        alt((parse_hex_int64, parse_dec_int64)),
+        terminated(register, operand_separator),
+        terminated(register, operand_separator),
+        integer64,
     ))(input)
 }
 
-pub fn i_branch_label(input: &str) -> IResult<&str, (&str, &str, &str, Vec<&str>)> {
+pub fn i_branch_label(input: &str) -> IResult<&str, (&str, &str, &str, &str)> {
     tuple((
         terminated(i_branch_mnemonic, space1),
-        terminated(register, comma_space),
-        terminated(register, comma_space),
+        terminated(register, operand_separator),
+        terminated(register, operand_separator),
         identifier,
     ))(input)
 }
 
-pub fn i_mem_imm(
-    input: &str,
-) -> IResult<&str, (&str, &str, (Option<&str>, Result<i64, ParseIntError>), &str)> {
+pub type IMemImmResult<'a> = IResult<&'a str, (&'a str, &'a str, Option<Imm<'a, i64>>, &'a str)>;
+
+pub fn i_mem_imm(input: &str) -> IMemImmResult<'_> {
     tuple((
         terminated(i_mem_mnemonic, space1),
-        terminated(register, comma_space),
-        terminated(alt((parse_hex_int64, parse_dec_int64)), tag("(")),
-        terminated(register, tag(")")),
+        terminated(register, operand_separator),
+        terminated(opt(integer64), space0),
+        memory_operand,
     ))(input)
 }
 
-pub fn i_mem_label(input: &str) -> IResult<&str, (&str, &str, Vec<&str>, &str)> {
+pub fn i_mem_label(input: &str) -> IResult<&str, (&str, &str, &str, &str)> {
     tuple((
         terminated(i_mem_mnemonic, space1),
-        terminated(register, comma_space),
-        terminated(identifier, tag("(")),
-        terminated(register, tag(")")),
+        terminated(register, operand_separator),
+        terminated(identifier, space0),
+        memory_operand,
     ))(input)
 }
 
-pub fn i_load_imm(
-    input: &str,
-) -> IResult<&str, (&str, &str, (Option<&str>, Result<i64, ParseIntError>))> {
+pub fn i_load_imm(input: &str) -> IResult<&str, (&str, &str, Imm<'_, i64>)> {
     tuple((
         terminated(i_load_mnemonic, space1),
-        terminated(register, comma_space),
-        alt((parse_hex_int64, parse_dec_int64)),
+        terminated(register, operand_separator),
+        integer64,
     ))(input)
 }
 
-pub fn i_load_label(input: &str) -> IResult<&str, (&str, &str, Vec<&str>)> {
+pub fn i_load_label(input: &str) -> IResult<&str, (&str, &str, &str)> {
     tuple((
         terminated(i_load_mnemonic, space1),
-        terminated(register, comma_space),
+        terminated(register, operand_separator),
         identifier,
     ))(input)
 }
 
-pub fn j_label(input: &str) -> IResult<&str, (&str, Vec<&str>)> {
+pub fn j_label(input: &str) -> IResult<&str, (&str, &str)> {
     pair(terminated(j_mnemonic, space1), identifier)(input)
 }
 
-pub type DirectiveAlignResult<'a> = IResult<&'a str, (Option<&'a str>, Result<i64, ParseIntError>)>;
+pub type DirectiveAlignResult<'a> = IResult<&'a str, Imm<'a, i64>>;
 
-pub fn directive_align<'a>(input: &'a str) -> DirectiveAlignResult {
-    preceded(
-        tag("."),
-        preceded(
-            tag("align"),
-            preceded(space1, alt((parse_hex_int64, parse_dec_int64))),
-        ),
-    )(input)
+pub fn directive_align<'a>(input: &'a str) -> DirectiveAlignResult<'a> {
+    preceded(directive_name("align"), preceded(space1, integer64))(input)
+}
+
+pub type DirectiveDataResult<'a> = IResult<&'a str, Option<Imm<'a, i64>>>;
+
+pub fn directive_data<'a>(input: &'a str) -> DirectiveDataResult<'a> {
+    preceded(directive_name("data"), opt(preceded(space1, integer64)))(input)
 }
 
-pub type DirectiveDataResult<'a> =
-    IResult<&'a str, Option<(Option<&'a str>, Result<i64, ParseIntError>)>>;
+pub type DirectiveAsciiResult<'a> = IResult<&'a str, Vec<Vec<u8>>>;
 
-pub fn directive_data<'a>(input: &'a str) -> DirectiveDataResult {
+pub fn directive_ascii<'a>(input: &'a str) -> DirectiveAsciiResult<'a> {
     preceded(
-        tag("."),
+        directive_name("ascii"),
         preceded(
-            tag("data"),
-            opt(preceded(space1, alt((parse_hex_int64, parse_dec_int64)))),
+            space1,
+            separated_nonempty_list(operand_separator, string_literal),
         ),
     )(input)
 }
 
-pub type DirectiveAsciiResult<'a> = IResult<&'a str, &'a str>;
+pub type DirectiveAsciizResult<'a> = IResult<&'a str, Vec<Vec<u8>>>;
 
-pub fn directive_ascii<'a>(input: &'a str) -> DirectiveAsciiResult {
+pub fn directive_asciiz<'a>(input: &'a str) -> DirectiveAsciizResult<'a> {
     preceded(
-        tag("."),
-        preceded(tag("ascii"), preceded(space1, not_line_ending)),
+        directive_name("asciiz"),
+        preceded(
+            space1,
+            separated_nonempty_list(operand_separator, string_literal),
+        ),
     )(input)
 }
 
-pub type DirectiveAsciizResult<'a> = IResult<&'a str, &'a str>;
+pub type DirectiveByteResult<'a> = IResult<&'a str, Vec<Expr>>;
 
-pub fn directive_asciiz<'a>(input: &'a str) -> DirectiveAsciizResult {
+pub fn directive_byte<'a>(input: &'a str) -> DirectiveByteResult<'a> {
     preceded(
-        tag("."),
-        preceded(tag("asciiz"), preceded(space1, not_line_ending)),
+        directive_name("byte"),
+        preceded(
+            space1,
+            separated_nonempty_list(operand_separator, expression),
+        ),
     )(input)
 }
 
-pub type DirectiveByteResult<'a> =
-    IResult<&'a str, Vec<(Option<&'a str>, Result<i16, ParseIntError>)>>;
+pub type DirectiveHalfResult<'a> = IResult<&'a str, Vec<Expr>>;
 
-pub fn directive_byte<'a>(input: &'a str) -> DirectiveByteResult {
+pub fn directive_half<'a>(input: &'a str) -> DirectiveHalfResult<'a> {
     preceded(
-        tag("."),
+        directive_name("half"),
         preceded(
-            tag("byte"),
-            preceded(
-                space1,
-                separated_nonempty_list(
-                    alt((pair(tag(","), space0), pair(tag(""), space1))), // alt needs all options to return the same type
-                    alt((parse_hex_int16, parse_dec_int16)),
-                ),
-            ),
+            space1,
+            separated_nonempty_list(operand_separator, expression),
         ),
     )(input)
 }
 
-pub type DirectiveHalfResult<'a> =
-    IResult<&'a str, Vec<(Option<&'a str>, Result<i32, ParseIntError>)>>;
+pub type DirectiveWordResult<'a> = IResult<&'a str, Vec<Expr>>;
 
-pub fn directive_half<'a>(input: &'a str) -> DirectiveHalfResult {
+pub fn directive_word<'a>(input: &'a str) -> DirectiveWordResult<'a> {
     preceded(
-        tag("."),
+        directive_name("word"),
         preceded(
-            tag("half"),
-            preceded(
-                space1,
-                separated_nonempty_list(
-                    alt((pair(tag(","), space0), pair(tag(""), space1))), // alt needs all options to return the same type
-                    alt((parse_hex_int32, parse_dec_int32)),
-                ),
-            ),
+            space1,
+            separated_nonempty_list(operand_separator, expression),
         ),
     )(input)
 }
 
-pub type DirectiveWordResult<'a> =
-    IResult<&'a str, Vec<(Option<&'a str>, Result<i64, ParseIntError>)>>;
+pub type DirectiveDWordResult<'a> = IResult<&'a str, Vec<Expr>>;
 
-pub fn directive_word<'a>(input: &'a str) -> DirectiveWordResult {
+pub fn directive_dword<'a>(input: &'a str) -> DirectiveDWordResult<'a> {
     preceded(
-        tag("."),
+        directive_name("dword"),
         preceded(
-            tag("word"),
-            preceded(
-                space1,
-                separated_nonempty_list(
-                    alt((pair(tag(","), space0), pair(tag(""), space1))), // alt needs all options to return the same type
-                    alt((parse_hex_int64, parse_dec_int64)),
-                ),
-            ),
+            space1,
+            separated_nonempty_list(operand_separator, expression),
         ),
     )(input)
 }
 
-pub type DirectiveSpaceResult<'a> = IResult<&'a str, (Option<&'a str>, Result<i64, ParseIntError>)>;
+pub type DirectiveSpaceResult<'a> = IResult<&'a str, (Imm<'a, i64>, Option<Imm<'a, i64>>)>;
 
-pub fn directive_space<'a>(input: &'a str) -> DirectiveSpaceResult {
+pub fn directive_space<'a>(input: &'a str) -> DirectiveSpaceResult<'a> {
     preceded(
-        tag("."),
+        directive_name("space"),
         preceded(
-            tag("space"),
-            preceded(space1, alt((parse_hex_int64, parse_dec_int64))),
+            space1,
+            pair(integer64, opt(preceded(operand_separator, integer64))),
         ),
     )(input)
 }
 
-pub type DirectiveKDataResult<'a> =
-    IResult<&'a str, Option<(Option<&'a str>, Result<i64, ParseIntError>)>>;
-
-pub fn directive_kdata<'a>(input: &'a str) -> DirectiveKDataResult {
+pub fn directive_globl(input: &str) -> IResult<&str, Vec<&str>> {
     preceded(
-        tag("."),
+        alt((directive_name("globl"), directive_name("global"))),
         preceded(
-            tag("kdata"),
-            opt(preceded(space1, alt((parse_hex_int64, parse_dec_int64)))),
+            space1,
+            separated_nonempty_list(operand_separator, identifier),
         ),
     )(input)
 }
 
-pub type DirectiveKTextResult<'a> =
-    IResult<&'a str, Option<(Option<&'a str>, Result<i64, ParseIntError>)>>;
+pub type DirectiveExternResult<'a> = IResult<&'a str, (&'a str, Option<Imm<'a, i64>>)>;
 
-pub fn directive_ktext<'a>(input: &'a str) -> DirectiveKTextResult {
+pub fn directive_extern<'a>(input: &'a str) -> DirectiveExternResult<'a> {
     preceded(
-        tag("."),
+        directive_name("extern"),
         preceded(
-            tag("ktext"),
-            opt(preceded(space1, alt((parse_hex_int64, parse_dec_int64)))),
+            space1,
+            pair(identifier, opt(preceded(operand_separator, integer64))),
         ),
     )(input)
 }
 
-pub type DirectiveTextResult<'a> =
-    IResult<&'a str, Option<(Option<&'a str>, Result<i64, ParseIntError>)>>;
+pub fn directive_set(input: &str) -> IResult<&str, &str> {
+    preceded(directive_name("set"), preceded(space1, identifier))(input)
+}
 
-pub fn directive_text<'a>(input: &'a str) -> DirectiveTextResult {
+pub fn directive_ent(input: &str) -> IResult<&str, Option<&str>> {
     preceded(
-        tag("."),
+        alt((directive_name("ent"), directive_name("end"))),
+        opt(preceded(space1, identifier)),
+    )(input)
+}
+
+// Bookkeeping that compilers emit for debuggers and linkers; none of it changes the image.
+const ANNOTATIONS: [&str; 14] = [
+    "abicalls",
+    "file",
+    "fmask",
+    "frame",
+    "gnu_attribute",
+    "ident",
+    "insn",
+    "loc",
+    "mask",
+    "module",
+    "nan",
+    "option",
+    "size",
+    "type",
+];
+
+pub fn directive_annotation(input: &str) -> IResult<&str, &str> {
+    terminated(
         preceded(
-            tag("text"),
-            opt(preceded(space1, alt((parse_hex_int64, parse_dec_int64)))),
+            char('.'),
+            verify(identifier, |s: &str| {
+                ANNOTATIONS.iter().any(|a| a.eq_ignore_ascii_case(s))
+            }),
         ),
+        verify(rest, |r: &str| {
+            r.is_empty() || r.starts_with(char::is_whitespace)
+        }),
     )(input)
 }
 
+pub type DirectiveKDataResult<'a> = IResult<&'a str, Option<Imm<'a, i64>>>;
+
+pub fn directive_kdata<'a>(input: &'a str) -> DirectiveKDataResult<'a> {
+    preceded(directive_name("kdata"), opt(preceded(space1, integer64)))(input)
+}
+
+pub type DirectiveKTextResult<'a> = IResult<&'a str, Option<Imm<'a, i64>>>;
+
+pub fn directive_ktext<'a>(input: &'a str) -> DirectiveKTextResult<'a> {
+    preceded(directive_name("ktext"), opt(preceded(space1, integer64)))(input)
+}
+
+pub type DirectiveTextResult<'a> = IResult<&'a str, Option<Imm<'a, i64>>>;
+
+pub fn directive_text<'a>(input: &'a str) -> DirectiveTextResult<'a> {
+    preceded(directive_name("text"), opt(preceded(space1, integer64)))(input)
+}
+
 pub enum ParsedDirective<'a> {
     Align(DirectiveAlignResult<'a>),
     Ascii(DirectiveAsciiResult<'a>),
     Asciiz(DirectiveAsciizResult<'a>),
     Byte(DirectiveByteResult<'a>),
     Data(DirectiveDataResult<'a>),
+    DWord(DirectiveDWordResult<'a>),
     Half(DirectiveHalfResult<'a>),
     KData(DirectiveKDataResult<'a>),
     KText(DirectiveKTextResult<'a>),
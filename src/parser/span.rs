@@ -0,0 +1,98 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId(pub u32);
+
+/// Location of a parsed item: 1-based line, 1-based columns with an exclusive end.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span {
+    pub file: FileId,
+    pub line: u32,
+    pub start_column: u32,
+    pub end_column: u32,
+}
+
+impl Span {
+    pub fn new(file: FileId, line: u32, start_column: u32, end_column: u32) -> Span {
+        Span {
+            file,
+            line,
+            start_column,
+            end_column,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.start_column)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Spanned<T> {
+        Spanned { node, span }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub contents: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+    pub fn add(&mut self, name: &str, contents: &str) -> FileId {
+        self.files.push(SourceFile {
+            name: name.to_owned(),
+            contents: contents.to_owned(),
+        });
+        FileId((self.files.len() - 1) as u32)
+    }
+    pub fn files(&self) -> impl Iterator<Item = (FileId, &SourceFile)> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (FileId(i as u32), f))
+    }
+    pub fn file(&self, file: FileId) -> Option<&SourceFile> {
+        self.files.get(file.0 as usize)
+    }
+    pub fn name(&self, file: FileId) -> Option<&str> {
+        self.file(file).map(|f| f.name.as_str())
+    }
+    pub fn line(&self, file: FileId, line: u32) -> Option<&str> {
+        if line == 0 {
+            return None;
+        }
+        self.file(file)
+            .and_then(|f| f.contents.lines().nth(line as usize - 1))
+    }
+    pub fn snippet(&self, span: Span) -> Option<&str> {
+        let line = self.line(span.file, span.line)?;
+        line.get(
+            (span.start_column as usize).saturating_sub(1)
+                ..(span.end_column as usize).saturating_sub(1),
+        )
+    }
+    pub fn locate(&self, span: Span) -> String {
+        match self.name(span.file) {
+            Some(name) => format!("{}:{}", name, span),
+            None => span.to_string(),
+        }
+    }
+}
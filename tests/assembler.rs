@@ -0,0 +1,531 @@
+//! Assembles small programs and checks the words and files that come out against ones
+//! worked out by hand from the MIPS encoding.
+
+mod common;
+
+use std::panic;
+
+use common::assembled;
+use mips_rs::{
+    assembler::{
+        assembler::{assemble, Assembled, AssemblerOptions, Endian, SegmentKind},
+        dump::{dump_segment, read_dump, DumpFormat},
+        elf::write_executable,
+        listing::write_listing,
+        output::{write_images, OutputFormat, OutputOptions},
+    },
+    machine::{
+        dump::load_dump,
+        elf::load_elf,
+        register::Reg,
+        state::{State, Status},
+    },
+    parser::{
+        parser::{parse, parse_file},
+        span::SourceMap,
+    },
+};
+
+const MAX_STEPS: u64 = 1_000;
+
+// prints the words in `values` and exits
+const PRINT_VALUES: &str = "
+.data
+values: .word 7, -2, 0x12345678
+count:  .byte 3
+.text
+main:
+    la   $t0, values
+    la   $t1, count
+    lbu  $t1, 0($t1)
+loop:
+    lw   $a0, 0($t0)
+    addi $v0, $zero, 1
+    syscall
+    addi $t0, $t0, 4
+    addi $t1, $t1, -1
+    bne  $t1, $zero, loop
+    addi $v0, $zero, 10
+    syscall
+";
+
+// the parser reports bad lines by panicking, with the message naming the line
+fn rejected(source: &str) -> String {
+    let payload = panic::catch_unwind(|| parse(source)).unwrap_err();
+    payload.downcast_ref::<String>().unwrap().clone()
+}
+
+fn text_words(assembled: &Assembled) -> Vec<u32> {
+    let text = assembled
+        .segments
+        .iter()
+        .find(|s| s.kind == SegmentKind::Text)
+        .unwrap();
+    text.bytes
+        .chunks(4)
+        .map(|word| assembled.endian.read_u32(word))
+        .collect()
+}
+
+#[test]
+fn branch_encoding() {
+    let assembled = assembled(
+        "
+.text
+main:
+    beq  $t0 $t1 main
+    bne  $t3, $zero, main
+    beq  $a0, $v0, 3
+    bne  $s1, $t2, -1
+",
+    );
+    // the first register is rs, in bits 25-21
+    assert_eq!(
+        text_words(&assembled),
+        [0x1109_ffff, 0x1560_fffe, 0x1082_0003, 0x162a_ffff]
+    );
+}
+
+#[test]
+fn shift_amounts() {
+    let assembled = assembled(
+        "
+.text
+main:
+    sll  $t0, $t0, 31
+    srl  $t1, $t0, 0
+",
+    );
+    assert_eq!(text_words(&assembled), [0x0008_47c0, 0x0008_4802]);
+}
+
+#[test]
+#[should_panic(expected = "Shift amount must be from 0 to 31")]
+fn shift_amount_out_of_range() {
+    // 40 would spill into the rd field
+    assembled(".text\nmain:\n    sll  $t0, $t0, 40\n");
+}
+
+#[test]
+fn immediate_ranges() {
+    let assembled = assembled(
+        "
+.text
+main:
+    addi $t0, $t0, -32768
+    ori  $t0, $t0, 0xffff
+    lw   $t0, 32767($t1)
+    bne  $t0, $zero, -32768
+    lui  $t0, 0xffff
+",
+    );
+    assert_eq!(
+        text_words(&assembled),
+        [
+            0x2108_8000,
+            0x3508_ffff,
+            0x8d28_7fff,
+            0x1500_8000,
+            0x3c08_ffff
+        ]
+    );
+
+    for (source, range) in [
+        ("addi $t0, $t0, 70000", "-32768 to 32767"),
+        ("sltiu $t0, $t0, 32768", "-32768 to 32767"),
+        ("lw $t0, 0x10000($t1)", "-32768 to 32767"),
+        ("sw $t0, -32769($t1)", "-32768 to 32767"),
+        ("beq $t0, $t1, 32768", "-32768 to 32767"),
+        ("andi $t0, $t0, -1", "0 to 65535"),
+        ("ori $t0, $t0, 0x10000", "0 to 65535"),
+        ("lui $t0, 0x10000", "0 to 65535"),
+    ] {
+        let message = rejected(&format!(".text\nmain:\n    {}\n", source));
+        assert!(
+            message.starts_with(&format!("Immediate must be from {} at 3:", range)),
+            "{}: {}",
+            source,
+            message
+        );
+    }
+}
+
+#[test]
+fn elf_round_trip() {
+    for endian in [Endian::Big, Endian::Little] {
+        let options = AssemblerOptions {
+            endian,
+            ..AssemblerOptions::default()
+        };
+        let assembled = assemble(&parse(PRINT_VALUES), &options).unwrap();
+        let mut loaded = load_elf(&write_executable(&assembled).unwrap()).unwrap();
+        assert_eq!(loaded.memory().endian(), endian);
+        for segment in &assembled.segments {
+            assert_eq!(
+                loaded
+                    .memory()
+                    .read_bytes(segment.start_address, segment.bytes.len() as u32),
+                segment.bytes,
+                "{} segment",
+                String::from(segment.kind)
+            );
+        }
+        assert_eq!(loaded.read_pc(), assembled.entry_point());
+        for (name, address) in &assembled.labels {
+            assert_eq!(loaded.find_label_by_name(name), Some(*address));
+        }
+
+        let mut parsed = State::from_assembled(&assembled);
+        assert_eq!(parsed.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+        assert_eq!(loaded.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+        assert_eq!(loaded.take_output(), b"7-2305419896");
+        assert_eq!(parsed.take_output(), b"7-2305419896");
+    }
+}
+
+#[test]
+fn memory_images() {
+    // 0x20080001 and 0x0000000c, stored little-endian
+    let assembled = assembled(
+        "
+.text
+main:
+    addi $t0, $zero, 1
+    syscall
+",
+    );
+    let image = |format: OutputFormat| {
+        let options = OutputOptions {
+            format,
+            ..OutputOptions::default()
+        };
+        let mut images = write_images(&assembled, &options).unwrap();
+        assert_eq!(images.len(), 1);
+        let (name, contents) = images.remove(0);
+        assert_eq!(name, ".text");
+        contents
+    };
+    assert_eq!(image(OutputFormat::Binary), [1, 0, 8, 0x20, 0xc, 0, 0, 0]);
+    assert_eq!(
+        String::from_utf8(image(OutputFormat::IntelHex)).unwrap(),
+        ":020000040040BA\n\
+         :08000000010008200C000000C3\n\
+         :0400000500400000B7\n\
+         :00000001FF\n"
+    );
+    assert_eq!(
+        String::from_utf8(image(OutputFormat::SRecord)).unwrap(),
+        "S00800002E7465787404\n\
+         S30D00400000010008200C0000007D\n\
+         S5030001FB\n\
+         S70500400000BA\n"
+    );
+    assert_eq!(
+        String::from_utf8(image(OutputFormat::ReadMemH)).unwrap(),
+        "// .text at 0x00400000\n20080001\n0000000C\n"
+    );
+    assert_eq!(
+        String::from_utf8(image(OutputFormat::ReadMemB)).unwrap(),
+        "// .text at 0x00400000\n\
+         00100000000010000000000000000001\n\
+         00000000000000000000000000001100\n"
+    );
+    assert_eq!(
+        String::from_utf8(image(OutputFormat::Logisim)).unwrap(),
+        "v2.0 raw\n20080001 c\n"
+    );
+}
+
+#[test]
+fn mars_dumps() {
+    let assembled = assembled(".data\n.word 0x41424344, 1, -1\n");
+    let words = [0x4142_4344, 1, 0xffff_ffff];
+    let dump = |format: DumpFormat| dump_segment(&assembled, SegmentKind::Data, format).unwrap();
+    let text = |format: DumpFormat| String::from_utf8(dump(format)).unwrap();
+    assert_eq!(text(DumpFormat::HexText), "41424344\n00000001\nffffffff\n");
+    assert_eq!(
+        text(DumpFormat::BinaryText),
+        "01000001010000100100001101000100\n\
+         00000000000000000000000000000001\n\
+         11111111111111111111111111111111\n"
+    );
+    assert_eq!(
+        text(DumpFormat::AsciiText),
+        " A  B  C  D\n\\0 \\0 \\0  .\n .  .  .  .\n"
+    );
+    assert_eq!(
+        text(DumpFormat::HexTextWithAddresses),
+        "0x10010000    0x41424344 0x00000001 0xffffffff\n"
+    );
+    assert_eq!(
+        dump(DumpFormat::Binary),
+        [0x44, 0x43, 0x42, 0x41, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]
+    );
+
+    for format in [
+        DumpFormat::Binary,
+        DumpFormat::HexText,
+        DumpFormat::BinaryText,
+        DumpFormat::HexTextWithAddresses,
+    ] {
+        assert_eq!(
+            read_dump(&dump(format), format, 0x1001_0000),
+            Ok(vec![(0x1001_0000, words.to_vec())])
+        );
+        let mut state = State::new(Endian::Little);
+        load_dump(&mut state, &dump(format), format, 0x1001_0000, false).unwrap();
+        for (i, word) in words.iter().enumerate() {
+            assert_eq!(state.memory().read_u32(0x1001_0000 + 4 * i as u32), *word);
+        }
+    }
+    assert!(read_dump(&dump(DumpFormat::AsciiText), DumpFormat::AsciiText, 0).is_err());
+}
+
+#[test]
+fn symbol_sizes() {
+    let assembled = assembled(
+        "
+.data
+byte:   .byte 1
+        .align 2
+words:  .word 2, 3
+string: .asciiz \"hi\"
+half:   .half 4
+end:
+.text
+main:
+    addi $v0, $zero, 10
+    syscall
+",
+    );
+    let size = |name: &str| assembled.symbols.get(name).unwrap().size;
+    // neither the .align after `byte` nor the half's natural alignment count
+    assert_eq!(size("byte"), 1);
+    assert_eq!(size("words"), 8);
+    assert_eq!(size("string"), 3);
+    assert_eq!(size("half"), 2);
+    assert_eq!(size("end"), 0);
+    assert_eq!(size("main"), 8);
+}
+
+#[test]
+fn tolerant_syntax() {
+    // tabs, free whitespace, upper case, `0X` and negative hex, spaces inside the
+    // parentheses, missing commas, `;` between statements and every kind of comment
+    let assembled = assembled(
+        "
+.DATA
+s: .asciiz \"a # b // c /* d */ ; e\" // the string keeps its comment characters
+.text
+Start:\tADDI\t$T0 ,  $T1,-0x10
+    LW $t0, 4( $sp ) ; addi $t0 $zero 10 /* two statements */
+\t  add $t0,$t1,$t2 # and a comment
+    ORI $t0, $t0, 0XFF  /* one
+    spanning lines */ sll $t0, $t0, 2
+",
+    );
+    assert_eq!(
+        text_words(&assembled),
+        [
+            0x2128_fff0,
+            0x8fa8_0004,
+            0x2008_000a,
+            0x012a_4020,
+            0x3508_00ff,
+            0x0008_4080
+        ]
+    );
+    let data = assembled
+        .segments
+        .iter()
+        .find(|s| s.kind == SegmentKind::Data)
+        .unwrap();
+    assert_eq!(data.bytes, b"a # b // c /* d */ ; e\0");
+    assert_eq!(assembled.labels["Start"], 0x0040_0000);
+}
+
+#[test]
+fn trailing_junk() {
+    assert_eq!(
+        rejected(".text\nmain: add $t0, $t1, $t2 junk\n"),
+        "Unexpected trailing characters at 2:25: junk"
+    );
+    assert_eq!(
+        rejected(".text\n    addi $t0, $t1, 1 2\n"),
+        "Unexpected trailing characters at 2:22: 2"
+    );
+    // a statement after `;` is checked like any other, with columns from the line
+    assert_eq!(
+        rejected(".text\n    add $t0, $t1, $t2; sub $t0, $t1, $t2 junk\n"),
+        "Unexpected trailing characters at 2:42: junk"
+    );
+}
+
+#[test]
+fn listing() {
+    // halfwords and doublewords are listed as numbers, bytes as they're stored, and only
+    // the pseudo-instructions as expansions, even with two statements on a line
+    let source = "\
+.data
+h: .half 3, 0x1234
+b: .byte 1, 2, 3, 4, 5
+d: .dword 0x1122334455667788
+w: .word 7
+.text
+main:
+    li   $t0, 0x12345678
+    add  $t0, $t1, $t2; sub $t0, $t1, $t2
+    li   $t1, 5; la $t2, w
+";
+    let mut sources = SourceMap::new();
+    let file = sources.add("list.s", source);
+    let assembled = assemble(&parse_file(source, file), &AssemblerOptions::default()).unwrap();
+    let listing = write_listing(&assembled, &sources);
+    let body: Vec<&str> = listing
+        .lines()
+        .skip_while(|l| !l.starts_with("Address"))
+        .skip(1)
+        .take_while(|l| !l.is_empty())
+        .collect();
+    assert_eq!(
+        body,
+        [
+            "                          1  .data",
+            "0x10010000 0x0003         2  h: .half 3, 0x1234",
+            "0x10010002 0x1234",
+            "0x10010004 0x01020304     3  b: .byte 1, 2, 3, 4, 5",
+            "0x10010008 0x05",
+            "0x10010010 0x1122334455667788     4  d: .dword 0x1122334455667788",
+            "0x10010018 0x00000007     5  w: .word 7",
+            "                          6  .text",
+            "                          7  main:",
+            "                          8      li   $t0, 0x12345678",
+            "0x00400000 0x3c011234            lui $at, 0x1234",
+            "0x00400004 0x34285678            ori $t0, $at, 0x5678",
+            "0x00400008 0x012a4020     9      add  $t0, $t1, $t2; sub $t0, $t1, $t2",
+            "0x0040000c 0x012a4022        sub $t0, $t1, $t2",
+            "                         10      li   $t1, 5; la $t2, w",
+            "0x00400010 0x24090005            addiu $t1, $zero, 5",
+            "                             la $t2, w",
+            "0x00400014 0x3c011001            lui $at, 0x1001",
+            "0x00400018 0x242a0018            addiu $t2, $at, 24",
+        ]
+    );
+}
+
+// a jump table and data made from labels
+const LABELS_IN_DATA: &str = "
+.data
+table: .word case0, case1, case2
+here:  .word here, here + 8, table - 4, (end - table) / 4, main << 1
+h:     .half h, end - table, h + 2
+end:
+.text
+main:
+    la   $t0, table
+    lw   $t1, 4($t0)
+    jr   $t1
+case0:
+    addi $s0, $zero, 10
+case1:
+    addi $s0, $zero, 11
+    addi $v0, $zero, 10
+    syscall
+case2:
+    addi $s0, $zero, 12
+";
+
+#[test]
+fn data_relocations() {
+    let assembled = assembled(LABELS_IN_DATA);
+    let data = assembled
+        .segments
+        .iter()
+        .find(|s| s.kind == SegmentKind::Data)
+        .unwrap();
+    let words: Vec<u32> = data.bytes[..32]
+        .chunks(4)
+        .map(|w| assembled.endian.read_u32(w))
+        .collect();
+    let halfs: Vec<u16> = data.bytes[32..]
+        .chunks(2)
+        .map(|h| assembled.endian.read_u16(h))
+        .collect();
+    // la takes two words, so case0 starts at 0x00400010; case1 is three long
+    assert_eq!(words[..3], [0x0040_0010, 0x0040_0014, 0x0040_0020]);
+    // here is at 0x1001000c and end at 0x10010026, 38 bytes after table
+    assert_eq!(
+        words[3..],
+        [0x1001_000c, 0x1001_0014, 0x1000_fffc, 9, 0x0080_0000]
+    );
+    // a halfword keeps the low 16 bits of an address
+    assert_eq!(halfs, [0x0020, 0x0026, 0x0022]);
+
+    // the program jumps through the second entry
+    let mut state = State::from_assembled(&assembled);
+    assert_eq!(state.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+    assert_eq!(state.read_reg(Reg::s0), 11);
+}
+
+#[test]
+fn data_alignment() {
+    let assembled = assembled(
+        "
+.data
+a: .byte 1
+b: .half 2
+c: .byte 3
+d: .word 4
+e: .byte 5
+f: .dword 6
+g: .space 3
+i: .word 7
+   .align 3
+j: .byte 8
+   .align 0
+k: .half 9
+l: .word 10
+m: .space 2
+n: .byte 11
+",
+    );
+    // each item goes to its natural alignment and its label with it, until `.align 0`
+    let offsets: Vec<(&str, u32)> = [
+        "a", "b", "c", "d", "e", "f", "g", "i", "j", "k", "l", "m", "n",
+    ]
+    .iter()
+    .map(|l| (*l, assembled.labels[*l] - 0x1001_0000))
+    .collect();
+    assert_eq!(
+        offsets,
+        [
+            ("a", 0x00),
+            ("b", 0x02),
+            ("c", 0x04),
+            ("d", 0x08),
+            ("e", 0x0c),
+            ("f", 0x10),
+            ("g", 0x18),
+            ("i", 0x1c),
+            ("j", 0x20),
+            ("k", 0x21),
+            ("l", 0x23),
+            ("m", 0x27),
+            ("n", 0x29),
+        ]
+    );
+    // padding and space are zeros; everything is little endian
+    let data = assembled
+        .segments
+        .iter()
+        .find(|s| s.kind == SegmentKind::Data)
+        .unwrap();
+    assert_eq!(
+        data.bytes,
+        [
+            1, 0, 2, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0, //
+            6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, //
+            8, 9, 0, 10, 0, 0, 0, 0, 0, 11,
+        ]
+    );
+}
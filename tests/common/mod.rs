@@ -0,0 +1,18 @@
+//! Helpers shared by the integration tests. Each test crate uses a different subset.
+#![allow(dead_code)]
+
+use mips_rs::{
+    assembler::assembler::{assemble, Assembled, AssemblerOptions},
+    machine::state::State,
+    parser::parser::parse,
+};
+
+/// Assembles `source` with the default options.
+pub fn assembled(source: &str) -> Assembled {
+    assemble(&parse(source), &AssemblerOptions::default()).unwrap()
+}
+
+/// A machine loaded with `source`, ready to run from `main`.
+pub fn machine(source: &str) -> State {
+    State::from_assembled(&assembled(source))
+}
@@ -0,0 +1,310 @@
+//! Drives watchpoints and breakpoints over a small program and checks where they stop
+//! and what they saw, worked out by hand, and runs a scripted debugger session.
+
+mod common;
+
+use std::convert::TryFrom;
+
+use common::machine;
+use mips_rs::{
+    debugger::Debugger,
+    machine::{
+        register::Reg,
+        state::{Access, Event, State, Status},
+        watch::{Condition, Hit, Trigger, Watchpoints},
+    },
+};
+
+// adds 1, 2, 3, 4 and 5 into x, which holds 1, 3, 6, 10 and 15 in turn
+const ACCUMULATE: &str = "
+.data
+x: .word 0
+.text
+main:
+    lui  $s0, 0x1001
+    addi $t0, $zero, 0
+loop:
+    addi $t0, $t0, 1
+    lw   $t1, 0($s0)
+    add  $t1, $t1, $t0
+    sw   $t1, 0($s0)
+    slti $t2, $t0, 5
+    bne  $t2, $zero, loop
+    addi $v0, $zero, 10
+    syscall
+";
+
+// calls a function that doubles its argument
+const CALL: &str = "
+.text
+main:
+    addi $a0, $zero, 5
+    jal  double
+    addi $s0, $v0, 1
+    addi $v0, $zero, 10
+    syscall
+double:
+    add  $v0, $a0, $a0
+    jr   $ra
+";
+
+const X: u32 = 0x1001_0000;
+const LW: u32 = 0x0040_000c;
+const SW: u32 = 0x0040_0014;
+
+// steps until a watchpoint stops the program, giving the address of the instruction
+// that set it off
+fn next_hit(state: &mut State, watchpoints: &mut Watchpoints) -> Option<(u32, Hit)> {
+    while state.status() == Status::Running {
+        let pc = state.read_pc();
+        if let Some(hit) = watchpoints.step(state).unwrap() {
+            return Some((pc, hit));
+        }
+    }
+    None
+}
+
+fn memory(write: bool, value: u32) -> Option<Event> {
+    Some(Event::Memory(Access {
+        address: X,
+        size: 4,
+        write,
+        value: Some(value),
+    }))
+}
+
+#[test]
+fn read_and_write_watchpoints() {
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let read = watchpoints.add(
+        Trigger::Read {
+            address: X,
+            length: 4,
+        },
+        None,
+    );
+    let hit = |event| Hit { id: read, event };
+    assert_eq!(
+        next_hit(&mut state, &mut watchpoints),
+        Some((LW, hit(memory(false, 0))))
+    );
+    // the store in between isn't a read
+    assert_eq!(
+        next_hit(&mut state, &mut watchpoints),
+        Some((LW, hit(memory(false, 1))))
+    );
+    assert_eq!(state.read_reg(Reg::t0), 2);
+
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let write = watchpoints.add(
+        Trigger::Write {
+            address: X,
+            length: 4,
+        },
+        None,
+    );
+    let hit = |event| Hit { id: write, event };
+    assert_eq!(
+        next_hit(&mut state, &mut watchpoints),
+        Some((SW, hit(memory(true, 1))))
+    );
+    assert_eq!(
+        next_hit(&mut state, &mut watchpoints),
+        Some((SW, hit(memory(true, 3))))
+    );
+    assert_eq!(watchpoints.get(write).unwrap().hits, 2);
+}
+
+#[test]
+fn access_watchpoints() {
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    // one byte in the middle of x, which the word accesses cover
+    let access = watchpoints.add(
+        Trigger::Access {
+            address: X + 2,
+            length: 1,
+        },
+        None,
+    );
+    let stops: Vec<u32> = (0..4)
+        .map(|_| next_hit(&mut state, &mut watchpoints).unwrap().0)
+        .collect();
+    assert_eq!(stops, [LW, SW, LW, SW]);
+
+    while next_hit(&mut state, &mut watchpoints).is_some() {}
+    // five loads and five stores in all
+    assert_eq!(watchpoints.get(access).unwrap().hits, 10);
+
+    // nothing touches the word after x
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let beside = watchpoints.add(
+        Trigger::Access {
+            address: X + 4,
+            length: 4,
+        },
+        None,
+    );
+    assert_eq!(watchpoints.run(&mut state, None), Ok(None));
+    assert_eq!(state.status(), Status::Exited(0));
+    assert_eq!(watchpoints.get(beside).unwrap().hits, 0);
+}
+
+#[test]
+fn conditions() {
+    // only the store made with $t0 == 3 stops, and only it counts as a hit
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let write = watchpoints.add(
+        Trigger::Write {
+            address: X,
+            length: 4,
+        },
+        Some(Condition::try_from("$t0 == 3").unwrap()),
+    );
+    assert_eq!(
+        next_hit(&mut state, &mut watchpoints),
+        Some((
+            SW,
+            Hit {
+                id: write,
+                event: memory(true, 6)
+            }
+        ))
+    );
+    assert_eq!(watchpoints.get(write).unwrap().hits, 1);
+    assert_eq!(next_hit(&mut state, &mut watchpoints), None);
+
+    // a conditional breakpoint on the store, checked before it runs
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let breakpoint = watchpoints.add(
+        Trigger::Execute(SW),
+        Some(Condition::try_from("$t0 == 3").unwrap()),
+    );
+    assert_eq!(
+        watchpoints.run(&mut state, None),
+        Ok(Some(Hit {
+            id: breakpoint,
+            event: None
+        }))
+    );
+    assert_eq!(state.read_pc(), SW);
+    assert_eq!(state.read_reg(Reg::t1), 6);
+    assert_eq!(state.memory().read_u32(X), 3);
+
+    // an expression becoming true stops after the store that makes it so
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let becomes = watchpoints.add(
+        Trigger::Becomes(Condition::try_from("mem[x] > 5").unwrap()),
+        None,
+    );
+    assert_eq!(
+        next_hit(&mut state, &mut watchpoints),
+        Some((
+            SW,
+            Hit {
+                id: becomes,
+                event: None
+            }
+        ))
+    );
+    assert_eq!(state.memory().read_u32(X), 6);
+    // and doesn't fire again while it stays true
+    assert_eq!(next_hit(&mut state, &mut watchpoints), None);
+}
+
+#[test]
+fn ignore_counts() {
+    // skipping two stores stops on the third, with $t0 == 3; the skipped ones still count
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let write = watchpoints.add(
+        Trigger::Write {
+            address: X,
+            length: 4,
+        },
+        None,
+    );
+    watchpoints.get_mut(write).unwrap().ignore = 2;
+    assert_eq!(
+        next_hit(&mut state, &mut watchpoints),
+        Some((
+            SW,
+            Hit {
+                id: write,
+                event: memory(true, 6)
+            }
+        ))
+    );
+    assert_eq!(state.read_reg(Reg::t0), 3);
+    let w = watchpoints.get(write).unwrap();
+    assert_eq!((w.hits, w.ignore), (3, 0));
+
+    // the ignore count only runs down on hits whose condition holds
+    let mut state = machine(ACCUMULATE);
+    let mut watchpoints = Watchpoints::new();
+    let breakpoint = watchpoints.add(
+        Trigger::Execute(LW),
+        Some(Condition::try_from("$t0 >= 2").unwrap()),
+    );
+    watchpoints.get_mut(breakpoint).unwrap().ignore = 1;
+    assert_eq!(
+        watchpoints.run(&mut state, None).unwrap().map(|h| h.id),
+        Some(breakpoint)
+    );
+    assert_eq!(state.read_reg(Reg::t0), 3);
+    assert_eq!(watchpoints.get(breakpoint).unwrap().hits, 2);
+}
+
+#[test]
+fn script() {
+    // a scripted session echoes each command after the prompt
+    let mut debugger = Debugger::new(machine(CALL));
+    let script = "\
+break double
+continue
+print $a0
+step
+print $v0
+finish
+step
+print $s0
+continue
+quit
+";
+    let mut out = Vec::new();
+    debugger
+        .repl(&mut script.as_bytes(), &mut out, true)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+0x00400000: 20040005  addi $a0, $zero, 5
+(mips) break double
+Breakpoint 1 at 0x00400014 (double)
+(mips) continue
+Breakpoint 1, double
+0x00400014: 00841020  add $v0, $a0, $a0
+(mips) print $a0
+$a0 = 0x00000005 (5)
+(mips) step
+0x00400018: 03e00008  jr $ra
+(mips) print $v0
+$v0 = 0x0000000a (10)
+(mips) finish
+0x00400008: 20500001  addi $s0, $v0, 1
+(mips) step
+0x0040000c: 2002000a  addi $v0, $zero, 10
+(mips) print $s0
+$s0 = 0x0000000b (11)
+(mips) continue
+Program exited with code 0
+(mips) quit
+"
+    );
+}
@@ -0,0 +1,401 @@
+//! Runs small programs against the memory-mapped devices: the keyboard and display
+//! with scripted keys, interrupts from the timers counting instructions, the
+//! bitmap display, and snapshots that carry the devices with them.
+
+mod common;
+
+use std::{convert::TryFrom, env, fs, process::Command};
+
+use mips_rs::machine::{
+    bitmap::{self, BitmapConfig, ImageFormat},
+    cp0::{self, ClockRate},
+    device::{
+        InterruptController, Terminal, Timer, CONTROLLER_ADDRESS, CONTROLLER_SIZE,
+        DISPLAY_INTERRUPT, KEYBOARD_INTERRUPT, TERMINAL_ADDRESS, TERMINAL_SIZE, TIMER_ADDRESS,
+        TIMER_SIZE,
+    },
+    memory::Memory,
+    register::Reg,
+    state::{State, Status},
+};
+
+const MAX_STEPS: u64 = 10_000;
+
+// echoes keys until a newline, polling both ready bits
+const ECHO: &str = "
+.text
+main:
+    lui  $t0, 0xffff
+wait_key:
+    lw   $t1, 0($t0)
+    andi $t1, $t1, 1
+    beq  $t1, $zero, wait_key
+    lw   $t2, 4($t0)
+wait_display:
+    lw   $t1, 8($t0)
+    andi $t1, $t1, 1
+    beq  $t1, $zero, wait_display
+    sw   $t2, 12($t0)
+    addi $t3, $zero, 10
+    bne  $t2, $t3, wait_key
+    addi $v0, $zero, 10
+    syscall
+";
+
+// counts polls until the first key, then exits with the count in $s0
+const COUNT_POLLS: &str = "
+.text
+main:
+    lui  $t0, 0xffff
+poll:
+    addi $s0, $s0, 1
+    lw   $t1, 0($t0)
+    andi $t1, $t1, 1
+    beq  $t1, $zero, poll
+    addi $v0, $zero, 10
+    syscall
+";
+
+// lets the controller's line 0 through, then waits for three timer interrupts
+const TICKS: &str = "
+.text
+main:
+    lui  $t0, 0xffff
+    addi $t1, $zero, 1
+    sw   $t1, 36($t0)
+    ori  $t1, $zero, 0x0401
+    mtc0 $t1, $12
+    addi $t2, $zero, 3
+wait:
+    bne  $s0, $t2, wait
+    addi $v0, $zero, 10
+    syscall
+
+.ktext 0x80000180
+    lui  $k0, 0xffff
+    addi $k1, $zero, 3
+    sw   $k1, 16($k0)
+    addi $s0, $s0, 1
+    mfc0 $s1, $9
+    mfc0 $s2, $14
+    eret
+";
+
+// takes the Count/Compare interrupt and exits from the handler
+const COMPARE: &str = "
+.text
+main:
+    addi $t1, $zero, 50
+    mtc0 $t1, $11
+    ori  $t1, $zero, 0x8001
+    mtc0 $t1, $12
+wait:
+    j    wait
+
+.ktext 0x80000180
+    mfc0 $s0, $9
+    mfc0 $s1, $13
+    addi $v0, $zero, 10
+    syscall
+";
+
+// three units down the diagonal of an 8x4 frame buffer: red, green and blue
+const DIAGONAL: &str = "
+.data
+frame: .space 128
+.text
+main:
+    lui  $t0, 0x1001
+    lui  $t1, 0x00ff
+    sw   $t1, 0($t0)
+    ori  $t1, $zero, 0xff00
+    sw   $t1, 36($t0)
+    addi $t1, $zero, 255
+    sw   $t1, 72($t0)
+    addi $v0, $zero, 10
+    syscall
+";
+
+// prints a string from .data and loads its first word into $s0
+const GREETING: &str = "
+.data
+msg: .asciiz \"hi\\n\"
+.text
+main:
+    la   $a0, msg
+    addi $v0, $zero, 4
+    syscall
+    lw   $s0, 0($a0)
+    add  $a0, $s0, $zero
+    addi $v0, $zero, 34
+    syscall
+    addi $v0, $zero, 10
+    syscall
+";
+
+fn machine(source: &str, terminal: Terminal) -> State {
+    let mut state = common::machine(source);
+    state
+        .memory_mut()
+        .attach(TERMINAL_ADDRESS, TERMINAL_SIZE, Box::new(terminal))
+        .unwrap();
+    state
+}
+
+#[test]
+fn echo() {
+    let mut terminal = Terminal::default();
+    terminal.type_at(0, b"polled\n");
+    let mut state = machine(ECHO, terminal);
+    assert_eq!(state.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+    assert_eq!(state.take_output(), b"polled\n");
+}
+
+#[test]
+fn keys_arrive_on_time() {
+    let mut terminal = Terminal::default();
+    terminal.type_at(100, b"x");
+    let mut state = machine(COUNT_POLLS, terminal);
+    assert_eq!(state.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+    // polls load the control register at steps 3, 7, 11, ... and the key turns up after
+    // the 100th
+    assert_eq!(state.read_reg(Reg::s0), 26);
+}
+
+#[test]
+fn looking_does_not_take_a_key() {
+    let mut terminal = Terminal::default();
+    terminal.type_at(0, b"ab");
+    let mut state = machine(ECHO, terminal);
+    state.step().unwrap();
+    let memory = state.memory();
+    assert_eq!(memory.read_u32(TERMINAL_ADDRESS), 1);
+    assert_eq!(memory.read_u32(TERMINAL_ADDRESS + 4), u32::from(b'a'));
+    assert_eq!(memory.read_u32(TERMINAL_ADDRESS + 4), u32::from(b'a'));
+    assert_eq!(
+        memory.read_device(TERMINAL_ADDRESS + 4, 4),
+        Some(u32::from(b'a'))
+    );
+    assert_eq!(memory.read_u32(TERMINAL_ADDRESS + 4), u32::from(b'b'));
+}
+
+#[test]
+fn interrupts() {
+    let mut state = machine(ECHO, Terminal::new(2));
+    let memory = state.memory_mut();
+    assert_eq!(memory.interrupts(), 0);
+    // the display is ready, so enabling its interrupt raises it straight away
+    memory.write_u32(TERMINAL_ADDRESS + 8, 2);
+    assert_eq!(memory.interrupts(), DISPLAY_INTERRUPT);
+    memory.write_u32(TERMINAL_ADDRESS + 12, u32::from(b'!'));
+    assert_eq!(memory.interrupts(), 0);
+    // dropped, as the display is busy
+    memory.write_u32(TERMINAL_ADDRESS + 12, u32::from(b'?'));
+    memory.write_u32(TERMINAL_ADDRESS, 2);
+    memory.device_mut(TERMINAL_ADDRESS).unwrap().feed(b"k");
+    assert_eq!(memory.interrupts(), KEYBOARD_INTERRUPT);
+    assert_eq!(memory.tick_devices(), b"");
+    assert_eq!(memory.tick_devices(), b"!");
+    assert_eq!(memory.interrupts(), KEYBOARD_INTERRUPT | DISPLAY_INTERRUPT);
+    assert_eq!(
+        memory.read_device(TERMINAL_ADDRESS + 4, 1),
+        Some(u32::from(b'k'))
+    );
+    assert_eq!(memory.interrupts(), DISPLAY_INTERRUPT);
+}
+
+#[test]
+fn timer_through_the_controller() {
+    let mut state = machine(TICKS, Terminal::default());
+    let memory = state.memory_mut();
+    memory
+        .attach(
+            TIMER_ADDRESS,
+            TIMER_SIZE,
+            Box::new(Timer::new(100, ClockRate::Instructions)),
+        )
+        .unwrap();
+    memory
+        .attach(
+            CONTROLLER_ADDRESS,
+            CONTROLLER_SIZE,
+            Box::new(InterruptController::default()),
+        )
+        .unwrap();
+    memory.connect(TIMER_ADDRESS, 0).unwrap();
+    assert_eq!(state.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+    assert_eq!(state.read_reg(Reg::s0), 3);
+    // the third interrupt came as the 300th instruction finished
+    assert_eq!(state.read_reg(Reg::s1), 304);
+    assert_eq!(
+        state.read_reg(Reg::s2),
+        state.find_label_by_name("wait").unwrap()
+    );
+    assert_eq!(state.read_cp0(cp0::STATUS) & cp0::STATUS_EXL, 0);
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let mut terminal = Terminal::default();
+    terminal.type_at(0, b"ab");
+    let mut state = machine(TICKS, terminal);
+    let memory = state.memory_mut();
+    memory
+        .attach(
+            TIMER_ADDRESS,
+            TIMER_SIZE,
+            Box::new(Timer::new(100, ClockRate::Instructions)),
+        )
+        .unwrap();
+    memory
+        .attach(
+            CONTROLLER_ADDRESS,
+            CONTROLLER_SIZE,
+            Box::new(InterruptController::default()),
+        )
+        .unwrap();
+    memory.connect(TIMER_ADDRESS, 0).unwrap();
+    // one interrupt in, with the timer part of the way to the next
+    assert_eq!(state.run(Some(150)), Ok(Status::Running));
+    assert_eq!(state.read_reg(Reg::s0), 1);
+
+    let mut restored = State::from_snapshot(&state.snapshot()).unwrap();
+    assert_eq!(restored.snapshot(), state.snapshot());
+    for r in [cp0::COUNT, cp0::STATUS, cp0::CAUSE, cp0::EPC] {
+        assert_eq!(restored.read_cp0(r), state.read_cp0(r));
+    }
+    assert_ne!(restored.read_cp0(cp0::STATUS), 0);
+    for address in [TIMER_ADDRESS, TIMER_ADDRESS + 8, CONTROLLER_ADDRESS + 4] {
+        assert_eq!(
+            restored.memory().read_u32(address),
+            state.memory().read_u32(address)
+        );
+    }
+    // the first key is waiting to be read and the second is still queued
+    for key in b"ab" {
+        assert_eq!(
+            restored.memory().read_device(TERMINAL_ADDRESS + 4, 4),
+            Some(u32::from(*key))
+        );
+    }
+
+    assert_eq!(state.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+    assert_eq!(restored.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+    assert_eq!(restored.steps(), state.steps());
+    for r in [Reg::s0, Reg::s1, Reg::s2] {
+        assert_eq!(restored.read_reg(r), state.read_reg(r));
+    }
+    assert_eq!(restored.read_reg(Reg::s1), 304);
+}
+
+#[test]
+fn count_reaches_compare() {
+    let mut state = machine(COMPARE, Terminal::default());
+    assert_eq!(state.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+    assert_eq!(state.read_reg(Reg::s0), 50);
+    assert_eq!(
+        state.read_reg(Reg::s1),
+        cp0::CAUSE_TI | cp0::TIMER_INTERRUPT
+    );
+    assert_ne!(state.read_cp0(cp0::STATUS) & cp0::STATUS_EXL, 0);
+}
+
+#[test]
+fn bitmap_display() {
+    let config = BitmapConfig::try_from("64x32,unit=8,base=data").unwrap();
+    assert_eq!((config.columns(), config.rows()), (8, 4));
+    let mut state = machine(DIAGONAL, Terminal::default());
+    assert_eq!(state.run(Some(MAX_STEPS)), Ok(Status::Exited(0)));
+
+    let image = bitmap::render(state.memory(), &config).unwrap();
+    let pixel = |x: usize, y: usize| &image[(y * 64 + x) * 3..][..3];
+    assert_eq!(pixel(0, 0), [0xff, 0, 0]);
+    assert_eq!(pixel(7, 7), [0xff, 0, 0]);
+    assert_eq!(pixel(8, 8), [0, 0xff, 0]);
+    assert_eq!(pixel(23, 23), [0, 0, 0xff]);
+    assert_eq!(pixel(24, 24), [0, 0, 0]);
+    assert_eq!(pixel(8, 0), [0, 0, 0]);
+
+    let ppm = bitmap::encode(state.memory(), &config, ImageFormat::Ppm).unwrap();
+    assert!(ppm.starts_with(b"P6\n64 32\n255\n"));
+    assert!(ppm.ends_with(&image));
+    let png = bitmap::encode(state.memory(), &config, ImageFormat::Png).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+}
+
+#[test]
+fn bitmap_settings() {
+    assert_eq!(
+        BitmapConfig::try_from("256x256,unit=16x8,base=0x10040000"),
+        Ok(BitmapConfig {
+            base: 0x1004_0000,
+            width: 256,
+            height: 256,
+            unit_width: 16,
+            unit_height: 8,
+        })
+    );
+    assert!(BitmapConfig::try_from("100x64,unit=8").is_err());
+    assert!(BitmapConfig::try_from("64x64,base=0x10010002").is_err());
+    assert!(BitmapConfig::try_from("64x64,colour=rgb").is_err());
+
+    let huge = BitmapConfig {
+        base: 0,
+        width: u32::MAX,
+        height: u32::MAX,
+        unit_width: 1,
+        unit_height: 1,
+    };
+    assert_eq!(huge.size(), u64::MAX);
+    let wide = BitmapConfig { height: 1, ..huge };
+    assert_eq!(wide.size(), u64::from(u32::MAX) * 4);
+
+    // configs built by hand are checked before drawing rather than overflowing or
+    // dividing by zero
+    let memory = Memory::default();
+    assert!(bitmap::render(&memory, &huge).is_err());
+    assert!(bitmap::render(&memory, &wide).is_err());
+    let no_units = BitmapConfig {
+        unit_width: 0,
+        ..BitmapConfig::default()
+    };
+    assert_eq!(no_units.columns(), 0);
+    assert_eq!(
+        bitmap::render(&memory, &no_units),
+        Err("Bitmap units can't be empty".to_owned())
+    );
+    let big = BitmapConfig {
+        width: 32768,
+        height: 32768,
+        unit_width: 16,
+        unit_height: 16,
+        ..BitmapConfig::default()
+    };
+    assert_eq!(
+        bitmap::encode(&memory, &big, ImageFormat::Png),
+        Err("A 32768x32768 display is too big to draw".to_owned())
+    );
+}
+
+#[test]
+fn bitmap_over_data() {
+    // the default frame buffer starts at .data, which the program still has to see
+    let dir = env::temp_dir().join(format!("mips-rs-bitmap-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (source, image) = (dir.join("greeting.s"), dir.join("greeting.ppm"));
+    fs::write(&source, GREETING).unwrap();
+    let run = Command::new(env!("CARGO_BIN_EXE_mips-rs"))
+        .args(["run", "--bitmap", "8x4", "--bitmap-out"])
+        .arg(&image)
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+    assert_eq!(run.stdout, b"hi\n0x000a6968");
+
+    let ppm = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let pixels = ppm.strip_prefix(b"P6\n8 4\n255\n").unwrap();
+    assert_eq!(pixels[..3], [0x0a, 0x69, 0x68]);
+    assert!(pixels[3..].iter().all(|b| *b == 0));
+}
@@ -0,0 +1,334 @@
+//! Runs every `tests/*.s` and compares where it ends up with `tests/golden/<name>.txt`:
+//! how it stopped, the registers, its data segments plus any `# memory: <start>-<end>`
+//! regions the source asks for, and what it printed. `tests/<name>.in` is fed to the
+//! program as input. Set `UPDATE_GOLDEN=1` to rewrite the golden files from the current
+//! simulator, and check what changed by hand before committing them.
+//!
+//! A program with a `tests/<name>.trace` is also run alongside that trace and the first
+//! instruction that went differently is reported. `sum.trace` was recorded from this
+//! simulator and only catches regressions; `store_load.trace` was worked out by hand.
+//! Each line of a trace is the pc, optionally the instruction word, and the registers
+//! the instruction changed, as in
+//!
+//! ```text
+//! 0x00400024 0x21290004 $t1=0x10010004
+//! ```
+//!
+//! `hi` and `lo` name those registers and `#` starts a comment. To check against a trace
+//! captured from another simulator, point `MIPS_REFERENCE_PROGRAM` and
+//! `MIPS_REFERENCE_TRACE` at the program and its trace.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use mips_rs::{
+    assembler::{
+        assembler::{assemble, AssemblerOptions, SegmentKind},
+        dump::DumpFormat,
+    },
+    machine::{
+        dump::dump_memory,
+        register::Reg,
+        state::{disassemble, Event, Register, State, Status},
+    },
+    parser::parser::parse,
+};
+
+const MAX_STEPS: u64 = 100_000;
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut programs: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "s"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+fn number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// the machine ready to run, and the memory regions worth comparing
+fn load(program: &Path) -> (State, Vec<(u32, u32)>) {
+    let source = fs::read_to_string(program).unwrap();
+    let assembled = assemble(&parse(&source), &AssemblerOptions::default())
+        .unwrap_or_else(|e| panic!("{}: {}", program.display(), e.message));
+    let mut state = State::from_assembled(&assembled);
+    if let Ok(input) = fs::read(program.with_extension("in")) {
+        state.feed_input(&input);
+    }
+    let mut regions: Vec<(u32, u32)> = assembled
+        .segments
+        .iter()
+        .filter(|s| !s.kind.is_text() && s.kind != SegmentKind::Bss && !s.bytes.is_empty())
+        .map(|s| (s.start_address, s.end_address()))
+        .collect();
+    for line in source.lines() {
+        let range = match line.trim().strip_prefix("# memory:") {
+            Some(range) => range.trim(),
+            None => continue,
+        };
+        let (start, end) = range
+            .split_once('-')
+            .and_then(|(s, e)| Some((number(s.trim())?, number(e.trim())?)))
+            .unwrap_or_else(|| panic!("{}: bad memory region {}", program.display(), range));
+        regions.push((start, end));
+    }
+    (state, regions)
+}
+
+fn outcome(state: &mut State, regions: &[(u32, u32)]) -> String {
+    let stopped = loop {
+        if state.steps() >= MAX_STEPS {
+            break format!("stopped after {} steps", MAX_STEPS);
+        }
+        match state.step() {
+            Ok(Status::Running) => (),
+            Ok(Status::Exited(code)) => break format!("exited with {}", code),
+            Ok(Status::DroppedOffBottom) => break "dropped off the bottom".to_owned(),
+            Err(e) => break format!("{} at pc 0x{:08x}", e, state.read_pc()),
+        }
+    };
+    let mut out = format!("{}\nsteps {}\n\n", stopped, state.steps());
+    for (r, value) in state.dump_reg()[..32].iter().enumerate() {
+        out += &format!("{:>5} 0x{:08x}\n", String::from(Reg::from(r as u8)), value);
+    }
+    out += &format!("{:>5} 0x{:08x}\n", "pc", state.read_pc());
+    out += &format!("{:>5} 0x{:08x}\n", "hi", state.read_hi());
+    out += &format!("{:>5} 0x{:08x}\n", "lo", state.read_lo());
+    for (start, end) in regions {
+        out += &format!("\nmemory 0x{:08x}-0x{:08x}\n", start, end);
+        let dump = dump_memory(state, *start, *end, DumpFormat::HexTextWithAddresses);
+        out += &String::from_utf8_lossy(&dump);
+    }
+    out += "\noutput\n";
+    out += &String::from_utf8_lossy(&state.take_output());
+    out
+}
+
+#[test]
+fn golden() {
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+    let mut failures = Vec::new();
+    for program in programs() {
+        let (mut state, regions) = load(&program);
+        let actual = outcome(&mut state, &regions);
+        let golden = program
+            .with_file_name("golden")
+            .join(program.file_stem().unwrap())
+            .with_extension("txt");
+        if update {
+            fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+        let expected = match fs::read_to_string(&golden) {
+            Ok(expected) => expected,
+            Err(_) => {
+                failures.push(format!(
+                    "{}: no golden file, run with UPDATE_GOLDEN=1 to make one",
+                    golden.display()
+                ));
+                continue;
+            }
+        };
+        let mut expected_lines = expected.lines();
+        let mut actual_lines = actual.lines();
+        for n in 1.. {
+            match (expected_lines.next(), actual_lines.next()) {
+                (None, None) => break,
+                (e, a) if e == a => (),
+                (e, a) => {
+                    failures.push(format!(
+                        "{}:{}: expected {:?}, got {:?}",
+                        golden.display(),
+                        n,
+                        e.unwrap_or("end of file"),
+                        a.unwrap_or("end of output")
+                    ));
+                    break;
+                }
+            }
+        }
+    }
+    if !failures.is_empty() {
+        panic!("\n{}", failures.join("\n"));
+    }
+}
+
+fn register_name(r: Register) -> String {
+    match r {
+        Register::General(n) => String::from(Reg::from(n)),
+        Register::Hi => "hi".to_owned(),
+        Register::Lo => "lo".to_owned(),
+    }
+}
+
+fn register(name: &str) -> Option<Register> {
+    let name = name.to_lowercase();
+    match name.as_str() {
+        "hi" | "$hi" => Some(Register::Hi),
+        "lo" | "$lo" => Some(Register::Lo),
+        // Reg::from panics on names it doesn't know
+        _ => (0..32u8)
+            .find(|n| {
+                register_name(Register::General(*n)) == name
+                    || name.strip_prefix('$').and_then(number) == Some(u32::from(*n))
+            })
+            .map(Register::General),
+    }
+}
+
+fn read_register(state: &State, r: Register) -> u32 {
+    match r {
+        Register::General(n) => state.read_reg(n),
+        Register::Hi => state.read_hi(),
+        Register::Lo => state.read_lo(),
+    }
+}
+
+struct TraceLine {
+    line: usize,
+    pc: u32,
+    word: Option<u32>,
+    changes: Vec<(Register, u32)>,
+}
+
+fn read_trace(trace: &Path) -> Vec<TraceLine> {
+    let text = fs::read_to_string(trace).unwrap_or_else(|e| panic!("{}: {}", trace.display(), e));
+    let mut lines = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let bad = |what: &str| -> ! { panic!("{}:{}: bad {}", trace.display(), i + 1, what) };
+        let mut fields = line
+            .split('#')
+            .next()
+            .unwrap()
+            .split_whitespace()
+            .peekable();
+        let pc = match fields.next() {
+            Some(pc) => number(pc).unwrap_or_else(|| bad("pc")),
+            None => continue,
+        };
+        let word = match fields.peek() {
+            Some(word) if !word.contains('=') => {
+                Some(number(fields.next().unwrap()).unwrap_or_else(|| bad("instruction word")))
+            }
+            _ => None,
+        };
+        let mut changes = Vec::new();
+        for change in fields {
+            let (name, value) = change.split_once('=').unwrap_or_else(|| bad(change));
+            let r = register(name).unwrap_or_else(|| bad(name));
+            changes.push((r, number(value).unwrap_or_else(|| bad(value))));
+        }
+        lines.push(TraceLine {
+            line: i + 1,
+            pc,
+            word,
+            changes,
+        });
+    }
+    lines
+}
+
+// where the simulator first parts ways with the trace, if it does
+fn diverges(program: &Path, trace: &Path) -> Option<String> {
+    let (mut state, _) = load(program);
+    for expected in read_trace(trace) {
+        let pc = state.read_pc();
+        let word = state.memory().read_u32(pc);
+        let at = format!(
+            "{}:{}: step {} at 0x{:08x} ({})",
+            trace.display(),
+            expected.line,
+            state.steps(),
+            pc,
+            disassemble(word, pc)
+        );
+        if state.status() != Status::Running {
+            return Some(format!("{}: the program already stopped", at));
+        }
+        if pc != expected.pc {
+            return Some(format!("{}: expected pc 0x{:08x}", at, expected.pc));
+        }
+        if let Some(w) = expected.word.filter(|w| *w != word) {
+            return Some(format!(
+                "{}: expected 0x{:08x} ({}), got 0x{:08x}",
+                at,
+                w,
+                disassemble(w, pc),
+                word
+            ));
+        }
+        state.record_events(true);
+        let stepped = state.step();
+        let events = state.take_events();
+        state.record_events(false);
+        if let Err(e) = stepped {
+            return Some(format!("{}: {}", at, e));
+        }
+        let changed: Vec<Register> = events
+            .iter()
+            .filter_map(|e| match *e {
+                Event::Register { register, old, new } if old != new => Some(register),
+                _ => None,
+            })
+            .collect();
+        for (r, value) in &expected.changes {
+            let got = read_register(&state, *r);
+            if got != *value {
+                return Some(format!(
+                    "{}: expected {}=0x{:08x}, got 0x{:08x}",
+                    at,
+                    register_name(*r),
+                    value,
+                    got
+                ));
+            }
+        }
+        if let Some(r) = changed
+            .iter()
+            .find(|r| !expected.changes.iter().any(|(e, _)| e == *r))
+        {
+            return Some(format!(
+                "{}: {} changed to 0x{:08x}, the trace leaves it alone",
+                at,
+                register_name(*r),
+                read_register(&state, *r)
+            ));
+        }
+    }
+    None
+}
+
+#[test]
+fn traces() {
+    let mut pairs: Vec<(PathBuf, PathBuf)> = programs()
+        .into_iter()
+        .map(|p| {
+            let trace = p.with_extension("trace");
+            (p, trace)
+        })
+        .filter(|(_, trace)| trace.exists())
+        .collect();
+    if let (Some(program), Some(trace)) = (
+        env::var_os("MIPS_REFERENCE_PROGRAM"),
+        env::var_os("MIPS_REFERENCE_TRACE"),
+    ) {
+        pairs.push((program.into(), trace.into()));
+    }
+    let failures: Vec<String> = pairs
+        .iter()
+        .filter_map(|(program, trace)| diverges(program, trace))
+        .collect();
+    if !failures.is_empty() {
+        panic!("\n{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,69 @@
+//! Drives the GDB server through the in-repo client over a Unix socket pair.
+#![cfg(unix)]
+
+mod common;
+
+use std::{os::unix::net::UnixStream, thread};
+
+use mips_rs::{
+    gdb::{Client, GdbServer},
+    machine::state::State,
+};
+
+const SUM: &str = "
+.text
+main:
+    addi $t0, $zero, 5
+    addi $t1, $zero, 7
+stop:
+    add  $t2, $t0, $t1
+    addi $v0, $zero, 10
+    syscall
+";
+
+// GDB's numbers for the registers used here
+const T0: usize = 8;
+const T2: usize = 10;
+const PC: usize = 37;
+
+#[test]
+fn breakpoint_session() {
+    let assembled = common::assembled(SUM);
+    let state = State::from_assembled(&assembled);
+    let main = assembled.labels["main"];
+    let stop = assembled.labels["stop"];
+
+    // the machine stays on this thread, as devices aren't Send
+    let (mut conn, stream) = UnixStream::pair().unwrap();
+    let client = thread::spawn(move || {
+        let mut client = Client::new(stream);
+
+        // all 72 registers, each four bytes in the target's (little-endian) order
+        let registers = client.request("g").unwrap();
+        assert_eq!(registers.len(), 72 * 8);
+        assert_eq!(&registers[PC * 8..][..8], hex_le(main));
+        assert_eq!(&registers[T0 * 8..][..8], "00000000");
+
+        assert_eq!(client.request(&format!("Z0,{:x},4", stop)).unwrap(), "OK");
+        assert_eq!(client.request("c").unwrap(), "T05");
+        assert_eq!(client.read_register(PC, false).unwrap(), stop);
+        assert_eq!(client.read_register(T0, false).unwrap(), 5);
+        assert_eq!(client.read_register(T2, false).unwrap(), 0);
+        assert_eq!(client.request("s").unwrap(), "S05");
+        assert_eq!(client.read_register(T2, false).unwrap(), 12);
+
+        // the program has exited, so there are no registers left to read
+        assert_eq!(client.request("c").unwrap(), "W00");
+        client.kill().unwrap();
+    });
+    GdbServer::new(state).serve(&mut conn).unwrap();
+    client.join().unwrap();
+}
+
+fn hex_le(value: u32) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -0,0 +1,165 @@
+//! Runs a small program forwards and back again, checking the machine against copies
+//! taken on the way, including going back further than the undo records reach.
+
+mod common;
+
+use std::convert::TryFrom;
+
+use common::machine;
+use mips_rs::machine::{
+    history::History,
+    register::Reg,
+    state::{State, Status},
+    watch::{Condition, Trigger, Watchpoints},
+};
+
+// adds 1, 2, 3, 4 and 5 into x, which holds 1, 3, 6, 10 and 15 in turn; 34 steps
+const ACCUMULATE: &str = "
+.data
+x: .word 0
+.text
+main:
+    lui  $s0, 0x1001
+    addi $t0, $zero, 0
+loop:
+    addi $t0, $t0, 1
+    lw   $t1, 0($s0)
+    add  $t1, $t1, $t0
+    sw   $t1, 0($s0)
+    slti $t2, $t0, 5
+    bne  $t2, $zero, loop
+    addi $v0, $zero, 10
+    syscall
+";
+
+const X: u32 = 0x1001_0000;
+const LW: u32 = 0x0040_000c;
+const SW: u32 = 0x0040_0014;
+const STEPS: u64 = 34;
+
+// what a step can change: the registers, pc, hi and lo, and the data
+#[derive(Debug, PartialEq, Eq)]
+struct View {
+    steps: u64,
+    pc: u32,
+    registers: Vec<u32>,
+    hi_lo: (u32, u32),
+    data: Vec<u8>,
+}
+
+fn view(state: &State) -> View {
+    View {
+        steps: state.steps(),
+        pc: state.read_pc(),
+        registers: (0..32u8).map(|r| state.read_reg(r)).collect(),
+        hi_lo: (state.read_hi(), state.read_lo()),
+        data: state.memory().read_bytes(X, 16),
+    }
+}
+
+// runs to the end, viewing the machine before every step and once it's finished
+fn run(state: &mut State, history: &mut History) -> Vec<View> {
+    let mut views = vec![view(state)];
+    while history.step(state) == Ok(Status::Running) {
+        views.push(view(state));
+    }
+    views.push(view(state));
+    assert_eq!(state.status(), Status::Exited(0));
+    assert_eq!(state.steps(), STEPS);
+    views
+}
+
+#[test]
+fn step_back() {
+    let mut state = machine(ACCUMULATE);
+    let mut history = History::new(&mut state);
+    let views = run(&mut state, &mut history);
+    assert_eq!(history.earliest(&state), 0);
+
+    assert_eq!(history.step_back(&mut state, 1), 1);
+    assert_eq!(state.status(), Status::Running);
+    assert_eq!(view(&state), views[STEPS as usize - 1]);
+    // back over the last store, which put 15 in x
+    assert_eq!(history.step_back(&mut state, 4), 4);
+    assert_eq!(view(&state), views[STEPS as usize - 5]);
+    assert_eq!(state.read_pc(), SW);
+    assert_eq!(state.memory().read_u32(X), 10);
+
+    assert_eq!(history.step_back(&mut state, 100), STEPS - 5);
+    assert_eq!(view(&state), views[0]);
+    assert_eq!(history.step_back(&mut state, 1), 0);
+
+    // and forwards again to the same end
+    assert_eq!(run(&mut state, &mut history), views[..]);
+}
+
+#[test]
+fn past_the_undo_records() {
+    // three undo records and a checkpoint every four steps, the newest eight of them:
+    // steps 4 to 32, so step 4 is as far back as it goes
+    let mut state = machine(ACCUMULATE);
+    let mut history = History::new(&mut state);
+    history.limit = 3;
+    history.checkpoint_interval = 4;
+    let views = run(&mut state, &mut history);
+    assert_eq!(history.earliest(&state), 4);
+
+    // each step back past the three records restores the checkpoint before it and
+    // replays forward from there
+    for steps in (4..STEPS).rev() {
+        assert_eq!(history.step_back(&mut state, 1), 1);
+        assert_eq!(view(&state), views[steps as usize], "at step {}", steps);
+    }
+    assert_eq!(history.step_back(&mut state, 1), 0);
+    assert_eq!(state.steps(), 4);
+
+    // a state with fewer steps than the history has records doesn't underflow
+    let mut state = machine(ACCUMULATE);
+    let mut history = History::new(&mut state);
+    run(&mut state, &mut history);
+    assert_eq!(history.earliest(&machine(ACCUMULATE)), 0);
+}
+
+#[test]
+fn reverse_continue() {
+    let mut state = machine(ACCUMULATE);
+    let mut history = History::new(&mut state);
+    run(&mut state, &mut history);
+
+    // back to just before each store into x, newest first
+    let mut watchpoints = Watchpoints::new();
+    let write = watchpoints.add(
+        Trigger::Write {
+            address: X,
+            length: 4,
+        },
+        None,
+    );
+    for (before, after) in [(10, 15), (6, 10)].iter() {
+        assert_eq!(
+            history.reverse_continue(&mut state, &watchpoints),
+            Some(write)
+        );
+        assert_eq!(state.read_pc(), SW);
+        assert_eq!(state.memory().read_u32(X), *before);
+        assert_eq!(state.read_reg(Reg::t1), *after);
+    }
+
+    // a breakpoint stops with the machine about to run it, when its condition holds
+    watchpoints.remove(write);
+    let breakpoint = watchpoints.add(
+        Trigger::Execute(LW),
+        Some(Condition::try_from("$t0 == 2").unwrap()),
+    );
+    assert_eq!(
+        history.reverse_continue(&mut state, &watchpoints),
+        Some(breakpoint)
+    );
+    assert_eq!(state.read_pc(), LW);
+    assert_eq!(state.read_reg(Reg::t0), 2);
+    assert_eq!(state.memory().read_u32(X), 1);
+
+    // nothing further back, so it goes all the way to the start
+    assert_eq!(history.reverse_continue(&mut state, &watchpoints), None);
+    assert_eq!(state.steps(), 0);
+}
@@ -0,0 +1,281 @@
+//! Runs small programs through the pipeline, cache and branch predictor models and the
+//! instruction statistics, and checks what they count against numbers worked out by hand.
+
+mod common;
+
+use std::{collections::BTreeMap, convert::TryFrom};
+
+use common::machine;
+use mips_rs::machine::{
+    cache::{Cache, CacheConfig, CacheStats, Caches},
+    pipeline::{Pipeline, PipelineOptions, Stalls, Timing},
+    predictor::{BranchPredictor, PredictorKind},
+    state::{InstructionClass, State, Status},
+    stats::Statistics,
+};
+
+// a load whose result is used by the very next instruction
+const LOAD_USE: &str = "
+.data
+x: .word 5
+.text
+main:
+    lui  $t0, 0x1001
+    lw   $t1, 0($t0)
+    add  $t2, $t1, $t1
+    addi $v0, $zero, 10
+    syscall
+";
+
+// two words 64 bytes apart, which share a set in a 64 byte direct mapped cache
+const CONFLICT: &str = "
+.data
+a: .word 1
+   .space 60
+b: .word 2
+.text
+main:
+    lui  $t0, 0x1001
+    lw   $t1, 0($t0)
+    lw   $t2, 64($t0)
+    lw   $t3, 0($t0)
+    lw   $t4, 4($t0)
+    addi $v0, $zero, 10
+    syscall
+";
+
+// an inner loop of four inside an outer loop of three
+const NESTED_LOOPS: &str = "
+.text
+main:
+    addi $s0, $zero, 3
+outer:
+    addi $t0, $zero, 4
+inner:
+    addi $t0, $t0, -1
+    bne  $t0, $zero, inner
+    addi $s0, $s0, -1
+    bne  $s0, $zero, outer
+    addi $v0, $zero, 10
+    syscall
+";
+
+// counts x down from 3, calling bump each time round
+const CALLS: &str = "
+.data
+x: .word 3
+.text
+main:
+    lui  $s0, 0x1001
+    lw   $t0, 0($s0)
+loop:
+    jal  bump
+    addi $t0, $t0, -1
+    bne  $t0, $zero, loop
+    sw   $t1, 4($s0)
+    addi $v0, $zero, 10
+    syscall
+bump:
+    sll  $t1, $t1, 1
+    ori  $t1, $t1, 1
+    jr   $ra
+";
+
+fn pipelined(source: &str, options: PipelineOptions) -> Pipeline {
+    let mut state = machine(source);
+    let mut pipeline = Pipeline::new(options);
+    pipeline.keep_diagram(usize::MAX);
+    while pipeline.step(&mut state) == Ok(Status::Running) {}
+    assert_eq!(state.status(), Status::Exited(0));
+    pipeline
+}
+
+fn data_cache(source: &str, config: &str) -> Cache {
+    let mut state = machine(source);
+    let mut caches = Caches {
+        data: Some(Cache::new(CacheConfig::try_from(config).unwrap())),
+        ..Caches::default()
+    };
+    state.record_events(true);
+    loop {
+        let pc = state.read_pc();
+        let status = state.step();
+        caches.record(pc, &state.take_events());
+        if status != Ok(Status::Running) {
+            break;
+        }
+    }
+    assert_eq!(state.status(), Status::Exited(0));
+    caches.data.unwrap()
+}
+
+fn predicted(source: &str, kind: PredictorKind) -> (State, BranchPredictor) {
+    let mut state = machine(source);
+    let mut predictor = BranchPredictor::new(kind, 4);
+    loop {
+        let pc = state.read_pc();
+        let status = state.step();
+        predictor.record(pc, &state);
+        if status != Ok(Status::Running) {
+            break;
+        }
+    }
+    assert_eq!(state.status(), Status::Exited(0));
+    (state, predictor)
+}
+
+#[test]
+fn load_use_stall() {
+    // with forwarding the add waits one cycle in ID for the loaded value to come out of
+    // MEM/WB: 5 instructions, 4 cycles to fill the pipeline and 1 stall
+    let pipeline = pipelined(LOAD_USE, PipelineOptions::default());
+    assert_eq!(pipeline.instructions(), 5);
+    assert_eq!(pipeline.cycles(), 10);
+    assert_eq!(
+        pipeline.stalls(),
+        Stalls {
+            load_use: 1,
+            ..Stalls::default()
+        }
+    );
+    assert_eq!(
+        pipeline.timings()[2].1,
+        Timing {
+            fetch: 3,
+            decode: 4,
+            execute: 6,
+            memory: 7,
+            write_back: 8,
+        }
+    );
+
+    // without it every value is read from the register file in the cycle it's written
+    // back, so the lw waits 2 cycles for $t0, the add 2 for $t1 and the syscall 2 for $v0
+    let pipeline = pipelined(
+        LOAD_USE,
+        PipelineOptions {
+            forward_ex_mem: false,
+            forward_mem_wb: false,
+            ..PipelineOptions::default()
+        },
+    );
+    assert_eq!(pipeline.cycles(), 15);
+    assert_eq!(
+        pipeline.stalls(),
+        Stalls {
+            load_use: 2,
+            data: 4,
+            ..Stalls::default()
+        }
+    );
+}
+
+#[test]
+fn conflict_miss() {
+    // four sets of 16 bytes: a and b are both in set 0, so loading b throws a out and
+    // loading a again misses, though a fully associative cache would still have it. The
+    // load from a + 4 is in the same block and hits.
+    let cache = data_cache(CONFLICT, "64,block=16,direct");
+    assert_eq!(
+        *cache.stats(),
+        CacheStats {
+            reads: 4,
+            read_misses: 3,
+            compulsory: 2,
+            conflict: 1,
+            fills: 3,
+            ..CacheStats::default()
+        }
+    );
+    assert_eq!(cache.sets()[0].evictions, 2);
+    assert!(cache.sets()[1..].iter().all(|s| s.accesses == 0));
+
+    // with two ways a and b sit side by side in set 0
+    let cache = data_cache(CONFLICT, "64,block=16,ways=2");
+    assert_eq!(cache.stats().read_misses, 2);
+    assert_eq!(cache.stats().conflict, 0);
+    assert_eq!(cache.sets()[0].evictions, 0);
+}
+
+#[test]
+fn two_bit_loop() {
+    let (state, predictor) = predicted(NESTED_LOOPS, PredictorKind::TwoBit);
+    let inner = state.find_label_by_name("inner").unwrap() + 4;
+    let outer = inner + 8;
+    let site = |pc: u32| {
+        let s = &predictor.sites()[&pc];
+        (s.executed, s.taken, s.correct)
+    };
+    // the inner branch goes T T T N three times. Its counter starts weakly not taken,
+    // so the first branch is wrong, and after that only each exit is: 8 of 12 right
+    assert_eq!(site(inner), (12, 9, 8));
+    // the outer one goes T T N: wrong to start with and wrong at the end
+    assert_eq!(site(outer), (3, 2, 1));
+    assert_eq!((predictor.correct(), predictor.branches()), (9, 15));
+
+    // one bit of history also gets the first branch after each exit wrong: 6 of 12
+    let (_, predictor) = predicted(NESTED_LOOPS, PredictorKind::OneBit);
+    assert_eq!(predictor.sites()[&inner].correct, 6);
+}
+
+#[test]
+fn statistics() {
+    let mut state = machine(CALLS);
+    let mut stats = Statistics::new(&state);
+    while stats.step(&mut state) == Ok(Status::Running) {}
+    assert_eq!(state.status(), Status::Exited(0));
+    assert_eq!(state.memory().read_u32(0x1001_0004), 7);
+
+    // 2 to start, 3 times round jal, addi and bne with sll, ori and jr in bump, then 3
+    assert_eq!(stats.instructions(), 23);
+    let classes: Vec<(InstructionClass, u64)> = [
+        (InstructionClass::Arithmetic, 5),
+        (InstructionClass::Logical, 3),
+        (InstructionClass::Shift, 3),
+        (InstructionClass::Load, 1),
+        (InstructionClass::Store, 1),
+        (InstructionClass::Branch, 3),
+        (InstructionClass::Jump, 6),
+        (InstructionClass::Syscall, 1),
+    ]
+    .to_vec();
+    assert_eq!(
+        classes
+            .iter()
+            .map(|(c, _)| (*c, stats.class(*c)))
+            .collect::<Vec<_>>(),
+        classes
+    );
+    assert_eq!(stats.alu(), 11);
+    // the bne goes back twice and falls through once; jumps aren't counted
+    assert_eq!((stats.branches_taken(), stats.branches_not_taken()), (2, 1));
+
+    let counts =
+        |map: &BTreeMap<String, u64>| map.iter().map(|(k, v)| (k.clone(), *v)).collect::<Vec<_>>();
+    let expected = |pairs: &[(&str, u64)]| {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        counts(stats.mnemonics()),
+        expected(&[
+            ("addi", 4),
+            ("bne", 3),
+            ("jal", 3),
+            ("jr", 3),
+            ("lui", 1),
+            ("lw", 1),
+            ("ori", 3),
+            ("sll", 3),
+            ("sw", 1),
+            ("syscall", 1),
+        ])
+    );
+    // the code after the loop still comes under its label
+    assert_eq!(
+        counts(stats.labels()),
+        expected(&[("bump", 9), ("loop", 12), ("main", 2)])
+    );
+}
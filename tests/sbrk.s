@@ -0,0 +1,17 @@
+# Grows the heap a word at a time, then asks for a negative amount
+
+.text
+main:
+    li   $v0, 9
+    li   $a0, 5
+    syscall
+    add  $s0, $v0, $zero    # $s0 = 0x10040000
+    li   $v0, 9
+    li   $a0, 4
+    syscall
+    add  $s1, $v0, $zero    # $s1 = 0x10040008, as 5 bytes took two words
+    li   $v0, 9
+    li   $a0, -3
+    syscall                 # stops the program
+    li   $v0, 10
+    syscall
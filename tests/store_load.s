@@ -1,12 +1,12 @@
 # Basic lw and beq tests
 
-.data
+.data 0x1000
 array_again:
 array:
 .word   1  255    1024   0xcafebabe
 array2: .word   1,255,    1024,   0xcafebabe
 
-.text
+.text 0x0
 main:   
     li  $v0, 0x1000 # $v0  = 0x1000 testing li
                       
@@ -30,8 +30,8 @@ main:
     lw  $t5, 0($v0) # $t5 = 0x00be0000
 
 
-    la  $t6, target     # $t6 = target = 0x0000005c
-    jr  $t6         #  PC = 0x0000005c  test indirect branches
+    la  $t6, target     # $t6 = target = 0x00000060
+    jr  $t6         #  PC = 0x00000060  test indirect branches
 
 skipped2:
 skipped:
@@ -40,7 +40,8 @@ skipped:
 
 end2:
 end:    lui $s1, 0xf00f # $s1 = 0xf00f0000 testing lui
-    jr $ra
+    li  $v0, 10     # exit
+    syscall
 
 target:
     addi    $s0, $zero, 2   # $s0 = 2
@@ -0,0 +1,39 @@
+# Reads n, fills squares with 1..n squared and prints their sum
+
+.data
+squares: .space 32
+label:   .asciiz "sum of squares: "
+
+.text
+main:
+    li   $v0, 5
+    syscall
+    add  $t0, $v0, $zero # $t0 = n, at most 8
+    la   $t1, squares
+    li   $t2, 1             # $t2 = i
+    li   $s0, 0             # $s0 = running sum
+    li   $t4, 0             # $t4 = i squared
+    li   $t5, 1             # $t5 = 2i - 1
+loop:
+    slt  $t3, $t0, $t2
+    bne  $t3, $zero, done
+    add  $t4, $t4, $t5
+    addi $t5, $t5, 2
+    sw   $t4, 0($t1)
+    add  $s0, $s0, $t4
+    addi $t1, $t1, 4
+    addi $t2, $t2, 1
+    j    loop
+done:
+    li   $v0, 4
+    la   $a0, label
+    syscall
+    li   $v0, 1
+    add  $a0, $s0, $zero
+    syscall
+    li   $v0, 11
+    li   $a0, 10
+    syscall
+    li   $v0, 17
+    li   $a0, 3
+    syscall
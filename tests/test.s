@@ -3,7 +3,9 @@ main:
 	li $t0, 1
 	add $t0, $t0, $0
 	beq $t0, $0, func
-	jr $ra
+	li $v0, 10
+	syscall
 func:
 	li $t1, 1
-	jr $ra
+	li $v0, 10
+	syscall
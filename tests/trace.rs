@@ -0,0 +1,190 @@
+//! Traces small programs in both formats and reads the records back, checking them
+//! against values worked out from the encoding by hand.
+
+mod common;
+
+use std::convert::TryInto;
+
+use mips_rs::machine::{
+    device::{Terminal, TERMINAL_ADDRESS, TERMINAL_SIZE},
+    state::{Event, State, Status},
+    trace::{TraceFilter, TraceFormat, Tracer, TRACE_MAGIC},
+};
+
+// loads a word and stores its low byte somewhere else
+const STORE_BYTE: &str = "
+.data
+x: .word 7
+.text
+main:
+    lui  $t0, 0x1001
+    lw   $t1, 0($t0)
+    sb   $t1, 5($t0)
+    addi $v0, $zero, 10
+    syscall
+";
+
+// waits for a key and reads it
+const READ_KEY: &str = "
+.text
+main:
+    lui  $t0, 0xffff
+wait:
+    lw   $t1, 0($t0)
+    andi $t1, $t1, 1
+    beq  $t1, $zero, wait
+    lw   $t2, 4($t0)
+    addi $v0, $zero, 10
+    syscall
+";
+
+const X: u32 = 0x1001_0000;
+
+fn traced(state: &mut State, format: TraceFormat) -> Vec<u8> {
+    let mut tracer = Tracer::new(Vec::new(), format, TraceFilter::default()).unwrap();
+    while tracer.step(state) == Ok(Status::Running) {}
+    assert_eq!(state.status(), Status::Exited(0));
+    tracer.finish().unwrap()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Record {
+    step: u64,
+    pc: u32,
+    word: u32,
+    // number, old and new value
+    registers: Vec<(u8, u32, u32)>,
+    // write, address, size and value
+    accesses: Vec<(bool, u32, u32, Option<u32>)>,
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> &'a [u8] {
+    let (taken, rest) = bytes.split_at(n);
+    *bytes = rest;
+    taken
+}
+
+fn read_u32(bytes: &mut &[u8]) -> u32 {
+    u32::from_le_bytes(take(bytes, 4).try_into().unwrap())
+}
+
+fn read_binary(mut bytes: &[u8]) -> Vec<Record> {
+    let bytes = &mut bytes;
+    assert_eq!(take(bytes, 7), TRACE_MAGIC);
+    assert_eq!(take(bytes, 1), [2]);
+    let mut records = Vec::new();
+    while !bytes.is_empty() {
+        let step = u64::from_le_bytes(take(bytes, 8).try_into().unwrap());
+        let (pc, word) = (read_u32(bytes), read_u32(bytes));
+        let (registers, accesses) = (read_u32(bytes), read_u32(bytes));
+        let registers = (0..registers)
+            .map(|_| (take(bytes, 1)[0], read_u32(bytes), read_u32(bytes)))
+            .collect();
+        let accesses = (0..accesses)
+            .map(|_| {
+                let flags = take(bytes, 1)[0];
+                let (address, size) = (read_u32(bytes), read_u32(bytes));
+                let value = if flags & 2 != 0 {
+                    Some(read_u32(bytes))
+                } else {
+                    None
+                };
+                (flags & 1 != 0, address, size, value)
+            })
+            .collect();
+        records.push(Record {
+            step,
+            pc,
+            word,
+            registers,
+            accesses,
+        });
+    }
+    records
+}
+
+#[test]
+fn json_lines() {
+    let trace = traced(&mut common::machine(STORE_BYTE), TraceFormat::JsonLines);
+    let lines: Vec<&str> = std::str::from_utf8(&trace).unwrap().lines().collect();
+    assert_eq!(
+        lines,
+        [
+            r#"{"step":0,"pc":4194304,"word":1007161345,"asm":"lui $t0, 0x1001","class":"arithmetic","regs":[{"reg":"$t0","old":0,"new":268500992}],"mem":[]}"#,
+            r#"{"step":1,"pc":4194308,"word":2366177280,"asm":"lw $t1, 0($t0)","class":"load","regs":[{"reg":"$t1","old":0,"new":7}],"mem":[{"op":"read","addr":268500992,"size":4,"value":7}]}"#,
+            r#"{"step":2,"pc":4194312,"word":2701721605,"asm":"sb $t1, 5($t0)","class":"store","regs":[],"mem":[{"op":"write","addr":268500997,"size":1,"value":7}]}"#,
+            r#"{"step":3,"pc":4194316,"word":537001994,"asm":"addi $v0, $zero, 10","class":"arithmetic","regs":[{"reg":"$v0","old":0,"new":10}],"mem":[]}"#,
+            r#"{"step":4,"pc":4194320,"word":12,"asm":"syscall","class":"syscall","regs":[],"mem":[]}"#,
+        ]
+    );
+}
+
+#[test]
+fn binary() {
+    let trace = traced(&mut common::machine(STORE_BYTE), TraceFormat::Binary);
+    let record = |step: u64, word: u32, registers, accesses| Record {
+        step,
+        pc: 0x0040_0000 + 4 * step as u32,
+        word,
+        registers,
+        accesses,
+    };
+    assert_eq!(
+        read_binary(&trace),
+        [
+            record(0, 0x3c08_1001, vec![(8, 0, X)], vec![]),
+            record(
+                1,
+                0x8d09_0000,
+                vec![(9, 0, 7)],
+                vec![(false, X, 4, Some(7))]
+            ),
+            record(2, 0xa109_0005, vec![], vec![(true, X + 5, 1, Some(7))]),
+            record(3, 0x2002_000a, vec![(2, 0, 10)], vec![]),
+            record(4, 0x0000_000c, vec![], vec![]),
+        ]
+    );
+}
+
+#[test]
+fn device_loads() {
+    // reading the key brings the next one into the data register, so only the value
+    // the load got says which key the program saw
+    let mut terminal = Terminal::default();
+    terminal.type_at(0, b"kl");
+    let mut state = common::machine(READ_KEY);
+    state
+        .memory_mut()
+        .attach(TERMINAL_ADDRESS, TERMINAL_SIZE, Box::new(terminal))
+        .unwrap();
+    let records = read_binary(&traced(&mut state, TraceFormat::Binary));
+    let key = records.iter().find(|r| r.word == 0x8d0a_0004).unwrap();
+    assert_eq!(
+        key.accesses,
+        [(false, TERMINAL_ADDRESS + 4, 4, Some(u32::from(b'k')))]
+    );
+    assert_eq!(key.registers, [(10, 0, u32::from(b'k'))]);
+    assert_eq!(
+        state.memory().read_u32(TERMINAL_ADDRESS + 4),
+        u32::from(b'l')
+    );
+}
+
+#[test]
+fn leaves_recording_alone() {
+    let mut state = common::machine(STORE_BYTE);
+    let mut tracer =
+        Tracer::new(Vec::new(), TraceFormat::JsonLines, TraceFilter::default()).unwrap();
+    tracer.step(&mut state).unwrap();
+    assert!(!state.is_recording_events());
+
+    // a caller that was recording still gets the events of the traced step
+    state.record_events(true);
+    tracer.step(&mut state).unwrap();
+    assert!(state.is_recording_events());
+    let loaded = state
+        .take_events()
+        .into_iter()
+        .any(|e| matches!(e, Event::Memory(a) if a.address == X && a.value == Some(7)));
+    assert!(loaded);
+}